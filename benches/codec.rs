@@ -0,0 +1,110 @@
+//! Encoding and decoding every message type, across body sizes, context
+//! counts and Dtab lengths.
+
+extern crate test;
+extern crate mux;
+
+use mux::{Tag, Msg, Tmsg, Rmsg, MuxReader, MuxWriter};
+use mux::misc::{Context, Dentry, Dtab, Header, Trace};
+use std::old_io::BufReader;
+use test::Bencher;
+
+static TAG: Tag = Tag(4, 7, 9);
+
+fn body(len: usize) -> Vec<u8> {
+    Vec::from_elem(len, 7)
+}
+
+fn contexts(n: usize) -> Vec<Context> {
+    range(0, n).map(|i| Context::new(format!("key{}", i).into_bytes(), body(16))).collect()
+}
+
+fn dtab(n: usize) -> Dtab {
+    Dtab(range(0, n).map(|i| {
+        Dentry::new(format!("/svc/{}", i), format!("/$/inet/127.0.0.1/{}", 8000 + i))
+    }).collect())
+}
+
+fn trace() -> Option<Trace> {
+    Some(Trace { span_id: 1, parent_id: 2, trace_id: 3, flags: 0 })
+}
+
+fn tdispatch(ncontexts: usize, ndentries: usize, len: usize) -> Msg {
+    Msg::Tx(TAG, Tmsg::Dispatch(contexts(ncontexts), "/svc/0".to_string(), dtab(ndentries), body(len)))
+}
+
+fn rdispatch(ncontexts: usize, len: usize) -> Msg {
+    Msg::Rx(TAG, Rmsg::DispatchOk(contexts(ncontexts), body(len)))
+}
+
+fn encode(msg: &Msg) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let written = match *msg {
+        Msg::Tx(ref tag, ref m) => buf.write_mux_framed_tmsg(tag, m),
+        Msg::Rx(ref tag, ref m) => buf.write_mux_framed_rmsg(tag, m),
+    };
+    written.unwrap();
+    buf
+}
+
+fn bench_encode(bench: &mut Bencher, msg: Msg) {
+    bench.bytes = encode(&msg).len() as u64;
+    bench.iter(|| encode(&msg));
+}
+
+fn bench_decode(bench: &mut Bencher, msg: Msg) {
+    let bytes = encode(&msg);
+    bench.bytes = bytes.len() as u64;
+    bench.iter(|| BufReader::new(bytes.as_slice()).read_mux_framed_msg().unwrap());
+}
+
+macro_rules! codec_benches {
+    ($($encode:ident, $decode:ident => $msg:expr;)*) => {
+        $(
+            #[bench]
+            fn $encode(bench: &mut Bencher) { bench_encode(bench, $msg) }
+
+            #[bench]
+            fn $decode(bench: &mut Bencher) { bench_decode(bench, $msg) }
+        )*
+    }
+}
+
+codec_benches! {
+    encode_treq_empty, decode_treq_empty => Msg::Tx(TAG, Tmsg::Req(None, Vec::new()));
+    encode_treq_traced_1k, decode_treq_traced_1k => Msg::Tx(TAG, Tmsg::Req(trace(), body(1024)));
+    encode_treq_64k, decode_treq_64k => Msg::Tx(TAG, Tmsg::Req(None, body(64 * 1024)));
+
+    encode_tdispatch_empty, decode_tdispatch_empty => tdispatch(0, 0, 0);
+    encode_tdispatch_1k, decode_tdispatch_1k => tdispatch(0, 0, 1024);
+    encode_tdispatch_64k, decode_tdispatch_64k => tdispatch(0, 0, 64 * 1024);
+    encode_tdispatch_1m, decode_tdispatch_1m => tdispatch(0, 0, 1024 * 1024);
+    encode_tdispatch_4_contexts, decode_tdispatch_4_contexts => tdispatch(4, 0, 0);
+    encode_tdispatch_32_contexts, decode_tdispatch_32_contexts => tdispatch(32, 0, 0);
+    encode_tdispatch_4_dentries, decode_tdispatch_4_dentries => tdispatch(0, 4, 0);
+    encode_tdispatch_32_dentries, decode_tdispatch_32_dentries => tdispatch(0, 32, 0);
+    encode_tdispatch_typical, decode_tdispatch_typical => tdispatch(4, 4, 1024);
+
+    encode_tdrain, decode_tdrain => Msg::Tx(TAG, Tmsg::Drain);
+    encode_tping, decode_tping => Msg::Tx(TAG, Tmsg::Ping);
+    encode_tdiscarded, decode_tdiscarded => Msg::Tx(Tag(0, 0, 0), Tmsg::Discarded(TAG, "timed out".to_string()));
+    encode_tlease, decode_tlease => Msg::Tx(Tag(0, 0, 0), Tmsg::Lease(0, 30 * 1000));
+    encode_tinit, decode_tinit => Msg::Tx(TAG, Tmsg::Init(1, vec![Header::new(b"tls".to_vec(), b"off".to_vec())]));
+
+    encode_rreq_ok_1k, decode_rreq_ok_1k => Msg::Rx(TAG, Rmsg::ReqOk(body(1024)));
+    encode_rreq_error, decode_rreq_error => Msg::Rx(TAG, Rmsg::ReqError("failed".to_string()));
+    encode_rreq_nack, decode_rreq_nack => Msg::Rx(TAG, Rmsg::ReqNack);
+
+    encode_rdispatch_empty, decode_rdispatch_empty => rdispatch(0, 0);
+    encode_rdispatch_1k, decode_rdispatch_1k => rdispatch(0, 1024);
+    encode_rdispatch_64k, decode_rdispatch_64k => rdispatch(0, 64 * 1024);
+    encode_rdispatch_1m, decode_rdispatch_1m => rdispatch(0, 1024 * 1024);
+    encode_rdispatch_32_contexts, decode_rdispatch_32_contexts => rdispatch(32, 0);
+    encode_rdispatch_error, decode_rdispatch_error => Msg::Rx(TAG, Rmsg::DispatchError(contexts(4), "failed".to_string()));
+    encode_rdispatch_nack, decode_rdispatch_nack => Msg::Rx(TAG, Rmsg::DispatchNack(contexts(4)));
+
+    encode_rdrain, decode_rdrain => Msg::Rx(TAG, Rmsg::Drain);
+    encode_rping, decode_rping => Msg::Rx(TAG, Rmsg::Ping);
+    encode_rinit, decode_rinit => Msg::Rx(TAG, Rmsg::Init(1, Vec::new()));
+    encode_rerr, decode_rerr => Msg::Rx(TAG, Rmsg::Err("unknown message".to_string()));
+}
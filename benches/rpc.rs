@@ -0,0 +1,123 @@
+//! End-to-end calls through client and server sessions over loopback TCP.
+//!
+//! `bench_rpc_latency` prints latency percentiles, which `#[bench]` doesn't
+//! report itself.
+
+extern crate test;
+extern crate time;
+extern crate mux;
+
+use mux::{Tmsg, Rmsg};
+use mux::batch::Batched;
+use mux::misc::Dtab;
+use mux::server::{Request, Server};
+use mux::session::ClientSession;
+use mux::transport::Transport;
+use std::old_io::{Acceptor, Listener};
+use std::old_io::net::tcp::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread::Thread;
+use test::Bencher;
+use time::precise_time_ns;
+
+fn echo(req: Request) -> Rmsg {
+    Rmsg::DispatchOk(Vec::new(), req.body)
+}
+
+/// Serves echo responses on an ephemeral port, returning its address.
+fn serve(batched: bool) -> String {
+    let mut listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = format!("{}", listener.socket_name().unwrap());
+    let mut acceptor = listener.listen().unwrap();
+    Thread::spawn(move|| {
+        let server = Server::new(echo);
+        for conn in acceptor.incoming() {
+            let conn = match conn { Err(_) => break, Ok(conn) => conn };
+            if batched {
+                server.serve(Batched::new(conn));
+            } else {
+                server.serve(conn);
+            }
+        }
+    });
+    addr
+}
+
+fn connect<T: Transport, F: FnOnce(TcpStream) -> T>(batched: bool, f: F) -> ClientSession<T> {
+    ClientSession::new(f(TcpStream::connect(serve(batched).as_slice()).unwrap()))
+}
+
+fn dispatch(len: usize) -> Tmsg {
+    Tmsg::Dispatch(Vec::new(), "/".to_string(), Dtab::empty(), Vec::from_elem(len, 7))
+}
+
+fn bench_serial(bench: &mut Bencher, len: usize) {
+    let client = connect(false, |c| c);
+    let req = dispatch(len);
+    bench.bytes = len as u64;
+    bench.iter(|| client.call(&req).unwrap());
+}
+
+/// Each iteration issues `n` concurrent calls.
+fn bench_concurrent<T: Transport>(bench: &mut Bencher, client: ClientSession<T>, n: usize, len: usize) {
+    let client = Arc::new(client);
+    let req = Arc::new(dispatch(len));
+    bench.bytes = (n * len) as u64;
+    bench.iter(|| {
+        let calls: Vec<_> = range(0, n).map(|_| {
+            let (client, req) = (client.clone(), req.clone());
+            Thread::scoped(move|| client.call(&*req).unwrap())
+        }).collect();
+        for call in calls.into_iter() {
+            call.join().ok();
+        }
+    });
+}
+
+#[bench]
+fn bench_rpc_ping(bench: &mut Bencher) {
+    let client = connect(false, |c| c);
+    bench.iter(|| client.ping().unwrap());
+}
+
+#[bench]
+fn bench_rpc_empty(bench: &mut Bencher) {
+    bench_serial(bench, 0);
+}
+
+#[bench]
+fn bench_rpc_1k(bench: &mut Bencher) {
+    bench_serial(bench, 1024);
+}
+
+#[bench]
+fn bench_rpc_64k(bench: &mut Bencher) {
+    bench_serial(bench, 64 * 1024);
+}
+
+#[bench]
+fn bench_rpc_concurrent_1k(bench: &mut Bencher) {
+    bench_concurrent(bench, connect(false, |c| c), 16, 1024);
+}
+
+#[bench]
+fn bench_rpc_concurrent_1k_batched(bench: &mut Bencher) {
+    bench_concurrent(bench, connect(true, |c| Batched::new(c)), 16, 1024);
+}
+
+#[bench]
+fn bench_rpc_latency(bench: &mut Bencher) {
+    let client = connect(false, |c| c);
+    let req = dispatch(1024);
+    let mut latencies = Vec::new();
+    bench.iter(|| {
+        let t0 = precise_time_ns();
+        client.call(&req).unwrap();
+        latencies.push(precise_time_ns() - t0);
+    });
+
+    latencies.sort();
+    let pct = |p: usize| latencies[(latencies.len() - 1) * p / 1000] / 1000;
+    println!("\nrpc latency (us) over {} calls: p50={} p90={} p99={} p999={} max={}",
+             latencies.len(), pct(500), pct(900), pct(990), pct(999), pct(1000));
+}
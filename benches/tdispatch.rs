@@ -1,28 +1,16 @@
 extern crate test;
 extern crate mux;
 
+use mux::{Tag, Tmsg, MuxReader, MuxWriter, frame_tmsg};
 use mux::misc::{Context, Dentry, Dtab};
-use mux::proto::{Tag, Msg, MsgType};
-use mux::reader::MuxReader;
-use mux::writer::MuxWriter;
-use std::io::{BufReader, MemWriter};
+use std::old_io::{BufReader, IoResult, MemWriter, Writer};
 use test::Bencher;
 
-#[inline]
-fn read(buf: &[u8]) {
-    BufReader::new(buf).read_mux_frame().ok();
-}
-
-#[inline]
-fn write(tag: &Tag, msg: &Msg) {
-    MemWriter::new().write_mux_frame(tag, msg).ok();
-}
-
 static TDISPATCH_BUF: &'static [u8] = &[
-    0, 0, 0, 0, 0, 0, 0, 65, // frame size
+    0, 0, 0, 65, // frame size
 
     2, // type: TDISPATCH
-    0, 1, 2, // tag
+    4, 7, 9, // tag
 
     // contexts:
     0, 2, // 2 contexts
@@ -57,23 +45,99 @@ static TDISPATCH_BUF: &'static [u8] = &[
     // data: [0 .. 20)
     0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19];
 
+static TAG: Tag = Tag(4, 7, 9);
+
+fn tdispatch(body: Vec<u8>) -> Tmsg {
+    Tmsg::Dispatch(
+        vec![Context::new(vec![1,2,3,4], vec![6,7]),
+             Context::new(vec![3,4], vec![6,7,8])],
+        "/BAD".to_string(),
+        Dtab(vec![Dentry::new("/BAD".to_string(), "/DAD".to_string())]),
+        body)
+}
+
+/// Frames a message the way the writer did before `encoded_len`: encoded
+/// into a temporary buffer to learn its length, then copied.
+fn write_buffered(tag: &Tag, msg: &Tmsg) {
+    let mut buf = MemWriter::new();
+    buf.write_mux_tmsg(tag, msg).unwrap();
+    let frame = buf.into_inner();
+    let mut out = Vec::with_capacity(4 + frame.len());
+    out.write_be_u32(frame.len() as u32).and_then(|_| out.write(frame.as_slice())).unwrap();
+}
+
+fn write_direct(tag: &Tag, msg: &Tmsg) {
+    let mut out = Vec::new();
+    frame_tmsg(&mut out, tag, msg).unwrap();
+}
+
+/// Counts writes, as syscalls on an unbuffered connection.
+struct Syscalls(usize);
+
+impl Writer for Syscalls {
+    fn write(&mut self, _: &[u8]) -> IoResult<()> {
+        self.0 += 1;
+        Ok(())
+    }
+}
+
 #[bench]
 fn bench_read_tdispatch(bench: &mut Bencher) {
-    bench.iter(|| read(TDISPATCH_BUF));
+    bench.iter(|| BufReader::new(TDISPATCH_BUF).read_mux_framed_tmsg().unwrap());
     bench.bytes = TDISPATCH_BUF.len() as u64;
 }
 
 #[bench]
 fn bench_write_tdispatch(bench: &mut Bencher) {
-    let msg = Msg::Tdispatch(
-        vec![Context::new(vec![1,2,3,4], vec![6,7]),
-             Context::new(vec![3,4], vec![6,7,8])],
-        "/BAD".to_string(),
-        Dtab(vec![Dentry::new("/BAD".to_string(), "/DAD".to_string())]),
-        vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19]);
+    let msg = tdispatch(range(0, 20).collect());
+    bench.iter(|| write_direct(&TAG, &msg));
+    bench.bytes = TDISPATCH_BUF.len() as u64;
+}
+
+#[bench]
+fn bench_write_tdispatch_buffered(bench: &mut Bencher) {
+    let msg = tdispatch(range(0, 20).collect());
+    bench.iter(|| write_buffered(&TAG, &msg));
+    bench.bytes = TDISPATCH_BUF.len() as u64;
+}
+
+#[bench]
+fn bench_write_tdispatch_64k(bench: &mut Bencher) {
+    let msg = tdispatch(Vec::from_elem(64 * 1024, 7));
+    bench.iter(|| write_direct(&TAG, &msg));
+    bench.bytes = 4 + msg.encoded_len() as u64;
+}
 
-    let tag = Tag(4, 7, 9);
+#[bench]
+fn bench_write_tdispatch_64k_buffered(bench: &mut Bencher) {
+    let msg = tdispatch(Vec::from_elem(64 * 1024, 7));
+    bench.iter(|| write_buffered(&TAG, &msg));
+    bench.bytes = 4 + msg.encoded_len() as u64;
+}
+
+/// Frames into a buffer and writes it in one call, as
+/// `Transport::write_frame` does for an unbuffered connection.
+#[bench]
+fn bench_write_tdispatch_unbuffered(bench: &mut Bencher) {
+    let msg = tdispatch(range(0, 20).collect());
+    let mut conn = Syscalls(0);
+    bench.iter(|| {
+        let mut buf = Vec::new();
+        frame_tmsg(&mut buf, &TAG, &msg).and_then(|_| conn.write(buf.as_slice())).unwrap()
+    });
+    bench.bytes = TDISPATCH_BUF.len() as u64;
+}
 
-    bench.iter(|| write(&tag, &msg));
+/// Encodes straight into a buffered writer.
+#[bench]
+fn bench_write_tdispatch_memwriter(bench: &mut Bencher) {
+    let msg = tdispatch(range(0, 20).collect());
+    bench.iter(|| MemWriter::new().write_mux_framed_tmsg(&TAG, &msg).unwrap());
     bench.bytes = TDISPATCH_BUF.len() as u64;
 }
+
+#[bench]
+fn bench_encoded_len(bench: &mut Bencher) {
+    let msg = tdispatch(range(0, 20).collect());
+    bench.iter(|| msg.encoded_len());
+}
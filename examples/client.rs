@@ -1,29 +1,32 @@
 //! Stupid awful single-threaded Mux client
 
+#[macro_use] extern crate log;
+extern crate env_logger;
 extern crate mux;
 
 use std::clone::Clone;
 use std::old_io::net::tcp::TcpStream;
 use std::old_io::timer::Timer;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUint, Ordering};
 use std::thread::Thread;
 use std::time::Duration;
 
 use mux::*;
+use mux::metrics::Counter;
 use mux::misc::*;
+use mux::transport::Transport;
 
 #[allow(unstable)]
 fn main() {
+    env_logger::init().unwrap();
     let dst = "127.0.0.1:6666";
-    let ctr = Arc::new(AtomicUint::new(0));
+    let ctr = Counter::new();
     let read_ctr = ctr.clone();
     Thread::spawn(move|| {
         let mut timer = Timer::new().unwrap();
         let mut last: usize = 0;
         loop {
-            let current = read_ctr.load(Ordering::SeqCst);
-            println!("{} rps", (current - last) / 2);
+            let current = read_ctr.get();
+            info!("{} rps", (current - last) / 2);
             last = current;
             timer.sleep(Duration::seconds(2));
         }
@@ -37,48 +40,48 @@ fn main() {
 
     loop {
         match TcpStream::connect(dst) {
-            Err(_) => println!("connect error"),
+            Err(_) => warn!("connect error"),
 
             Ok(mut conn) => {
                 let id = format!("{}", conn.socket_name().unwrap());
-                println!("-- {}: connected: {}", dst, id);
+                info!("-- {}: connected: {}", dst, id);
                 //conn.set_read_timeout(Some(50));
                 //conn.set_write_timeout(Some(50));
 
                 loop {
-                    //println!("{}: writing: {}", id, tmsg)
-                    match conn.write_mux_framed_tmsg(&Tag(1,2,3), &tmsg) {
+                    //debug!("{}: writing: {}", id, tmsg)
+                    match conn.write_frame(|buf| frame_tmsg(buf, &Tag(1,2,3), &tmsg)) {
                         Err(ioe) => {
-                            println!("{}: write error: {}", id, ioe);
+                            warn!("{}: write error: {}", id, ioe);
                             break;
                         },
                         Ok(_) => ()
                     };
                     match conn.flush() {
                         Err(ioe) => {
-                            println!("{}: flush error: {}", id, ioe);
+                            warn!("{}: flush error: {}", id, ioe);
                             break;
                         },
                         Ok(_) => ()
                     };
-                    //println!("{}: wrote: {}", id, tmsg);
+                    //debug!("{}: wrote: {}", id, tmsg);
 
                     let (_, _) = match conn.read_mux_framed_rmsg() {
                         Err(ioe) => {
-                            println!("{}: read error: {}", id, ioe);
+                            warn!("{}: read error: {}", id, ioe);
                             break;
                         },
 
                         Ok(framed) => framed
                     };
-                    //println!("{}: read: {}", id, msg);
+                    //debug!("{}: read: {}", id, msg);
 
-                    ctr.fetch_add(1, Ordering::SeqCst);
+                    ctr.incr();
                 }
 
                 conn.close_read().ok();
                 conn.close_write().ok();
-                println!("-- {}: disconnected", id);
+                info!("-- {}: disconnected", id);
             }
         }
     }
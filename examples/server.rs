@@ -1,32 +1,37 @@
 //! Simplistic mux echo server
 // Only serves one client at a time.  Kinda sucks.  A lot.
 
+#[macro_use] extern crate log;
+extern crate env_logger;
 extern crate mux;
 
 use std::old_io::{Acceptor, Listener};
 use std::old_io::net::tcp::TcpListener;
 use std::old_io::timer::Timer;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicUint, Ordering};
 use std::thread::Thread;
 use std::time::Duration;
 
 use mux::*;
+use mux::batch::Batched;
+use mux::framebuf::FrameBuf;
+use mux::metrics::Counter;
+use mux::transport::Transport;
 
 #[allow(unstable)]
 fn main() {
-    let ctr_arc = Arc::new(AtomicUint::new(0));
+    env_logger::init().unwrap();
+    let requests = Counter::new();
 
     // log rps periodically:
-    let read_ctr = ctr_arc.clone();
+    let read_ctr = requests.clone();
     Thread::spawn(move|| {
         let mut timer = Timer::new().unwrap();
         let mut last: usize = 0;
         loop {
-            let current = read_ctr.load(Ordering::SeqCst);
+            let current = read_ctr.get();
             let delta = (current - last) / 60;
             if delta > 0 {
-                println!("{} rps", delta);
+                info!("{} rps", delta);
                 last = current;
             }
             timer.sleep(Duration::seconds(60));
@@ -36,23 +41,28 @@ fn main() {
     let addr = "0.0.0.0:6666";
     let listener = TcpListener::bind(addr).unwrap();
     let mut acceptor = listener.listen();
-    println!("serving on {}", addr);
+    info!("serving on {}", addr);
 
     for conn in acceptor.incoming() {
         match conn {
             Err(_) => (),
-            Ok(mut conn) => {
-                let ctr = ctr_arc.clone();
+            Ok(conn) => {
+                let ctr = requests.clone();
                 Thread::spawn(move|| {
                     let id = format!("{}", conn.peer_name().unwrap());
-                    println!("-- {}: connected", id);
+                    info!("-- {}: connected", id);
+                    // requests are decoded from one reused buffer.
+                    let mut frames = FrameBuf::new(conn.clone());
+                    // responses are encoded straight into a batch, which is
+                    // written once every request read so far is answered.
+                    let mut conn = Batched::new(conn);
                     //conn.set_read_timeout(Some(50));
                     //conn.set_write_timeout(Some(50));
 
                     loop {
-                        let (tag, req) = match conn.read_mux_framed_tmsg() {
+                        let (tag, req) = match frames.read_mux_tmsg() {
                             Err(ioe) => {
-                                println!("{}: read error: {}", id, ioe);
+                                warn!("{}: read error: {}", id, ioe);
                                 break;
                             },
                             Ok(framed) => framed,
@@ -66,27 +76,27 @@ fn main() {
                             _ => Rmsg::Err("idk man".to_string()),
                         };
 
-                        match conn.write_mux_framed_rmsg(&tag, &rsp) {
+                        match conn.write_frame(|buf| frame_rmsg(buf, &tag, &rsp)) {
                             Err(ioe) => {
-                                println!("{}: write error: {}", id, ioe);
+                                warn!("{}: write error: {}", id, ioe);
                                 break;
                             },
                             Ok(_) => ()
                         };
-                        match conn.flush() {
+                        let flushed = if frames.has_frame() { conn.flush() } else { conn.flush_batch() };
+                        match flushed {
                             Err(ioe) => {
-                                println!("{}: flush error: {}", id, ioe);
+                                warn!("{}: flush error: {}", id, ioe);
                                 break;
                             },
                             Ok(_) => ()
                         };
 
-                        ctr.fetch_add(1, Ordering::SeqCst);
+                        ctr.incr();
                     }
 
-                    conn.close_read().ok();
-                    conn.close_write().ok();
-                    println!("-- {}: disconnected", id);
+                    conn.close();
+                    info!("-- {}: disconnected", id);
                 });
             }
         }
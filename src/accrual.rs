@@ -0,0 +1,245 @@
+//! Failure accrual marks an endpoint dead once its failures exceed a policy.
+//!
+//! A dead endpoint is left out of rotation for a jittered, exponentially
+//! growing interval.  When the interval elapses, one caller is admitted to
+//! probe the endpoint; a successful probe returns it to rotation and a failed
+//! one marks it dead again for a longer interval.
+
+#[allow(unstable)]
+
+use std::time::Duration;
+
+use time::precise_time_ns;
+
+use backoff::Backoff;
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub enum Policy {
+    /// Marks an endpoint dead after the given number of consecutive
+    /// failures.
+    ConsecutiveFailures(usize),
+
+    /// Marks an endpoint dead when its success rate over a window of the
+    /// most recent requests falls below the given rate.
+    SuccessRate(f64, usize),
+}
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub struct AccrualConfig {
+    pub policy: Policy,
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl AccrualConfig {
+    pub fn default() -> AccrualConfig {
+        AccrualConfig {
+            policy: Policy::ConsecutiveFailures(5),
+            min_backoff: Duration::seconds(5),
+            max_backoff: Duration::seconds(300),
+        }
+    }
+}
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub enum Admission {
+    /// The endpoint is alive.
+    Accept,
+
+    /// The endpoint was dead and may now be probed by this caller.
+    Probe,
+
+    /// The endpoint is dead, or is being probed by another caller.
+    Reject,
+}
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+enum State {
+    Alive,
+    Dead(u64),
+    Probing,
+}
+
+pub struct FailureAccrual {
+    policy: Policy,
+    state: State,
+    consecutive: usize,
+    // outcomes of the most recent requests, as a ring of `window` entries.
+    outcomes: Vec<bool>,
+    window: usize,
+    next_outcome: usize,
+    backoff: Backoff,
+}
+
+impl FailureAccrual {
+    pub fn new(config: AccrualConfig) -> FailureAccrual {
+        let window = match config.policy {
+            Policy::SuccessRate(_, window) => window,
+            Policy::ConsecutiveFailures(_) => 0,
+        };
+        FailureAccrual {
+            policy: config.policy,
+            state: State::Alive,
+            consecutive: 0,
+            outcomes: Vec::with_capacity(window),
+            window: window,
+            next_outcome: 0,
+            backoff: Backoff::exponential(config.min_backoff, config.max_backoff),
+        }
+    }
+
+    /// True if the endpoint is alive or ready to be probed.
+    pub fn is_available(&self) -> bool {
+        self.is_available_at(precise_time_ns())
+    }
+
+    pub fn admit(&mut self) -> Admission {
+        self.admit_at(precise_time_ns())
+    }
+
+    pub fn record_success(&mut self) {
+        match self.state {
+            State::Probing => {
+                self.state = State::Alive;
+                self.backoff.reset();
+                self.consecutive = 0;
+                self.outcomes.clear();
+                self.next_outcome = 0;
+            },
+            _ => {
+                self.consecutive = 0;
+                self.push_outcome(true);
+            }
+        }
+    }
+
+    pub fn record_failure(&mut self) {
+        self.record_failure_at(precise_time_ns())
+    }
+
+    fn is_available_at(&self, now: u64) -> bool {
+        match self.state {
+            State::Alive => true,
+            State::Dead(until) => now >= until,
+            State::Probing => false,
+        }
+    }
+
+    fn admit_at(&mut self, now: u64) -> Admission {
+        match self.state {
+            State::Alive => Admission::Accept,
+            State::Dead(until) if now >= until => {
+                self.state = State::Probing;
+                Admission::Probe
+            },
+            State::Dead(_) | State::Probing => Admission::Reject,
+        }
+    }
+
+    fn record_failure_at(&mut self, now: u64) {
+        let dead = match self.state {
+            State::Probing => true,
+            State::Dead(_) => false,
+            State::Alive => {
+                self.consecutive += 1;
+                self.push_outcome(false);
+                self.is_tripped()
+            }
+        };
+        if dead {
+            let delay = self.backoff.next().unwrap();
+            let delay_ns = delay.num_nanoseconds().unwrap_or(0) as u64;
+            self.state = State::Dead(now + delay_ns);
+        }
+    }
+
+    fn push_outcome(&mut self, ok: bool) {
+        let window = self.window;
+        if window == 0 {
+            return;
+        }
+        if self.outcomes.len() < window {
+            self.outcomes.push(ok);
+        } else {
+            self.outcomes[self.next_outcome] = ok;
+        }
+        self.next_outcome = (self.next_outcome + 1) % window;
+    }
+
+    fn is_tripped(&self) -> bool {
+        match self.policy {
+            Policy::ConsecutiveFailures(n) => self.consecutive >= n,
+
+            Policy::SuccessRate(rate, window) => {
+                // don't judge an endpoint before the window has filled.
+                if self.outcomes.len() < window {
+                    return false;
+                }
+                let successes = self.outcomes.iter().filter(|ok| **ok).count();
+                (successes as f64) / (window as f64) < rate
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::{AccrualConfig, Admission, FailureAccrual, Policy};
+
+    fn mk(policy: Policy) -> FailureAccrual {
+        FailureAccrual::new(AccrualConfig {
+            policy: policy,
+            min_backoff: Duration::seconds(1),
+            max_backoff: Duration::seconds(1),
+        })
+    }
+
+    static SEC: u64 = 1000000000;
+
+    #[test]
+    fn test_consecutive_failures() {
+        let mut fa = mk(Policy::ConsecutiveFailures(3));
+        fa.record_failure_at(0);
+        fa.record_failure_at(0);
+        fa.record_success();
+        fa.record_failure_at(0);
+        fa.record_failure_at(0);
+        assert_eq!(fa.admit_at(0), Admission::Accept);
+
+        fa.record_failure_at(0);
+        assert!(!fa.is_available_at(0));
+        assert_eq!(fa.admit_at(0), Admission::Reject);
+
+        // a failed probe marks the endpoint dead again.
+        assert!(fa.is_available_at(SEC));
+        assert_eq!(fa.admit_at(SEC), Admission::Probe);
+        assert_eq!(fa.admit_at(SEC), Admission::Reject);
+        fa.record_failure_at(SEC);
+        assert_eq!(fa.admit_at(SEC), Admission::Reject);
+
+        // a successful probe revives it.
+        assert_eq!(fa.admit_at(2 * SEC), Admission::Probe);
+        fa.record_success();
+        assert_eq!(fa.admit_at(2 * SEC), Admission::Accept);
+    }
+
+    #[test]
+    fn test_success_rate() {
+        let mut fa = mk(Policy::SuccessRate(0.5, 4));
+        fa.record_failure_at(0);
+        fa.record_failure_at(0);
+        fa.record_failure_at(0);
+        assert_eq!(fa.admit_at(0), Admission::Accept);
+
+        fa.record_success();
+        fa.record_success();
+        fa.record_success();
+        fa.record_failure_at(0);
+        assert_eq!(fa.admit_at(0), Admission::Accept);
+
+        fa.record_failure_at(0);
+        fa.record_failure_at(0);
+        assert_eq!(fa.admit_at(0), Admission::Reject);
+    }
+}
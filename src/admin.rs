@@ -0,0 +1,404 @@
+//! An HTTP server through which operators may inspect and control a
+//! running mux server.
+//!
+//! It speaks just enough HTTP/1.0 for curl and monitoring systems, and
+//! should listen only on a local or otherwise trusted address:
+//!
+//!     GET  /                    lists the endpoints configured
+//!     GET  /sessions            each session's peer, outstanding tags and
+//!                               their ages, lease and drain state
+//!     GET  /metrics             metrics, in Prometheus's text format
+//!     GET  /dtab                the base Dtab
+//!     POST /drain               drains every session
+//!     POST /sessions/ID/drain   drains one session
+//!     POST /dtab/reload         reloads the base Dtab
+//!
+//! Endpoints for anything not configured respond 404:
+//!
+//!     let server = Server::new(proxy.clone());
+//!     let admin = Admin::new()
+//!         .sessions(server.sessions())
+//!         .dtab(proxy.base_dtab(), move|| Dtab::parse(...));
+//!     Thread::spawn(move|| { admin.listen("127.0.0.1:9990"); });
+
+#[allow(unstable)]
+
+use std::old_io::{BufferedReader, Buffer, IoError, IoResult, InvalidInput, Acceptor, Listener, Writer};
+use std::old_io::net::ip::ToSocketAddr;
+use std::old_io::net::tcp::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread::Thread;
+
+use metrics::Metrics;
+use misc::Dtab;
+use server::Sessions;
+
+// the longest request or header line read, the most headers, and how long
+// a client has to send them, so that a slow or endless request can't hold
+// a thread and its memory.
+static MAX_LINE: usize = 8192;
+static MAX_HEADERS: usize = 100;
+static READ_TIMEOUT_MS: u64 = 5000;
+
+pub struct Admin {
+    sessions: Option<Sessions>,
+    metrics: Option<Metrics>,
+    dtab: Option<Arc<Mutex<Dtab>>>,
+    reload: Option<Box<Fn() -> IoResult<Dtab> + Send + Sync>>,
+}
+
+enum Route {
+    Index,
+    Sessions,
+    Metrics,
+    Dtab,
+    DrainAll,
+    Drain(usize),
+    Reload,
+}
+
+impl Route {
+    fn parse(path: &str) -> Option<Route> {
+        let (prefix, suffix) = ("/sessions/", "/drain");
+        match path {
+            "/" => Some(Route::Index),
+            "/sessions" => Some(Route::Sessions),
+            "/metrics" => Some(Route::Metrics),
+            "/dtab" => Some(Route::Dtab),
+            "/drain" => Some(Route::DrainAll),
+            "/dtab/reload" => Some(Route::Reload),
+            _ if path.len() > prefix.len() + suffix.len() && path.starts_with(prefix) && path.ends_with(suffix) => {
+                path.slice(prefix.len(), path.len() - suffix.len()).parse().map(Route::Drain)
+            },
+            _ => None,
+        }
+    }
+
+    fn method(&self) -> &'static str {
+        match *self {
+            Route::DrainAll | Route::Drain(_) | Route::Reload => "POST",
+            _ => "GET",
+        }
+    }
+}
+
+impl Admin {
+    pub fn new() -> Admin {
+        Admin { sessions: None, metrics: None, dtab: None, reload: None }
+    }
+
+    /// Reports, and drains, a server's sessions (see `Server::sessions`).
+    pub fn sessions(mut self, sessions: Sessions) -> Admin {
+        self.sessions = Some(sessions);
+        self
+    }
+
+    /// Reports the metrics registered in `metrics`.
+    pub fn metrics(mut self, metrics: Metrics) -> Admin {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Reports a base Dtab, such as `Proxy::base_dtab`, and replaces it
+    /// with the result of `reload` when asked to.
+    pub fn dtab<F>(mut self, dtab: Arc<Mutex<Dtab>>, reload: F) -> Admin
+        where F: Fn() -> IoResult<Dtab> + Send + Sync + 'static
+    {
+        self.dtab = Some(dtab);
+        self.reload = Some(Box::new(reload));
+        self
+    }
+
+    /// Serves each connection accepted on a background thread, until the
+    /// acceptor fails.
+    pub fn serve_all<A: Acceptor<TcpStream>>(self, mut acceptor: A) -> IoError {
+        let admin = Arc::new(self);
+        loop {
+            match acceptor.accept() {
+                Err(ioe) => return ioe,
+                Ok(conn) => {
+                    let admin = admin.clone();
+                    Thread::spawn(move|| admin.serve(conn));
+                },
+            }
+        }
+    }
+
+    /// Serves connections to `addr` until the listener fails.
+    pub fn listen<A: ToSocketAddr>(self, addr: A) -> IoError {
+        match TcpListener::bind(addr).and_then(|l| l.listen()) {
+            Err(ioe) => ioe,
+            Ok(acceptor) => self.serve_all(acceptor),
+        }
+    }
+
+    /// Answers one request, ignoring its headers and any body, and closes
+    /// the connection.  Requests must arrive within `READ_TIMEOUT_MS`.
+    fn serve(&self, mut conn: TcpStream) {
+        conn.set_read_timeout(Some(READ_TIMEOUT_MS));
+        let request = match read_request(&mut BufferedReader::new(conn.clone())) {
+            Err(ref ioe) if ioe.kind == InvalidInput => Err(format!("{}\n", ioe.desc)),
+            Err(_) => return,
+            Ok(request) => Ok(request),
+        };
+
+        let (status, body) = match request {
+            Err(msg) => (400, msg),
+            Ok(request) => {
+                let parts: Vec<&str> = request.trim().split(' ').collect();
+                if parts.len() < 2 {
+                    (400, "bad request\n".to_string())
+                } else {
+                    self.respond(parts[0], parts[1])
+                }
+            },
+        };
+        let head = format!("HTTP/1.0 {} {}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                           status, reason(status), body.len());
+        let mut writer = conn;
+        writer.write(head.as_bytes())
+            .and_then(|_| writer.write(body.as_bytes()))
+            .and_then(|_| writer.flush())
+            .ok();
+    }
+
+    /// The status and body of the response to a request.
+    fn respond(&self, method: &str, path: &str) -> (u16, String) {
+        let path = path.split('?').next().unwrap_or("");
+        match Route::parse(path) {
+            None => (404, "not found\n".to_string()),
+            Some(ref route) if method != route.method() => (405, format!("use {}\n", route.method())),
+            Some(route) => match self.perform(route) {
+                None => (404, "not configured\n".to_string()),
+                Some(rsp) => rsp,
+            },
+        }
+    }
+
+    fn perform(&self, route: Route) -> Option<(u16, String)> {
+        match route {
+            Route::Index => Some((200, self.index())),
+
+            Route::Sessions => self.sessions.as_ref().map(|s| (200, show_sessions(s))),
+
+            Route::Metrics => self.metrics.as_ref().map(|m| (200, m.prometheus())),
+
+            Route::Dtab => self.dtab.as_ref().map(|d| (200, format!("{}\n", *d.lock().unwrap()))),
+
+            Route::DrainAll => self.sessions.as_ref().map(|s| (200, format!("drained {} sessions\n", s.drain_all()))),
+
+            Route::Drain(id) => self.sessions.as_ref().map(|s| match s.drain(id) {
+                None => (404, format!("no session {}\n", id)),
+                Some(Err(ioe)) => (500, format!("session {}: {}\n", id, ioe)),
+                Some(Ok(_)) => (200, format!("draining session {}\n", id)),
+            }),
+
+            Route::Reload => match (self.dtab.as_ref(), self.reload.as_ref()) {
+                (Some(dtab), Some(reload)) => Some(match (**reload)() {
+                    Err(ioe) => (500, format!("reload failed: {}\n", ioe)),
+                    Ok(reloaded) => {
+                        let shown = format!("{}\n", reloaded);
+                        *dtab.lock().unwrap() = reloaded;
+                        (200, shown)
+                    },
+                }),
+                _ => None,
+            },
+        }
+    }
+
+    fn index(&self) -> String {
+        let mut out = "GET  /\n".to_string();
+        if self.sessions.is_some() {
+            out.push_str("GET  /sessions\nPOST /drain\nPOST /sessions/ID/drain\n");
+        }
+        if self.metrics.is_some() {
+            out.push_str("GET  /metrics\n");
+        }
+        if self.dtab.is_some() {
+            out.push_str("GET  /dtab\nPOST /dtab/reload\n");
+        }
+        out
+    }
+}
+
+/// Reads a request line, and skips the headers after it.
+fn read_request<B: Buffer>(reader: &mut B) -> IoResult<String> {
+    read_line(reader).and_then(|request| {
+        for _ in range(0, MAX_HEADERS) {
+            match read_line(reader) {
+                Err(ioe) => return Err(ioe),
+                Ok(line) => if line.trim().is_empty() { return Ok(request) },
+            }
+        }
+        Err(IoError { kind: InvalidInput, desc: "too many headers", detail: None })
+    })
+}
+
+/// Reads a line of up to `MAX_LINE` bytes.
+fn read_line<B: Buffer>(reader: &mut B) -> IoResult<String> {
+    let mut line = Vec::new();
+    loop {
+        match reader.read_byte() {
+            Err(ioe) => return Err(ioe),
+            Ok(b) => {
+                line.push(b);
+                if b == b'\n' {
+                    break;
+                }
+            },
+        }
+        if line.len() >= MAX_LINE {
+            return Err(IoError { kind: InvalidInput, desc: "line too long", detail: None });
+        }
+    }
+    String::from_utf8(line).map_err(|_| IoError { kind: InvalidInput, desc: "line isn't utf-8", detail: None })
+}
+
+/// A line for each session, followed by one for each of its outstanding
+/// tags:
+///
+///     1 127.0.0.1:50047 requests=120 pending=1 lease=4500ms state=active
+///       tag=7 age=1203ms
+fn show_sessions(sessions: &Sessions) -> String {
+    let mut out = String::new();
+    for s in sessions.status().iter() {
+        let lease = match s.lease {
+            None => "-".to_string(),
+            Some(left) => format!("{}ms", left.num_milliseconds()),
+        };
+        let state = match (s.draining, s.drain_acked) {
+            (false, _) => "active",
+            (true, false) => "draining",
+            (true, true) => "drain-acked",
+        };
+        out.push_str(format!("{} {} requests={} pending={} lease={} state={}\n",
+                             s.id, s.peer, s.metrics.requests.get(), s.pending.len(), lease, state).as_slice());
+        for &(tag, age) in s.pending.iter() {
+            out.push_str(format!("  tag={} age={}ms\n", tag, age.num_milliseconds()).as_slice());
+        }
+    }
+    out
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::iter::repeat;
+    use std::old_io::{BufReader, IoError, InvalidInput, Listener, Reader, Writer};
+    use std::old_io::net::tcp::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread::Thread;
+
+    use memory;
+    use metrics::{Metrics, SessionMetrics};
+    use misc::Dtab;
+    use proto::Rmsg;
+    use server::{Request, Server};
+    use super::{Admin, MAX_LINE, read_request};
+
+    fn echo(req: Request) -> Rmsg {
+        Rmsg::DispatchOk(Vec::new(), req.body)
+    }
+
+    #[test]
+    fn test_respond() {
+        let registry = Metrics::new();
+        let server = Server::new(echo).metrics(SessionMetrics::registered(&registry, "mux_server"));
+        let (_client, conn) = memory::pair();
+        let _session = server.serve(conn);
+
+        let base = Arc::new(Mutex::new(Dtab::parse("/s=>/t").unwrap()));
+        let fail = Arc::new(AtomicBool::new(false));
+        let failing = fail.clone();
+        let admin = Admin::new()
+            .sessions(server.sessions())
+            .metrics(registry)
+            .dtab(base.clone(), move|| if failing.load(Ordering::SeqCst) {
+                Err(IoError { kind: InvalidInput, desc: "unreadable", detail: None })
+            } else {
+                Dtab::parse("/s=>/u")
+            });
+
+        let id = server.sessions().status()[0].id;
+        let (status, sessions) = admin.respond("GET", "/sessions");
+        assert_eq!(status, 200);
+        assert_eq!(sessions, format!("{} - requests=0 pending=0 lease=- state=active\n", id));
+
+        let (status, metrics) = admin.respond("GET", "/metrics?name[]=x");
+        assert_eq!(status, 200);
+        assert!(metrics.contains("\nmux_server_requests_total 0\n"), "{}", metrics);
+
+        assert_eq!(admin.respond("GET", "/dtab"), (200, "/s=>/t\n".to_string()));
+        assert_eq!(admin.respond("POST", "/dtab/reload"), (200, "/s=>/u\n".to_string()));
+        assert_eq!(*base.lock().unwrap(), Dtab::parse("/s=>/u").unwrap());
+        fail.store(true, Ordering::SeqCst);
+        assert_eq!(admin.respond("POST", "/dtab/reload").0, 500);
+        assert_eq!(*base.lock().unwrap(), Dtab::parse("/s=>/u").unwrap());
+
+        let drain = format!("/sessions/{}/drain", id);
+        assert_eq!(admin.respond("GET", drain.as_slice()).0, 405);
+        assert_eq!(admin.respond("POST", format!("/sessions/{}/drain", id + 1).as_slice()).0, 404);
+        assert_eq!(admin.respond("POST", "/sessions/x/drain").0, 404);
+        assert_eq!(admin.respond("POST", drain.as_slice()), (200, format!("draining session {}\n", id)));
+        assert!(admin.respond("GET", "/sessions").1.contains("state=draining"));
+        assert_eq!(admin.respond("GET", "/nothing").0, 404);
+
+        // endpoints for what isn't configured aren't found.
+        let bare = Admin::new();
+        assert_eq!(bare.respond("GET", "/"), (200, "GET  /\n".to_string()));
+        assert_eq!(bare.respond("GET", "/sessions").0, 404);
+        assert_eq!(bare.respond("POST", "/dtab/reload").0, 404);
+    }
+
+    #[test]
+    fn test_http() {
+        let base = Arc::new(Mutex::new(Dtab::parse("/s=>/t").unwrap()));
+        let admin = Admin::new().dtab(base, || Dtab::parse("/s=>/u"));
+        let mut listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.socket_name().unwrap();
+        let acceptor = listener.listen().unwrap();
+        Thread::spawn(move|| { admin.serve_all(acceptor); });
+
+        let mut conn = TcpStream::connect(addr).unwrap();
+        conn.write(b"GET /dtab HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let rsp = conn.read_to_string().unwrap();
+        assert!(rsp.starts_with("HTTP/1.0 200 OK\r\n"), "{}", rsp);
+        assert!(rsp.contains("\r\nContent-Length: 7\r\n"), "{}", rsp);
+        assert!(rsp.ends_with("\r\n\r\n/s=>/t\n"), "{}", rsp);
+
+        // an endless request line is cut off.
+        let mut conn = TcpStream::connect(addr).unwrap();
+        let long: String = repeat('x').take(MAX_LINE - 5).collect();
+        conn.write(format!("GET /{}", long).as_bytes()).unwrap();
+        let rsp = conn.read_to_string().unwrap();
+        assert!(rsp.starts_with("HTTP/1.0 400 Bad Request\r\n"), "{}", rsp);
+        assert!(rsp.ends_with("line too long\n"), "{}", rsp);
+    }
+
+    #[test]
+    fn test_read_request() {
+        let mut r = BufReader::new(b"GET / HTTP/1.0\r\nHost: x\r\n\r\nbody");
+        assert_eq!(read_request(&mut r).unwrap(), "GET / HTTP/1.0\r\n".to_string());
+        assert_eq!(r.read_to_end().unwrap(), b"body".to_vec());
+
+        let mut request = "GET / HTTP/1.0\r\n".to_string();
+        for _ in range(0, 101) {
+            request.push_str("X: y\r\n");
+        }
+        request.push_str("\r\n");
+        assert_eq!(read_request(&mut BufReader::new(request.as_bytes())).unwrap_err().desc, "too many headers");
+        // cut off before the headers end.
+        assert!(read_request(&mut BufReader::new(b"GET / HTTP/1.0\r\n")).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+//! quickcheck generators for the protocol's types, and round-trip
+//! properties over them: whatever the writer encodes, the reader must
+//! decode to an equal value, in exactly `encoded_len` bytes.
+
+#[allow(unstable)]
+
+use quickcheck::{Arbitrary, Gen};
+
+use misc::{Context, Dentry, Dtab, Header, Trace};
+use proto::{Tag, Tmsg, Rmsg};
+
+impl Arbitrary for Tag {
+    /// Tags never carry the fragment flag; fragments are not messages.
+    fn arbitrary<G: Gen>(g: &mut G) -> Tag {
+        Tag(g.gen::<u8>() & 0x7f, g.gen(), g.gen())
+    }
+}
+
+impl Arbitrary for Trace {
+    fn arbitrary<G: Gen>(g: &mut G) -> Trace {
+        Trace { span_id: g.gen(), parent_id: g.gen(), trace_id: g.gen(), flags: g.gen() }
+    }
+}
+
+impl Arbitrary for Context {
+    fn arbitrary<G: Gen>(g: &mut G) -> Context {
+        Context::new(Arbitrary::arbitrary(g), Arbitrary::arbitrary(g))
+    }
+}
+
+impl Arbitrary for Dentry {
+    fn arbitrary<G: Gen>(g: &mut G) -> Dentry {
+        Dentry::new(Arbitrary::arbitrary(g), Arbitrary::arbitrary(g))
+    }
+}
+
+impl Arbitrary for Dtab {
+    fn arbitrary<G: Gen>(g: &mut G) -> Dtab {
+        Dtab(Arbitrary::arbitrary(g))
+    }
+}
+
+impl Arbitrary for Header {
+    fn arbitrary<G: Gen>(g: &mut G) -> Header {
+        Header::new(Arbitrary::arbitrary(g), Arbitrary::arbitrary(g))
+    }
+}
+
+impl Arbitrary for Tmsg {
+    fn arbitrary<G: Gen>(g: &mut G) -> Tmsg {
+        match g.gen_range(0u8, 7) {
+            0 => Tmsg::Req(Arbitrary::arbitrary(g), Arbitrary::arbitrary(g)),
+            1 => Tmsg::Dispatch(Arbitrary::arbitrary(g), Arbitrary::arbitrary(g),
+                                Arbitrary::arbitrary(g), Arbitrary::arbitrary(g)),
+            2 => Tmsg::Drain,
+            3 => Tmsg::Ping,
+            4 => Tmsg::Discarded(Arbitrary::arbitrary(g), Arbitrary::arbitrary(g)),
+            5 => Tmsg::Lease(g.gen(), g.gen()),
+            _ => Tmsg::Init(g.gen(), Arbitrary::arbitrary(g)),
+        }
+    }
+}
+
+impl Arbitrary for Rmsg {
+    fn arbitrary<G: Gen>(g: &mut G) -> Rmsg {
+        match g.gen_range(0u8, 10) {
+            0 => Rmsg::ReqOk(Arbitrary::arbitrary(g)),
+            1 => Rmsg::ReqError(Arbitrary::arbitrary(g)),
+            2 => Rmsg::ReqNack,
+            3 => Rmsg::DispatchOk(Arbitrary::arbitrary(g), Arbitrary::arbitrary(g)),
+            4 => Rmsg::DispatchError(Arbitrary::arbitrary(g), Arbitrary::arbitrary(g)),
+            5 => Rmsg::DispatchNack(Arbitrary::arbitrary(g)),
+            6 => Rmsg::Drain,
+            7 => Rmsg::Ping,
+            8 => Rmsg::Init(g.gen(), Arbitrary::arbitrary(g)),
+            _ => Rmsg::Err(Arbitrary::arbitrary(g)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use quickcheck::quickcheck;
+    use std::old_io::BufReader;
+
+    use framebuf::FrameBuf;
+    use misc::{Context, Dtab, Trace};
+    use proto::{Msg, Tag, Tmsg, Rmsg};
+    use reader::MuxReader;
+    use writer::MuxWriter;
+
+    #[test]
+    fn test_tmsg_roundtrip() {
+        fn prop(tag: Tag, msg: Tmsg) -> bool {
+            let mut buf = Vec::new();
+            buf.write_mux_framed_tmsg(&tag, &msg).unwrap();
+            let mut reader = BufReader::new(buf.as_slice());
+            buf.len() == 4 + msg.encoded_len()
+                && reader.read_mux_framed_tmsg().ok() == Some((tag, msg))
+                && reader.eof()
+        }
+        quickcheck(prop as fn(Tag, Tmsg) -> bool);
+    }
+
+    #[test]
+    fn test_rmsg_roundtrip() {
+        fn prop(tag: Tag, msg: Rmsg) -> bool {
+            let mut buf = Vec::new();
+            buf.write_mux_framed_rmsg(&tag, &msg).unwrap();
+            let mut reader = BufReader::new(buf.as_slice());
+            buf.len() == 4 + msg.encoded_len()
+                && reader.read_mux_framed_rmsg().ok() == Some((tag, msg))
+                && reader.eof()
+        }
+        quickcheck(prop as fn(Tag, Rmsg) -> bool);
+    }
+
+    #[test]
+    fn test_msg_roundtrip() {
+        // T-messages and R-messages alike decode by the sign of their type.
+        fn prop(tmsgs: Vec<(Tag, Tmsg)>, rmsgs: Vec<(Tag, Rmsg)>) -> bool {
+            let mut buf = Vec::new();
+            for &(ref tag, ref msg) in tmsgs.iter() {
+                buf.write_mux_framed_tmsg(tag, msg).unwrap();
+            }
+            for &(ref tag, ref msg) in rmsgs.iter() {
+                buf.write_mux_framed_rmsg(tag, msg).unwrap();
+            }
+
+            let expected: Vec<Msg> = tmsgs.into_iter().map(|(t, m)| Msg::Tx(t, m))
+                .chain(rmsgs.into_iter().map(|(t, m)| Msg::Rx(t, m)))
+                .collect();
+            let mut frames = FrameBuf::new(BufReader::new(buf.as_slice()));
+            let decoded: Vec<Msg> = range(0, expected.len())
+                .filter_map(|_| frames.read_mux_msg().ok())
+                .collect();
+            decoded == expected && frames.next_frame().is_err()
+        }
+        quickcheck(prop as fn(Vec<(Tag, Tmsg)>, Vec<(Tag, Rmsg)>) -> bool);
+    }
+
+    #[test]
+    fn test_trace_roundtrip() {
+        fn prop(trace: Option<Trace>) -> bool {
+            let mut buf = Vec::new();
+            buf.write_mux_trace(&trace).unwrap();
+            BufReader::new(buf.as_slice()).read_mux_trace().ok() == Some(trace)
+        }
+        quickcheck(prop as fn(Option<Trace>) -> bool);
+    }
+
+    #[test]
+    fn test_contexts_roundtrip() {
+        fn prop(contexts: Vec<Context>) -> bool {
+            let mut buf = Vec::new();
+            buf.write_mux_contexts(contexts.as_slice()).unwrap();
+            BufReader::new(buf.as_slice()).read_mux_contexts().ok() == Some(contexts)
+        }
+        quickcheck(prop as fn(Vec<Context>) -> bool);
+    }
+
+    #[test]
+    fn test_dtab_roundtrip() {
+        fn prop(dtab: Dtab) -> bool {
+            let mut buf = Vec::new();
+            buf.write_mux_dtab(&dtab).unwrap();
+            BufReader::new(buf.as_slice()).read_mux_dtab().ok() == Some(dtab)
+        }
+        quickcheck(prop as fn(Dtab) -> bool);
+    }
+}
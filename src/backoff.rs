@@ -0,0 +1,120 @@
+//! Jittered backoff schedules.
+
+#[allow(unstable)]
+
+use std::cmp;
+use std::num::Int;
+use std::rand::{thread_rng, Rng};
+use std::time::Duration;
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+enum Kind {
+    Exponential,
+    Decorrelated,
+}
+
+/// An endless schedule of delays.
+#[derive(Clone,Debug)]
+pub struct Backoff {
+    kind: Kind,
+    base_ms: i64,
+    max_ms: i64,
+    attempt: u32,
+    prev_ms: i64,
+}
+
+impl Backoff {
+    /// Doubles the delay with each attempt, from `base` up to `max`.  Each
+    /// delay is chosen uniformly from the upper half of its interval, so
+    /// that peers backing off together don't retry in lockstep.
+    pub fn exponential(base: Duration, max: Duration) -> Backoff {
+        Backoff::new(Kind::Exponential, base, max)
+    }
+
+    /// Chooses each delay uniformly between `base` and three times the
+    /// previous delay, up to `max`.  This spreads out competing peers more
+    /// than exponential backoff does, while growing at a similar rate.
+    pub fn decorrelated(base: Duration, max: Duration) -> Backoff {
+        Backoff::new(Kind::Decorrelated, base, max)
+    }
+
+    fn new(kind: Kind, base: Duration, max: Duration) -> Backoff {
+        let base_ms = cmp::max(base.num_milliseconds(), 1);
+        Backoff {
+            kind: kind,
+            base_ms: base_ms,
+            max_ms: cmp::max(max.num_milliseconds(), base_ms),
+            attempt: 0,
+            prev_ms: base_ms,
+        }
+    }
+
+    /// Restarts the schedule from `base`.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+        self.prev_ms = self.base_ms;
+    }
+
+    fn next_exponential(&mut self) -> i64 {
+        let shift = cmp::min(self.attempt, 32) as usize;
+        let ceil = cmp::min(self.base_ms.saturating_mul(1i64 << shift), self.max_ms);
+        self.attempt += 1;
+
+        let floor = ceil / 2;
+        if ceil > floor { thread_rng().gen_range(floor, ceil + 1) } else { ceil }
+    }
+
+    fn next_decorrelated(&mut self) -> i64 {
+        let ceil = cmp::min(self.prev_ms.saturating_mul(3), self.max_ms);
+        let ms = if ceil > self.base_ms {
+            thread_rng().gen_range(self.base_ms, ceil + 1)
+        } else {
+            ceil
+        };
+        self.attempt += 1;
+        self.prev_ms = ms;
+        ms
+    }
+}
+
+impl Iterator for Backoff {
+    type Item = Duration;
+
+    fn next(&mut self) -> Option<Duration> {
+        let ms = match self.kind {
+            Kind::Exponential => self.next_exponential(),
+            Kind::Decorrelated => self.next_decorrelated(),
+        };
+        Some(Duration::milliseconds(ms))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+    use super::Backoff;
+
+    #[test]
+    fn test_exponential() {
+        let mut backoff = Backoff::exponential(Duration::milliseconds(10), Duration::milliseconds(100));
+        let delays: Vec<i64> = backoff.by_ref().take(6).map(|d| d.num_milliseconds()).collect();
+        let ceils = [10, 20, 40, 80, 100, 100];
+        for (d, c) in delays.iter().zip(ceils.iter()) {
+            assert!(*d <= *c && *d >= *c / 2, "{} not in [{}, {}]", d, *c / 2, c);
+        }
+
+        backoff.reset();
+        assert!(backoff.next().unwrap().num_milliseconds() <= 10);
+    }
+
+    #[test]
+    fn test_decorrelated() {
+        let mut backoff = Backoff::decorrelated(Duration::milliseconds(10), Duration::milliseconds(100));
+        let mut prev = 10;
+        for d in backoff.by_ref().take(20) {
+            let ms = d.num_milliseconds();
+            assert!(ms >= 10 && ms <= 100 && ms <= prev * 3, "{} after {}", ms, prev);
+            prev = ms;
+        }
+    }
+}
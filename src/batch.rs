@@ -0,0 +1,376 @@
+//! Coalescing frame writes.
+//!
+//! A `BatchWriter` encodes frames into one buffer, reserving each frame's
+//! length prefix and filling it in once the frame is encoded, and writes
+//! the buffer to the underlying writer in a single call once it holds
+//! `max_bytes`, or once its oldest frame has waited `max_delay`.
+//! `Batched` adapts it to a session's transport, whose frames are encoded
+//! directly into the batch (see `Transport::write_frame`).
+
+#[allow(unstable)]
+
+use std::old_io::{IoResult, Reader, Writer};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::Thread;
+use std::time::Duration;
+use time::precise_time_ns;
+
+use proto::{Tag, Tmsg, Rmsg};
+use transport::{PeerCredentials, PeerIdentity, Transport};
+use writer::MuxWriter;
+
+pub struct BatchWriter<W> {
+    inner: W,
+    buf: Vec<u8>,
+    max_bytes: usize,
+    max_delay: Duration,
+    // when the oldest unwritten frame was buffered.
+    since_ns: Option<u64>,
+}
+
+impl<W: Writer> BatchWriter<W> {
+    /// Writes batches of up to 16KB, delaying frames up to 1ms.
+    pub fn new(inner: W) -> BatchWriter<W> {
+        BatchWriter {
+            inner: inner,
+            buf: Vec::new(),
+            max_bytes: 16 * 1024,
+            max_delay: Duration::milliseconds(1),
+            since_ns: None,
+        }
+    }
+
+    pub fn max_bytes(mut self, max_bytes: usize) -> BatchWriter<W> {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// A zero delay (or a negative one) writes the batch on each flush.
+    pub fn max_delay(mut self, max_delay: Duration) -> BatchWriter<W> {
+        self.max_delay = if max_delay < Duration::zero() { Duration::zero() } else { max_delay };
+        self
+    }
+
+    pub fn write_tmsg(&mut self, tag: &Tag, msg: &Tmsg) -> IoResult<()> {
+        self.write_frame_with(|buf| buf.write_mux_tmsg(tag, msg))
+    }
+
+    pub fn write_rmsg(&mut self, tag: &Tag, msg: &Rmsg) -> IoResult<()> {
+        self.write_frame_with(|buf| buf.write_mux_rmsg(tag, msg))
+    }
+
+    /// Buffers a frame whose contents are encoded by `f`.
+    pub fn write_frame_with<F: FnOnce(&mut Vec<u8>) -> IoResult<()>>(&mut self, f: F) -> IoResult<()> {
+        let start = self.buf.len();
+        self.buf.push_all(&[0, 0, 0, 0]);
+        match f(&mut self.buf) {
+            Err(ioe) => {
+                self.buf.truncate(start);
+                return Err(ioe);
+            },
+            Ok(_) => (),
+        }
+
+        let len = (self.buf.len() - start - 4) as u32;
+        for i in range(0, 4) {
+            self.buf[start + i] = (len >> (24 - 8 * i)) as u8;
+        }
+        self.buffered()
+    }
+
+    /// Buffers whatever `f` appends: whole frames, length prefixes and all.
+    pub fn append_with<F: FnOnce(&mut Vec<u8>) -> IoResult<()>>(&mut self, f: F) -> IoResult<()> {
+        let start = self.buf.len();
+        match f(&mut self.buf) {
+            Err(ioe) => {
+                self.buf.truncate(start);
+                Err(ioe)
+            },
+            Ok(_) => self.buffered(),
+        }
+    }
+
+    /// The number of bytes awaiting a write.
+    pub fn buffered_len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Writes the buffer if it's full or its oldest frame has waited
+    /// `max_delay`.
+    pub fn flush_if_due(&mut self) -> IoResult<()> {
+        let full = self.buf.len() >= self.max_bytes;
+        let late = match (self.since_ns, self.max_delay.num_nanoseconds()) {
+            (Some(since), Some(delay)) => precise_time_ns() - since >= delay as u64,
+            _ => false,
+        };
+        if full || late { self.flush() } else { Ok(()) }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Returns the underlying writer, discarding anything buffered.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    fn buffered(&mut self) -> IoResult<()> {
+        if self.since_ns.is_none() {
+            self.since_ns = Some(precise_time_ns());
+        }
+        if self.buf.len() >= self.max_bytes { self.flush() } else { Ok(()) }
+    }
+}
+
+impl<W: Writer> Writer for BatchWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        self.buf.push_all(buf);
+        self.buffered()
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.since_ns = None;
+        if self.buf.is_empty() {
+            return self.inner.flush();
+        }
+        let written = self.inner.write(self.buf.as_slice()).and_then(|_| self.inner.flush());
+        self.buf.clear();
+        written
+    }
+}
+
+/// A transport whose writes are batched.  Sessions flush after each
+/// frame; here a flush only writes the batch when it's due, and a
+/// background thread writes batches that come due while idle.  The thread
+/// sleeps until a frame is buffered, and isn't started if frames are never
+/// delayed.
+#[derive(Clone)]
+pub struct Batched<T> {
+    conn: T,
+    shared: Arc<Shared<T>>,
+    // stops the flusher once the last clone is dropped.
+    _stop: Option<Arc<Stop<T>>>,
+}
+
+struct Shared<W> {
+    batch: Mutex<BatchWriter<W>>,
+    // signalled when a frame is buffered into an empty batch, or on stopping.
+    buffered: Condvar,
+    stopped: AtomicBool,
+}
+
+struct Stop<W>(Arc<Shared<W>>);
+
+impl<W: Writer + Send + 'static> Drop for Stop<W> {
+    fn drop(&mut self) {
+        let _batch = self.0.batch.lock().unwrap();
+        self.0.stopped.store(true, Ordering::SeqCst);
+        self.0.buffered.notify_one();
+    }
+}
+
+impl<T: Transport> Batched<T> {
+    pub fn new(conn: T) -> Batched<T> {
+        Batched::with_writer(BatchWriter::new(conn))
+    }
+
+    /// Wraps the transport underlying `writer`, keeping its thresholds.
+    pub fn with_writer(writer: BatchWriter<T>) -> Batched<T> {
+        let conn = writer.get_ref().clone();
+        let delayed = writer.max_delay > Duration::zero();
+        let shared = Arc::new(Shared {
+            batch: Mutex::new(writer),
+            buffered: Condvar::new(),
+            stopped: AtomicBool::new(false),
+        });
+        // without a delay, each flush writes the batch.
+        let stop = if delayed {
+            flush_when_due(shared.clone());
+            Some(Arc::new(Stop(shared.clone())))
+        } else {
+            None
+        };
+        Batched { conn: conn, shared: shared, _stop: stop }
+    }
+
+    /// Writes the batch now, whether or not it's due: e.g. once a server
+    /// has answered every request it's read.
+    pub fn flush_batch(&mut self) -> IoResult<()> {
+        self.shared.batch.lock().unwrap().flush()
+    }
+
+    /// Buffers with `f`, waking the flusher if the batch was empty.
+    fn buffer<F: FnOnce(&mut BatchWriter<T>) -> IoResult<()>>(&self, f: F) -> IoResult<()> {
+        let mut batch = self.shared.batch.lock().unwrap();
+        let was_empty = batch.since_ns.is_none();
+        let result = f(&mut *batch);
+        if was_empty && batch.since_ns.is_some() {
+            self.shared.buffered.notify_one();
+        }
+        result
+    }
+}
+
+fn flush_when_due<W: Writer + Send + 'static>(shared: Arc<Shared<W>>) {
+    Thread::spawn(move|| {
+        let mut batch = shared.batch.lock().unwrap();
+        while !shared.stopped.load(Ordering::SeqCst) {
+            let due = match (batch.since_ns, batch.max_delay.num_nanoseconds()) {
+                (Some(since), Some(delay)) => since + delay as u64,
+                _ => {
+                    batch = shared.buffered.wait(batch).unwrap();
+                    continue;
+                },
+            };
+            let now = precise_time_ns();
+            if now < due {
+                batch = shared.buffered.wait_timeout(batch, Duration::nanoseconds((due - now) as i64)).unwrap().0;
+            } else if batch.flush().is_err() {
+                break;
+            }
+        }
+    });
+}
+
+impl<T: Transport> Reader for Batched<T> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        self.conn.read(buf)
+    }
+}
+
+impl<T: Transport> Writer for Batched<T> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        self.buffer(|batch| batch.write(buf))
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.shared.batch.lock().unwrap().flush_if_due()
+    }
+}
+
+impl<T: Transport> Transport for Batched<T> {
+    fn close(&mut self) {
+        self.shared.batch.lock().unwrap().flush().ok();
+        self.conn.close();
+    }
+
+    fn peer_identity(&self) -> Option<PeerIdentity> {
+        self.conn.peer_identity()
+    }
+
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        self.conn.peer_credentials()
+    }
+
+    fn peer_addr(&self) -> Option<String> {
+        self.conn.peer_addr()
+    }
+
+    fn write_frame<F: FnOnce(&mut Vec<u8>) -> IoResult<()>>(&mut self, f: F) -> IoResult<()> {
+        self.buffer(|batch| batch.append_with(f))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::old_io::{BufReader, IoResult, Writer};
+    use std::old_io::timer::sleep;
+    use std::time::Duration;
+
+    use memory;
+    use misc::Dtab;
+    use proto::{Tag, Tmsg, Rmsg};
+    use reader::MuxReader;
+    use server::{Request, Server};
+    use session::ClientSession;
+    use transport::Transport;
+    use writer::frame_tmsg;
+    use super::{BatchWriter, Batched};
+
+    /// Records each write.
+    struct Writes(Vec<Vec<u8>>);
+
+    impl Writer for Writes {
+        fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+            self.0.push(buf.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let mut batch = BatchWriter::new(Writes(Vec::new())).max_delay(Duration::seconds(60));
+        for _ in range(0, 10) {
+            batch.write_tmsg(&Tag(0, 0, 1), &Tmsg::Ping).unwrap();
+        }
+        batch.flush_if_due().unwrap();
+        assert_eq!(batch.get_ref().0.len(), 0);
+        assert_eq!(batch.buffered_len(), 80);
+
+        batch.flush().unwrap();
+        let writes = batch.into_inner().0;
+        assert_eq!(writes.len(), 1);
+        let mut reader = BufReader::new(writes[0].as_slice());
+        for _ in range(0, 10) {
+            assert_eq!(reader.read_mux_framed_tmsg().unwrap(), (Tag(0, 0, 1), Tmsg::Ping));
+        }
+        assert!(reader.eof());
+    }
+
+    #[test]
+    fn test_thresholds() {
+        let mut batch = BatchWriter::new(Writes(Vec::new())).max_bytes(20).max_delay(Duration::seconds(60));
+        batch.write_rmsg(&Tag(0, 0, 1), &Rmsg::Ping).unwrap();
+        batch.write_rmsg(&Tag(0, 0, 2), &Rmsg::Ping).unwrap();
+        assert_eq!(batch.get_ref().0.len(), 0);
+        batch.write_rmsg(&Tag(0, 0, 3), &Rmsg::Ping).unwrap();
+        assert_eq!(batch.get_ref().0.len(), 1);
+
+        let mut batch = batch.max_bytes(1024).max_delay(Duration::milliseconds(1));
+        batch.write_rmsg(&Tag(0, 0, 4), &Rmsg::Ping).unwrap();
+        sleep(Duration::milliseconds(2));
+        batch.flush_if_due().unwrap();
+        assert_eq!(batch.get_ref().0.len(), 2);
+    }
+
+    #[test]
+    fn test_write_frame() {
+        let (client, mut server) = memory::pair();
+        let mut batched = Batched::with_writer(BatchWriter::new(client).max_delay(Duration::seconds(60)));
+        batched.write_frame(|buf| frame_tmsg(buf, &Tag(0, 0, 1), &Tmsg::Ping)).unwrap();
+        batched.write_frame(|buf| frame_tmsg(buf, &Tag(0, 0, 2), &Tmsg::Ping)).unwrap();
+        assert_eq!(batched.shared.batch.lock().unwrap().buffered_len(), 16);
+
+        batched.flush_batch().unwrap();
+        assert_eq!(server.read_mux_framed_tmsg().unwrap(), (Tag(0, 0, 1), Tmsg::Ping));
+        assert_eq!(server.read_mux_framed_tmsg().unwrap(), (Tag(0, 0, 2), Tmsg::Ping));
+    }
+
+    #[test]
+    fn test_flusher() {
+        // an idle batch is written once it's due...
+        let (client, mut server) = memory::pair();
+        let mut batched = Batched::with_writer(BatchWriter::new(client).max_delay(Duration::milliseconds(1)));
+        batched.write_frame(|buf| frame_tmsg(buf, &Tag(0, 0, 1), &Tmsg::Ping)).unwrap();
+        assert_eq!(server.read_mux_framed_tmsg().unwrap(), (Tag(0, 0, 1), Tmsg::Ping));
+
+        // ...and without a delay, on each flush.
+        let (client, mut server) = memory::pair();
+        let mut batched = Batched::with_writer(BatchWriter::new(client).max_delay(Duration::zero()));
+        assert!(batched._stop.is_none());
+        batched.write_frame(|buf| frame_tmsg(buf, &Tag(0, 0, 2), &Tmsg::Ping)).unwrap();
+        batched.flush().unwrap();
+        assert_eq!(server.read_mux_framed_tmsg().unwrap(), (Tag(0, 0, 2), Tmsg::Ping));
+    }
+
+    #[test]
+    fn test_session() {
+        let (client, server) = memory::pair();
+        let _server = Server::new(|req: Request| Rmsg::DispatchOk(Vec::new(), req.body)).serve(Batched::new(server));
+        let client = ClientSession::new(Batched::new(client));
+        let req = Tmsg::Dispatch(Vec::new(), "/".to_string(), Dtab::empty(), b"mom".to_vec());
+        assert_eq!(client.call(&req).unwrap(), Rmsg::DispatchOk(Vec::new(), b"mom".to_vec()));
+    }
+}
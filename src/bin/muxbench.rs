@@ -0,0 +1,315 @@
+//! Generates load against a mux server and reports latency percentiles.
+//!
+//!     muxbench -c 4 -o 16 -d 30 localhost:6666            # closed-loop
+//!     muxbench -c 4 -o 16 -r 20000 -d 30 localhost:6666   # open-loop
+//!
+//! Each of `-c` connections keeps up to `-o` requests outstanding.  By
+//! default every request is sent as soon as the last on its tag completes,
+//! finding the server's maximum throughput.  With `-r`, requests are
+//! scheduled at a fixed rate regardless of how quickly they complete, and
+//! each one's latency is measured from when it was due, so that time spent
+//! queued behind a slow server is counted rather than omitted.
+
+#![allow(unstable)]
+
+extern crate getopts;
+extern crate mux;
+extern crate time;
+
+use getopts::{optflag, optopt, getopts, usage, Matches};
+use std::os;
+use std::old_io::{IoResult, Writer, stderr};
+use std::old_io::timer::sleep;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUint, Ordering};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread::Thread;
+use std::time::Duration;
+use time::precise_time_ns;
+
+use mux::{Tmsg, Rmsg};
+use mux::histogram::Histogram;
+use mux::misc::Dtab;
+use mux::session::ClientSession;
+use mux::transport::Transport;
+use mux::unix::UnixAddr;
+
+#[derive(Clone)]
+struct Config {
+    addr: String,
+    connections: usize,
+    outstanding: usize,
+    // requests per second, or None to run closed-loop.
+    rate: Option<u64>,
+    duration_ns: u64,
+    interval_ns: u64,
+    req: Tmsg,
+}
+
+/// What a worker saw.
+struct Stats {
+    latency: Histogram,
+    ok: u64,
+    nacks: u64,
+    errors: u64,
+    failures: u64,
+}
+
+impl Stats {
+    fn new() -> Stats {
+        Stats { latency: Histogram::new(), ok: 0, nacks: 0, errors: 0, failures: 0 }
+    }
+
+    fn record(&mut self, rsp: &IoResult<Rmsg>, latency_ns: u64) {
+        self.latency.record(latency_ns);
+        match *rsp {
+            Ok(Rmsg::ReqOk(_)) | Ok(Rmsg::DispatchOk(_, _)) | Ok(Rmsg::Ping) => self.ok += 1,
+            Ok(Rmsg::ReqNack) | Ok(Rmsg::DispatchNack(_)) => self.nacks += 1,
+            Ok(_) => self.errors += 1,
+            Err(_) => self.failures += 1,
+        }
+    }
+
+    fn merge(&mut self, other: &Stats) {
+        self.latency.merge(&other.latency);
+        self.ok += other.ok;
+        self.nacks += other.nacks;
+        self.errors += other.errors;
+        self.failures += other.failures;
+    }
+}
+
+fn main() {
+    let args = os::args();
+    let opts = [
+        optopt("c", "connections", "the number of connections (default: 1)", "N"),
+        optopt("o", "outstanding", "requests outstanding per connection (default: 1)", "N"),
+        optopt("r", "rate", "send N requests per second, open-loop (at most 1000000000)", "N"),
+        optopt("d", "duration", "run for N seconds (default: 10)", "N"),
+        optopt("i", "interval", "report progress every N seconds (default: 1)", "N"),
+        optopt("t", "type", "dispatch (the default), req or ping", "TYPE"),
+        optopt("", "dst", "the Tdispatch destination (default: /)", "PATH"),
+        optopt("", "dtab", "the Tdispatch Dtab", "DTAB"),
+        optopt("s", "size", "the request body size (default: 0)", "BYTES"),
+        optflag("h", "help", "print this message"),
+    ];
+
+    let matches = match getopts(args.tail(), &opts) {
+        Err(f) => return fail(&f.to_string()),
+        Ok(m) => m,
+    };
+    if matches.opt_present("h") || matches.free.len() != 1 {
+        let brief = format!("Usage: {} [options] ADDR", args[0]);
+        print!("{}", usage(brief.as_slice(), &opts));
+        return;
+    }
+    match config(&matches) {
+        Err(msg) => fail(&msg),
+        Ok(config) => if !run(config) { os::set_exit_status(1) },
+    }
+}
+
+fn fail(msg: &String) {
+    writeln!(&mut stderr(), "muxbench: {}", msg).ok();
+    os::set_exit_status(2);
+}
+
+// the most requests per second -r may schedule.
+static MAX_RATE: u64 = 1000000000;
+
+fn number(matches: &Matches, opt: &str, default: u64) -> Result<u64, String> {
+    match matches.opt_str(opt) {
+        None => Ok(default),
+        Some(n) => match n.parse() {
+            Some(n) if n > 0 => Ok(n),
+            _ => Err(format!("-{}: not a positive number: {}", opt, n)),
+        },
+    }
+}
+
+fn config(matches: &Matches) -> Result<Config, String> {
+    let numbers = (number(matches, "c", 1), number(matches, "o", 1), number(matches, "d", 10), number(matches, "i", 1));
+    let (connections, outstanding, duration, interval) = match numbers {
+        (Ok(c), Ok(o), Ok(d), Ok(i)) => (c, o, d, i),
+        (Err(msg), _, _, _) | (_, Err(msg), _, _) | (_, _, Err(msg), _) | (_, _, _, Err(msg)) => return Err(msg),
+    };
+    let rate = match matches.opt_str("r") {
+        None => None,
+        Some(_) => match number(matches, "r", 1) {
+            Err(msg) => return Err(msg),
+            // requests are scheduled a whole number of nanoseconds apart.
+            Ok(rate) if rate > MAX_RATE => return Err(format!("-r: more than {} per second: {}", MAX_RATE, rate)),
+            Ok(rate) => Some(rate),
+        },
+    };
+    let size = match matches.opt_str("s") {
+        None => 0,
+        Some(n) => match n.parse() {
+            None => return Err(format!("-s: not a number: {}", n)),
+            Some(size) => size,
+        },
+    };
+
+    let body = Vec::from_elem(size, 0u8);
+    let typ = matches.opt_str("t").unwrap_or("dispatch".to_string());
+    let req = match typ.as_slice() {
+        "ping" => Tmsg::Ping,
+        "req" => Tmsg::Req(None, body),
+        "dispatch" => {
+            let dtab = match matches.opt_str("dtab") {
+                None => Dtab::empty(),
+                Some(s) => match Dtab::parse(s.as_slice()) {
+                    Err(ioe) => return Err(format!("--dtab: {}", ioe)),
+                    Ok(dtab) => dtab,
+                },
+            };
+            Tmsg::Dispatch(Vec::new(), matches.opt_str("dst").unwrap_or("/".to_string()), dtab, body)
+        },
+        _ => return Err(format!("unknown request type: {}", typ)),
+    };
+
+    Ok(Config {
+        addr: matches.free[0].clone(),
+        connections: connections as usize,
+        outstanding: outstanding as usize,
+        rate: rate,
+        duration_ns: duration * 1000000000,
+        interval_ns: interval * 1000000000,
+        req: req,
+    })
+}
+
+/// Runs the benchmark and prints its results.  Returns false if nothing
+/// succeeded.
+fn run(config: Config) -> bool {
+    let config = Arc::new(config);
+    let mut sessions = Vec::new();
+    for _ in range(0, config.connections) {
+        match connect(config.addr.as_slice()) {
+            Err(ioe) => {
+                fail(&format!("{}: {}", config.addr, ioe));
+                return false;
+            },
+            Ok(session) => sessions.push(Arc::new(session)),
+        }
+    }
+
+    let start = precise_time_ns();
+    let deadline = start + config.duration_ns;
+    let done = Arc::new(AtomicBool::new(false));
+    let completed = Arc::new(AtomicUint::new(0));
+
+    // in open-loop mode, workers take requests' due times from a shared
+    // queue, which falls behind when every worker is busy.
+    let schedule = match config.rate {
+        None => None,
+        Some(rate) => {
+            let (tx, rx) = channel();
+            let done = done.clone();
+            Thread::spawn(move|| {
+                let period_ns = 1000000000 / rate;
+                let mut due = precise_time_ns();
+                while due < deadline && !done.load(Ordering::SeqCst) {
+                    // timers are only as fine as a millisecond: requests
+                    // due sooner than that are sent in a burst.
+                    let now = precise_time_ns();
+                    if due > now + 1000000 {
+                        sleep(Duration::nanoseconds((due - now) as i64));
+                    }
+                    if tx.send(due).is_err() {
+                        break;
+                    }
+                    due += period_ns;
+                }
+            });
+            Some(Arc::new(Mutex::new(rx)))
+        },
+    };
+
+    let mut workers = Vec::new();
+    for session in sessions.iter() {
+        for _ in range(0, config.outstanding) {
+            let (config, session, schedule, completed) =
+                (config.clone(), session.clone(), schedule.clone(), completed.clone());
+            workers.push(Thread::scoped(move|| {
+                let mut stats = Stats::new();
+                loop {
+                    let t0 = match schedule {
+                        None => precise_time_ns(),
+                        Some(ref schedule) => match next_due(&**schedule) {
+                            None => break,
+                            Some(due) => due,
+                        },
+                    };
+                    if t0 >= deadline {
+                        break;
+                    }
+                    let rsp = session.call(&config.req);
+                    stats.record(&rsp, precise_time_ns() - t0);
+                    completed.fetch_add(1, Ordering::SeqCst);
+                }
+                stats
+            }));
+        }
+    }
+
+    report_progress(start, deadline, config.interval_ns, &*done, &*completed);
+    let mut stats = Stats::new();
+    for worker in workers.into_iter() {
+        match worker.join() {
+            Err(_) => (),
+            Ok(s) => stats.merge(&s),
+        }
+    }
+    done.store(true, Ordering::SeqCst);
+
+    let elapsed_s = (precise_time_ns() - start) as f64 / 1e9;
+    let h = &stats.latency;
+    println!("{} requests in {:.1}s: {:.0} rps", h.count(), elapsed_s, h.count() as f64 / elapsed_s);
+    println!("ok={} nacks={} errors={} failures={}", stats.ok, stats.nacks, stats.errors, stats.failures);
+    println!("latency (us): min={} mean={} p50={} p90={} p99={} p999={} max={}",
+             h.min() / 1000, h.mean() / 1000,
+             h.percentile(50.0) / 1000, h.percentile(90.0) / 1000,
+             h.percentile(99.0) / 1000, h.percentile(99.9) / 1000,
+             h.max() / 1000);
+    stats.ok > 0
+}
+
+/// Runs benchmarks over either TCP or Unix sockets.
+trait Caller: Send + Sync {
+    fn call(&self, req: &Tmsg) -> IoResult<Rmsg>;
+}
+
+impl<T: Transport> Caller for ClientSession<T> {
+    fn call(&self, req: &Tmsg) -> IoResult<Rmsg> {
+        ClientSession::call(self, req)
+    }
+}
+
+fn connect(addr: &str) -> IoResult<Box<Caller + Send + Sync>> {
+    match UnixAddr::parse(addr) {
+        Some(unix) => ClientSession::connect_unix(&unix).map(|s| Box::new(s) as Box<Caller + Send + Sync>),
+        None => ClientSession::connect(addr).map(|s| Box::new(s) as Box<Caller + Send + Sync>),
+    }
+}
+
+fn next_due(schedule: &Mutex<Receiver<u64>>) -> Option<u64> {
+    schedule.lock().unwrap().recv().ok()
+}
+
+fn report_progress(start: u64, deadline: u64, interval_ns: u64, done: &AtomicBool, completed: &AtomicUint) {
+    let mut last = 0;
+    let mut next = start + interval_ns;
+    while next < deadline && !done.load(Ordering::SeqCst) {
+        let now = precise_time_ns();
+        if next > now {
+            sleep(Duration::nanoseconds((next - now) as i64));
+        }
+        let current = completed.load(Ordering::SeqCst);
+        writeln!(&mut stderr(), "{:.0}s: {} rps",
+                 (next - start) as f64 / 1e9,
+                 (current - last) as u64 * 1000000000 / interval_ns).ok();
+        last = current;
+        next += interval_ns;
+    }
+}
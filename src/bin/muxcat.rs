@@ -0,0 +1,248 @@
+//! Sends requests to a mux server and prints its responses.
+//!
+//!     echo hi | muxcat -d /svc/echo --dtab '/svc=>/$/inet/localhost/8080' localhost:6666
+//!     muxcat -t ping -n 1000 -p 8 -l -q unix:/tmp/mux.sock
+//!
+//! Request bodies are read from stdin unless `--file` or `--empty` is
+//! given.  Addresses are `HOST:PORT`, `unix:PATH` or `unix:@NAME`.
+
+#![allow(unstable)]
+
+extern crate getopts;
+extern crate mux;
+extern crate time;
+
+use getopts::{optflag, optmulti, optopt, getopts, usage, Matches};
+use std::os;
+use std::old_io::{File, IoResult, Reader, Writer, stdin, stdout, stderr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUint, Ordering};
+use std::sync::mpsc::channel;
+use std::thread::Thread;
+use time::precise_time_ns;
+
+use mux::{Tmsg, Rmsg};
+use mux::misc::{Context, Dtab};
+use mux::session::ClientSession;
+use mux::transport::Transport;
+use mux::unix::UnixAddr;
+
+struct Output {
+    quiet: bool,
+    raw: bool,
+    latency: bool,
+}
+
+fn main() {
+    let args = os::args();
+    let opts = [
+        optopt("t", "type", "dispatch (the default), req, ping or drain", "TYPE"),
+        optopt("d", "dst", "the Tdispatch destination (default: /)", "PATH"),
+        optopt("", "dtab", "the Tdispatch Dtab, e.g. '/s=>/$/inet/x/80;/t=>/s'", "DTAB"),
+        optmulti("c", "context", "a Tdispatch context (repeatable)", "KEY=VALUE"),
+        optopt("f", "file", "read the request body from FILE rather than stdin", "FILE"),
+        optflag("e", "empty", "send an empty body"),
+        optopt("n", "count", "send the request N times (default: 1)", "N"),
+        optopt("p", "parallel", "keep up to N requests outstanding (default: 1)", "N"),
+        optflag("l", "latency", "print each request's latency, and a summary, to stderr"),
+        optflag("q", "quiet", "don't print responses"),
+        optflag("r", "raw", "print only response bodies, as they were received"),
+        optflag("h", "help", "print this message"),
+    ];
+
+    let matches = match getopts(args.tail(), &opts) {
+        Err(f) => return fail(&f.to_string()),
+        Ok(m) => m,
+    };
+    if matches.opt_present("h") || matches.free.len() != 1 {
+        let brief = format!("Usage: {} [options] ADDR", args[0]);
+        print!("{}", usage(brief.as_slice(), &opts));
+        return;
+    }
+
+    let req = match request(&matches) {
+        Err(msg) => return fail(&msg),
+        Ok(req) => req,
+    };
+    let (count, parallel) = match (number(&matches, "n"), number(&matches, "p")) {
+        (Ok(count), Ok(parallel)) => (count, std::cmp::max(parallel, 1)),
+        (Err(msg), _) | (_, Err(msg)) => return fail(&msg),
+    };
+    let out = Output {
+        quiet: matches.opt_present("q"),
+        raw: matches.opt_present("r"),
+        latency: matches.opt_present("l"),
+    };
+
+    let addr = matches.free[0].as_slice();
+    let ok = match UnixAddr::parse(addr) {
+        Some(unix) => ClientSession::connect_unix(&unix).map(|s| run(s, req, count, parallel, &out)),
+        None => ClientSession::connect(addr).map(|s| run(s, req, count, parallel, &out)),
+    };
+    match ok {
+        Err(ioe) => fail(&format!("{}: {}", addr, ioe)),
+        Ok(false) => os::set_exit_status(1),
+        Ok(true) => (),
+    }
+}
+
+fn fail(msg: &String) {
+    writeln!(&mut stderr(), "muxcat: {}", msg).ok();
+    os::set_exit_status(2);
+}
+
+fn number(matches: &Matches, opt: &str) -> Result<usize, String> {
+    match matches.opt_str(opt) {
+        None => Ok(1),
+        Some(n) => n.parse().ok_or(format!("-{}: not a number: {}", opt, n)),
+    }
+}
+
+fn request(matches: &Matches) -> Result<Tmsg, String> {
+    let typ = matches.opt_str("t").unwrap_or("dispatch".to_string());
+    match typ.as_slice() {
+        "ping" => return Ok(Tmsg::Ping),
+        "drain" => return Ok(Tmsg::Drain),
+        "dispatch" | "req" => (),
+        _ => return Err(format!("unknown request type: {}", typ)),
+    }
+
+    let body = match read_body(matches) {
+        Err(ioe) => return Err(format!("reading body: {}", ioe)),
+        Ok(body) => body,
+    };
+    if typ == "req" {
+        return Ok(Tmsg::Req(None, body));
+    }
+
+    let dtab = match matches.opt_str("dtab") {
+        None => Dtab::empty(),
+        Some(s) => match Dtab::parse(s.as_slice()) {
+            Err(ioe) => return Err(format!("--dtab: {}", ioe)),
+            Ok(dtab) => dtab,
+        },
+    };
+    let mut contexts = Vec::new();
+    for c in matches.opt_strs("c").iter() {
+        match c.find('=') {
+            None => return Err(format!("--context: expected KEY=VALUE: {}", c)),
+            Some(i) => contexts.push(Context::new(c.slice_to(i).as_bytes().to_vec(),
+                                                  c.slice_from(i + 1).as_bytes().to_vec())),
+        }
+    }
+    let dst = matches.opt_str("d").unwrap_or("/".to_string());
+    Ok(Tmsg::Dispatch(contexts, dst, dtab, body))
+}
+
+fn read_body(matches: &Matches) -> IoResult<Vec<u8>> {
+    if matches.opt_present("e") {
+        return Ok(Vec::new());
+    }
+    match matches.opt_str("f") {
+        None => stdin().read_to_end(),
+        Some(path) => File::open(&Path::new(path)).and_then(|mut f| f.read_to_end()),
+    }
+}
+
+/// Sends `count` requests from `parallel` threads, printing responses as
+/// they arrive.  Returns false if any failed.
+fn run<T: Transport>(session: ClientSession<T>, req: Tmsg, count: usize, parallel: usize, out: &Output) -> bool {
+    let session = Arc::new(session);
+    let req = Arc::new(req);
+    let next = Arc::new(AtomicUint::new(0));
+    let (tx, rx) = channel();
+    for _ in range(0, std::cmp::min(parallel, count)) {
+        let (session, req, next, tx) = (session.clone(), req.clone(), next.clone(), tx.clone());
+        Thread::spawn(move|| {
+            while next.fetch_add(1, Ordering::SeqCst) < count {
+                let t0 = precise_time_ns();
+                let rsp = session.call(&*req);
+                if tx.send((rsp, precise_time_ns() - t0)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+    drop(tx);
+
+    let mut latencies = Vec::with_capacity(count);
+    let mut failures = 0;
+    for (rsp, ns) in rx.iter() {
+        latencies.push(ns);
+        if out.latency {
+            writeln!(&mut stderr(), "{}us", ns / 1000).ok();
+        }
+        match rsp {
+            Err(ioe) => {
+                failures += 1;
+                writeln!(&mut stderr(), "muxcat: {}", ioe).ok();
+            },
+            Ok(rsp) => {
+                if !is_ok(&rsp) {
+                    failures += 1;
+                }
+                if !out.quiet {
+                    print_rsp(&rsp, out.raw);
+                }
+            },
+        }
+    }
+    session.close();
+
+    if out.latency && !latencies.is_empty() {
+        latencies.sort();
+        let pct = |p: usize| latencies[(latencies.len() - 1) * p / 100] / 1000;
+        writeln!(&mut stderr(), "{} requests, {} failed; latency (us): min={} p50={} p90={} p99={} max={}",
+                 latencies.len(), failures, pct(0), pct(50), pct(90), pct(99), pct(100)).ok();
+    }
+    failures == 0 && latencies.len() == count
+}
+
+fn is_ok(rsp: &Rmsg) -> bool {
+    match *rsp {
+        Rmsg::ReqOk(_) | Rmsg::DispatchOk(_, _) | Rmsg::Ping | Rmsg::Drain => true,
+        _ => false,
+    }
+}
+
+fn print_rsp(rsp: &Rmsg, raw: bool) {
+    let body = match *rsp {
+        Rmsg::ReqOk(ref body) | Rmsg::DispatchOk(_, ref body) => Some(body),
+        _ => None,
+    };
+    if raw {
+        match body {
+            Some(body) => { stdout().write(body.as_slice()).ok(); },
+            None => (),
+        }
+        return;
+    }
+
+    match *rsp {
+        Rmsg::DispatchOk(ref contexts, _)
+        | Rmsg::DispatchError(ref contexts, _)
+        | Rmsg::DispatchNack(ref contexts) => {
+            for c in contexts.iter() {
+                println!("context: {}={}", String::from_utf8_lossy(c.key.as_slice()),
+                         String::from_utf8_lossy(c.val.as_slice()));
+            }
+        },
+        _ => (),
+    }
+    match *rsp {
+        Rmsg::ReqOk(_) => println!("Rreq ok"),
+        Rmsg::ReqError(ref msg) => println!("Rreq error: {}", msg),
+        Rmsg::ReqNack => println!("Rreq nack"),
+        Rmsg::DispatchOk(_, _) => println!("Rdispatch ok"),
+        Rmsg::DispatchError(_, ref msg) => println!("Rdispatch error: {}", msg),
+        Rmsg::DispatchNack(_) => println!("Rdispatch nack"),
+        Rmsg::Drain => println!("Rdrain"),
+        Rmsg::Ping => println!("Rping"),
+        Rmsg::Init(version, _) => println!("Rinit: version {}", version),
+        Rmsg::Err(ref msg) => println!("Rerr: {}", msg),
+    }
+    match body {
+        Some(body) => println!("{}", String::from_utf8_lossy(body.as_slice())),
+        None => (),
+    }
+}
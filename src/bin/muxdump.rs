@@ -0,0 +1,110 @@
+//! Decodes mux traffic from packet captures or raw byte streams.
+//!
+//!     tcpdump -i lo -w mux.pcap port 6666
+//!     muxdump -p 6666 mux.pcap
+//!     muxdump -r < requests.bin
+//!
+//! Each frame is printed on a line of its own: the capture time (or, for
+//! raw streams, the frame's offset), the connection and direction, the tag
+//! (marked `+` if more fragments follow) and the message.  Captures may
+//! start partway through a connection; bytes skipped to find the next frame
+//! are noted.
+
+#![allow(unstable)]
+
+extern crate getopts;
+extern crate mux;
+
+use getopts::{optflag, optopt, getopts, usage};
+use std::os;
+use std::old_io::{BufferedReader, File, Reader, Writer, stdin, stderr};
+
+use mux::Msg;
+use mux::dump;
+use mux::dump::Decoded;
+
+fn main() {
+    let args = os::args();
+    let opts = [
+        optflag("r", "raw", "read a raw byte stream rather than a capture"),
+        optopt("p", "port", "only show connections to or from PORT", "PORT"),
+        optflag("h", "help", "print this message"),
+    ];
+
+    let matches = match getopts(args.tail(), &opts) {
+        Err(f) => return fail(&f.to_string()),
+        Ok(m) => m,
+    };
+    if matches.opt_present("h") || matches.free.len() > 1 {
+        let brief = format!("Usage: {} [options] [FILE]", args[0]);
+        print!("{}", usage(brief.as_slice(), &opts));
+        return;
+    }
+    let port: Option<u16> = match matches.opt_str("p") {
+        None => None,
+        Some(p) => match p.parse() {
+            None => return fail(&format!("-p: not a port: {}", p)),
+            port => port,
+        },
+    };
+
+    // the input is decoded as it's read.
+    let (name, reader) = match matches.free.first() {
+        None => ("stdin".to_string(), Ok(Box::new(stdin()) as Box<Reader>)),
+        Some(path) => {
+            let file = File::open(&Path::new(path.as_slice()));
+            (path.clone(), file.map(|f| Box::new(BufferedReader::new(f)) as Box<Reader>))
+        },
+    };
+    let reader = match reader {
+        Err(ioe) => return fail(&format!("{}: {}", name, ioe)),
+        Ok(reader) => reader,
+    };
+
+    let print = |d: Decoded| {
+        let shown = match (port, d.flow) {
+            (Some(port), Some(flow)) => flow.src.port == port || flow.dst.port == port,
+            _ => true,
+        };
+        if shown {
+            print_decoded(&d);
+        }
+    };
+    // raw streams must be asked for: anything else is a capture, or an
+    // error.
+    let decoded = if matches.opt_present("r") {
+        dump::decode_stream(reader, print)
+    } else {
+        dump::decode_capture(reader, print)
+    };
+    match decoded {
+        Err(ioe) => fail(&format!("{}: {}", name, ioe)),
+        Ok(_) => (),
+    }
+}
+
+fn fail(msg: &String) {
+    writeln!(&mut stderr(), "muxdump: {}", msg).ok();
+    os::set_exit_status(2);
+}
+
+fn print_decoded(d: &Decoded) {
+    let mut line = match d.time {
+        Some(t) => format!("{}.{:06}", t.sec, t.nsec / 1000),
+        None => format!("@{}", d.offset),
+    };
+    match d.flow {
+        Some(flow) => line.push_str(format!(" {} > {}", flow.src, flow.dst).as_slice()),
+        None => (),
+    }
+
+    if d.skipped > 0 {
+        println!("{} ... skipped {} bytes", line, d.skipped);
+    }
+    let tag = format!("tag={}{}", d.tag.with_fragment(false).to_u32(), if d.tag.is_fragment() { "+" } else { "" });
+    match d.msg {
+        Ok(Msg::Tx(_, ref msg)) => println!("{} {} {}", line, tag, msg),
+        Ok(Msg::Rx(_, ref msg)) => println!("{} {} {}", line, tag, msg),
+        Err(ref ioe) => println!("{} {} type={} undecodable: {}", line, tag, d.msg_type, ioe),
+    }
+}
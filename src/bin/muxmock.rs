@@ -0,0 +1,183 @@
+//! Serves scripted responses, for testing clients without a real backend.
+//!
+//!     muxmock -l 127.0.0.1:6666 -s script.txt -r requests.tsv --drain-after 30
+//!
+//! See `mux::mock` for the script format.  Without a script, requests are
+//! echoed.  Tdrain and Tlease are sent to each connection on a schedule
+//! measured from when it was accepted.
+
+#![allow(unstable)]
+
+extern crate getopts;
+extern crate mux;
+extern crate time;
+
+use getopts::{optopt, optflag, getopts, usage, Matches};
+use std::os;
+use std::old_io::{Acceptor, File, IoError, Listener, Writer, stderr};
+use std::old_io::net::tcp::TcpListener;
+use std::old_io::timer::sleep;
+use std::thread::Thread;
+use std::time::Duration;
+use time::precise_time_ns;
+
+use mux::mock;
+use mux::mock::Mock;
+use mux::server::{Handler, Server, ServerSession};
+use mux::transport::Transport;
+use mux::unix::{UnixAddr, UnixSocketListener};
+
+/// Control messages to send each connection, in seconds since it was
+/// accepted.
+#[derive(Clone,Copy)]
+struct Schedule {
+    drain_after: Option<u64>,
+    // how often to grant a lease, and for how long.
+    lease: Option<(u64, Duration)>,
+}
+
+fn main() {
+    let args = os::args();
+    let opts = [
+        optopt("l", "listen", "the address to serve on (default: 0.0.0.0:6666)", "ADDR"),
+        optopt("s", "script", "respond according to the rules in FILE", "FILE"),
+        optopt("r", "record", "write a line to FILE for each request received", "FILE"),
+        optopt("", "drain-after", "send each connection a Tdrain after N seconds", "N"),
+        optopt("", "lease", "grant each connection a lease of N milliseconds...", "N"),
+        optopt("", "lease-every", "...every N seconds (default: 10)", "N"),
+        optflag("h", "help", "print this message"),
+    ];
+
+    let matches = match getopts(args.tail(), &opts) {
+        Err(f) => return fail(&f.to_string()),
+        Ok(m) => m,
+    };
+    if matches.opt_present("h") || !matches.free.is_empty() {
+        let brief = format!("Usage: {} [options]", args[0]);
+        print!("{}", usage(brief.as_slice(), &opts));
+        return;
+    }
+
+    let schedule = match schedule(&matches) {
+        Err(msg) => return fail(&msg),
+        Ok(schedule) => schedule,
+    };
+
+    let rules = match matches.opt_str("s") {
+        None => Vec::new(),
+        Some(path) => match File::open(&Path::new(path.as_slice())).and_then(|mut f| f.read_to_string()) {
+            Err(ioe) => return fail(&format!("{}: {}", path, ioe)),
+            Ok(script) => match mock::parse(script.as_slice()) {
+                Err(ioe) => return fail(&format!("{}: {}", path, ioe)),
+                Ok(rules) => rules,
+            },
+        },
+    };
+    let mut mock = Mock::new(rules);
+    match matches.opt_str("r") {
+        None => (),
+        Some(path) => match File::create(&Path::new(path.as_slice())) {
+            Err(ioe) => return fail(&format!("{}: {}", path, ioe)),
+            Ok(f) => mock = mock.record(f),
+        },
+    }
+    let server = Server::new(mock).accept_legacy(true);
+
+    let addr = matches.opt_str("l").unwrap_or("0.0.0.0:6666".to_string());
+    let ioe = match UnixAddr::parse(addr.as_slice()) {
+        Some(unix) => match UnixSocketListener::bind(&unix) {
+            Err(ioe) => ioe,
+            Ok(listener) => serve(&server, listener, schedule, addr.as_slice()),
+        },
+        None => match TcpListener::bind(addr.as_slice()).and_then(|l| l.listen()) {
+            Err(ioe) => ioe,
+            Ok(acceptor) => serve(&server, acceptor, schedule, addr.as_slice()),
+        },
+    };
+    fail(&format!("{}: {}", addr, ioe));
+}
+
+fn fail(msg: &String) {
+    writeln!(&mut stderr(), "muxmock: {}", msg).ok();
+    os::set_exit_status(2);
+}
+
+fn seconds(matches: &Matches, opt: &str) -> Result<Option<u64>, String> {
+    match matches.opt_str(opt) {
+        None => Ok(None),
+        Some(n) => n.parse().map(|n| Some(n)).ok_or(format!("--{}: not a number: {}", opt, n)),
+    }
+}
+
+fn schedule(matches: &Matches) -> Result<Schedule, String> {
+    let (drain_after, lease, every) = match (seconds(matches, "drain-after"), seconds(matches, "lease"), seconds(matches, "lease-every")) {
+        (Ok(d), Ok(l), Ok(e)) => (d, l, e),
+        (Err(msg), _, _) | (_, Err(msg), _) | (_, _, Err(msg)) => return Err(msg),
+    };
+    let every = every.unwrap_or(10);
+    if every == 0 {
+        return Err("--lease-every: must be at least a second".to_string());
+    }
+    Ok(Schedule {
+        drain_after: drain_after,
+        lease: lease.map(|ms| (every, Duration::milliseconds(ms as i64))),
+    })
+}
+
+fn serve<T: Transport, A: Acceptor<T>, H: Handler + 'static>(
+    server: &Server<H>,
+    mut acceptor: A,
+    schedule: Schedule,
+    addr: &str
+) -> IoError {
+    println!("serving on {}", addr);
+    loop {
+        match acceptor.accept() {
+            Err(ioe) => return ioe,
+            Ok(conn) => {
+                let session = server.serve(conn);
+                if schedule.drain_after.is_some() || schedule.lease.is_some() {
+                    Thread::spawn(move|| control(session, schedule));
+                }
+            },
+        }
+    }
+}
+
+/// Sends a session its scheduled Tdrain and Tleases until it closes.
+fn control<T: Transport>(session: ServerSession<T>, schedule: Schedule) {
+    let start = precise_time_ns();
+    let mut drain_at = schedule.drain_after.map(|s| start + s * 1000000000);
+    let mut lease_at = schedule.lease.map(|(every, _)| start + every * 1000000000);
+    loop {
+        let next = match (drain_at, lease_at) {
+            (None, None) => return,
+            (Some(d), None) => d,
+            (None, Some(l)) => l,
+            (Some(d), Some(l)) => std::cmp::min(d, l),
+        };
+        let now = precise_time_ns();
+        if next > now {
+            sleep(Duration::nanoseconds((next - now) as i64));
+        }
+        if session.is_closed() {
+            return;
+        }
+
+        if drain_at == Some(next) {
+            drain_at = None;
+            if session.drain().is_err() {
+                return;
+            }
+        }
+        match (lease_at, schedule.lease) {
+            (Some(at), Some((every, duration))) if at == next => {
+                lease_at = Some(at + every * 1000000000);
+                if session.lease(duration).is_err() {
+                    return;
+                }
+            },
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! Routes mux requests to backends by destination.
+//!
+//!     muxproxy -l 0.0.0.0:6666 --dtab '/svc=>/#/users' \
+//!         --cluster /#/users=10.0.0.1:8080,10.0.0.2:8080
+//!
+//! See `mux::proxy` for how destinations are resolved.  Addresses to listen
+//! on are `HOST:PORT`, `unix:PATH` or `unix:@NAME`.  Destinations may name
+//! backends directly, as `/$/inet/HOST/PORT`, only if given `--allow-inet`.
+//!
+//! With `--admin`, an HTTP server reports sessions and metrics, and drains
+//! sessions or reloads the base Dtab on request (see `mux::admin`).  The
+//! Dtab is reloaded from `--dtab-file` if given.
+
+#![allow(unstable)]
+
+extern crate getopts;
+extern crate mux;
+
+use getopts::{optflag, optmulti, optopt, getopts, usage, Matches};
+use std::os;
+use std::old_io::{File, IoResult, Listener, Reader, Writer, stderr};
+use std::old_io::net::tcp::TcpListener;
+use std::old_io::timer::sleep;
+use std::thread::Thread;
+use std::time::Duration;
+
+use mux::admin::Admin;
+use mux::metrics::{Metrics, SessionMetrics};
+use mux::misc::Dtab;
+use mux::pool::PoolConfig;
+use mux::proxy::Proxy;
+use mux::server::Server;
+use mux::unix::UnixAddr;
+
+fn main() {
+    let args = os::args();
+    let opts = [
+        optopt("l", "listen", "the address to serve on (default: 0.0.0.0:6666)", "ADDR"),
+        optopt("", "dtab", "the Dtab applied before each request's own", "DTAB"),
+        optopt("", "dtab-file", "read the base Dtab from FILE, rereading it on reload", "FILE"),
+        optmulti("", "cluster", "route NAME to a set of addresses (repeatable)", "NAME=HOST:PORT,..."),
+        optmulti("", "allow-inet", "allow /$/inet destinations to name HOST:PORT (repeatable)", "HOST:PORT"),
+        optopt("", "sessions", "sessions to each backend (default: 1)", "N"),
+        optopt("", "check-every", "ping backends every N seconds (default: 5)", "N"),
+        optopt("", "admin", "serve admin requests over HTTP on ADDR (e.g. 127.0.0.1:9990)", "ADDR"),
+        optflag("h", "help", "print this message"),
+    ];
+
+    let matches = match getopts(args.tail(), &opts) {
+        Err(f) => return fail(&f.to_string()),
+        Ok(m) => m,
+    };
+    if matches.opt_present("h") || !matches.free.is_empty() {
+        let brief = format!("Usage: {} [options]", args[0]);
+        print!("{}", usage(brief.as_slice(), &opts));
+        return;
+    }
+
+    let (sessions, check_every) = match (number(&matches, "sessions", 1), number(&matches, "check-every", 5)) {
+        (Ok(s), Ok(c)) => (s, c),
+        (Err(msg), _) | (_, Err(msg)) => return fail(&msg),
+    };
+    let (dtab, dtab_file) = (matches.opt_str("dtab"), matches.opt_str("dtab-file"));
+    if dtab.is_some() && dtab_file.is_some() {
+        return fail(&"--dtab and --dtab-file are exclusive".to_string());
+    }
+    let base = match load_dtab(&dtab, &dtab_file) {
+        Err(ioe) => return fail(&format!("{}: {}", if dtab.is_some() { "--dtab" } else { "--dtab-file" }, ioe)),
+        Ok(dtab) => dtab,
+    };
+
+    let metrics = Metrics::new();
+    let mut config = PoolConfig::default();
+    config.sessions = sessions as usize;
+    let mut proxy = Proxy::new(base, config)
+        .metrics(SessionMetrics::registered(&metrics, "muxproxy_backend"));
+    for c in matches.opt_strs("cluster").iter() {
+        let addrs: Vec<String> = match c.find('=') {
+            None => Vec::new(),
+            Some(i) => c.slice_from(i + 1).split(',').filter(|a| !a.is_empty()).map(|a| a.to_string()).collect(),
+        };
+        if addrs.is_empty() || !c.starts_with("/") {
+            return fail(&format!("--cluster: expected NAME=HOST:PORT,...: {}", c));
+        }
+        proxy = proxy.cluster(c.slice_to(c.find('=').unwrap()), addrs.as_slice());
+    }
+    for addr in matches.opt_strs("allow-inet").iter() {
+        proxy = proxy.allow_inet(addr.as_slice());
+    }
+
+    let checked = proxy.clone();
+    Thread::spawn(move|| loop {
+        sleep(Duration::seconds(check_every as i64));
+        checked.check();
+    });
+
+    let server = Server::new(proxy.clone())
+        .accept_legacy(true)
+        .metrics(SessionMetrics::registered(&metrics, "muxproxy"));
+    match matches.opt_str("admin") {
+        None => (),
+        Some(admin_addr) => {
+            let admin = Admin::new()
+                .sessions(server.sessions())
+                .metrics(metrics)
+                .dtab(proxy.base_dtab(), move|| load_dtab(&dtab, &dtab_file));
+            // bound before serving, so that a bad address stops the proxy.
+            let acceptor = match TcpListener::bind(admin_addr.as_slice()).and_then(|l| l.listen()) {
+                Err(ioe) => return fail(&format!("--admin {}: {}", admin_addr, ioe)),
+                Ok(acceptor) => acceptor,
+            };
+            println!("admin on {}", admin_addr);
+            Thread::spawn(move|| {
+                let ioe = admin.serve_all(acceptor);
+                fail(&format!("--admin {}: {}", admin_addr, ioe));
+            });
+        },
+    }
+
+    let addr = matches.opt_str("l").unwrap_or("0.0.0.0:6666".to_string());
+    println!("serving on {}", addr);
+    let ioe = match UnixAddr::parse(addr.as_slice()) {
+        Some(unix) => server.listen_unix(&unix),
+        None => server.listen(addr.as_slice()),
+    };
+    fail(&format!("{}: {}", addr, ioe));
+}
+
+fn fail(msg: &String) {
+    writeln!(&mut stderr(), "muxproxy: {}", msg).ok();
+    os::set_exit_status(2);
+}
+
+/// Reads the base Dtab from `file`, or parses `dtab`.
+fn load_dtab(dtab: &Option<String>, file: &Option<String>) -> IoResult<Dtab> {
+    match (dtab, file) {
+        (_, &Some(ref path)) => {
+            File::open(&Path::new(path.as_slice()))
+                .and_then(|mut f| f.read_to_string())
+                .and_then(|s| Dtab::parse(s.as_slice()))
+        },
+        (&Some(ref s), _) => Dtab::parse(s.as_slice()),
+        (&None, &None) => Ok(Dtab::empty()),
+    }
+}
+
+fn number(matches: &Matches, opt: &str, default: u64) -> Result<u64, String> {
+    match matches.opt_str(opt) {
+        None => Ok(default),
+        Some(n) => match n.parse() {
+            Some(n) if n > 0 => Ok(n),
+            _ => Err(format!("--{}: not a positive number: {}", opt, n)),
+        },
+    }
+}
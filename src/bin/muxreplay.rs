@@ -0,0 +1,173 @@
+//! Records mux sessions, and replays them against a server.
+//!
+//!     muxreplay record -l 127.0.0.1:7777 -o session.rec localhost:6666
+//!     muxreplay replay session.rec localhost:6666
+//!     muxreplay show session.rec
+//!
+//! `record` relays connections to an upstream server, recording every frame
+//! sent each way.  `replay` sends each recorded connection's requests to a
+//! server, one connection after another, and reports responses that differ
+//! from those recorded; it exits with status 1 if any do.  See
+//! `mux::record` for the format.
+
+#![allow(unstable)]
+
+extern crate getopts;
+extern crate mux;
+
+use getopts::{optflag, optopt, getopts, usage};
+use std::os;
+use std::old_io::{Acceptor, File, IoError, Listener, Writer, stderr};
+use std::old_io::net::tcp::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use mux::{Msg, Rmsg};
+use mux::record;
+use mux::record::{Direction, Record, RecordWriter};
+use mux::transport::Transport;
+use mux::unix::{UnixAddr, UnixSocket, UnixSocketListener};
+
+fn main() {
+    let args = os::args();
+    let opts = [
+        optopt("l", "listen", "record: the address to relay from (default: 0.0.0.0:7777)", "ADDR"),
+        optopt("o", "output", "record: the file to record to", "FILE"),
+        optopt("t", "timeout", "replay: seconds to wait for responses (default: 10)", "N"),
+        optflag("h", "help", "print this message"),
+    ];
+
+    let matches = match getopts(args.tail(), &opts) {
+        Err(f) => return fail(&f.to_string()),
+        Ok(m) => m,
+    };
+    let free: Vec<&str> = matches.free.iter().map(|s| s.as_slice()).collect();
+    let command = if matches.opt_present("h") || free.is_empty() { "help" } else { free[0] };
+    match (command, free.len()) {
+        ("record", 2) => return match matches.opt_str("o") {
+            None => fail(&"record: --output is required".to_string()),
+            Some(path) => {
+                let listen = matches.opt_str("l").unwrap_or("0.0.0.0:7777".to_string());
+                record_sessions(listen.as_slice(), path.as_slice(), free[1])
+            },
+        },
+        ("replay", 3) => {
+            let timeout = match matches.opt_str("t") {
+                None => 10,
+                Some(n) => match n.parse() {
+                    Some(n) if n > 0 => n,
+                    _ => return fail(&format!("-t: not a positive number: {}", n)),
+                },
+            };
+            return replay(free[1], free[2], Duration::seconds(timeout));
+        },
+        ("show", 2) => return show(free[1]),
+        _ => (),
+    }
+    let brief = format!("Usage: {0} record -o FILE [-l ADDR] UPSTREAM\n       {0} replay [-t N] FILE ADDR\n       {0} show FILE", args[0]);
+    print!("{}", usage(brief.as_slice(), &opts));
+}
+
+fn fail(msg: &String) {
+    writeln!(&mut stderr(), "muxreplay: {}", msg).ok();
+    os::set_exit_status(2);
+}
+
+fn read_recording(path: &str) -> Result<Vec<Record>, String> {
+    File::open(&Path::new(path))
+        .and_then(|f| record::read_all(f))
+        .map_err(|ioe| format!("{}: {}", path, ioe))
+}
+
+fn record_sessions(listen: &str, path: &str, upstream: &str) {
+    let recording = match File::create(&Path::new(path)).and_then(|f| RecordWriter::new(f)) {
+        Err(ioe) => return fail(&format!("{}: {}", path, ioe)),
+        Ok(w) => Arc::new(Mutex::new(w)),
+    };
+    println!("relaying {} to {}", listen, upstream);
+    let ioe = match UnixAddr::parse(listen) {
+        Some(unix) => match UnixSocketListener::bind(&unix) {
+            Err(ioe) => ioe,
+            Ok(listener) => relay_all(listener, upstream, recording),
+        },
+        None => match TcpListener::bind(listen).and_then(|l| l.listen()) {
+            Err(ioe) => ioe,
+            Ok(acceptor) => relay_all(acceptor, upstream, recording),
+        },
+    };
+    fail(&format!("{}: {}", listen, ioe));
+}
+
+fn relay_all<C: Transport, A: Acceptor<C>>(mut acceptor: A, upstream: &str, recording: Arc<Mutex<RecordWriter<File>>>) -> IoError {
+    loop {
+        let client = match acceptor.accept() {
+            Err(ioe) => return ioe,
+            Ok(c) => c,
+        };
+        let connected = match UnixAddr::parse(upstream) {
+            Some(unix) => UnixSocket::connect(&unix).map(|s| record::relay(client, s, recording.clone())),
+            None => TcpStream::connect(upstream).map(|s| record::relay(client, s, recording.clone())),
+        };
+        match connected {
+            // the client's connection is dropped, and so closed.
+            Err(ioe) => { writeln!(&mut stderr(), "muxreplay: {}: {}", upstream, ioe).ok(); },
+            Ok(_) => (),
+        }
+    }
+}
+
+fn replay(path: &str, addr: &str, timeout: Duration) {
+    let records = match read_recording(path) {
+        Err(msg) => return fail(&msg),
+        Ok(records) => records,
+    };
+    let (mut requests, mut matched, mut mismatched) = (0, 0, 0);
+    for &(connection, ref records) in record::by_connection(records.as_slice()).iter() {
+        let report = match UnixAddr::parse(addr) {
+            Some(unix) => UnixSocket::connect(&unix).map(|s| record::replay(records.as_slice(), s, timeout)),
+            None => TcpStream::connect(addr).map(|s| record::replay(records.as_slice(), s, timeout)),
+        };
+        let report = match report {
+            Err(ioe) => return fail(&format!("{}: {}", addr, ioe)),
+            Ok(report) => report,
+        };
+
+        for m in report.mismatches.iter() {
+            let describe = |rsp: &Option<Rmsg>, missing: &str| match *rsp {
+                None => missing.to_string(),
+                Some(ref rsp) => rsp.to_string(),
+            };
+            println!("conn={} tag={}: expected {}", connection, m.tag.to_u32(), describe(&m.expected, "no response"));
+            println!("conn={} tag={}:      got {}", connection, m.tag.to_u32(), describe(&m.actual, "no response"));
+        }
+        requests += report.requests;
+        matched += report.matched;
+        mismatched += report.mismatches.len();
+    }
+    println!("{} requests, {} matched, {} mismatched", requests, matched, mismatched);
+    if mismatched > 0 {
+        os::set_exit_status(1);
+    }
+}
+
+fn show(path: &str) {
+    let records = match read_recording(path) {
+        Err(msg) => return fail(&msg),
+        Ok(records) => records,
+    };
+    for r in records.iter() {
+        let arrow = match r.direction { Direction::FromClient => ">", Direction::FromServer => "<" };
+        let tag = r.tag();
+        let msg = if tag.is_fragment() {
+            format!("fragment, {} bytes", r.frame.len() - 4)
+        } else {
+            match r.msg() {
+                Err(ioe) => format!("undecodable: {}", ioe),
+                Ok(Msg::Tx(_, msg)) => msg.to_string(),
+                Ok(Msg::Rx(_, msg)) => msg.to_string(),
+            }
+        };
+        println!("{}.{:06} conn={} {} tag={} {}", r.time.sec, r.time.nsec / 1000, r.connection, arrow,
+                 tag.with_fragment(false).to_u32(), msg);
+    }
+}
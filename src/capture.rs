@@ -0,0 +1,569 @@
+//! Reading TCP segments from packet captures.
+//!
+//! Both pcap and pcapng files are read, in either byte order, with
+//! Ethernet, Linux cooked, BSD loopback or raw IP link layers.  Segments
+//! are reordered into each direction's byte stream by a `SegmentQueue`.
+//! IP fragments and IPv6 extension headers aren't supported; such packets
+//! are skipped.
+
+#[allow(unstable)]
+
+use std::cmp;
+use std::old_io::{IoResult, IoError, EndOfFile, InvalidInput, Reader};
+use std::old_io::net::ip::{IpAddr, SocketAddr};
+use time::Timespec;
+
+use reader::MAX_FRAME;
+
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct Packet {
+    /// When the packet was captured, if the capture says.
+    pub time: Option<Timespec>,
+    /// The pcap LINKTYPE of `data`.
+    pub link: u16,
+    pub data: Vec<u8>,
+}
+
+/// One direction of a TCP connection.
+#[derive(Clone,Copy,PartialEq,Eq,Hash,Debug)]
+pub struct Flow {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+}
+
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct Segment {
+    pub flow: Flow,
+    pub seq: u32,
+    pub syn: bool,
+    pub payload: Vec<u8>,
+}
+
+const LINKTYPE_NULL: u16 = 0;
+const LINKTYPE_ETHERNET: u16 = 1;
+const LINKTYPE_RAW: u16 = 101;
+const LINKTYPE_LINUX_SLL: u16 = 113;
+const LINKTYPE_IPV4: u16 = 228;
+const LINKTYPE_IPV6: u16 = 229;
+
+const PCAPNG_SECTION: u32 = 0x0A0D0D0A;
+const PCAPNG_INTERFACE: u32 = 1;
+const PCAPNG_SIMPLE_PACKET: u32 = 3;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+const PCAPNG_BYTE_ORDER: u32 = 0x1A2B3C4D;
+
+enum Format {
+    // whether timestamps' fractions are nanoseconds rather than micro.
+    Pcap { nanos: bool, link: u16 },
+    // each interface's link type and timestamp units per second.
+    Pcapng { interfaces: Vec<(u16, u64)> },
+}
+
+/// Reads packets from a pcap or pcapng file, as told by its first bytes.
+pub struct CaptureReader<R> {
+    reader: R,
+    format: Format,
+    little_endian: bool,
+}
+
+impl<R: Reader> CaptureReader<R> {
+    pub fn new(mut reader: R) -> IoResult<CaptureReader<R>> {
+        let magic = match reader.read_exact(4) {
+            Err(ioe) => return Err(ioe),
+            Ok(magic) => magic,
+        };
+        let (format, little_endian) = match u32_at(magic.as_slice(), 0, false) {
+            0xd4c3b2a1 => (Format::Pcap { nanos: false, link: 0 }, true),
+            0xa1b2c3d4 => (Format::Pcap { nanos: false, link: 0 }, false),
+            0x4d3cb2a1 => (Format::Pcap { nanos: true, link: 0 }, true),
+            0xa1b23c4d => (Format::Pcap { nanos: true, link: 0 }, false),
+            PCAPNG_SECTION => (Format::Pcapng { interfaces: Vec::new() }, true),
+            _ => return Err(bad_capture("unknown capture format", Some(format!("{:?}", magic)))),
+        };
+        let mut capture = CaptureReader { reader: reader, format: format, little_endian: little_endian };
+
+        let header = match capture.format {
+            Format::Pcap { .. } => capture.reader.read_exact(20),
+            Format::Pcapng { .. } => capture.read_section(),
+        };
+        match (header, &mut capture.format) {
+            (Err(ioe), _) => return Err(ioe),
+            (Ok(h), &mut Format::Pcap { ref mut link, .. }) => *link = u32_at(h.as_slice(), 16, little_endian) as u16,
+            (Ok(_), &mut Format::Pcapng { .. }) => (),
+        }
+        Ok(capture)
+    }
+
+    /// Reads the next packet, or returns None at the end of the capture.
+    pub fn next_packet(&mut self) -> IoResult<Option<Packet>> {
+        let packet = match self.format {
+            Format::Pcap { .. } => self.next_pcap(),
+            Format::Pcapng { .. } => self.next_pcapng(),
+        };
+        match packet {
+            Err(ref ioe) if ioe.kind == EndOfFile => Ok(None),
+            p => p,
+        }
+    }
+
+    fn next_pcap(&mut self) -> IoResult<Option<Packet>> {
+        let le = self.little_endian;
+        let (nanos, link) = match self.format {
+            Format::Pcap { nanos, link } => (nanos, link),
+            Format::Pcapng { .. } => unreachable!(),
+        };
+        self.reader.read_exact(16).and_then(|h| {
+            let (sec, frac, len) = (u32_at(h.as_slice(), 0, le), u32_at(h.as_slice(), 4, le), u32_at(h.as_slice(), 8, le));
+            if len as usize > MAX_FRAME {
+                return Err(bad_capture("bad packet length", Some(format!("{} bytes", len))));
+            }
+            // fractions of a second must be less than one.
+            let (per_sec, scale) = if nanos { (1000000000, 1) } else { (1000000, 1000) };
+            if frac >= per_sec {
+                return Err(bad_capture("bad packet timestamp", Some(format!("{}.{}", sec, frac))));
+            }
+            let nsec = frac * scale;
+            self.reader.read_exact(len as usize).map(|data| Some(Packet {
+                time: Some(Timespec::new(sec as i64, nsec as i32)),
+                link: link,
+                data: data,
+            }))
+        })
+    }
+
+    fn next_pcapng(&mut self) -> IoResult<Option<Packet>> {
+        loop {
+            let (typ, body) = match self.read_block() {
+                Err(ioe) => return Err(ioe),
+                Ok(block) => block,
+            };
+            let le = self.little_endian;
+            let b = body.as_slice();
+            let interfaces = match self.format {
+                Format::Pcapng { ref mut interfaces } => interfaces,
+                Format::Pcap { .. } => unreachable!(),
+            };
+            match typ {
+                PCAPNG_INTERFACE if b.len() >= 8 => {
+                    match tsresol(b.slice_from(8), le) {
+                        Err(ioe) => return Err(ioe),
+                        Ok(per_sec) => interfaces.push((u16_at(b, 0, le), per_sec)),
+                    }
+                },
+                PCAPNG_ENHANCED_PACKET if b.len() >= 20 => {
+                    let (id, len) = (u32_at(b, 0, le) as usize, u32_at(b, 12, le) as usize);
+                    if id >= interfaces.len() || 20 + len > b.len() {
+                        return Err(bad_capture("bad enhanced packet block", None));
+                    }
+                    let (link, per_sec) = interfaces[id];
+                    let ts = ((u32_at(b, 4, le) as u64) << 32) | u32_at(b, 8, le) as u64;
+                    return Ok(Some(Packet {
+                        time: Some(Timespec::new((ts / per_sec) as i64, nanos(ts % per_sec, per_sec) as i32)),
+                        link: link,
+                        data: b.slice(20, 20 + len).to_vec(),
+                    }));
+                },
+                PCAPNG_SIMPLE_PACKET if b.len() >= 4 && !interfaces.is_empty() => {
+                    let len = cmp::min(u32_at(b, 0, le) as usize, b.len() - 4);
+                    return Ok(Some(Packet { time: None, link: interfaces[0].0, data: b.slice(4, 4 + len).to_vec() }));
+                },
+                // statistics, name resolution and so on.
+                _ => (),
+            }
+        }
+    }
+
+    /// Reads a block's type and body, handling section headers, which
+    /// may change the byte order, along the way.
+    fn read_block(&mut self) -> IoResult<(u32, Vec<u8>)> {
+        loop {
+            let typ = match self.reader.read_exact(4) {
+                Err(ioe) => return Err(ioe),
+                Ok(t) => u32_at(t.as_slice(), 0, self.little_endian),
+            };
+            if typ == PCAPNG_SECTION {
+                match self.read_section() {
+                    Err(ioe) => return Err(ioe),
+                    Ok(_) => continue,
+                }
+            }
+            let len = match self.reader.read_exact(4) {
+                Err(ioe) => return Err(ioe),
+                Ok(l) => u32_at(l.as_slice(), 0, self.little_endian) as usize,
+            };
+            if len < 12 || len > MAX_FRAME {
+                return Err(bad_capture("bad block length", Some(format!("{} bytes", len))));
+            }
+            // the body is followed by a copy of its length.
+            return self.reader.read_exact(len - 8).map(|mut body| {
+                body.truncate(len - 12);
+                (typ, body)
+            });
+        }
+    }
+
+    /// Reads the rest of a section header block, whose type has been read.
+    /// A new section starts with no interfaces.
+    fn read_section(&mut self) -> IoResult<Vec<u8>> {
+        self.reader.read_exact(8).and_then(|h| {
+            let little_endian = if u32_at(h.as_slice(), 4, true) == PCAPNG_BYTE_ORDER {
+                true
+            } else if u32_at(h.as_slice(), 4, false) == PCAPNG_BYTE_ORDER {
+                false
+            } else {
+                return Err(bad_capture("bad byte-order magic", None));
+            };
+            let len = u32_at(h.as_slice(), 0, little_endian) as usize;
+            if len < 16 || len > MAX_FRAME {
+                return Err(bad_capture("bad block length", Some(format!("{} bytes", len))));
+            }
+            self.little_endian = little_endian;
+            self.format = Format::Pcapng { interfaces: Vec::new() };
+            self.reader.read_exact(len - 12)
+        })
+    }
+}
+
+/// The units per second of an interface's timestamps, from its options.
+/// Resolutions too fine for a u64 (over 10^19, or 2^63) are refused.
+fn tsresol(mut options: &[u8], le: bool) -> IoResult<u64> {
+    while options.len() >= 4 {
+        let (code, len) = (u16_at(options, 0, le), u16_at(options, 2, le) as usize);
+        if code == 0 || 4 + len > options.len() {
+            break;
+        }
+        if code == 9 && len >= 1 {
+            let v = options[4];
+            let (exp, max) = if v & 0x80 == 0 { (v, 19) } else { (v & 0x7f, 63) };
+            if exp > max {
+                return Err(bad_capture("bad if_tsresol", Some(format!("{:#x}", v))));
+            }
+            return Ok(if v & 0x80 == 0 { 10u64.pow(exp as usize) } else { 1u64 << exp as usize });
+        }
+        options = options.slice_from(cmp::min(4 + (len + 3) / 4 * 4, options.len()));
+    }
+    Ok(1000000)
+}
+
+/// Converts `frac` units of `per_sec` to nanoseconds.
+fn nanos(frac: u64, per_sec: u64) -> u64 {
+    // frac * 10^9 overflows for resolutions finer than about 2^34.
+    if per_sec <= 1 << 34 {
+        frac * 1000000000 / per_sec
+    } else {
+        (frac as f64 / per_sec as f64 * 1e9) as u64
+    }
+}
+
+/// Extracts a TCP segment from a packet, or returns None if it carries
+/// something else.
+pub fn parse_tcp(packet: &Packet) -> Option<Segment> {
+    let d = packet.data.as_slice();
+    let ip = match packet.link {
+        LINKTYPE_ETHERNET if d.len() >= 14 => {
+            // skip any 802.1Q tags.
+            let mut at = 12;
+            while at + 4 <= d.len() && u16_at(d, at, false) == 0x8100 {
+                at += 4;
+            }
+            d.slice_from(cmp::min(at + 2, d.len()))
+        },
+        LINKTYPE_LINUX_SLL if d.len() >= 16 => d.slice_from(16),
+        LINKTYPE_NULL if d.len() >= 4 => d.slice_from(4),
+        LINKTYPE_RAW | LINKTYPE_IPV4 | LINKTYPE_IPV6 => d,
+        _ => return None,
+    };
+    if ip.is_empty() {
+        return None;
+    }
+
+    let (src, dst, tcp) = match ip[0] >> 4 {
+        4 if ip.len() >= 20 => {
+            let (ihl, total) = ((ip[0] & 0xf) as usize * 4, u16_at(ip, 2, false) as usize);
+            let fragmented = u16_at(ip, 6, false) & 0x3fff != 0;
+            if ip[9] != 6 || fragmented || ihl < 20 || total < ihl || total > ip.len() {
+                return None;
+            }
+            (IpAddr::Ipv4Addr(ip[12], ip[13], ip[14], ip[15]),
+             IpAddr::Ipv4Addr(ip[16], ip[17], ip[18], ip[19]),
+             ip.slice(ihl, total))
+        },
+        6 if ip.len() >= 40 => {
+            let total = 40 + u16_at(ip, 4, false) as usize;
+            if ip[6] != 6 || total > ip.len() {
+                return None;
+            }
+            (ipv6(ip.slice(8, 24)), ipv6(ip.slice(24, 40)), ip.slice(40, total))
+        },
+        _ => return None,
+    };
+
+    if tcp.len() < 20 {
+        return None;
+    }
+    let offset = (tcp[12] >> 4) as usize * 4;
+    if offset < 20 || offset > tcp.len() {
+        return None;
+    }
+    Some(Segment {
+        flow: Flow {
+            src: SocketAddr { ip: src, port: u16_at(tcp, 0, false) },
+            dst: SocketAddr { ip: dst, port: u16_at(tcp, 2, false) },
+        },
+        seq: u32_at(tcp, 4, false),
+        syn: tcp[13] & 0x02 != 0,
+        payload: tcp.slice_from(offset).to_vec(),
+    })
+}
+
+fn ipv6(b: &[u8]) -> IpAddr {
+    let g = |i: usize| u16_at(b, i * 2, false);
+    IpAddr::Ipv6Addr(g(0), g(1), g(2), g(3), g(4), g(5), g(6), g(7))
+}
+
+// segments held awaiting one that was lost, before it's given up on.
+const MAX_PENDING: usize = 256;
+
+/// Reorders one direction's segments into its byte stream, dropping
+/// retransmissions.
+pub struct SegmentQueue {
+    next: Option<u32>,
+    pending: Vec<(u32, Vec<u8>)>,
+}
+
+impl SegmentQueue {
+    pub fn new() -> SegmentQueue {
+        SegmentQueue { next: None, pending: Vec::new() }
+    }
+
+    /// Adds a segment, returning the bytes it makes contiguous and whether
+    /// any were lost before them.  Data is assumed to start with the first
+    /// segment seen, which needn't be the connection's first.
+    pub fn push(&mut self, seg: &Segment) -> (Vec<u8>, bool) {
+        // a SYN occupies a sequence number of its own.
+        let seq = if seg.syn { seq_add(seg.seq, 1) } else { seg.seq };
+        if self.next.is_none() || seg.syn {
+            self.next = Some(seq);
+            self.pending.clear();
+        }
+        if !seg.payload.is_empty() {
+            self.pending.push((seq, seg.payload.clone()));
+        }
+
+        let mut data = Vec::new();
+        let mut gap = false;
+        loop {
+            let next = self.next.unwrap();
+            // the earliest pending segment.
+            let first = self.pending.iter().enumerate()
+                .min_by(|&(_, &(seq, _))| seq_diff(seq, next))
+                .map(|(i, &(seq, _))| (i, seq_diff(seq, next)));
+            match first {
+                None => break,
+                Some((_, ahead)) if ahead > 0 && self.pending.len() <= MAX_PENDING => break,
+                Some((i, ahead)) => {
+                    let (seq, bytes) = self.pending.swap_remove(i);
+                    if ahead > 0 {
+                        gap = true;
+                        data.push_all(bytes.as_slice());
+                        self.next = Some(seq_add(seq, bytes.len()));
+                    } else if ((-ahead) as usize) < bytes.len() {
+                        data.push_all(bytes.slice_from((-ahead) as usize));
+                        self.next = Some(seq_add(seq, bytes.len()));
+                    }
+                },
+            }
+        }
+        (data, gap)
+    }
+}
+
+// sequence numbers wrap, so are compared by their distance.
+fn seq_diff(a: u32, b: u32) -> i32 {
+    (a as i64 - b as i64) as i32
+}
+
+fn seq_add(seq: u32, n: usize) -> u32 {
+    (seq as u64 + n as u64) as u32
+}
+
+fn u16_at(b: &[u8], i: usize, le: bool) -> u16 {
+    if le {
+        (b[i] as u16) | ((b[i + 1] as u16) << 8)
+    } else {
+        ((b[i] as u16) << 8) | (b[i + 1] as u16)
+    }
+}
+
+fn u32_at(b: &[u8], i: usize, le: bool) -> u32 {
+    let (hi, lo) = if le { (u16_at(b, i + 2, le), u16_at(b, i, le)) } else { (u16_at(b, i, le), u16_at(b, i + 2, le)) };
+    ((hi as u32) << 16) | (lo as u32)
+}
+
+fn bad_capture(desc: &'static str, detail: Option<String>) -> IoError {
+    IoError { kind: InvalidInput, desc: desc, detail: detail }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::old_io::{BufReader, InvalidInput, MemWriter, Writer};
+    use std::old_io::net::ip::{IpAddr, SocketAddr};
+    use time::Timespec;
+
+    use super::{CaptureReader, Flow, Packet, Segment, SegmentQueue, parse_tcp};
+
+    pub fn flow(src_port: u16, dst_port: u16) -> Flow {
+        Flow {
+            src: SocketAddr { ip: IpAddr::Ipv4Addr(10, 0, 0, 1), port: src_port },
+            dst: SocketAddr { ip: IpAddr::Ipv4Addr(10, 0, 0, 2), port: dst_port },
+        }
+    }
+
+    /// An Ethernet frame carrying an IPv4 TCP segment.
+    pub fn ethernet(flow: Flow, seq: u32, syn: bool, payload: &[u8]) -> Vec<u8> {
+        let ip = |addr: IpAddr| match addr {
+            IpAddr::Ipv4Addr(a, b, c, d) => vec![a, b, c, d],
+            _ => unreachable!(),
+        };
+        let mut w = MemWriter::new();
+        w.write(&[0u8; 12]).unwrap();
+        w.write_be_u16(0x0800).unwrap();
+        w.write(&[0x45, 0]).unwrap();
+        w.write_be_u16((20 + 20 + payload.len()) as u16).unwrap();
+        w.write(&[0, 0, 0x40, 0, 64, 6, 0, 0]).unwrap();
+        w.write(ip(flow.src.ip).as_slice()).unwrap();
+        w.write(ip(flow.dst.ip).as_slice()).unwrap();
+        w.write_be_u16(flow.src.port).unwrap();
+        w.write_be_u16(flow.dst.port).unwrap();
+        w.write_be_u32(seq).unwrap();
+        w.write_be_u32(0).unwrap();
+        w.write(&[0x50, if syn { 0x02 } else { 0x18 }, 0xff, 0xff, 0, 0, 0, 0]).unwrap();
+        w.write(payload).unwrap();
+        w.into_inner()
+    }
+
+    /// A little-endian, microsecond pcap file of Ethernet frames.
+    pub fn pcap(frames: &[(u32, Vec<u8>)]) -> Vec<u8> {
+        let mut w = MemWriter::new();
+        w.write(&[0xd4, 0xc3, 0xb2, 0xa1]).unwrap();
+        w.write_le_u16(2).unwrap();
+        w.write_le_u16(4).unwrap();
+        w.write(&[0u8; 8]).unwrap();
+        w.write_le_u32(65535).unwrap();
+        w.write_le_u32(1).unwrap();
+        for &(usec, ref frame) in frames.iter() {
+            w.write_le_u32(1000).unwrap();
+            w.write_le_u32(usec).unwrap();
+            w.write_le_u32(frame.len() as u32).unwrap();
+            w.write_le_u32(frame.len() as u32).unwrap();
+            w.write(frame.as_slice()).unwrap();
+        }
+        w.into_inner()
+    }
+
+    /// A big-endian pcapng file of Ethernet frames, with nanosecond
+    /// timestamps.
+    fn pcapng(frames: &[(u32, Vec<u8>)]) -> Vec<u8> {
+        let mut w = MemWriter::new();
+        w.write_be_u32(0x0A0D0D0A).unwrap();
+        w.write_be_u32(28).unwrap();
+        w.write_be_u32(0x1A2B3C4D).unwrap();
+        w.write_be_u16(1).unwrap();
+        w.write_be_u16(0).unwrap();
+        w.write(&[0xffu8; 8]).unwrap();
+        w.write_be_u32(28).unwrap();
+
+        // an interface with if_tsresol=9, i.e. nanoseconds.
+        w.write_be_u32(1).unwrap();
+        w.write_be_u32(32).unwrap();
+        w.write_be_u16(1).unwrap();
+        w.write_be_u16(0).unwrap();
+        w.write_be_u32(65535).unwrap();
+        w.write_be_u16(9).unwrap();
+        w.write_be_u16(1).unwrap();
+        w.write(&[9, 0, 0, 0]).unwrap();
+        w.write(&[0u8; 4]).unwrap();
+        w.write_be_u32(32).unwrap();
+
+        for &(usec, ref frame) in frames.iter() {
+            let padded = (frame.len() + 3) / 4 * 4;
+            let ts = 1000 * 1000000000u64 + usec as u64 * 1000;
+            w.write_be_u32(6).unwrap();
+            w.write_be_u32((32 + padded) as u32).unwrap();
+            w.write_be_u32(0).unwrap();
+            w.write_be_u32((ts >> 32) as u32).unwrap();
+            w.write_be_u32(ts as u32).unwrap();
+            w.write_be_u32(frame.len() as u32).unwrap();
+            w.write_be_u32(frame.len() as u32).unwrap();
+            w.write(frame.as_slice()).unwrap();
+            w.write(Vec::from_elem(padded - frame.len(), 0u8).as_slice()).unwrap();
+            w.write_be_u32((32 + padded) as u32).unwrap();
+        }
+        w.into_inner()
+    }
+
+    #[test]
+    fn test_read_captures() {
+        let frames = vec![(5, ethernet(flow(5000, 6666), 7, false, b"hi")),
+                          (6, ethernet(flow(6666, 5000), 9, true, b""))];
+        for capture in vec![pcap(frames.as_slice()), pcapng(frames.as_slice())].into_iter() {
+            let mut r = CaptureReader::new(BufReader::new(capture.as_slice())).unwrap();
+            let packet = r.next_packet().unwrap().unwrap();
+            assert_eq!(packet.time, Some(Timespec::new(1000, 5000)));
+            assert_eq!(parse_tcp(&packet).unwrap(),
+                       Segment { flow: flow(5000, 6666), seq: 7, syn: false, payload: b"hi".to_vec() });
+            let packet = r.next_packet().unwrap().unwrap();
+            assert_eq!(packet.time, Some(Timespec::new(1000, 6000)));
+            assert!(parse_tcp(&packet).unwrap().syn);
+            assert_eq!(r.next_packet().unwrap(), None);
+        }
+
+        // a microsecond fraction of a whole second or more is refused.
+        let capture = pcap(&[(1000000, ethernet(flow(5000, 6666), 7, false, b"hi"))]);
+        let mut r = CaptureReader::new(BufReader::new(capture.as_slice())).unwrap();
+        assert_eq!(r.next_packet().unwrap_err().kind, InvalidInput);
+
+        assert!(CaptureReader::new(BufReader::new(b"not a capture")).is_err());
+        assert_eq!(parse_tcp(&Packet { time: None, link: 1, data: vec![0; 14] }), None);
+    }
+
+    #[test]
+    fn test_tsresol() {
+        fn option(v: u8) -> Vec<u8> {
+            vec![0, 9, 0, 1, v, 0, 0, 0]
+        }
+        assert_eq!(super::tsresol(&[], false).unwrap(), 1000000);
+        assert_eq!(super::tsresol(option(9).as_slice(), false).unwrap(), 1000000000);
+        assert_eq!(super::tsresol(option(19).as_slice(), false).unwrap(), 10000000000000000000);
+        assert_eq!(super::tsresol(option(0x80 | 63).as_slice(), false).unwrap(), 1 << 63);
+        assert!(super::tsresol(option(20).as_slice(), false).is_err());
+        assert!(super::tsresol(option(0x80 | 64).as_slice(), false).is_err());
+        assert!(super::tsresol(option(0xff).as_slice(), false).is_err());
+
+        assert_eq!(super::nanos(5, 10), 500000000);
+        assert_eq!(super::nanos(1 << 62, 1 << 63), 500000000);
+    }
+
+    #[test]
+    fn test_segment_queue() {
+        let seg = |seq: u32, syn: bool, payload: &[u8]| Segment {
+            flow: flow(1, 2), seq: seq, syn: syn, payload: payload.to_vec(),
+        };
+        let mut q = SegmentQueue::new();
+        assert_eq!(q.push(&seg(0xfffffffe, true, b"")), (Vec::new(), false));
+        // out of order, across the sequence number's wrap.
+        assert_eq!(q.push(&seg(2, false, b"cd")), (Vec::new(), false));
+        assert_eq!(q.push(&seg(0xffffffff, false, b"ab1")), (b"ab1cd".to_vec(), false));
+        // retransmissions are dropped, or trimmed.
+        assert_eq!(q.push(&seg(2, false, b"cd")), (Vec::new(), false));
+        assert_eq!(q.push(&seg(3, false, b"def")), (b"ef".to_vec(), false));
+
+        // a lost segment is eventually given up on.
+        for i in range(0, 256) {
+            assert_eq!(q.push(&seg(10 + i, false, b"x")), (Vec::new(), false));
+        }
+        let (data, gap) = q.push(&seg(266, false, b"x"));
+        assert!(gap);
+        assert_eq!(data.len(), 257);
+    }
+}
@@ -0,0 +1,311 @@
+//! Decoding mux frames from captured traffic.
+//!
+//! A `StreamDecoder` splits one direction's bytes into frames.  A capture
+//! may begin partway through a connection, or lose segments, so until a
+//! frame has been found the decoder scans for one byte by byte: it accepts
+//! a frame only if its header is plausible (a known type, and a length
+//! under `RESYNC_MAX_FRAME`) and its message decodes.  Once in sync, any
+//! frame with a known type is accepted, and decoding errors are reported
+//! rather than skipped.
+
+#[allow(unstable)]
+
+use std::collections::HashMap;
+use std::old_io::{IoResult, BufReader, EndOfFile, Reader};
+use std::iter::repeat;
+use time::Timespec;
+
+use capture::{CaptureReader, Flow, SegmentQueue, parse_tcp};
+use proto::{Msg, MsgType, Tag};
+use reader::{FrameReader, MuxReader, MAX_FRAME};
+
+/// The largest frame accepted while resynchronizing.  A smaller bound
+/// makes arbitrary bytes less likely to pass for a frame header.
+pub static RESYNC_MAX_FRAME: usize = 1024 * 1024;
+
+/// A decoded frame.
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct Decoded {
+    /// When the packet completing the frame was captured, for captures.
+    pub time: Option<Timespec>,
+    /// The direction in which the frame was sent, for captures.
+    pub flow: Option<Flow>,
+    /// The frame's position in its direction's stream.
+    pub offset: u64,
+    /// The bytes skipped since the previous frame to find this one.
+    pub skipped: u64,
+    pub msg_type: i8,
+    /// The frame's tag, including its fragment flag.
+    pub tag: Tag,
+    pub msg: IoResult<Msg>,
+}
+
+/// Splits a byte stream into frames.
+pub struct StreamDecoder {
+    // the unconsumed bytes are buf[start..].
+    buf: Vec<u8>,
+    start: usize,
+    // the stream offset of buf[start].
+    offset: u64,
+    synced: bool,
+    skipped: u64,
+}
+
+impl StreamDecoder {
+    pub fn new() -> StreamDecoder {
+        StreamDecoder { buf: Vec::new(), start: 0, offset: 0, synced: false, skipped: 0 }
+    }
+
+    /// Appends bytes to the stream.  If `gap`, some were lost before them,
+    /// and the decoder resynchronizes.
+    pub fn push(&mut self, bytes: &[u8], gap: bool) {
+        if gap {
+            let unconsumed = (self.buf.len() - self.start) as u64;
+            self.skipped += unconsumed;
+            self.offset += unconsumed;
+            self.buf.clear();
+            self.start = 0;
+            self.synced = false;
+        }
+        self.buf.push_all(bytes);
+    }
+
+    /// Returns the next whole frame, or None until more bytes arrive.
+    pub fn next_frame(&mut self) -> Option<Decoded> {
+        let mut at = 0;
+        let mut found = None;
+        loop {
+            let rest = self.buf.slice_from(self.start + at);
+            if rest.len() < 8 {
+                break;
+            }
+            let len = ((rest[0] as usize) << 24) | ((rest[1] as usize) << 16) | ((rest[2] as usize) << 8) | (rest[3] as usize);
+            let max = if self.synced { MAX_FRAME } else { RESYNC_MAX_FRAME };
+            if len < 4 || len > max || MsgType::from_i8(rest[4] as i8).is_none() {
+                self.synced = false;
+                at += 1;
+                continue;
+            }
+            if rest.len() < 4 + len {
+                break;
+            }
+
+            let frame = BufReader::new(rest).read_frame().unwrap();
+            let msg = BufReader::new(frame.as_slice()).read_mux_msg();
+            if !self.synced && msg.is_err() {
+                at += 1;
+                continue;
+            }
+            self.synced = true;
+            found = Some((frame, msg));
+            break;
+        }
+
+        let skipped = self.skipped + at as u64;
+        match found {
+            None => {
+                self.consume(at);
+                self.skipped = skipped;
+                None
+            },
+            Some((frame, msg)) => {
+                let offset = self.offset + at as u64;
+                self.consume(at + 4 + frame.len());
+                self.skipped = 0;
+                Some(Decoded {
+                    time: None,
+                    flow: None,
+                    offset: offset,
+                    skipped: skipped,
+                    msg_type: frame[0] as i8,
+                    tag: Tag(frame[1], frame[2], frame[3]),
+                    msg: msg,
+                })
+            },
+        }
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.start += n;
+        self.offset += n as u64;
+        // drop what's consumed once it's most of the buffer, so that each
+        // byte is copied a bounded number of times however it's consumed.
+        if self.start >= 4096 && self.start * 2 >= self.buf.len() {
+            self.buf = self.buf.slice_from(self.start).to_vec();
+            self.start = 0;
+        }
+    }
+}
+
+/// Decodes the frames in a raw byte stream, as sent in one direction of a
+/// connection, passing each to `f`.
+pub fn decode_stream<R: Reader, F: FnMut(Decoded)>(mut reader: R, mut f: F) -> IoResult<()> {
+    let mut decoder = StreamDecoder::new();
+    let mut buf: Vec<u8> = repeat(0).take(64 * 1024).collect();
+    loop {
+        match reader.read(buf.as_mut_slice()) {
+            Err(ref ioe) if ioe.kind == EndOfFile => return Ok(()),
+            Err(ioe) => return Err(ioe),
+            Ok(n) => decoder.push(buf.slice_to(n), false),
+        }
+        loop {
+            match decoder.next_frame() {
+                None => break,
+                Some(d) => f(d),
+            }
+        }
+    }
+}
+
+/// Decodes the frames sent in each direction of each TCP connection in a
+/// pcap or pcapng capture, passing each to `f` in the order they were
+/// completed.
+pub fn decode_capture<R: Reader, F: FnMut(Decoded)>(reader: R, mut f: F) -> IoResult<()> {
+    let mut capture = match CaptureReader::new(reader) {
+        Err(ioe) => return Err(ioe),
+        Ok(c) => c,
+    };
+    let mut flows: HashMap<Flow, (SegmentQueue, StreamDecoder)> = HashMap::new();
+    loop {
+        let packet = match capture.next_packet() {
+            Err(ioe) => return Err(ioe),
+            Ok(None) => return Ok(()),
+            Ok(Some(p)) => p,
+        };
+        let seg = match parse_tcp(&packet) {
+            None => continue,
+            Some(seg) => seg,
+        };
+
+        // a SYN starts a new connection, perhaps on a reused port.
+        if seg.syn {
+            flows.remove(&seg.flow);
+        }
+        if !flows.contains_key(&seg.flow) {
+            flows.insert(seg.flow, (SegmentQueue::new(), StreamDecoder::new()));
+        }
+        let &mut (ref mut queue, ref mut decoder) = flows.get_mut(&seg.flow).unwrap();
+        let (bytes, gap) = queue.push(&seg);
+        decoder.push(bytes.as_slice(), gap);
+        loop {
+            match decoder.next_frame() {
+                None => break,
+                Some(mut d) => {
+                    d.time = packet.time;
+                    d.flow = Some(seg.flow);
+                    f(d)
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::old_io::{BufReader, MemWriter};
+
+    use capture::test::{ethernet, flow, pcap};
+    use misc::Dtab;
+    use proto::{Msg, Tag, Tmsg, Rmsg};
+    use writer::MuxWriter;
+    use super::{StreamDecoder, decode_capture, decode_stream};
+
+    fn dispatch(body: &[u8]) -> Tmsg {
+        Tmsg::Dispatch(Vec::new(), "/s".to_string(), Dtab::parse("/s=>/t").unwrap(), body.to_vec())
+    }
+
+    fn frames() -> Vec<u8> {
+        let mut w = MemWriter::new();
+        for i in range(1, 4) {
+            w.write_mux_framed_tmsg(&Tag(0, 0, i), &dispatch(b"hello")).unwrap();
+        }
+        w.into_inner()
+    }
+
+    #[test]
+    fn test_decode_stream() {
+        let mut decoded = Vec::new();
+        decode_stream(BufReader::new(frames().as_slice()), |d| decoded.push(d)).unwrap();
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(decoded[2].tag, Tag(0, 0, 3));
+        assert_eq!(decoded[2].msg_type, 2);
+        assert_eq!(decoded[2].skipped, 0);
+        assert_eq!(decoded[2].msg, Ok(Msg::Tx(Tag(0, 0, 3), dispatch(b"hello"))));
+        assert_eq!(format!("{}", decoded[2].msg.as_ref().unwrap()),
+                   "tag=3 Tdispatch dst=/s dtab=/s=>/t body=5B \"hello\"");
+    }
+
+    #[test]
+    fn test_resync() {
+        // start partway through the first frame, a byte at a time.
+        let bytes = frames();
+        let mut decoder = StreamDecoder::new();
+        let mut decoded = Vec::new();
+        for b in bytes.slice_from(5).iter() {
+            decoder.push(&[*b], false);
+            match decoder.next_frame() {
+                None => (),
+                Some(d) => decoded.push(d),
+            }
+        }
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].tag, Tag(0, 0, 2));
+        assert_eq!(decoded[0].skipped as usize, bytes.len() / 3 - 5);
+        assert_eq!(decoded[0].offset as usize, bytes.len() / 3 - 5);
+        assert_eq!(decoded[1].skipped, 0);
+
+        // a gap discards what's buffered.
+        let mut decoder = StreamDecoder::new();
+        decoder.push(bytes.slice_to(10), false);
+        assert!(decoder.next_frame().is_none());
+        decoder.push(bytes.slice_from(bytes.len() / 3 * 2), true);
+        let d = decoder.next_frame().unwrap();
+        assert_eq!((d.tag, d.skipped), (Tag(0, 0, 3), 10));
+    }
+
+    #[test]
+    fn test_compaction() {
+        // many frames pushed at once are consumed without copying the rest
+        // each time, and what's consumed is eventually dropped.
+        let mut w = MemWriter::new();
+        for i in range(0, 10000) {
+            w.write_mux_framed_tmsg(&Tag(0, (i >> 8) as u8, i as u8), &Tmsg::Ping).unwrap();
+        }
+        let bytes = w.into_inner();
+        let mut decoder = StreamDecoder::new();
+        decoder.push(bytes.as_slice(), false);
+        for i in range(0, 10000) {
+            let d = decoder.next_frame().unwrap();
+            assert_eq!((d.tag, d.offset), (Tag(0, (i >> 8) as u8, i as u8), i as u64 * 8));
+        }
+        assert!(decoder.next_frame().is_none());
+        assert!(decoder.buf.len() - decoder.start < 8);
+        assert!(decoder.buf.len() < bytes.len());
+    }
+
+    #[test]
+    fn test_decode_capture() {
+        let mut rsp = MemWriter::new();
+        rsp.write_mux_framed_rmsg(&Tag(0, 0, 1), &Rmsg::DispatchOk(Vec::new(), b"hi".to_vec())).unwrap();
+        let (req, rsp) = (frames(), rsp.into_inner());
+
+        // the request is split across segments, which arrive out of order.
+        let (c, s) = (flow(5000, 6666), flow(6666, 5000));
+        let capture = pcap(&[
+            (1, ethernet(c, 99, true, b"")),
+            (2, ethernet(c, 110, false, req.slice_from(10))),
+            (3, ethernet(c, 100, false, req.slice_to(10))),
+            (4, ethernet(s, 0, false, rsp.as_slice())),
+        ]);
+
+        let mut decoded = Vec::new();
+        decode_capture(BufReader::new(capture.as_slice()), |d| decoded.push(d)).unwrap();
+        assert_eq!(decoded.len(), 4);
+        for d in decoded.slice_to(3).iter() {
+            assert_eq!(d.flow, Some(c));
+            assert_eq!(d.time.unwrap().nsec, 3000);
+        }
+        assert_eq!(decoded[3].flow, Some(s));
+        assert_eq!(decoded[3].msg, Ok(Msg::Rx(Tag(0, 0, 1), Rmsg::DispatchOk(Vec::new(), b"hi".to_vec()))));
+    }
+}
@@ -0,0 +1,255 @@
+//! Decoding frames from a reusable buffer.
+//!
+//! `FrameReader::read_frame` allocates a vector per frame.  A `FrameBuf`
+//! instead reads into one buffer, which grows to fit the largest frame seen
+//! and is otherwise reused, and lends out each frame until the next is
+//! read.
+
+#[allow(unstable)]
+
+use std::iter::repeat;
+use std::old_io::{IoResult, IoError, InvalidInput, BufReader, Reader, Seek};
+
+use proto::{Msg, Tag, Tmsg, Rmsg};
+use reader::{MuxReader, MAX_FRAME};
+
+/// A frame borrowed from a `FrameBuf`.
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct FrameRef<'a> {
+    /// The frame's contents: its type, tag and payload.
+    pub bytes: &'a [u8],
+}
+
+impl<'a> FrameRef<'a> {
+    pub fn msg_type(&self) -> i8 {
+        self.bytes[0] as i8
+    }
+
+    /// The frame's tag, including its fragment flag.
+    pub fn tag(&self) -> Tag {
+        Tag(self.bytes[1], self.bytes[2], self.bytes[3])
+    }
+
+    pub fn payload(&self) -> &'a [u8] {
+        self.bytes.slice_from(4)
+    }
+
+    pub fn to_msg(&self) -> IoResult<Msg> {
+        decode(self.bytes)
+    }
+}
+
+/// Decodes a message from a frame's contents (its type, tag and payload).
+/// Errors note the offset within the frame at which decoding failed.
+pub fn decode(bytes: &[u8]) -> IoResult<Msg> {
+    decode_with(bytes, |r| r.read_mux_msg())
+}
+
+fn decode_with<T, F>(bytes: &[u8], f: F) -> IoResult<T>
+    where F: FnOnce(&mut BufReader) -> IoResult<T>
+{
+    let mut r = BufReader::new(bytes);
+    let decoded = f(&mut r);
+    decoded.map_err(|ioe| {
+        let offset = r.tell().unwrap_or(0);
+        let detail = match ioe.detail {
+            None => format!("at byte {}", offset),
+            Some(ref detail) => format!("{}, at byte {}", detail, offset),
+        };
+        IoError { kind: ioe.kind, desc: ioe.desc, detail: Some(detail) }
+    })
+}
+
+pub struct FrameBuf<R> {
+    reader: R,
+    buf: Vec<u8>,
+    // the unconsumed bytes are buf[start..end].
+    start: usize,
+    end: usize,
+    // the size of the frame last lent out, consumed on the next read.
+    lent: usize,
+    max_frame: usize,
+}
+
+impl<R: Reader> FrameBuf<R> {
+    /// Starts with a 64KB buffer and refuses frames over 16MB.
+    pub fn new(reader: R) -> FrameBuf<R> {
+        FrameBuf {
+            reader: reader,
+            buf: repeat(0).take(64 * 1024).collect(),
+            start: 0,
+            end: 0,
+            lent: 0,
+            max_frame: MAX_FRAME,
+        }
+    }
+
+    pub fn max_frame(mut self, max_frame: usize) -> FrameBuf<R> {
+        self.max_frame = max_frame;
+        self
+    }
+
+    /// Reads the next frame, which is valid until the next read.
+    pub fn next_frame(&mut self) -> IoResult<FrameRef> {
+        self.start += self.lent;
+        self.lent = 0;
+
+        let len = match self.fill(4) {
+            Err(ioe) => return Err(ioe),
+            Ok(_) => {
+                let b = self.buf.slice(self.start, self.start + 4);
+                ((b[0] as usize) << 24) | ((b[1] as usize) << 16) | ((b[2] as usize) << 8) | (b[3] as usize)
+            },
+        };
+        if len < 4 || len > self.max_frame {
+            return Err(IoError {
+                kind: InvalidInput,
+                desc: "bad frame size",
+                detail: Some(format!("{} bytes", len)),
+            });
+        }
+
+        match self.fill(4 + len) {
+            Err(ioe) => Err(ioe),
+            Ok(_) => {
+                self.lent = 4 + len;
+                Ok(FrameRef { bytes: self.buf.slice(self.start + 4, self.start + 4 + len) })
+            },
+        }
+    }
+
+    pub fn read_mux_msg(&mut self) -> IoResult<Msg> {
+        self.next_frame().and_then(|f| f.to_msg())
+    }
+
+    pub fn read_mux_tmsg(&mut self) -> IoResult<(Tag, Tmsg)> {
+        self.next_frame().and_then(|f| decode_with(f.bytes, |r| r.read_mux_tmsg()))
+    }
+
+    pub fn read_mux_rmsg(&mut self) -> IoResult<(Tag, Rmsg)> {
+        self.next_frame().and_then(|f| decode_with(f.bytes, |r| r.read_mux_rmsg()))
+    }
+
+    /// True if a whole frame beyond the one last read is already
+    /// buffered, so the next read won't wait on the reader.
+    pub fn has_frame(&self) -> bool {
+        let start = self.start + self.lent;
+        if self.end - start < 4 {
+            return false;
+        }
+        let b = self.buf.slice(start, start + 4);
+        let len = ((b[0] as usize) << 24) | ((b[1] as usize) << 16) | ((b[2] as usize) << 8) | (b[3] as usize);
+        self.end - start >= 4 + len
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    /// Reads until at least `n` unconsumed bytes are buffered.
+    fn fill(&mut self, n: usize) -> IoResult<()> {
+        if self.end - self.start >= n {
+            return Ok(());
+        }
+
+        // make room by moving what's unconsumed to the front, then growing.
+        if self.start + n > self.buf.len() {
+            let unconsumed = self.end - self.start;
+            for i in range(0, unconsumed) {
+                self.buf[i] = self.buf[self.start + i];
+            }
+            self.start = 0;
+            self.end = unconsumed;
+        }
+        if n > self.buf.len() {
+            let grow = n - self.buf.len();
+            self.buf.extend(repeat(0).take(grow));
+        }
+
+        while self.end - self.start < n {
+            match self.reader.read(self.buf.slice_from_mut(self.end)) {
+                Err(ioe) => return Err(ioe),
+                Ok(read) => self.end += read,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::old_io::{BufReader, MemWriter};
+
+    use misc::Dtab;
+    use proto::{Msg, Tag, Tmsg};
+    use writer::MuxWriter;
+    use super::{FrameBuf, decode};
+
+    #[test]
+    fn test_frames() {
+        let mut w = MemWriter::new();
+        let big = Tmsg::Req(None, range(0, 200).map(|i| i as u8).collect());
+        for i in range(0, 10) {
+            w.write_mux_framed_tmsg(&Tag(0, 0, i), &Tmsg::Ping).unwrap();
+            w.write_mux_framed_tmsg(&Tag(0, 1, i), &big).unwrap();
+        }
+        let bytes = w.into_inner();
+
+        // a small buffer is compacted and grown as frames are read.
+        let mut frames = FrameBuf::new(BufReader::new(bytes.as_slice()));
+        frames.buf.truncate(16);
+        for i in range(0, 10) {
+            assert_eq!(frames.read_mux_tmsg().unwrap(), (Tag(0, 0, i), Tmsg::Ping));
+            {
+                let frame = frames.next_frame().unwrap();
+                assert_eq!(frame.msg_type(), 1);
+                assert_eq!(frame.tag(), Tag(0, 1, i));
+                assert_eq!(frame.to_msg().unwrap(), Msg::Tx(Tag(0, 1, i), big.clone()));
+            }
+        }
+        assert!(frames.next_frame().is_err());
+    }
+
+    #[test]
+    fn test_has_frame() {
+        let mut w = MemWriter::new();
+        w.write_mux_framed_tmsg(&Tag(0, 0, 1), &Tmsg::Ping).unwrap();
+        w.write_mux_framed_tmsg(&Tag(0, 0, 2), &Tmsg::Ping).unwrap();
+        let bytes = w.into_inner();
+
+        let mut frames = FrameBuf::new(BufReader::new(bytes.slice_to(bytes.len() - 1)));
+        assert!(!frames.has_frame());
+        frames.next_frame().unwrap();
+        // the second frame is buffered, but for its last byte.
+        assert!(!frames.has_frame());
+
+        let mut frames = FrameBuf::new(BufReader::new(bytes.as_slice()));
+        frames.next_frame().unwrap();
+        assert!(frames.has_frame());
+        frames.next_frame().unwrap();
+        assert!(!frames.has_frame());
+    }
+
+    #[test]
+    fn test_decode_offset() {
+        let err = decode(&[99, 0, 0, 1]).unwrap_err();
+        assert_eq!(err.detail, Some("99, at byte 1".to_string()));
+
+        // a Tdispatch cut off within its destination.
+        let mut w = MemWriter::new();
+        let req = Tmsg::Dispatch(Vec::new(), "/s/users".to_string(), Dtab::empty(), Vec::new());
+        w.write_mux_framed_tmsg(&Tag(0, 0, 1), &req).unwrap();
+        let bytes = w.into_inner();
+        let err = decode(bytes.slice(4, 12)).unwrap_err();
+        assert_eq!(err.detail, Some("at byte 8".to_string()));
+    }
+
+    #[test]
+    fn test_max_frame() {
+        let mut w = MemWriter::new();
+        w.write_mux_framed_tmsg(&Tag(0, 0, 1), &Tmsg::Req(None, Vec::from_elem(100, 0))).unwrap();
+        let bytes = w.into_inner();
+        let mut frames = FrameBuf::new(BufReader::new(bytes.as_slice())).max_frame(64);
+        assert!(frames.next_frame().is_err());
+    }
+}
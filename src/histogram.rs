@@ -0,0 +1,246 @@
+//! Latency histograms.
+//!
+//! Values are counted in log-linear buckets, in the manner of HdrHistogram:
+//! each power of two is split into 128 equal buckets, so that any recorded
+//! value is reported to within 1/128th (under 1%) of itself, across the
+//! full range of a u64, in a fixed ~60KB.  An `AtomicHistogram` counts
+//! values in the same buckets, but may be recorded to from many threads
+//! without a lock.
+
+#[allow(unstable)]
+
+use std::cmp;
+use std::iter::repeat;
+use std::sync::atomic::{AtomicUint, Ordering};
+
+// values below 2^BITS are counted exactly.
+const BITS: usize = 7;
+const SUB_BUCKETS: usize = 1 << BITS;
+const BUCKETS: usize = (64 - BITS + 1) * SUB_BUCKETS;
+
+#[derive(Clone)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    count: u64,
+    sum: u64,
+    min: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            counts: repeat(0).take(BUCKETS).collect(),
+            count: 0,
+            sum: 0,
+            min: 0,
+            max: 0,
+        }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        self.counts[index(value)] += 1;
+        self.min = if self.count == 0 { value } else { cmp::min(self.min, value) };
+        self.max = cmp::max(self.max, value);
+        self.count += 1;
+        self.sum += value;
+    }
+
+    /// Adds another histogram's values to this one's.
+    pub fn merge(&mut self, other: &Histogram) {
+        if other.count == 0 {
+            return;
+        }
+        for (c, o) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *c += *o;
+        }
+        self.min = if self.count == 0 { other.min } else { cmp::min(self.min, other.min) };
+        self.max = cmp::max(self.max, other.max);
+        self.count += other.count;
+        self.sum += other.sum;
+    }
+
+    pub fn count(&self) -> u64 { self.count }
+
+    pub fn min(&self) -> u64 { self.min }
+
+    pub fn max(&self) -> u64 { self.max }
+
+    /// The total of all values recorded.
+    pub fn sum(&self) -> u64 { self.sum }
+
+    pub fn mean(&self) -> u64 {
+        if self.count == 0 { 0 } else { self.sum / self.count }
+    }
+
+    /// The value at or below which `p` percent of values fall, or 0 if
+    /// none have been recorded.
+    pub fn percentile(&self, p: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = cmp::max((p / 100.0 * self.count as f64).ceil() as u64, 1);
+        let mut seen = 0;
+        for (i, c) in self.counts.iter().enumerate() {
+            seen += *c;
+            if seen >= rank {
+                return cmp::max(cmp::min(highest(i), self.max), self.min);
+            }
+        }
+        self.max
+    }
+}
+
+/// A histogram whose buckets are atomic counters.  Recording takes no
+/// lock; a snapshot taken while values are recorded may include some of a
+/// value's totals but not others.
+pub struct AtomicHistogram {
+    counts: Vec<AtomicUint>,
+    sum: AtomicUint,
+    // usize::MAX until a value is recorded.
+    min: AtomicUint,
+    max: AtomicUint,
+}
+
+impl AtomicHistogram {
+    pub fn new() -> AtomicHistogram {
+        AtomicHistogram {
+            counts: range(0, BUCKETS).map(|_| AtomicUint::new(0)).collect(),
+            sum: AtomicUint::new(0),
+            min: AtomicUint::new(!0),
+            max: AtomicUint::new(0),
+        }
+    }
+
+    pub fn record(&self, value: u64) {
+        self.counts[index(value)].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value as usize, Ordering::Relaxed);
+        let v = value as usize;
+        loop {
+            let min = self.min.load(Ordering::Relaxed);
+            if v >= min || self.min.compare_and_swap(min, v, Ordering::Relaxed) == min {
+                break;
+            }
+        }
+        loop {
+            let max = self.max.load(Ordering::Relaxed);
+            if v <= max || self.max.compare_and_swap(max, v, Ordering::Relaxed) == max {
+                break;
+            }
+        }
+    }
+
+    /// A copy of the values recorded so far.
+    pub fn snapshot(&self) -> Histogram {
+        let mut h = Histogram::new();
+        for (c, a) in h.counts.iter_mut().zip(self.counts.iter()) {
+            *c = a.load(Ordering::Relaxed) as u64;
+        }
+        // the count is that of the buckets copied, so that percentiles
+        // are consistent.
+        h.count = h.counts.iter().fold(0, |n, c| n + *c);
+        if h.count > 0 {
+            h.sum = self.sum.load(Ordering::Relaxed) as u64;
+            h.min = self.min.load(Ordering::Relaxed) as u64;
+            h.max = self.max.load(Ordering::Relaxed) as u64;
+        }
+        h
+    }
+}
+
+fn index(value: u64) -> usize {
+    if value < SUB_BUCKETS as u64 {
+        return value as usize;
+    }
+    let shift = 63 - value.leading_zeros() as usize - BITS;
+    (shift + 1) * SUB_BUCKETS + (value >> shift) as usize - SUB_BUCKETS
+}
+
+/// The highest value counted in bucket `i`.
+fn highest(i: usize) -> u64 {
+    if i < SUB_BUCKETS {
+        return i as u64;
+    }
+    let shift = i / SUB_BUCKETS - 1;
+    let lowest = ((i % SUB_BUCKETS + SUB_BUCKETS) as u64) << shift;
+    lowest + ((1 << shift) - 1)
+}
+
+#[cfg(test)]
+mod test {
+    use std::iter::range_step;
+    use std::u64;
+    use std::sync::Arc;
+    use std::thread::Thread;
+    use super::{AtomicHistogram, Histogram, index, highest, BUCKETS};
+
+    #[test]
+    fn test_buckets() {
+        assert_eq!(index(0), 0);
+        assert_eq!(index(127), 127);
+        assert_eq!(index(128), 128);
+        assert_eq!(index(256), 256);
+        assert_eq!(index(257), 256);
+        assert_eq!(highest(256), 257);
+        assert_eq!(index(u64::MAX), BUCKETS - 1);
+        assert_eq!(highest(BUCKETS - 1), u64::MAX);
+
+        for v in range_step(1000u64, 100000, 7) {
+            let h = highest(index(v));
+            assert!(h >= v && h - v <= v / 128);
+        }
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut h = Histogram::new();
+        assert_eq!(h.percentile(50.0), 0);
+
+        for v in range(1u64, 10001) {
+            h.record(v * 1000);
+        }
+        assert_eq!(h.count(), 10000);
+        assert_eq!(h.min(), 1000);
+        assert_eq!(h.max(), 10000000);
+        assert_eq!(h.mean(), 5000500);
+        assert_eq!(h.sum(), 50005000000);
+        for &(p, expected) in [(50.0, 5000000u64), (90.0, 9000000), (99.0, 9900000), (99.9, 9990000)].iter() {
+            let v = h.percentile(p);
+            assert!(v >= expected && v - expected <= expected / 128, "p{} = {}", p, v);
+        }
+        assert_eq!(h.percentile(100.0), 10000000);
+
+        let mut merged = Histogram::new();
+        merged.merge(&h);
+        merged.record(1);
+        assert_eq!(merged.count(), 10001);
+        assert_eq!(merged.min(), 1);
+        assert_eq!(merged.percentile(50.0), h.percentile(50.0));
+    }
+
+    #[test]
+    fn test_atomic() {
+        let atomic = Arc::new(AtomicHistogram::new());
+        assert_eq!(atomic.snapshot().count(), 0);
+        assert_eq!(atomic.snapshot().min(), 0);
+
+        let threads: Vec<_> = range(0u64, 4).map(|t| {
+            let atomic = atomic.clone();
+            Thread::scoped(move|| {
+                for v in range(1u64, 1001) {
+                    atomic.record(t * 1000 + v);
+                }
+            })
+        }).collect();
+        drop(threads);
+
+        let mut h = Histogram::new();
+        for v in range(1u64, 4001) {
+            h.record(v);
+        }
+        let snapshot = atomic.snapshot();
+        assert_eq!((snapshot.count(), snapshot.sum(), snapshot.min(), snapshot.max()),
+                   (h.count(), h.sum(), h.min(), h.max()));
+        assert_eq!(snapshot.percentile(99.0), h.percentile(99.0));
+    }
+}
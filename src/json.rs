@@ -0,0 +1,404 @@
+//! Converting messages to and from JSON, for logs and tooling.  Enabled by
+//! the `json` feature.
+//!
+//! A message becomes an object holding its `type` (e.g. `"Tdispatch"`) and
+//! fields, and, for a `Msg`, its `tag`:
+//!
+//!     {"body":"hello","contexts":[{"key":"k","value":"v"}],"dst":"/s",
+//!      "dtab":"/s=>/t","tag":1,"type":"Tdispatch"}
+//!
+//! Rreq and Rdispatch also carry a `status` of `ok`, `error` or `nack`.
+//! Byte strings (bodies, and context and header keys and values) are
+//! strings if they're valid UTF-8, and otherwise objects such as
+//! `{"base64":"AP8="}`.  Dtabs are written in Finagle's syntax, and trace
+//! ids in hex.  When decoding, missing contexts, Dtabs and headers are taken
+//! to be empty.
+
+#[allow(unstable)]
+
+use std::collections::BTreeMap;
+use std::num::from_str_radix;
+use std::old_io::{IoError, IoResult, InvalidInput};
+use std::str;
+use std::u64;
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use rustc_serialize::json::{self, Json, ToJson};
+
+use misc::{Context, Dtab, Header, Trace};
+use proto::{Msg, Tag, Tmsg, Rmsg, MAX_TAG};
+
+type Object = BTreeMap<String, Json>;
+
+impl ToJson for Tmsg {
+    fn to_json(&self) -> Json {
+        let mut obj = typed(format!("{:?}", self.get_type()));
+        match *self {
+            Tmsg::Req(ref trace, ref body) => {
+                match *trace {
+                    None => (),
+                    Some(ref trace) => put(&mut obj, "trace", trace_json(trace)),
+                }
+                put(&mut obj, "body", bytes_json(body.as_slice()));
+            },
+            Tmsg::Dispatch(ref contexts, ref dst, ref dtab, ref body) => {
+                put(&mut obj, "contexts", contexts_json(contexts.as_slice()));
+                put(&mut obj, "dst", dst.to_json());
+                put(&mut obj, "dtab", dtab.to_string().to_json());
+                put(&mut obj, "body", bytes_json(body.as_slice()));
+            },
+            Tmsg::Drain | Tmsg::Ping => (),
+            Tmsg::Discarded(which, ref why) => {
+                put(&mut obj, "which", which.to_u32().to_json());
+                put(&mut obj, "why", why.to_json());
+            },
+            Tmsg::Lease(unit, n) => {
+                put(&mut obj, "unit", unit.to_json());
+                put(&mut obj, "length", n.to_json());
+            },
+            Tmsg::Init(version, ref headers) => {
+                put(&mut obj, "version", version.to_json());
+                put(&mut obj, "headers", headers_json(headers.as_slice()));
+            },
+        }
+        Json::Object(obj)
+    }
+}
+
+impl ToJson for Rmsg {
+    fn to_json(&self) -> Json {
+        let mut obj = typed(format!("{:?}", self.get_type()));
+        match *self {
+            Rmsg::ReqOk(ref body) => {
+                put(&mut obj, "status", "ok".to_json());
+                put(&mut obj, "body", bytes_json(body.as_slice()));
+            },
+            Rmsg::ReqError(ref why) => {
+                put(&mut obj, "status", "error".to_json());
+                put(&mut obj, "why", why.to_json());
+            },
+            Rmsg::ReqNack => put(&mut obj, "status", "nack".to_json()),
+
+            Rmsg::DispatchOk(ref contexts, ref body) => {
+                put(&mut obj, "status", "ok".to_json());
+                put(&mut obj, "contexts", contexts_json(contexts.as_slice()));
+                put(&mut obj, "body", bytes_json(body.as_slice()));
+            },
+            Rmsg::DispatchError(ref contexts, ref why) => {
+                put(&mut obj, "status", "error".to_json());
+                put(&mut obj, "contexts", contexts_json(contexts.as_slice()));
+                put(&mut obj, "why", why.to_json());
+            },
+            Rmsg::DispatchNack(ref contexts) => {
+                put(&mut obj, "status", "nack".to_json());
+                put(&mut obj, "contexts", contexts_json(contexts.as_slice()));
+            },
+
+            Rmsg::Drain | Rmsg::Ping => (),
+
+            Rmsg::Init(version, ref headers) => {
+                put(&mut obj, "version", version.to_json());
+                put(&mut obj, "headers", headers_json(headers.as_slice()));
+            },
+
+            Rmsg::Err(ref why) => put(&mut obj, "why", why.to_json()),
+        }
+        Json::Object(obj)
+    }
+}
+
+impl ToJson for Msg {
+    fn to_json(&self) -> Json {
+        let (tag, json) = match *self {
+            Msg::Tx(tag, ref msg) => (tag, msg.to_json()),
+            Msg::Rx(tag, ref msg) => (tag, msg.to_json()),
+        };
+        match json {
+            Json::Object(mut obj) => {
+                put(&mut obj, "tag", tag.to_u32().to_json());
+                Json::Object(obj)
+            },
+            json => json,
+        }
+    }
+}
+
+/// Decodes a Tmsg from the JSON `to_json` produces.
+pub fn decode_tmsg(json: &Json) -> IoResult<Tmsg> {
+    string(json, "type").and_then(|t| match t.as_slice() {
+        "Treq" => trace(json).and_then(move |trace| {
+            bytes(json, "body").map(move |body| Tmsg::Req(trace, body))
+        }),
+        "Tdispatch" => contexts(json).and_then(move |contexts| {
+            string(json, "dst").and_then(move |dst| {
+                dtab(json).and_then(move |dtab| {
+                    bytes(json, "body").map(move |body| Tmsg::Dispatch(contexts, dst, dtab, body))
+                })
+            })
+        }),
+        "Tdrain" => Ok(Tmsg::Drain),
+        "Tping" => Ok(Tmsg::Ping),
+        "Tdiscarded" => number(json, "which", MAX_TAG as u64).and_then(|which| {
+            string(json, "why").map(move |why| Tmsg::Discarded(Tag::from_u32(which as u32), why))
+        }),
+        "Tlease" => number(json, "unit", 0xff).and_then(|unit| {
+            number(json, "length", u64::MAX).map(move |n| Tmsg::Lease(unit as u8, n))
+        }),
+        "Tinit" => number(json, "version", 0xffff).and_then(|version| {
+            headers(json).map(move |headers| Tmsg::Init(version as u16, headers))
+        }),
+        _ => Err(invalid("type", t.as_slice())),
+    })
+}
+
+/// Decodes an Rmsg from the JSON `to_json` produces.
+pub fn decode_rmsg(json: &Json) -> IoResult<Rmsg> {
+    string(json, "type").and_then(|t| match t.as_slice() {
+        "Rreq" => string(json, "status").and_then(|status| match status.as_slice() {
+            "ok" => bytes(json, "body").map(Rmsg::ReqOk),
+            "error" => string(json, "why").map(Rmsg::ReqError),
+            "nack" => Ok(Rmsg::ReqNack),
+            _ => Err(invalid("status", status.as_slice())),
+        }),
+        "Rdispatch" => contexts(json).and_then(move |contexts| {
+            string(json, "status").and_then(move |status| match status.as_slice() {
+                "ok" => bytes(json, "body").map(move |body| Rmsg::DispatchOk(contexts, body)),
+                "error" => string(json, "why").map(move |why| Rmsg::DispatchError(contexts, why)),
+                "nack" => Ok(Rmsg::DispatchNack(contexts)),
+                _ => Err(invalid("status", status.as_slice())),
+            })
+        }),
+        "Rdrain" => Ok(Rmsg::Drain),
+        "Rping" => Ok(Rmsg::Ping),
+        "Rinit" => number(json, "version", 0xffff).and_then(|version| {
+            headers(json).map(move |headers| Rmsg::Init(version as u16, headers))
+        }),
+        "Rerr" => string(json, "why").map(Rmsg::Err),
+        _ => Err(invalid("type", t.as_slice())),
+    })
+}
+
+/// Decodes a Msg, which must carry a tag, from the JSON `to_json`
+/// produces.
+pub fn decode_msg(json: &Json) -> IoResult<Msg> {
+    number(json, "tag", MAX_TAG as u64).and_then(|tag| {
+        let tag = Tag::from_u32(tag as u32);
+        string(json, "type").and_then(|t| {
+            if t.starts_with("T") {
+                decode_tmsg(json).map(|msg| Msg::Tx(tag, msg))
+            } else {
+                decode_rmsg(json).map(|msg| Msg::Rx(tag, msg))
+            }
+        })
+    })
+}
+
+/// Parses a Msg from a JSON string, such as a line of a log.
+pub fn parse_msg(s: &str) -> IoResult<Msg> {
+    match json::from_str(s) {
+        Err(e) => Err(invalid("json", format!("{:?}", e).as_slice())),
+        Ok(json) => decode_msg(&json),
+    }
+}
+
+fn typed(t: String) -> Object {
+    let mut obj = BTreeMap::new();
+    put(&mut obj, "type", t.to_json());
+    obj
+}
+
+fn put(obj: &mut Object, key: &str, val: Json) {
+    obj.insert(key.to_string(), val);
+}
+
+fn bytes_json(bytes: &[u8]) -> Json {
+    match str::from_utf8(bytes) {
+        Ok(s) => s.to_json(),
+        Err(_) => {
+            let mut obj = BTreeMap::new();
+            put(&mut obj, "base64", bytes.to_base64(STANDARD).to_json());
+            Json::Object(obj)
+        },
+    }
+}
+
+fn pair_json(key: &[u8], val: &[u8]) -> Json {
+    let mut obj = BTreeMap::new();
+    put(&mut obj, "key", bytes_json(key));
+    put(&mut obj, "value", bytes_json(val));
+    Json::Object(obj)
+}
+
+fn contexts_json(contexts: &[Context]) -> Json {
+    Json::Array(contexts.iter().map(|c| pair_json(c.key.as_slice(), c.val.as_slice())).collect())
+}
+
+fn headers_json(headers: &[Header]) -> Json {
+    Json::Array(headers.iter().map(|h| pair_json(h.key.as_slice(), h.val.as_slice())).collect())
+}
+
+fn trace_json(trace: &Trace) -> Json {
+    let mut obj = BTreeMap::new();
+    put(&mut obj, "trace_id", format!("{:016x}", trace.trace_id).to_json());
+    put(&mut obj, "span_id", format!("{:016x}", trace.span_id).to_json());
+    put(&mut obj, "parent_id", format!("{:016x}", trace.parent_id).to_json());
+    put(&mut obj, "flags", trace.flags.to_json());
+    Json::Object(obj)
+}
+
+fn invalid(key: &str, why: &str) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: "bad json message",
+        detail: Some(format!("{}: {}", key, why)),
+    }
+}
+
+fn field<'a>(json: &'a Json, key: &str) -> IoResult<&'a Json> {
+    match json.find(key) {
+        None => Err(invalid(key, "missing")),
+        Some(val) => Ok(val),
+    }
+}
+
+fn string(json: &Json, key: &str) -> IoResult<String> {
+    field(json, key).and_then(|val| match val.as_string() {
+        None => Err(invalid(key, "not a string")),
+        Some(s) => Ok(s.to_string()),
+    })
+}
+
+fn number(json: &Json, key: &str, max: u64) -> IoResult<u64> {
+    field(json, key).and_then(|val| match val.as_u64() {
+        Some(n) if n <= max => Ok(n),
+        _ => Err(invalid(key, format!("not a number up to {}", max).as_slice())),
+    })
+}
+
+fn hex(json: &Json, key: &str) -> IoResult<u64> {
+    string(json, key).and_then(|s| match from_str_radix(s.as_slice(), 16) {
+        None => Err(invalid(key, "not hex")),
+        Some(n) => Ok(n),
+    })
+}
+
+fn bytes(json: &Json, key: &str) -> IoResult<Vec<u8>> {
+    field(json, key).and_then(|val| match *val {
+        Json::String(ref s) => Ok(s.as_bytes().to_vec()),
+        _ => match val.find("base64").and_then(|b| b.as_string()) {
+            None => Err(invalid(key, "not a string or base64")),
+            Some(b) => b.from_base64().map_err(|_| invalid(key, "bad base64")),
+        },
+    })
+}
+
+fn pairs(json: &Json, key: &str) -> IoResult<Vec<(Vec<u8>, Vec<u8>)>> {
+    match json.find(key) {
+        None => Ok(Vec::new()),
+        Some(val) => match val.as_array() {
+            None => Err(invalid(key, "not an array")),
+            Some(items) => items.iter().map(|item| {
+                bytes(item, "key").and_then(|k| bytes(item, "value").map(move |v| (k, v)))
+            }).collect(),
+        },
+    }
+}
+
+fn contexts(json: &Json) -> IoResult<Vec<Context>> {
+    pairs(json, "contexts").map(|ps| ps.into_iter().map(|(k, v)| Context::new(k, v)).collect())
+}
+
+fn headers(json: &Json) -> IoResult<Vec<Header>> {
+    pairs(json, "headers").map(|ps| ps.into_iter().map(|(k, v)| Header::new(k, v)).collect())
+}
+
+fn dtab(json: &Json) -> IoResult<Dtab> {
+    match json.find("dtab") {
+        None => Ok(Dtab::empty()),
+        Some(_) => string(json, "dtab").and_then(|s| Dtab::parse(s.as_slice())),
+    }
+}
+
+fn trace(json: &Json) -> IoResult<Option<Trace>> {
+    match json.find("trace") {
+        None | Some(&Json::Null) => Ok(None),
+        Some(t) => hex(t, "trace_id").and_then(|trace_id| {
+            hex(t, "span_id").and_then(|span_id| {
+                hex(t, "parent_id").and_then(|parent_id| {
+                    number(t, "flags", 0xff).map(|flags| Some(Trace {
+                        span_id: span_id,
+                        parent_id: parent_id,
+                        trace_id: trace_id,
+                        flags: flags as u8,
+                    }))
+                })
+            })
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rustc_serialize::json::ToJson;
+
+    use misc::{Context, Dtab, Header, Trace};
+    use proto::{Msg, Tag, Tmsg, Rmsg};
+    use super::parse_msg;
+
+    fn assert_round_trip(msg: Msg) {
+        let json = msg.to_json().to_string();
+        assert_eq!(parse_msg(json.as_slice()), Ok(msg));
+    }
+
+    #[test]
+    fn test_to_json() {
+        let msg = Msg::Tx(Tag(0, 0, 1), Tmsg::Dispatch(
+            vec![Context::new(b"k".to_vec(), vec![0, 255])],
+            "/s".to_string(), Dtab::parse("/s=>/t").unwrap(), b"hello".to_vec()));
+        assert_eq!(msg.to_json().to_string(),
+                   "{\"body\":\"hello\",\"contexts\":[{\"key\":\"k\",\"value\":{\"base64\":\"AP8=\"}}],\
+                    \"dst\":\"/s\",\"dtab\":\"/s=>/t\",\"tag\":1,\"type\":\"Tdispatch\"}");
+        assert_eq!(Rmsg::ReqNack.to_json().to_string(), "{\"status\":\"nack\",\"type\":\"Rreq\"}");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let trace = Trace { span_id: 1, parent_id: 2, trace_id: 0xfedcba9876543210, flags: 1 };
+        let contexts = vec![trace.to_context(), Context::new(b"k".to_vec(), b"v".to_vec())];
+        let headers = vec![Header::new(b"tls".to_vec(), vec![1, 2])];
+        let tag = Tag(0, 1, 2);
+
+        assert_round_trip(Msg::Tx(tag, Tmsg::Req(Some(trace), vec![0, 1, 2])));
+        assert_round_trip(Msg::Tx(tag, Tmsg::Req(None, Vec::new())));
+        assert_round_trip(Msg::Tx(tag, Tmsg::Dispatch(contexts.clone(), "/s".to_string(),
+                                                      Dtab::parse("/s=>/t;/u=>/v").unwrap(), b"hi".to_vec())));
+        assert_round_trip(Msg::Tx(tag, Tmsg::Drain));
+        assert_round_trip(Msg::Tx(tag, Tmsg::Ping));
+        assert_round_trip(Msg::Tx(tag, Tmsg::Discarded(Tag(0, 0, 7), "timeout".to_string())));
+        assert_round_trip(Msg::Tx(tag, Tmsg::Lease(0, 1000)));
+        assert_round_trip(Msg::Tx(tag, Tmsg::Init(1, headers.clone())));
+
+        assert_round_trip(Msg::Rx(tag, Rmsg::ReqOk(b"ok".to_vec())));
+        assert_round_trip(Msg::Rx(tag, Rmsg::ReqError("oops".to_string())));
+        assert_round_trip(Msg::Rx(tag, Rmsg::ReqNack));
+        assert_round_trip(Msg::Rx(tag, Rmsg::DispatchOk(contexts.clone(), vec![255])));
+        assert_round_trip(Msg::Rx(tag, Rmsg::DispatchError(contexts.clone(), "oops".to_string())));
+        assert_round_trip(Msg::Rx(tag, Rmsg::DispatchNack(Vec::new())));
+        assert_round_trip(Msg::Rx(tag, Rmsg::Drain));
+        assert_round_trip(Msg::Rx(tag, Rmsg::Ping));
+        assert_round_trip(Msg::Rx(tag, Rmsg::Init(1, headers)));
+        assert_round_trip(Msg::Rx(tag, Rmsg::Err("bad".to_string())));
+    }
+
+    #[test]
+    fn test_parse() {
+        // contexts and the Dtab may be left out.
+        assert_eq!(parse_msg("{\"tag\": 3, \"type\": \"Tdispatch\", \"dst\": \"/s\", \"body\": \"\"}"),
+                   Ok(Msg::Tx(Tag(0, 0, 3), Tmsg::Dispatch(Vec::new(), "/s".to_string(), Dtab::empty(), Vec::new()))));
+
+        assert!(parse_msg("{\"type\": \"Tping\"}").is_err());
+        assert!(parse_msg("{\"tag\": 1, \"type\": \"Tpong\"}").is_err());
+        assert!(parse_msg("{\"tag\": 16777216, \"type\": \"Tping\"}").is_err());
+        assert!(parse_msg("{\"tag\": 1, \"type\": \"Rreq\", \"status\": \"ok\", \"body\": 7}").is_err());
+        assert!(parse_msg("{\"tag\": 1,").is_err());
+    }
+}
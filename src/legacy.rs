@@ -0,0 +1,103 @@
+//! Compatibility with peers that predate Tdispatch.
+//!
+//! Treq carries only a trace and a body, so a downgraded Tdispatch loses its
+//! destination, Dtab and contexts; its trace is preserved.
+
+use std::ascii::AsciiExt;
+
+use misc::{Context, Dtab, Trace};
+use proto::{Tmsg, Rmsg};
+
+/// Rewrites a Tdispatch as a Treq.  Other messages have no legacy form.
+pub fn downgrade_tmsg(msg: &Tmsg) -> Option<Tmsg> {
+    match *msg {
+        Tmsg::Dispatch(ref contexts, _, _, ref body) => {
+            Some(Tmsg::Req(Trace::from_contexts(contexts.as_slice()), body.clone()))
+        },
+        _ => None,
+    }
+}
+
+/// Rewrites a Treq as a Tdispatch with no destination or Dtab, carrying its
+/// trace as a context.
+pub fn upgrade_tmsg(msg: Tmsg) -> Tmsg {
+    match msg {
+        Tmsg::Req(trace, body) => {
+            let contexts: Vec<Context> = trace.iter().map(|t| t.to_context()).collect();
+            Tmsg::Dispatch(contexts, String::new(), Dtab::empty(), body)
+        },
+        msg => msg,
+    }
+}
+
+/// True if `rsp` is how a peer that predates Tdispatch refuses one: an
+/// Rerr naming an unknown message type.  Other Rerrs are the peer's
+/// failures, and don't call for a downgrade.
+pub fn is_unknown_type(rsp: &Rmsg) -> bool {
+    match *rsp {
+        Rmsg::Err(ref why) => why.as_slice().to_ascii_lowercase().contains("unknown message type"),
+        _ => false,
+    }
+}
+
+/// Rewrites an Rdispatch as the corresponding Rreq.
+pub fn downgrade_rmsg(rsp: Rmsg) -> Rmsg {
+    match rsp {
+        Rmsg::DispatchOk(_, body) => Rmsg::ReqOk(body),
+        Rmsg::DispatchError(_, desc) => Rmsg::ReqError(desc),
+        Rmsg::DispatchNack(_) => Rmsg::ReqNack,
+        rsp => rsp,
+    }
+}
+
+/// Rewrites an Rreq as the corresponding Rdispatch, with no contexts.
+pub fn upgrade_rmsg(rsp: Rmsg) -> Rmsg {
+    match rsp {
+        Rmsg::ReqOk(body) => Rmsg::DispatchOk(Vec::new(), body),
+        Rmsg::ReqError(desc) => Rmsg::DispatchError(Vec::new(), desc),
+        Rmsg::ReqNack => Rmsg::DispatchNack(Vec::new()),
+        rsp => rsp,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use misc::{Context, Dentry, Dtab, Trace};
+    use proto::{Tmsg, Rmsg};
+    use super::{downgrade_tmsg, upgrade_tmsg, downgrade_rmsg, upgrade_rmsg, is_unknown_type};
+
+    static TRACE: Trace = Trace { span_id: 1, parent_id: 2, trace_id: 3, flags: 4 };
+
+    #[test]
+    fn test_tmsg() {
+        let dispatch = Tmsg::Dispatch(
+            vec![Context::new(b"k".to_vec(), b"v".to_vec()), TRACE.to_context()],
+            "/dst".to_string(),
+            Dtab(vec![Dentry::new("/dst".to_string(), "/$/inet/127.1/8080".to_string())]),
+            b"mom".to_vec());
+        let req = downgrade_tmsg(&dispatch).unwrap();
+        assert_eq!(req, Tmsg::Req(Some(TRACE), b"mom".to_vec()));
+        assert_eq!(upgrade_tmsg(req),
+                   Tmsg::Dispatch(vec![TRACE.to_context()], String::new(), Dtab::empty(), b"mom".to_vec()));
+
+        assert_eq!(downgrade_tmsg(&Tmsg::Ping), None);
+    }
+
+    #[test]
+    fn test_rmsg() {
+        for rsp in vec![Rmsg::DispatchOk(Vec::new(), b"mom".to_vec()),
+                        Rmsg::DispatchError(Vec::new(), "nope".to_string()),
+                        Rmsg::DispatchNack(Vec::new()),
+                        Rmsg::Err("bad".to_string())].into_iter() {
+            assert_eq!(upgrade_rmsg(downgrade_rmsg(rsp.clone())), rsp);
+        }
+    }
+
+    #[test]
+    fn test_is_unknown_type() {
+        assert!(is_unknown_type(&Rmsg::Err("unknown message type: 2 [tag=1]".to_string())));
+        assert!(is_unknown_type(&Rmsg::Err("Unknown message type".to_string())));
+        assert!(!is_unknown_type(&Rmsg::Err("session is draining".to_string())));
+        assert!(!is_unknown_type(&Rmsg::DispatchError(Vec::new(), "unknown message type".to_string())));
+    }
+}
@@ -5,12 +5,46 @@
 #![crate_type = "rlib"]
 #![crate_type = "dylib"]
 
+extern crate libc;
+extern crate time;
+#[macro_use] extern crate log;
+#[cfg(test)] extern crate quickcheck;
+#[cfg(feature = "json")] extern crate "rustc-serialize" as rustc_serialize;
+#[cfg(feature = "ssl")] extern crate openssl;
+
 pub use proto::{Tag, Msg, Tmsg, Rmsg};
 pub use reader::MuxReader;
-pub use writer::MuxWriter;
+pub use writer::{MuxWriter, frame_tmsg, frame_rmsg};
 
+pub mod accrual;
+pub mod admin;
+pub mod backoff;
+pub mod batch;
+pub mod capture;
+pub mod dump;
+pub mod framebuf;
+pub mod histogram;
+#[cfg(feature = "json")] pub mod json;
+pub mod legacy;
+pub mod memory;
+pub mod metrics;
 pub mod misc;
+pub mod mock;
+pub mod pool;
+pub mod proxy;
+pub mod record;
+pub mod retry;
+pub mod server;
+pub mod session;
+pub mod span;
+#[cfg(feature = "ssl")] pub mod ssl;
+pub mod stream;
+pub mod thrift;
+pub mod tls;
+pub mod transport;
+pub mod unix;
 
+#[cfg(test)] mod arbitrary;
 mod proto;
 mod reader;
 mod writer;
@@ -0,0 +1,299 @@
+//! An in-memory duplex transport, for exercising sessions without sockets.
+//!
+//! Each end of a pair may be configured with `Faults` that apply to the
+//! bytes it writes: delays, short writes, disconnects partway through a
+//! frame and corruption.
+
+#[allow(unstable)]
+
+use std::collections::RingBuf;
+use std::old_io::{IoResult, IoError, ConnectionReset, EndOfFile, Reader, Writer, standard_error};
+use std::old_io::timer::sleep;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use transport::Transport;
+
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct Faults {
+    /// Slept before delivering each chunk.
+    pub delay: Duration,
+    /// Writes are delivered in chunks of at most this many bytes, so the
+    /// reader sees them piecemeal.
+    pub chunk: Option<usize>,
+    /// The connection is reset after this many more bytes are written.
+    pub disconnect_after: Option<usize>,
+    /// The byte this many bytes further into the stream is inverted.
+    pub corrupt_at: Option<usize>,
+}
+
+impl Faults {
+    pub fn none() -> Faults {
+        Faults {
+            delay: Duration::zero(),
+            chunk: None,
+            disconnect_after: None,
+            corrupt_at: None,
+        }
+    }
+}
+
+struct Pipe {
+    buf: RingBuf<u8>,
+    closed: bool,
+}
+
+struct Half {
+    pipe: Mutex<Pipe>,
+    ready: Condvar,
+}
+
+impl Half {
+    fn new() -> Half {
+        Half {
+            pipe: Mutex::new(Pipe { buf: RingBuf::new(), closed: false }),
+            ready: Condvar::new(),
+        }
+    }
+
+    fn push(&self, bytes: &[u8]) -> bool {
+        let mut pipe = self.pipe.lock().unwrap();
+        if pipe.closed {
+            return false;
+        }
+        pipe.buf.extend(bytes.iter().map(|b| *b));
+        self.ready.notify_all();
+        true
+    }
+
+    fn close(&self) {
+        self.pipe.lock().unwrap().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+/// One end of an in-memory connection.  Clones share the end.
+#[derive(Clone)]
+pub struct MemoryStream {
+    rx: Arc<Half>,
+    tx: Arc<Half>,
+    faults: Arc<Mutex<Faults>>,
+}
+
+/// Returns the two connected ends of a new in-memory connection.
+pub fn pair() -> (MemoryStream, MemoryStream) {
+    let (a, b) = (Arc::new(Half::new()), Arc::new(Half::new()));
+    let left = MemoryStream { rx: a.clone(), tx: b.clone(), faults: Arc::new(Mutex::new(Faults::none())) };
+    let right = MemoryStream { rx: b, tx: a, faults: Arc::new(Mutex::new(Faults::none())) };
+    (left, right)
+}
+
+impl MemoryStream {
+    /// Replaces the faults applied to subsequent writes from this end.
+    pub fn set_faults(&self, faults: Faults) {
+        *self.faults.lock().unwrap() = faults;
+    }
+
+    fn reset(&self) -> IoError {
+        self.rx.close();
+        self.tx.close();
+        IoError {
+            kind: ConnectionReset,
+            desc: "injected disconnect",
+            detail: None,
+        }
+    }
+}
+
+impl Reader for MemoryStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let mut pipe = self.rx.pipe.lock().unwrap();
+        while pipe.buf.is_empty() && !pipe.closed {
+            pipe = self.rx.ready.wait(pipe).unwrap();
+        }
+        if pipe.buf.is_empty() {
+            return Err(standard_error(EndOfFile));
+        }
+
+        let mut n = 0;
+        while n < buf.len() {
+            match pipe.buf.pop_front() {
+                None => break,
+                Some(b) => { buf[n] = b; n += 1; },
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Writer for MemoryStream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        let mut bytes = buf.to_vec();
+        let mut faults = self.faults.lock().unwrap();
+
+        match faults.corrupt_at {
+            Some(i) if i < bytes.len() => {
+                bytes[i] = !bytes[i];
+                faults.corrupt_at = None;
+            },
+            Some(i) => faults.corrupt_at = Some(i - bytes.len()),
+            None => (),
+        }
+
+        // everything up to the disconnect is delivered before the reset.
+        let (deliver, disconnect) = match faults.disconnect_after {
+            Some(n) if n < bytes.len() => (n, true),
+            Some(n) => {
+                faults.disconnect_after = Some(n - bytes.len());
+                (bytes.len(), false)
+            },
+            None => (bytes.len(), false),
+        };
+
+        let chunk = match faults.chunk {
+            Some(n) if n > 0 => n,
+            _ => bytes.len(),
+        };
+        let mut off = 0;
+        while off < deliver {
+            let end = if off + chunk < deliver { off + chunk } else { deliver };
+            if faults.delay > Duration::zero() {
+                sleep(faults.delay);
+            }
+            if !self.tx.push(bytes.slice(off, end)) {
+                return Err(standard_error(EndOfFile));
+            }
+            off = end;
+        }
+
+        if disconnect {
+            return Err(self.reset());
+        }
+        Ok(())
+    }
+}
+
+impl Transport for MemoryStream {
+    fn close(&mut self) {
+        self.rx.close();
+        self.tx.close();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::old_io::timer::sleep;
+    use std::sync::mpsc::channel;
+    use std::sync::Mutex;
+    use std::thread::Thread;
+    use std::time::Duration;
+
+    use misc::Dtab;
+    use proto::{Msg, Tag, Tmsg, Rmsg, MARKER_TAG};
+    use reader::MuxReader;
+    use server::{Request, Server};
+    use session::ClientSession;
+    use writer::MuxWriter;
+    use super::{pair, Faults};
+
+    fn dispatch(body: &[u8]) -> Tmsg {
+        Tmsg::Dispatch(Vec::new(), "/".to_string(), Dtab::empty(), body.to_vec())
+    }
+
+    fn echo(req: Request) -> Rmsg {
+        Rmsg::DispatchOk(Vec::new(), req.body)
+    }
+
+    #[test]
+    fn test_partial_writes() {
+        let (client, server) = pair();
+        let faults = Faults { delay: Duration::milliseconds(1), chunk: Some(1), ..Faults::none() };
+        client.set_faults(faults.clone());
+        server.set_faults(faults);
+
+        let _server = Server::new(echo).serve(server);
+        let session = ClientSession::new(client);
+        assert_eq!(session.call(&dispatch(b"mom")).unwrap(), Rmsg::DispatchOk(Vec::new(), b"mom".to_vec()));
+    }
+
+    #[test]
+    fn test_disconnect_mid_frame() {
+        let (client, mut server) = pair();
+        let session = ClientSession::new(client);
+        let (tx, rx) = channel();
+        Thread::spawn(move|| { tx.send(session.call(&dispatch(b"mom")).is_err()).unwrap(); });
+
+        let tag = match server.read_mux_framed_msg().unwrap() {
+            Msg::Tx(tag, _) => tag,
+            msg => panic!("unexpected message: {:?}", msg),
+        };
+        server.set_faults(Faults { disconnect_after: Some(6), ..Faults::none() });
+        assert!(server.write_mux_framed_rmsg(&tag, &Rmsg::DispatchOk(Vec::new(), b"mom".to_vec())).is_err());
+        assert!(rx.recv().unwrap());
+    }
+
+    #[test]
+    fn test_corruption() {
+        let (client, mut server) = pair();
+        let session = ClientSession::new(client);
+        let (tx, rx) = channel();
+        let timeout = tx.clone();
+        Thread::spawn(move|| { tx.send(Some(session.call(&Tmsg::Ping).is_err())).ok(); });
+        Thread::spawn(move|| {
+            sleep(Duration::seconds(5));
+            timeout.send(None).ok();
+        });
+
+        let tag = match server.read_mux_framed_msg().unwrap() {
+            Msg::Tx(tag, _) => tag,
+            msg => panic!("unexpected message: {:?}", msg),
+        };
+        // inverting the size's first byte claims a frame of nearly 4GB,
+        // which is refused.
+        server.set_faults(Faults { corrupt_at: Some(0), ..Faults::none() });
+        server.write_mux_framed_rmsg(&tag, &Rmsg::Ping).unwrap();
+        assert_eq!(rx.recv().unwrap(), Some(true));
+    }
+
+    #[test]
+    fn test_client_drain_and_lease() {
+        let (client, mut server) = pair();
+        let session = ClientSession::new(client);
+
+        server.write_mux_framed_tmsg(&MARKER_TAG, &Tmsg::Lease(0, 1000)).unwrap();
+        server.write_mux_framed_tmsg(&Tag(0, 0, 2), &Tmsg::Drain).unwrap();
+        // the lease has no response.
+        assert_eq!(server.read_mux_framed_msg().unwrap(), Msg::Rx(Tag(0, 0, 2), Rmsg::Drain));
+        assert!(session.is_draining());
+        assert!(session.call(&Tmsg::Ping).is_err());
+    }
+
+    #[test]
+    fn test_server_discard() {
+        let (mut client, server) = pair();
+        let (started_tx, started) = channel();
+        let (release, release_rx) = channel::<()>();
+        let (started_tx, release_rx) = (Mutex::new(started_tx), Mutex::new(release_rx));
+        let session = Server::new(move |req: Request| {
+            started_tx.lock().unwrap().send(()).unwrap();
+            release_rx.lock().unwrap().recv().unwrap();
+            Rmsg::DispatchOk(Vec::new(), req.body)
+        }).serve(server);
+
+        client.write_mux_framed_tmsg(&Tag(0, 0, 1), &dispatch(b"mom")).unwrap();
+        started.recv().unwrap();
+        client.write_mux_framed_tmsg(&MARKER_TAG, &Tmsg::Discarded(Tag(0, 0, 1), "bye".to_string())).unwrap();
+        client.write_mux_framed_tmsg(&Tag(0, 0, 3), &Tmsg::Ping).unwrap();
+        assert_eq!(client.read_mux_framed_msg().unwrap(), Msg::Rx(Tag(0, 0, 3), Rmsg::Ping));
+
+        // once drained, the session closes after the discarded request
+        // completes and its tag is answered.
+        session.drain().unwrap();
+        assert_eq!(client.read_mux_framed_msg().unwrap(), Msg::Tx(Tag(0, 0, 1), Tmsg::Drain));
+        client.write_mux_framed_rmsg(&Tag(0, 0, 1), &Rmsg::Drain).unwrap();
+        release.send(()).unwrap();
+        assert_eq!(client.read_mux_framed_msg().unwrap(),
+                   Msg::Rx(Tag(0, 0, 1), Rmsg::DispatchError(Vec::new(), "request discarded".to_string())));
+        assert!(client.read_mux_framed_msg().is_err());
+    }
+}
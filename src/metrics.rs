@@ -0,0 +1,502 @@
+//! Counters, gauges and histograms describing sessions.
+//!
+//! Each session keeps a `SessionMetrics`.  Built with `child`, its counters
+//! and gauges are the session's own but also feed the parent's, so a
+//! parent registered in a `Registry` reports totals across sessions:
+//!
+//!     let metrics = Metrics::new();
+//!     let server = Server::new(handler)
+//!         .metrics(SessionMetrics::registered(&metrics, "mux_server"));
+//!     ...
+//!     print!("{}", metrics.prometheus());
+//!
+//! `Metrics` keeps what's registered for rendering in Prometheus's text
+//! format; other `Registry` implementations may export metrics elsewhere.
+
+#[allow(unstable)]
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::old_io::{IoResult, Reader, Writer};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicInt, AtomicUint, Ordering};
+
+use histogram::{AtomicHistogram, Histogram};
+use transport::{PeerCredentials, PeerIdentity, Transport};
+
+/// A count that only increases.
+#[derive(Clone)]
+pub struct Counter {
+    value: Arc<AtomicUint>,
+    parent: Option<Box<Counter>>,
+}
+
+impl Counter {
+    pub fn new() -> Counter {
+        Counter { value: Arc::new(AtomicUint::new(0)), parent: None }
+    }
+
+    /// A new counter whose increments are also added to this one.
+    pub fn child(&self) -> Counter {
+        Counter { value: Arc::new(AtomicUint::new(0)), parent: Some(Box::new(self.clone())) }
+    }
+
+    pub fn incr(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: usize) {
+        self.value.fetch_add(n, Ordering::SeqCst);
+        match self.parent {
+            None => (),
+            Some(ref parent) => parent.add(n),
+        }
+    }
+
+    pub fn get(&self) -> usize {
+        self.value.load(Ordering::SeqCst)
+    }
+}
+
+/// A value that may rise and fall.
+#[derive(Clone)]
+pub struct Gauge {
+    value: Arc<AtomicInt>,
+    parent: Option<Box<Gauge>>,
+}
+
+impl Gauge {
+    pub fn new() -> Gauge {
+        Gauge { value: Arc::new(AtomicInt::new(0)), parent: None }
+    }
+
+    /// A new gauge whose changes are also applied to this one, which
+    /// therefore reports the sum of its children.
+    pub fn child(&self) -> Gauge {
+        Gauge { value: Arc::new(AtomicInt::new(0)), parent: Some(Box::new(self.clone())) }
+    }
+
+    pub fn set(&self, v: isize) {
+        let old = self.value.swap(v, Ordering::SeqCst);
+        match self.parent {
+            None => (),
+            Some(ref parent) => parent.add(v - old),
+        }
+    }
+
+    pub fn add(&self, n: isize) {
+        self.value.fetch_add(n, Ordering::SeqCst);
+        match self.parent {
+            None => (),
+            Some(ref parent) => parent.add(n),
+        }
+    }
+
+    pub fn get(&self) -> isize {
+        self.value.load(Ordering::SeqCst)
+    }
+}
+
+/// A distribution of values, such as latencies, kept in an
+/// `AtomicHistogram` so that sessions sharing it don't contend for a lock.
+#[derive(Clone)]
+pub struct Stat {
+    histogram: Option<Arc<AtomicHistogram>>,
+}
+
+impl Stat {
+    pub fn new() -> Stat {
+        Stat { histogram: Some(Arc::new(AtomicHistogram::new())) }
+    }
+
+    /// A stat that records nothing.
+    pub fn null() -> Stat {
+        Stat { histogram: None }
+    }
+
+    pub fn record(&self, v: u64) {
+        match self.histogram {
+            None => (),
+            Some(ref h) => h.record(v),
+        }
+    }
+
+    /// A copy of the values recorded so far.
+    pub fn snapshot(&self) -> Histogram {
+        match self.histogram {
+            None => Histogram::new(),
+            Some(ref h) => h.snapshot(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum Metric {
+    Counter(Counter),
+    Gauge(Gauge),
+    Stat(Stat),
+}
+
+/// Where metrics are registered by name, with a line of help text.
+/// Registering a name again returns the metric already registered.
+pub trait Registry {
+    fn counter(&self, name: &str, help: &str) -> Counter;
+    fn gauge(&self, name: &str, help: &str) -> Gauge;
+    fn stat(&self, name: &str, help: &str) -> Stat;
+}
+
+/// A registry that keeps its metrics for rendering.
+#[derive(Clone)]
+pub struct Metrics {
+    metrics: Arc<Mutex<BTreeMap<String, (String, Metric)>>>,
+}
+
+// the quantiles rendered for each stat.
+static QUANTILES: [(&'static str, f64); 4] = [("0.5", 50.0), ("0.9", 90.0), ("0.99", 99.0), ("0.999", 99.9)];
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics { metrics: Arc::new(Mutex::new(BTreeMap::new())) }
+    }
+
+    /// The metrics registered, with their help text, ordered by name.
+    pub fn snapshot(&self) -> Vec<(String, String, Metric)> {
+        let metrics = self.metrics.lock().unwrap();
+        metrics.iter().map(|(name, &(ref help, ref m))| (name.clone(), help.clone(), m.clone())).collect()
+    }
+
+    /// Renders the metrics in Prometheus's text exposition format.  Stats
+    /// are rendered as summaries, with their median, 90th, 99th and 99.9th
+    /// percentiles.
+    pub fn prometheus(&self) -> String {
+        let mut out = String::new();
+        for (name, help, metric) in self.snapshot().into_iter() {
+            let kind = match metric {
+                Metric::Counter(_) => "counter",
+                Metric::Gauge(_) => "gauge",
+                Metric::Stat(_) => "summary",
+            };
+            let help = help.replace("\\", "\\\\").replace("\n", "\\n");
+            out.push_str(format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind).as_slice());
+            match metric {
+                Metric::Counter(c) => out.push_str(format!("{} {}\n", name, c.get()).as_slice()),
+                Metric::Gauge(g) => out.push_str(format!("{} {}\n", name, g.get()).as_slice()),
+                Metric::Stat(s) => {
+                    let h = s.snapshot();
+                    for &(q, p) in QUANTILES.iter() {
+                        out.push_str(format!("{}{{quantile=\"{}\"}} {}\n", name, q, h.percentile(p)).as_slice());
+                    }
+                    out.push_str(format!("{}_sum {}\n{}_count {}\n", name, h.sum(), name, h.count()).as_slice());
+                },
+            }
+        }
+        out
+    }
+
+    /// Registers `metric` unless `name` is taken, and returns whatever's
+    /// registered under it.
+    fn register(&self, name: &str, help: &str, metric: Metric) -> Metric {
+        let mut metrics = self.metrics.lock().unwrap();
+        match metrics.get(name) {
+            Some(&(_, ref m)) => return m.clone(),
+            None => (),
+        }
+        metrics.insert(name.to_string(), (help.to_string(), metric.clone()));
+        metric
+    }
+}
+
+/// A name already registered to a different kind of metric yields one that
+/// isn't registered, and so isn't reported.
+impl Registry for Metrics {
+    fn counter(&self, name: &str, help: &str) -> Counter {
+        match self.register(name, help, Metric::Counter(Counter::new())) {
+            Metric::Counter(c) => c,
+            _ => Counter::new(),
+        }
+    }
+
+    fn gauge(&self, name: &str, help: &str) -> Gauge {
+        match self.register(name, help, Metric::Gauge(Gauge::new())) {
+            Metric::Gauge(g) => g,
+            _ => Gauge::new(),
+        }
+    }
+
+    fn stat(&self, name: &str, help: &str) -> Stat {
+        match self.register(name, help, Metric::Stat(Stat::new())) {
+            Metric::Stat(s) => s,
+            _ => Stat::null(),
+        }
+    }
+}
+
+/// The metrics kept by a client or server session.
+#[derive(Clone)]
+pub struct SessionMetrics {
+    /// Requests sent by a client, or received by a server.
+    pub requests: Counter,
+    /// Nacks received by a client, or sent by a server.
+    pub nacks: Counter,
+    /// Requests discarded by a client's callers, or by a server's client.
+    pub discards: Counter,
+    /// Tdrains sent or received.
+    pub drains: Counter,
+    /// Tleases sent or received.
+    pub leases: Counter,
+    /// Requests awaiting a response.
+    pub pending: Gauge,
+    pub bytes_read: Counter,
+    pub bytes_written: Counter,
+    /// The sizes of frames read and written, excluding their length
+    /// prefixes, in bytes.
+    pub frames_read: Stat,
+    pub frames_written: Stat,
+    /// The time between a request's being sent, or received, and its
+    /// response, in microseconds.
+    pub latency: Stat,
+}
+
+impl SessionMetrics {
+    /// Metrics that report nowhere.  Its stats record nothing: see `child`.
+    pub fn new() -> SessionMetrics {
+        SessionMetrics {
+            requests: Counter::new(),
+            nacks: Counter::new(),
+            discards: Counter::new(),
+            drains: Counter::new(),
+            leases: Counter::new(),
+            pending: Gauge::new(),
+            bytes_read: Counter::new(),
+            bytes_written: Counter::new(),
+            frames_read: Stat::null(),
+            frames_written: Stat::null(),
+            latency: Stat::null(),
+        }
+    }
+
+    /// Metrics registered in `registry` under names beginning with
+    /// `prefix`, e.g. `mux_client_requests_total`.  Sessions should be
+    /// given `child`ren of them.
+    pub fn registered<R: Registry>(registry: &R, prefix: &str) -> SessionMetrics {
+        let name = |n: &str| format!("{}_{}", prefix, n);
+        SessionMetrics {
+            requests: registry.counter(name("requests_total").as_slice(), "Requests sent or received."),
+            nacks: registry.counter(name("nacks_total").as_slice(), "Nacks sent or received."),
+            discards: registry.counter(name("discards_total").as_slice(), "Requests discarded."),
+            drains: registry.counter(name("drains_total").as_slice(), "Tdrains sent or received."),
+            leases: registry.counter(name("leases_total").as_slice(), "Tleases sent or received."),
+            pending: registry.gauge(name("pending").as_slice(), "Requests awaiting a response."),
+            bytes_read: registry.counter(name("read_bytes_total").as_slice(), "Bytes read."),
+            bytes_written: registry.counter(name("written_bytes_total").as_slice(), "Bytes written."),
+            frames_read: registry.stat(name("read_frame_bytes").as_slice(), "The sizes of frames read."),
+            frames_written: registry.stat(name("written_frame_bytes").as_slice(), "The sizes of frames written."),
+            latency: registry.stat(name("latency_microseconds").as_slice(), "Request latency."),
+        }
+    }
+
+    /// Metrics for one session, which also feed these.  Counters and
+    /// gauges are the session's own, but stats are shared: at ~60KB
+    /// apiece, histograms aren't kept for each session.
+    pub fn child(&self) -> SessionMetrics {
+        SessionMetrics {
+            requests: self.requests.child(),
+            nacks: self.nacks.child(),
+            discards: self.discards.child(),
+            drains: self.drains.child(),
+            leases: self.leases.child(),
+            pending: self.pending.child(),
+            bytes_read: self.bytes_read.child(),
+            bytes_written: self.bytes_written.child(),
+            frames_read: self.frames_read.clone(),
+            frames_written: self.frames_written.clone(),
+            latency: self.latency.clone(),
+        }
+    }
+}
+
+/// Counts the bytes and frames passing in one direction.  Frames are
+/// delimited by their length prefixes, however the stream is split.
+struct Meter {
+    bytes: Counter,
+    frames: Stat,
+    // the length prefix read so far, and how many of its bytes.
+    len: usize,
+    header: usize,
+    // the bytes left in the current frame.
+    remaining: usize,
+}
+
+impl Meter {
+    fn new(bytes: Counter, frames: Stat) -> Meter {
+        Meter { bytes: bytes, frames: frames, len: 0, header: 0, remaining: 0 }
+    }
+
+    fn count(&mut self, buf: &[u8]) {
+        self.bytes.add(buf.len());
+        let mut i = 0;
+        while i < buf.len() {
+            if self.remaining > 0 {
+                let n = cmp::min(self.remaining, buf.len() - i);
+                self.remaining -= n;
+                i += n;
+                continue;
+            }
+            self.len = (self.len << 8) | (buf[i] as usize);
+            self.header += 1;
+            i += 1;
+            if self.header == 4 {
+                self.frames.record(self.len as u64);
+                self.remaining = self.len;
+                self.len = 0;
+                self.header = 0;
+            }
+        }
+    }
+}
+
+/// A transport that counts the bytes and frames read and written through
+/// it in a `SessionMetrics`.  Clones count into the same metrics, but each
+/// must carry whole frames in each direction it's used.
+pub struct Metered<T> {
+    inner: T,
+    read: Meter,
+    written: Meter,
+}
+
+impl<T> Metered<T> {
+    pub fn new(inner: T, metrics: &SessionMetrics) -> Metered<T> {
+        Metered {
+            inner: inner,
+            read: Meter::new(metrics.bytes_read.clone(), metrics.frames_read.clone()),
+            written: Meter::new(metrics.bytes_written.clone(), metrics.frames_written.clone()),
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: Clone> Clone for Metered<T> {
+    fn clone(&self) -> Metered<T> {
+        Metered {
+            inner: self.inner.clone(),
+            read: Meter::new(self.read.bytes.clone(), self.read.frames.clone()),
+            written: Meter::new(self.written.bytes.clone(), self.written.frames.clone()),
+        }
+    }
+}
+
+impl<T: Reader> Reader for Metered<T> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let n = self.inner.read(buf);
+        match n {
+            Err(_) => (),
+            Ok(n) => self.read.count(buf.slice_to(n)),
+        }
+        n
+    }
+}
+
+impl<T: Writer> Writer for Metered<T> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        let written = self.inner.write(buf);
+        if written.is_ok() {
+            self.written.count(buf);
+        }
+        written
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.inner.flush()
+    }
+}
+
+impl<T: Transport> Transport for Metered<T> {
+    fn close(&mut self) {
+        self.inner.close();
+    }
+
+    /// Counts the frame as it's encoded.
+    fn write_frame<F: FnOnce(&mut Vec<u8>) -> IoResult<()>>(&mut self, f: F) -> IoResult<()> {
+        let Metered { ref mut inner, ref mut written, .. } = *self;
+        inner.write_frame(move |buf| {
+            let start = buf.len();
+            f(buf).map(|_| written.count(buf.slice_from(start)))
+        })
+    }
+
+    fn peer_identity(&self) -> Option<PeerIdentity> {
+        self.inner.peer_identity()
+    }
+
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        self.inner.peer_credentials()
+    }
+
+    fn peer_addr(&self) -> Option<String> {
+        self.inner.peer_addr()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::old_io::{MemWriter, Writer};
+
+    use proto::{Tag, Tmsg};
+    use writer::MuxWriter;
+    use super::{Counter, Gauge, Metered, Metrics, Registry, SessionMetrics};
+
+    #[test]
+    fn test_children() {
+        let (c, g) = (Counter::new(), Gauge::new());
+        let (c1, c2, g1, g2) = (c.child(), c.child(), g.child(), g.child());
+        c1.incr();
+        c2.add(2);
+        g1.set(3);
+        g2.add(4);
+        g1.set(1);
+        assert_eq!((c.get(), c1.get(), c2.get()), (3, 1, 2));
+        assert_eq!((g.get(), g1.get(), g2.get()), (5, 1, 4));
+    }
+
+    #[test]
+    fn test_registry() {
+        let metrics = Metrics::new();
+        metrics.counter("a_total", "As.").add(2);
+        metrics.counter("a_total", "As.").incr();
+        // a name can't be registered to two kinds of metric.
+        metrics.gauge("a_total", "As.").set(7);
+        let stat = metrics.stat("b", "Bs,\nwith a newline.");
+        for v in range(1u64, 101) {
+            stat.record(v);
+        }
+
+        assert_eq!(metrics.prometheus(),
+                   "# HELP a_total As.\n# TYPE a_total counter\na_total 3\n\
+                    # HELP b Bs,\\nwith a newline.\n# TYPE b summary\n\
+                    b{quantile=\"0.5\"} 50\nb{quantile=\"0.9\"} 90\nb{quantile=\"0.99\"} 99\n\
+                    b{quantile=\"0.999\"} 100\nb_sum 5050\nb_count 100\n");
+    }
+
+    #[test]
+    fn test_metered() {
+        let metrics = SessionMetrics::registered(&Metrics::new(), "mux").child();
+        let mut frames = MemWriter::new();
+        frames.write_mux_framed_tmsg(&Tag(0, 0, 1), &Tmsg::Ping).unwrap();
+        frames.write_mux_framed_tmsg(&Tag(0, 0, 2), &Tmsg::Lease(0, 1000)).unwrap();
+        let bytes = frames.into_inner();
+
+        // frames split across writes are counted whole.
+        let mut w = Metered::new(MemWriter::new(), &metrics);
+        for chunk in bytes.chunks(3) {
+            w.write(chunk).unwrap();
+        }
+        assert_eq!(w.get_ref().get_ref(), bytes.as_slice());
+        assert_eq!(metrics.bytes_written.get(), bytes.len());
+        let sizes = metrics.frames_written.snapshot();
+        assert_eq!((sizes.count(), sizes.min(), sizes.max()), (2, 4, 4 + 9));
+        assert_eq!(metrics.bytes_read.get(), 0);
+    }
+}
@@ -1,4 +1,5 @@
-use std::old_io::IoError;
+use std::fmt;
+use std::old_io::{IoError, IoResult, InvalidInput, BufReader, MemWriter, Reader, Writer};
 
 #[derive(Clone,PartialEq,Eq,Debug)]
 pub struct Dentry {
@@ -14,6 +15,59 @@ pub struct Dtab(pub Vec<Dentry>);
 impl Dtab {
     #[inline]
     pub fn empty() -> Dtab { Dtab(Vec::with_capacity(0)) }
+
+    /// Parses the textual form of a Dtab: dentries such as `/s=>/$/inet/x/80`,
+    /// separated by semicolons.  Whitespace around either is ignored.
+    pub fn parse(s: &str) -> IoResult<Dtab> {
+        let mut dentries = Vec::new();
+        for d in s.split(';').map(|d| d.trim()).filter(|d| !d.is_empty()) {
+            let parts: Vec<&str> = d.split_str("=>").map(|p| p.trim()).collect();
+            if parts.len() != 2 || !parts[0].starts_with("/") || parts[1].is_empty() {
+                return Err(IoError {
+                    kind: InvalidInput,
+                    desc: "bad dentry",
+                    detail: Some(d.to_string()),
+                });
+            }
+            dentries.push(Dentry::new(parts[0].to_string(), parts[1].to_string()));
+        }
+        Ok(Dtab(dentries))
+    }
+
+    /// A Dtab consisting of this one's dentries followed by `other`'s, which
+    /// therefore take precedence.
+    pub fn concat(&self, other: &Dtab) -> Dtab {
+        let (&Dtab(ref a), &Dtab(ref b)) = (self, other);
+        let mut dentries = a.clone();
+        dentries.push_all(b.as_slice());
+        Dtab(dentries)
+    }
+
+    /// Rewrites `path` by the last dentry whose source is a prefix of it,
+    /// component by component, or returns None if there is none.
+    pub fn lookup(&self, path: &str) -> Option<String> {
+        let &Dtab(ref dentries) = self;
+        for d in dentries.iter().rev() {
+            let src = d.src.trim_right_matches('/');
+            if !path.starts_with(src) {
+                continue;
+            }
+            let rest = path.slice_from(src.len());
+            if rest.is_empty() || rest.starts_with("/") {
+                return Some(format!("{}{}", d.tree.trim_right_matches('/'), rest));
+            }
+        }
+        None
+    }
+}
+
+/// Formats the Dtab as Finagle does, e.g. `/s=>/t;/u=>/v`.
+impl fmt::Display for Dtab {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let &Dtab(ref dentries) = self;
+        let shown: Vec<String> = dentries.iter().map(|d| format!("{}=>{}", d.src, d.tree)).collect();
+        f.write_str(shown.connect(";").as_slice())
+    }
 }
 
 #[derive(Clone,PartialEq,Eq,Debug)]
@@ -25,6 +79,13 @@ impl Context {
 #[derive(Clone,PartialEq,Eq,Debug)]
 pub struct Contexts(pub Vec<Context>);
 
+/// A session header, as exchanged in Tinit and Rinit.
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct Header { pub key: Vec<u8>, pub val: Vec<u8> }
+impl Header {
+    pub fn new(k: Vec<u8>, v: Vec<u8>) -> Header { Header{key: k, val: v} }
+}
+
 #[derive(Clone,Copy,Eq,PartialEq,Debug)]
 pub struct Trace {
     pub span_id: u64,
@@ -33,6 +94,55 @@ pub struct Trace {
     pub flags: u8,
 }
 
+/// The broadcast context in which Tdispatch carries a trace.
+pub static TRACE_CONTEXT_KEY: &'static [u8] = b"com.twitter.finagle.tracing.TraceContext";
+
+impl Trace {
+    /// Encodes the span, parent and trace ids, followed by the flags as a
+    /// u64.
+    pub fn to_context(&self) -> Context {
+        let mut buf = MemWriter::new();
+        buf.write_be_u64(self.span_id).ok();
+        buf.write_be_u64(self.parent_id).ok();
+        buf.write_be_u64(self.trace_id).ok();
+        buf.write_be_u64(self.flags as u64).ok();
+        Context::new(TRACE_CONTEXT_KEY.to_vec(), buf.into_inner())
+    }
+
+    pub fn from_contexts(contexts: &[Context]) -> Option<Trace> {
+        contexts.iter()
+            .find(|c| c.key.as_slice() == TRACE_CONTEXT_KEY && c.val.len() == 32)
+            .map(|c| {
+                let mut r = BufReader::new(c.val.as_slice());
+                Trace {
+                    span_id: r.read_be_u64().unwrap(),
+                    parent_id: r.read_be_u64().unwrap(),
+                    trace_id: r.read_be_u64().unwrap(),
+                    flags: r.read_be_u64().unwrap() as u8,
+                }
+            })
+    }
+}
+
+/// Formats the trace as Finagle does: the trace, span and parent ids in
+/// hex, e.g. `000000000000000a.000000000000000c<:000000000000000b`.
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}.{:016x}<:{:016x}", self.trace_id, self.span_id, self.parent_id)
+    }
+}
+
+/// The broadcast context key Finagle uses to propagate the number of times
+/// a request has been retried.
+pub static RETRIES_KEY: &'static [u8] = b"com.twitter.finagle.Retries";
+
+/// Reads the retry count from a request's contexts.
+pub fn retries(contexts: &[Context]) -> Option<u32> {
+    contexts.iter()
+        .find(|c| c.key.as_slice() == RETRIES_KEY && c.val.len() == 4)
+        .map(|c| c.val.iter().fold(0, |n, b| (n << 8) | (*b as u32)))
+}
+
 pub trait Detailed {
     fn detail(&self, d: &str) -> Self;
 }
@@ -46,3 +156,36 @@ impl Detailed for IoError {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Dentry, Dtab};
+
+    #[test]
+    fn test_parse_dtab() {
+        assert_eq!(Dtab::parse("").unwrap(), Dtab::empty());
+        assert_eq!(Dtab::parse(" /s => /$/inet/localhost/8080 ; /t=>/s/t;").unwrap(),
+                   Dtab(vec![Dentry::new("/s".to_string(), "/$/inet/localhost/8080".to_string()),
+                             Dentry::new("/t".to_string(), "/s/t".to_string())]));
+        assert!(Dtab::parse("/s").is_err());
+        assert!(Dtab::parse("s=>/t").is_err());
+        assert!(Dtab::parse("/s=>").is_err());
+    }
+
+    #[test]
+    fn test_lookup_dtab() {
+        let dtab = Dtab::parse("/s=>/#/users;/s/old=>/#/legacy;/=>/#/default").unwrap();
+        assert_eq!(dtab.lookup("/s/1"), Some("/#/default/s/1".to_string()));
+
+        let dtab = Dtab::parse("/=>/#/default;/s=>/#/users;/s/old=>/#/legacy").unwrap();
+        assert_eq!(dtab.lookup("/s"), Some("/#/users".to_string()));
+        assert_eq!(dtab.lookup("/s/1"), Some("/#/users/1".to_string()));
+        assert_eq!(dtab.lookup("/s/old/1"), Some("/#/legacy/1".to_string()));
+        assert_eq!(dtab.lookup("/sx/1"), Some("/#/default/sx/1".to_string()));
+        assert_eq!(Dtab::parse("/s=>/t").unwrap().lookup("/u"), None);
+
+        let local = Dtab::parse("/s=>/#/staging").unwrap();
+        assert_eq!(dtab.concat(&local).lookup("/s/1"), Some("/#/staging/1".to_string()));
+        assert_eq!(local.concat(&dtab).lookup("/s/1"), Some("/#/users/1".to_string()));
+    }
+}
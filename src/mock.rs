@@ -0,0 +1,278 @@
+//! Scripted responses, for testing clients without a real backend.
+//!
+//! A script lists rules, one per line, each matching requests by
+//! destination prefix or body and saying how to reply:
+//!
+//!     # MATCH          [OPTIONS]   REPLY [TEXT]
+//!     dst=/svc/users               ok {"id": 1}
+//!     dst=/svc/slow    delay=500   echo
+//!     body=boom        every=3     error it broke
+//!     dst=/svc/old                 rerr not supported
+//!     *                            nack
+//!
+//! A MATCH is `*`, `dst=PREFIX` or `body=SUBSTRING`, and a REPLY is `echo`,
+//! `ok`, `error`, `nack` or `rerr`; the text of `ok`, `error` and `rerr`
+//! is the rest of the line.  `delay=MS` waits before replying, and
+//! `every=N` applies a rule to only every Nth request it matches, passing
+//! the others on to later rules.  The first rule to apply wins; requests
+//! no rule applies to are echoed.
+
+#[allow(unstable)]
+
+use std::old_io::{IoResult, IoError, InvalidInput, Writer};
+use std::old_io::timer::sleep;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUint, Ordering};
+use std::time::Duration;
+use time;
+
+use proto::Rmsg;
+use server::{Handler, Request};
+
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub enum Match {
+    Any,
+    /// Destinations beginning with a prefix.
+    Dst(String),
+    /// Bodies containing a substring.
+    Body(Vec<u8>),
+}
+
+impl Match {
+    pub fn matches(&self, req: &Request) -> bool {
+        match *self {
+            Match::Any => true,
+            Match::Dst(ref prefix) => req.dst.starts_with(prefix.as_slice()),
+            Match::Body(ref s) => s.is_empty() || req.body.windows(s.len()).any(|w| w == s.as_slice()),
+        }
+    }
+}
+
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub enum Reply {
+    /// A DispatchOk carrying the request's contexts and body.
+    Echo,
+    Ok(Vec<u8>),
+    Error(String),
+    Nack,
+    /// An Rerr, as a server that doesn't understand the request sends.
+    Rerr(String),
+}
+
+pub struct Rule {
+    pub matcher: Match,
+    pub reply: Reply,
+    pub delay: Option<Duration>,
+    pub every: usize,
+    matched: AtomicUint,
+}
+
+impl Rule {
+    pub fn new(matcher: Match, reply: Reply) -> Rule {
+        Rule { matcher: matcher, reply: reply, delay: None, every: 1, matched: AtomicUint::new(0) }
+    }
+
+    pub fn delay(mut self, delay: Duration) -> Rule {
+        self.delay = Some(delay);
+        self
+    }
+
+    pub fn every(mut self, every: usize) -> Rule {
+        self.every = every;
+        self
+    }
+
+    /// True if the rule applies to `req`, counting it if it matches.
+    fn applies(&self, req: &Request) -> bool {
+        self.matcher.matches(req) && (self.matched.fetch_add(1, Ordering::SeqCst) + 1) % self.every == 0
+    }
+}
+
+/// Parses a script, as described above.  Blank lines and lines beginning
+/// with `#` are ignored.
+pub fn parse(script: &str) -> IoResult<Vec<Rule>> {
+    let mut rules = Vec::new();
+    for (n, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("#") {
+            continue;
+        }
+        match parse_rule(line) {
+            Err(desc) => return Err(IoError {
+                kind: InvalidInput,
+                desc: desc,
+                detail: Some(format!("line {}: {}", n + 1, line)),
+            }),
+            Ok(rule) => rules.push(rule),
+        }
+    }
+    Ok(rules)
+}
+
+fn parse_rule(line: &str) -> Result<Rule, &'static str> {
+    let words: Vec<&str> = line.words().collect();
+    let matcher = if words[0] == "*" {
+        Match::Any
+    } else if words[0].starts_with("dst=") {
+        Match::Dst(words[0].slice_from(4).to_string())
+    } else if words[0].starts_with("body=") {
+        Match::Body(words[0].slice_from(5).as_bytes().to_vec())
+    } else {
+        return Err("unknown match");
+    };
+
+    let mut delay = None;
+    let mut every = 1;
+    let mut i = 1;
+    while i < words.len() && words[i].contains_char('=') {
+        let (name, value) = match words[i].find('=') {
+            None => unreachable!(),
+            Some(eq) => (words[i].slice_to(eq), words[i].slice_from(eq + 1).parse()),
+        };
+        match (name, value) {
+            ("delay", Some(ms)) => delay = Some(Duration::milliseconds(ms as i64)),
+            ("every", Some(n)) if n > 0 => every = n,
+            _ => return Err("bad option"),
+        }
+        i += 1;
+    }
+
+    if i == words.len() {
+        return Err("no reply");
+    }
+    let text = words.slice_from(i + 1).connect(" ");
+    let reply = match words[i] {
+        "echo" => Reply::Echo,
+        "ok" => Reply::Ok(text.into_bytes()),
+        "error" => Reply::Error(text),
+        "nack" => Reply::Nack,
+        "rerr" => Reply::Rerr(text),
+        _ => return Err("unknown reply"),
+    };
+
+    let rule = Rule::new(matcher, reply).every(every);
+    Ok(match delay { None => rule, Some(d) => rule.delay(d) })
+}
+
+/// Serves requests according to a script.
+pub struct Mock {
+    rules: Vec<Rule>,
+    record: Option<Mutex<Box<Writer + Send>>>,
+}
+
+impl Mock {
+    pub fn new(rules: Vec<Rule>) -> Mock {
+        Mock { rules: rules, record: None }
+    }
+
+    /// Writes a line to `w` for each request received: the time in
+    /// milliseconds since the epoch, the destination, the Dtab and the body
+    /// (escaped), separated by tabs.
+    pub fn record<W: Writer + Send + 'static>(mut self, w: W) -> Mock {
+        self.record = Some(Mutex::new(Box::new(w) as Box<Writer + Send>));
+        self
+    }
+}
+
+impl Handler for Mock {
+    fn dispatch(&self, req: Request) -> Rmsg {
+        match self.record {
+            None => (),
+            Some(ref w) => {
+                let now = time::get_time();
+                let line = format!("{}\t{}\t{}\t{}\n",
+                                   now.sec * 1000 + (now.nsec / 1000000) as i64,
+                                   req.dst, req.dtab,
+                                   String::from_utf8_lossy(req.body.as_slice()).escape_default());
+                let mut w = w.lock().unwrap();
+                w.write_str(line.as_slice()).and_then(|_| w.flush()).ok();
+            },
+        }
+
+        let rule = self.rules.iter().find(|r| r.applies(&req));
+        match rule.and_then(|r| r.delay) {
+            None => (),
+            Some(delay) => sleep(delay),
+        }
+        match rule.map(|r| r.reply.clone()).unwrap_or(Reply::Echo) {
+            Reply::Echo => Rmsg::DispatchOk(req.contexts, req.body),
+            Reply::Ok(body) => Rmsg::DispatchOk(Vec::new(), body),
+            Reply::Error(msg) => Rmsg::DispatchError(Vec::new(), msg),
+            Reply::Nack => Rmsg::DispatchNack(Vec::new()),
+            Reply::Rerr(msg) => Rmsg::Err(msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::old_io::{IoResult, Writer};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use misc::Dtab;
+    use proto::{Tmsg, Rmsg};
+    use server::{Handler, Request};
+    use super::{Match, Mock, Reply, parse};
+
+    fn request(dst: &str, body: &[u8]) -> Request {
+        let dtab = Dtab::parse("/s=>/t").unwrap();
+        Request::from_tmsg(Tmsg::Dispatch(Vec::new(), dst.to_string(), dtab, body.to_vec())).unwrap()
+    }
+
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Writer for Shared {
+        fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+            self.0.lock().unwrap().push_all(buf);
+            Ok(())
+        }
+    }
+
+    static SCRIPT: &'static str = "
+        # comment
+        dst=/svc/users              ok {\"id\": 1}
+        dst=/svc/slow   delay=10    echo
+        body=boom       every=2     error it  broke
+        dst=/svc/old                rerr no
+        *                           nack
+    ";
+
+    #[test]
+    fn test_parse() {
+        let rules = parse(SCRIPT).unwrap();
+        assert_eq!(rules.len(), 5);
+        assert_eq!(rules[0].matcher, Match::Dst("/svc/users".to_string()));
+        assert_eq!(rules[0].reply, Reply::Ok(b"{\"id\": 1}".to_vec()));
+        assert_eq!(rules[1].delay, Some(Duration::milliseconds(10)));
+        assert_eq!(rules[2].matcher, Match::Body(b"boom".to_vec()));
+        assert_eq!(rules[2].every, 2);
+        assert_eq!(rules[2].reply, Reply::Error("it broke".to_string()));
+        assert_eq!(rules[4].matcher, Match::Any);
+
+        assert!(parse("dst=/x").is_err());
+        assert!(parse("dst=/x delay=soon echo").is_err());
+        assert!(parse("host=x echo").is_err());
+        assert!(parse("* teapot").is_err());
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+        let mock = Mock::new(parse(SCRIPT).unwrap()).record(Shared(recorded.clone()));
+
+        assert_eq!(mock.dispatch(request("/svc/users/1", b"")), Rmsg::DispatchOk(Vec::new(), b"{\"id\": 1}".to_vec()));
+        assert_eq!(mock.dispatch(request("/svc/slow", b"hi")), Rmsg::DispatchOk(Vec::new(), b"hi".to_vec()));
+        // only every other boom is an error; the rest fall through.
+        assert_eq!(mock.dispatch(request("/x", b"kaboom")), Rmsg::DispatchNack(Vec::new()));
+        assert_eq!(mock.dispatch(request("/x", b"kaboom")), Rmsg::DispatchError(Vec::new(), "it broke".to_string()));
+        assert_eq!(mock.dispatch(request("/svc/old", b"")), Rmsg::Err("no".to_string()));
+        assert_eq!(Mock::new(Vec::new()).dispatch(request("/x", b"echo")), Rmsg::DispatchOk(Vec::new(), b"echo".to_vec()));
+
+        let recorded = String::from_utf8(recorded.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = recorded.lines().collect();
+        assert_eq!(lines.len(), 5);
+        let fields: Vec<&str> = lines[1].split('\t').collect();
+        assert_eq!(fields.slice_from(1), ["/svc/slow", "/s=>/t", "hi"].as_slice());
+    }
+}
@@ -0,0 +1,408 @@
+//! A pool of client sessions to several endpoints.
+//!
+//! Each request is balanced onto an endpoint by comparing two randomly
+//! chosen endpoints (power of two choices), and then onto the least loaded
+//! of that endpoint's sessions.  Endpoints that drain their sessions or fail
+//! pings are ejected until a subsequent `check` succeeds.  Endpoints that
+//! accrue failures are marked dead and probed before they return to rotation
+//! (see `accrual`).
+
+#[allow(unstable)]
+
+use std::cmp;
+use std::num::Float;
+use std::old_io::{IoResult, IoError, ResourceUnavailable};
+use std::old_io::net::tcp::TcpStream;
+use std::rand::{thread_rng, Rng};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use time::precise_time_ns;
+
+use accrual::{AccrualConfig, Admission, FailureAccrual};
+use metrics::SessionMetrics;
+use proto::{Tmsg, Rmsg};
+use session::{Client, ClientSession, Discard};
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub enum Strategy {
+    /// Prefer the endpoint with fewer outstanding requests.
+    LeastLoaded,
+
+    /// Prefer the endpoint with the lower peak-EWMA latency, scaled by its
+    /// outstanding requests.  Latency observations decay over the given
+    /// window.
+    Ewma(Duration),
+}
+
+#[derive(Clone,Copy,PartialEq,Debug)]
+pub struct PoolConfig {
+    /// The number of sessions maintained to each endpoint.
+    pub sessions: usize,
+    pub strategy: Strategy,
+    pub accrual: AccrualConfig,
+}
+
+impl PoolConfig {
+    pub fn default() -> PoolConfig {
+        PoolConfig {
+            sessions: 1,
+            strategy: Strategy::Ewma(Duration::seconds(10)),
+            accrual: AccrualConfig::default(),
+        }
+    }
+}
+
+/// An exponentially-weighted moving average of latency that jumps to any
+/// observation above it (i.e. "peak" EWMA), so slow endpoints are penalized
+/// immediately and forgiven gradually.
+struct Ewma {
+    decay_ns: f64,
+    stamp_ns: u64,
+    cost_ns: f64,
+}
+
+impl Ewma {
+    fn new(decay: Duration) -> Ewma {
+        Ewma {
+            decay_ns: decay.num_nanoseconds().unwrap_or(0) as f64,
+            stamp_ns: precise_time_ns(),
+            cost_ns: 0.0,
+        }
+    }
+
+    fn observe(&mut self, rtt_ns: u64) {
+        let now = precise_time_ns();
+        let rtt = rtt_ns as f64;
+        if rtt > self.cost_ns || self.decay_ns <= 0.0 {
+            self.cost_ns = rtt;
+        } else {
+            let elapsed = if now > self.stamp_ns { (now - self.stamp_ns) as f64 } else { 0.0 };
+            let w = (-elapsed / self.decay_ns).exp();
+            self.cost_ns = self.cost_ns * w + rtt * (1.0 - w);
+        }
+        self.stamp_ns = now;
+    }
+}
+
+struct Endpoint {
+    addr: String,
+    sessions: Mutex<Vec<Arc<ClientSession<TcpStream>>>>,
+    ewma: Mutex<Ewma>,
+    accrual: Mutex<FailureAccrual>,
+    ejected: AtomicBool,
+    metrics: Option<SessionMetrics>,
+}
+
+impl Endpoint {
+    fn new(addr: String, config: &PoolConfig, metrics: Option<SessionMetrics>) -> Endpoint {
+        let decay = match config.strategy {
+            Strategy::Ewma(decay) => decay,
+            Strategy::LeastLoaded => Duration::zero(),
+        };
+        Endpoint {
+            addr: addr,
+            sessions: Mutex::new(Vec::new()),
+            ewma: Mutex::new(Ewma::new(decay)),
+            accrual: Mutex::new(FailureAccrual::new(config.accrual)),
+            ejected: AtomicBool::new(false),
+            metrics: metrics,
+        }
+    }
+
+    fn is_ejected(&self) -> bool { self.ejected.load(Ordering::SeqCst) }
+
+    fn eject(&self) { self.ejected.store(true, Ordering::SeqCst) }
+
+    fn is_live(&self) -> bool {
+        !self.is_ejected() && self.accrual.lock().unwrap().is_available()
+    }
+
+    /// Records the outcome of a request.  Connection failures and Rerr
+    /// responses count against the endpoint; nacks and application errors
+    /// do not.
+    fn record(&self, rsp: &IoResult<Rmsg>) {
+        let mut accrual = self.accrual.lock().unwrap();
+        match *rsp {
+            Err(_) | Ok(Rmsg::Err(_)) => accrual.record_failure(),
+            Ok(_) => accrual.record_success(),
+        }
+    }
+
+    /// Pings a dead endpoint to determine whether it may return to rotation.
+    fn probe(&self, n: usize) -> IoResult<()> {
+        let rsp = self.session(n).and_then(|s| s.ping()).map(|_| Rmsg::Ping);
+        self.record(&rsp);
+        rsp.map(|_| ())
+    }
+
+    fn load(&self) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.iter().fold(0, |sum, s| sum + s.load())
+    }
+
+    fn cost(&self, strategy: Strategy) -> f64 {
+        let load = self.load() as f64;
+        match strategy {
+            Strategy::LeastLoaded => load,
+            Strategy::Ewma(_) => self.ewma.lock().unwrap().cost_ns * (load + 1.0),
+        }
+    }
+
+    /// Returns the least loaded available session, replacing sessions that
+    /// have been drained or closed.
+    fn session(&self, n: usize) -> IoResult<Arc<ClientSession<TcpStream>>> {
+        let missing = {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.retain(|s| s.is_available());
+            n - cmp::min(n, sessions.len())
+        };
+
+        // sessions are connected without the lock held, so that a slow
+        // connect doesn't stall balancing (see `load`).
+        let mut connected = Vec::new();
+        let mut failure = None;
+        for _ in range(0, missing) {
+            let session = TcpStream::connect(self.addr.as_slice()).map(|conn| match self.metrics {
+                None => ClientSession::new(conn),
+                Some(ref metrics) => ClientSession::metered(conn, metrics.child()),
+            });
+            match session {
+                Err(ioe) => {
+                    failure = Some(ioe);
+                    break;
+                },
+                Ok(s) => connected.push(Arc::new(s)),
+            }
+        }
+
+        let mut sessions = self.sessions.lock().unwrap();
+        // callers racing to replace sessions may connect too many; the
+        // excess are dropped, and so closed.
+        for s in connected.into_iter() {
+            if sessions.len() < n {
+                sessions.push(s);
+            }
+        }
+        if sessions.is_empty() {
+            self.eject();
+            return Err(failure.unwrap_or(IoError {
+                kind: ResourceUnavailable,
+                desc: "no sessions",
+                detail: None,
+            }));
+        }
+
+        let mut best: Option<&Arc<ClientSession<TcpStream>>> = None;
+        for s in sessions.iter() {
+            best = match best {
+                Some(b) if b.load() <= s.load() => Some(b),
+                _ => Some(s),
+            };
+        }
+        Ok(best.unwrap().clone())
+    }
+
+    /// Pings each of the endpoint's sessions, ejecting the endpoint on
+    /// failure and restoring it on success.
+    fn check(&self, n: usize) -> bool {
+        let healthy = match self.session(n) {
+            Err(_) => false,
+            Ok(_) => {
+                let sessions = self.sessions.lock().unwrap().clone();
+                sessions.iter().all(|s| s.ping().is_ok() && s.is_available())
+            }
+        };
+        self.ejected.store(!healthy, Ordering::SeqCst);
+        healthy
+    }
+}
+
+pub struct Pool {
+    endpoints: Vec<Arc<Endpoint>>,
+    config: PoolConfig,
+}
+
+impl Pool {
+    /// Sessions are established lazily, as requests are balanced onto each
+    /// endpoint.
+    pub fn new(addrs: &[String], config: PoolConfig) -> Pool {
+        Pool {
+            endpoints: addrs.iter().map(|a| Arc::new(Endpoint::new(a.clone(), &config, None))).collect(),
+            config: config,
+        }
+    }
+
+    /// Each session keeps a `child` of `metrics`.
+    pub fn metrics(mut self, metrics: SessionMetrics) -> Pool {
+        // no sessions have been established, so endpoints may be replaced.
+        let config = self.config;
+        self.endpoints = self.endpoints.iter()
+            .map(|ep| Arc::new(Endpoint::new(ep.addr.clone(), &config, Some(metrics.clone()))))
+            .collect();
+        self
+    }
+
+    /// The addresses of endpoints currently in rotation.
+    pub fn available(&self) -> Vec<String> {
+        self.endpoints.iter()
+            .filter(|ep| ep.is_live())
+            .map(|ep| ep.addr.clone())
+            .collect()
+    }
+
+    pub fn call(&self, msg: &Tmsg) -> IoResult<Rmsg> {
+        self.call_with(msg, None)
+    }
+
+    /// Like `call`, but abandons the request when `discard` fires (see
+    /// `ClientSession::call_discardable`).  Discarded requests don't count
+    /// against the endpoint.
+    pub fn call_discardable(&self, msg: &Tmsg, discard: &Discard) -> IoResult<Rmsg> {
+        self.call_with(msg, Some(discard))
+    }
+
+    fn call_with(&self, msg: &Tmsg, discard: Option<&Discard>) -> IoResult<Rmsg> {
+        let ep = match self.pick() {
+            None => return Err(IoError {
+                kind: ResourceUnavailable,
+                desc: "no endpoints",
+                detail: None,
+            }),
+            Some(ep) => ep,
+        };
+
+        let admission = ep.accrual.lock().unwrap().admit();
+        match admission {
+            Admission::Accept => (),
+            Admission::Probe => match ep.probe(self.config.sessions) {
+                Err(ioe) => return Err(ioe),
+                Ok(_) => (),
+            },
+            Admission::Reject => return Err(IoError {
+                kind: ResourceUnavailable,
+                desc: "endpoint marked dead",
+                detail: Some(ep.addr.clone()),
+            }),
+        }
+
+        let session = match ep.session(self.config.sessions) {
+            Err(ioe) => {
+                ep.record(&Err(ioe.clone()));
+                return Err(ioe);
+            },
+            Ok(s) => s,
+        };
+
+        let start = precise_time_ns();
+        let rsp = match discard {
+            None => session.call(msg),
+            Some(discard) => session.call_discardable(msg, discard),
+        };
+        if discard.map_or(false, |d| d.is_discarded()) {
+            return rsp;
+        }
+        ep.ewma.lock().unwrap().observe(precise_time_ns() - start);
+        ep.record(&rsp);
+
+        if session.is_draining() || session.is_closed() {
+            ep.eject();
+        }
+        rsp
+    }
+
+    /// Pings all endpoints, ejecting those that fail and returning those
+    /// that succeed to rotation.
+    pub fn check(&self) {
+        for ep in self.endpoints.iter() {
+            ep.check(self.config.sessions);
+        }
+    }
+
+    fn pick(&self) -> Option<Arc<Endpoint>> {
+        let mut live: Vec<&Arc<Endpoint>> = self.endpoints.iter().filter(|ep| ep.is_live()).collect();
+        if live.is_empty() {
+            // When every endpoint has been ejected, it's better to try one
+            // than to fail outright.
+            live = self.endpoints.iter().collect();
+        }
+
+        match live.len() {
+            0 => None,
+            1 => Some(live[0].clone()),
+            n => {
+                let mut rng = thread_rng();
+                let i = rng.gen_range(0, n);
+                let j = (i + rng.gen_range(1, n)) % n;
+                let (a, b) = (live[i], live[j]);
+                if a.cost(self.config.strategy) <= b.cost(self.config.strategy) {
+                    Some(a.clone())
+                } else {
+                    Some(b.clone())
+                }
+            }
+        }
+    }
+}
+
+impl Client for Pool {
+    fn call(&self, msg: &Tmsg) -> IoResult<Rmsg> { Pool::call(self, msg) }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use accrual::{AccrualConfig, Policy};
+    use misc::Dtab;
+    use proto::{Tmsg, Rmsg};
+    use session::test::serve_echo;
+    use super::{Pool, PoolConfig, Strategy};
+
+    fn dispatch(body: &[u8]) -> Tmsg {
+        Tmsg::Dispatch(Vec::new(), "/".to_string(), Dtab::empty(), body.to_vec())
+    }
+
+    #[test]
+    fn test_balances() {
+        let addrs = vec![serve_echo(false), serve_echo(false)];
+        for strategy in vec![Strategy::LeastLoaded, Strategy::Ewma(Duration::seconds(1))].into_iter() {
+            let config = PoolConfig { sessions: 2, strategy: strategy, accrual: AccrualConfig::default() };
+            let pool = Pool::new(addrs.as_slice(), config);
+            for _ in range(0, 10) {
+                assert_eq!(pool.call(&dispatch(b"mom")).unwrap(),
+                           Rmsg::DispatchOk(Vec::new(), b"mom".to_vec()));
+            }
+            assert_eq!(pool.available().len(), 2);
+        }
+    }
+
+    #[test]
+    fn test_ejects_unreachable() {
+        let addrs = vec!["127.0.0.1:1".to_string(), serve_echo(false)];
+        let pool = Pool::new(addrs.as_slice(), PoolConfig::default());
+        pool.check();
+        assert_eq!(pool.available(), vec![addrs[1].clone()]);
+        pool.call(&dispatch(b"mom")).unwrap();
+    }
+
+    #[test]
+    fn test_accrues_rerr() {
+        // the echo server answers Tdrain requests with Rerr.
+        let addrs = vec![serve_echo(false)];
+        let mut config = PoolConfig::default();
+        config.accrual = AccrualConfig {
+            policy: Policy::ConsecutiveFailures(2),
+            min_backoff: Duration::seconds(60),
+            max_backoff: Duration::seconds(60),
+        };
+        let pool = Pool::new(addrs.as_slice(), config);
+
+        assert_eq!(pool.call(&Tmsg::Drain).unwrap(), Rmsg::Err("idk man".to_string()));
+        assert_eq!(pool.available().len(), 1);
+        pool.call(&Tmsg::Drain).unwrap();
+        assert_eq!(pool.available().len(), 0);
+        assert!(pool.call(&dispatch(b"mom")).is_err());
+    }
+}
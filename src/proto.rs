@@ -1,10 +1,43 @@
-use misc::{Context, Dtab, Trace};
+use std::cmp;
+use std::fmt;
+use std::str;
+
+use misc::{Context, Dtab, Header, Trace, RETRIES_KEY, TRACE_CONTEXT_KEY, retries};
 
 #[derive(Clone,PartialEq,Eq,Debug,Copy)]
 pub struct Tag(pub u8, pub u8, pub u8);
 
 pub static MARKER_TAG: Tag = Tag(0,0,0);
 
+/// Tags are 23 bits wide: the high bit is reserved to flag fragments.
+pub static MAX_TAG: u32 = (1 << 23) - 1;
+
+impl Tag {
+    #[inline]
+    pub fn from_u32(n: u32) -> Tag {
+        Tag(((n >> 16) & 0xff) as u8, ((n >> 8) & 0xff) as u8, (n & 0xff) as u8)
+    }
+
+    #[inline]
+    pub fn to_u32(&self) -> u32 {
+        let &Tag(b0, b1, b2) = self;
+        ((b0 as u32) << 16) | ((b1 as u32) << 8) | (b2 as u32)
+    }
+
+    /// True if the tag flags a fragment, to be followed by more of the
+    /// message.
+    #[inline]
+    pub fn is_fragment(&self) -> bool {
+        self.0 & 0x80 != 0
+    }
+
+    #[inline]
+    pub fn with_fragment(&self, more: bool) -> Tag {
+        let &Tag(b0, b1, b2) = self;
+        Tag(if more { b0 | 0x80 } else { b0 & 0x7f }, b1, b2)
+    }
+}
+
 mod types {
     pub const TREQ: i8 =  1;
     pub const RREQ: i8 = -1;
@@ -22,6 +55,9 @@ mod types {
 
     pub const TLEASE: i8 = 67;
 
+    pub const TINIT: i8 =  68;
+    pub const RINIT: i8 = -68;
+
     pub const RERR: i8 = -128;
 }
 
@@ -34,6 +70,7 @@ pub enum MsgType {
     Tping, Rping,
     Tdiscarded,
     Tlease,
+    Tinit, Rinit,
     Rerr,
 }
 
@@ -56,6 +93,9 @@ impl MsgType {
 
             types::TLEASE => Some(MsgType::Tlease),
 
+            types::TINIT => Some(MsgType::Tinit),
+            types::RINIT => Some(MsgType::Rinit),
+
             types::RERR => Some(MsgType::Rerr),
 
             _ => None
@@ -80,6 +120,9 @@ impl MsgType {
 
             MsgType::Tlease => types::TLEASE,
 
+            MsgType::Tinit => types::TINIT,
+            MsgType::Rinit => types::RINIT,
+
             MsgType::Rerr => types::RERR,
         }
     }
@@ -93,6 +136,7 @@ pub enum Tmsg {
     Ping,
     Discarded(Tag, String),
     Lease(u8, u64),
+    Init(u16, Vec<Header>),
 }
 
 
@@ -105,6 +149,21 @@ impl Tmsg {
             &Tmsg::Ping => MsgType::Tping,
             &Tmsg::Discarded(_, _) => MsgType::Tdiscarded,
             &Tmsg::Lease(_, _) => MsgType::Tlease,
+            &Tmsg::Init(_, _) => MsgType::Tinit,
+        }
+    }
+
+    /// The size of the message's frame: its type, tag and fields.
+    pub fn encoded_len(&self) -> usize {
+        4 + match self {
+            &Tmsg::Req(ref trace, ref body) => trace_len(trace) + body.len(),
+            &Tmsg::Dispatch(ref contexts, ref dst, ref dtab, ref body) => {
+                contexts_len(contexts.as_slice()) + 2 + dst.len() + dtab_len(dtab) + body.len()
+            },
+            &Tmsg::Drain | &Tmsg::Ping => 0,
+            &Tmsg::Discarded(_, ref msg) => 3 + msg.len(),
+            &Tmsg::Lease(_, _) => 1 + 8,
+            &Tmsg::Init(_, ref headers) => init_len(headers.as_slice()),
         }
     }
 }
@@ -122,6 +181,8 @@ pub enum Rmsg {
     Drain,
     Ping,
 
+    Init(u16, Vec<Header>),
+
     Err(String),
 }
 
@@ -140,23 +201,192 @@ impl Rmsg {
 
             &Rmsg::Ping => MsgType::Rping,
 
+            &Rmsg::Init(_, _) => MsgType::Rinit,
+
             &Rmsg::Err(_) => MsgType::Rerr,
         }
     }
+
+    /// The size of the message's frame: its type, tag and fields.
+    pub fn encoded_len(&self) -> usize {
+        4 + match self {
+            &Rmsg::ReqOk(ref body) => 1 + body.len(),
+            &Rmsg::ReqError(ref msg) => 1 + msg.len(),
+            &Rmsg::ReqNack => 1,
+
+            &Rmsg::DispatchOk(ref contexts, ref body) => 1 + contexts_len(contexts.as_slice()) + body.len(),
+            &Rmsg::DispatchError(ref contexts, ref msg) => 1 + contexts_len(contexts.as_slice()) + msg.len(),
+            &Rmsg::DispatchNack(ref contexts) => 1 + contexts_len(contexts.as_slice()),
+
+            &Rmsg::Drain | &Rmsg::Ping => 0,
+
+            &Rmsg::Init(_, ref headers) => init_len(headers.as_slice()),
+
+            &Rmsg::Err(ref msg) => msg.len(),
+        }
+    }
+}
+
+fn trace_len(trace: &Option<Trace>) -> usize {
+    // a key count, then the ids (key 1) and flags (key 2).
+    match *trace {
+        None => 1,
+        Some(_) => 1 + (2 + 24) + (2 + 1),
+    }
 }
 
+fn contexts_len(contexts: &[Context]) -> usize {
+    contexts.iter().fold(2, |n, c| n + 2 + c.key.len() + 2 + c.val.len())
+}
+
+fn dtab_len(dtab: &Dtab) -> usize {
+    let &Dtab(ref dentries) = dtab;
+    dentries.iter().fold(2, |n, d| n + 2 + d.src.len() + 2 + d.tree.len())
+}
+
+fn init_len(headers: &[Header]) -> usize {
+    headers.iter().fold(2, |n, h| n + 4 + h.key.len() + 4 + h.val.len())
+}
+
+#[derive(Clone,Eq,PartialEq,Debug)]
 pub enum Msg {
     Tx(Tag, Tmsg),
     Rx(Tag, Rmsg),
 }
 
+/// Shows the message's type and fields, e.g.
+/// `Tdispatch dst=/s dtab=/s=>/t contexts=[retries=1] body=5B "hello"`.
+/// Empty Dtabs and context lists are omitted.
+impl fmt::Display for Tmsg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Tmsg::Req(None, ref body) => write!(f, "Treq {}", show_body(body.as_slice())),
+            Tmsg::Req(Some(ref trace), ref body) => write!(f, "Treq trace={} {}", trace, show_body(body.as_slice())),
+            Tmsg::Dispatch(ref contexts, ref dst, ref dtab, ref body) => {
+                let mut shown = vec![format!("Tdispatch dst={}", dst)];
+                let &Dtab(ref dentries) = dtab;
+                if !dentries.is_empty() {
+                    shown.push(format!("dtab={}", dtab));
+                }
+                if !contexts.is_empty() {
+                    shown.push(format!("contexts={}", show_contexts(contexts.as_slice())));
+                }
+                shown.push(show_body(body.as_slice()));
+                f.write_str(shown.connect(" ").as_slice())
+            },
+            Tmsg::Drain => f.write_str("Tdrain"),
+            Tmsg::Ping => f.write_str("Tping"),
+            Tmsg::Discarded(which, ref why) => write!(f, "Tdiscarded tag={} {:?}", which.to_u32(), why),
+            Tmsg::Lease(0, n) => write!(f, "Tlease {}ms", n),
+            Tmsg::Lease(unit, n) => write!(f, "Tlease {} unit={}", n, unit),
+            Tmsg::Init(version, ref headers) => {
+                write!(f, "Tinit version={} headers={}", version, show_headers(headers.as_slice()))
+            },
+        }
+    }
+}
+
+/// Shows the message's type, status and fields, e.g. `Rreq error "oops"`.
+impl fmt::Display for Rmsg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Rmsg::ReqOk(ref body) => write!(f, "Rreq ok {}", show_body(body.as_slice())),
+            Rmsg::ReqError(ref why) => write!(f, "Rreq error {:?}", why),
+            Rmsg::ReqNack => f.write_str("Rreq nack"),
+
+            Rmsg::DispatchOk(ref contexts, ref body) if contexts.is_empty() => {
+                write!(f, "Rdispatch ok {}", show_body(body.as_slice()))
+            },
+            Rmsg::DispatchOk(ref contexts, ref body) => {
+                write!(f, "Rdispatch ok contexts={} {}", show_contexts(contexts.as_slice()), show_body(body.as_slice()))
+            },
+            Rmsg::DispatchError(ref contexts, ref why) if contexts.is_empty() => {
+                write!(f, "Rdispatch error {:?}", why)
+            },
+            Rmsg::DispatchError(ref contexts, ref why) => {
+                write!(f, "Rdispatch error contexts={} {:?}", show_contexts(contexts.as_slice()), why)
+            },
+            Rmsg::DispatchNack(ref contexts) if contexts.is_empty() => f.write_str("Rdispatch nack"),
+            Rmsg::DispatchNack(ref contexts) => {
+                write!(f, "Rdispatch nack contexts={}", show_contexts(contexts.as_slice()))
+            },
+
+            Rmsg::Drain => f.write_str("Rdrain"),
+            Rmsg::Ping => f.write_str("Rping"),
+
+            Rmsg::Init(version, ref headers) => {
+                write!(f, "Rinit version={} headers={}", version, show_headers(headers.as_slice()))
+            },
+
+            Rmsg::Err(ref why) => write!(f, "Rerr {:?}", why),
+        }
+    }
+}
+
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Msg::Tx(tag, ref msg) => write!(f, "tag={} {}", tag.to_u32(), msg),
+            Msg::Rx(tag, ref msg) => write!(f, "tag={} {}", tag.to_u32(), msg),
+        }
+    }
+}
+
+// the most of a body, or of a context or header value, shown.
+const ABBREV: usize = 32;
+
+fn show_body(body: &[u8]) -> String {
+    format!("body={}B {}", body.len(), show_bytes(body))
+}
+
+/// Shows printable UTF-8 as an escaped string, and anything else in hex.
+fn show_bytes(bytes: &[u8]) -> String {
+    let more = if bytes.len() > ABBREV { "..." } else { "" };
+    match str::from_utf8(bytes) {
+        Ok(s) if s.chars().all(|c| !c.is_control() || c.is_whitespace()) => {
+            let mut n = cmp::min(s.len(), ABBREV);
+            while !s.is_char_boundary(n) {
+                n -= 1;
+            }
+            format!("\"{}{}\"", s.slice_to(n).escape_default(), more)
+        },
+        _ => {
+            let hex: Vec<String> = bytes.iter().take(ABBREV).map(|b| format!("{:02x}", b)).collect();
+            format!("0x{}{}", hex.concat(), more)
+        },
+    }
+}
+
+/// Shows the trace and retries contexts decoded, and others as key=value.
+fn show_contexts(contexts: &[Context]) -> String {
+    let shown: Vec<String> = contexts.iter().map(|c| {
+        let one = [c.clone()];
+        let trace = if c.key.as_slice() == TRACE_CONTEXT_KEY { Trace::from_contexts(&one) } else { None };
+        let retried = if c.key.as_slice() == RETRIES_KEY { retries(&one) } else { None };
+        match (trace, retried) {
+            (Some(trace), _) => format!("trace={}", trace),
+            (_, Some(n)) => format!("retries={}", n),
+            _ => format!("{}={}", String::from_utf8_lossy(c.key.as_slice()), show_bytes(c.val.as_slice())),
+        }
+    }).collect();
+    format!("[{}]", shown.connect(", "))
+}
+
+pub fn show_headers(headers: &[Header]) -> String {
+    let shown: Vec<String> = headers.iter()
+        .map(|h| format!("{}={}", String::from_utf8_lossy(h.key.as_slice()), show_bytes(h.val.as_slice())))
+        .collect();
+    format!("[{}]", shown.connect(", "))
+}
+
 #[cfg(test)]
 mod test {
-    use misc::{Context, Dentry, Dtab};
+    use misc::{Context, Dentry, Dtab, Header, Trace};
+    use retry::retries_context;
     use std::old_io::{Reader, BufReader, MemWriter};
     use reader::MuxReader;
     use writer::MuxWriter;
-    use super::{MsgType, Tmsg, Tag};
+    use super::{Msg, MsgType, Rmsg, Tmsg, Tag};
 
     fn assert_encode(msg: &Tmsg) -> Vec<u8> {
         let mut writer = MemWriter::new();
@@ -224,8 +454,52 @@ mod test {
         assert_decode_encoded(3 + 3, &Tmsg::Discarded(Tag(0,1,0), "msg".to_string()));
     }
 
+    #[test]
+    fn test_decode_tinit() {
+        let headers = vec![Header::new(b"tls".to_vec(), b"on".to_vec())];
+        assert_decode_encoded(2 + 4+3 + 4+2, &Tmsg::Init(1, headers));
+    }
+
+    #[test]
+    fn test_tag_u32() {
+        assert_eq!(Tag::from_u32(0x010203), Tag(1, 2, 3));
+        assert_eq!(Tag(0x7f, 0xff, 0xff).to_u32(), super::MAX_TAG);
+        assert_eq!(Tag::from_u32(Tag(4, 7, 9).to_u32()), Tag(4, 7, 9));
+    }
+
+    #[test]
+    fn test_tag_fragment() {
+        let tag = Tag(4, 7, 9).with_fragment(true);
+        assert_eq!(tag, Tag(0x84, 7, 9));
+        assert!(tag.is_fragment());
+        assert_eq!(tag.with_fragment(false), Tag(4, 7, 9));
+        assert!(!Tag(4, 7, 9).is_fragment());
+    }
+
     #[test]
     fn test_decode_tlease() {
         assert_decode_encoded(1 + 8, &Tmsg::Lease(60, 30));
     }
+
+    #[test]
+    fn test_display() {
+        let trace = Trace { trace_id: 10, span_id: 12, parent_id: 11, flags: 0 };
+        let contexts = vec![trace.to_context(), retries_context(2), Context::new(b"k".to_vec(), vec![0, 255])];
+        let msg = Tmsg::Dispatch(contexts, "/s".to_string(), Dtab::parse("/s=>/t;/u=>/v").unwrap(), b"hi".to_vec());
+        assert_eq!(format!("{}", msg),
+                   "Tdispatch dst=/s dtab=/s=>/t;/u=>/v \
+                    contexts=[trace=000000000000000a.000000000000000c<:000000000000000b, retries=2, k=0x00ff] \
+                    body=2B \"hi\"");
+        assert_eq!(format!("{}", Tmsg::Dispatch(Vec::new(), "/s".to_string(), Dtab::empty(), Vec::new())),
+                   "Tdispatch dst=/s body=0B \"\"");
+
+        let long: Vec<u8> = range(0, 40).map(|_| b'a').collect();
+        assert_eq!(format!("{}", Tmsg::Req(None, long)),
+                   format!("Treq body=40B \"{}...\"", "a".repeat(32)));
+        assert_eq!(format!("{}", Rmsg::ReqOk(vec![1, 2, 0xff])), "Rreq ok body=3B 0x0102ff");
+        assert_eq!(format!("{}", Rmsg::DispatchError(Vec::new(), "oops".to_string())), "Rdispatch error \"oops\"");
+        assert_eq!(format!("{}", Tmsg::Init(1, vec![Header::new(b"tls".to_vec(), b"on".to_vec())])),
+                   "Tinit version=1 headers=[tls=\"on\"]");
+        assert_eq!(format!("{}", Msg::Tx(Tag(0, 0, 3), Tmsg::Lease(0, 500))), "tag=3 Tlease 500ms");
+    }
 }
@@ -0,0 +1,329 @@
+//! Routes Tdispatches to pools of backends by destination.
+//!
+//! A request's destination is rewritten by the proxy's base Dtab followed
+//! by the request's own Dtab, repeatedly, until it names a cluster (e.g.
+//! `/#/users`, configured with `cluster`) or an address (`/$/inet/HOST/PORT`).
+//! Where cluster names overlap, the longest that matches is used.  Addresses
+//! are refused unless allowed with `allow_inet`, as a request's own Dtab
+//! could otherwise send it anywhere.  It's then forwarded, unchanged, on
+//! one of the backend's pooled sessions, which assigns it a tag of its own.
+//! A client's Tdiscarded is relayed to the backend; pings and drains are
+//! answered on each side independently, and backends that drain are
+//! replaced as their pools see fit.
+
+#[allow(unstable)]
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use metrics::SessionMetrics;
+use misc::Dtab;
+use pool::{Pool, PoolConfig};
+use proto::{Tmsg, Rmsg};
+use server::{Handler, Request, Responder};
+use session::Discard;
+use stream::BodyReader;
+use transport::Transport;
+
+// rewrites allowed before a destination is considered unroutable, which
+// catches Dtabs that loop.
+const MAX_DEPTH: usize = 16;
+
+static INET_PREFIX: &'static str = "/$/inet/";
+
+#[derive(Clone)]
+pub struct Proxy {
+    base: Arc<Mutex<Dtab>>,
+    config: PoolConfig,
+    // kept by each backend session, as a child.
+    metrics: Option<SessionMetrics>,
+    // clusters, and allowed addresses as they're routed to, by name.
+    pools: Arc<Mutex<HashMap<String, Arc<Pool>>>>,
+    // the HOST:PORT addresses `/$/inet` may name.
+    inet: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Proxy {
+    pub fn new(base: Dtab, config: PoolConfig) -> Proxy {
+        Proxy {
+            base: Arc::new(Mutex::new(base)),
+            config: config,
+            metrics: None,
+            pools: Arc::new(Mutex::new(HashMap::new())),
+            inet: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// The base Dtab, which may be replaced while the proxy runs.  Requests
+    /// already being routed keep the Dtab they started with.
+    pub fn base_dtab(&self) -> Arc<Mutex<Dtab>> {
+        self.base.clone()
+    }
+
+    /// Backend sessions keep children of `metrics`, in pools created
+    /// afterwards: set them before adding clusters.
+    pub fn metrics(mut self, metrics: SessionMetrics) -> Proxy {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Routes destinations beginning with `name`, such as `/#/users`, to a
+    /// pool of `addrs`.
+    pub fn cluster(self, name: &str, addrs: &[String]) -> Proxy {
+        let pool = Arc::new(self.pool(addrs));
+        self.pools.lock().unwrap().insert(name.trim_right_matches('/').to_string(), pool);
+        self
+    }
+
+    /// Allows destinations to name `addr`, given as `HOST:PORT`, directly
+    /// with `/$/inet/HOST/PORT`.  Other addresses are unroutable.
+    pub fn allow_inet(self, addr: &str) -> Proxy {
+        self.inet.lock().unwrap().insert(addr.to_string());
+        self
+    }
+
+    /// The pool to which `dst` is routed, given the request's Dtab.
+    pub fn resolve(&self, dst: &str, local: &Dtab) -> Option<Arc<Pool>> {
+        let dtab = self.base.lock().unwrap().concat(local);
+        let mut path = dst.to_string();
+        for _ in range(0, MAX_DEPTH) {
+            match self.bound(path.as_slice()) {
+                Some(pool) => return Some(pool),
+                None => (),
+            }
+            path = match dtab.lookup(path.as_slice()) {
+                None => return None,
+                Some(p) => p,
+            };
+        }
+        None
+    }
+
+    /// Pings every backend, ejecting those that fail (see `Pool::check`).
+    pub fn check(&self) {
+        let pools: Vec<Arc<Pool>> = self.pools.lock().unwrap().values().map(|p| p.clone()).collect();
+        for pool in pools.iter() {
+            pool.check();
+        }
+    }
+
+    /// The pool a path names directly, if any: that of the longest cluster
+    /// name it begins with, or of an allowed address.
+    fn bound(&self, path: &str) -> Option<Arc<Pool>> {
+        let mut pools = self.pools.lock().unwrap();
+        let longest = pools.iter()
+            .filter(|&(name, _)| has_prefix(path, name.as_slice()))
+            .max_by(|&(name, _)| name.len())
+            .map(|(_, pool)| pool.clone());
+        if longest.is_some() || !path.starts_with(INET_PREFIX) {
+            return longest;
+        }
+
+        let parts: Vec<&str> = path.slice_from(INET_PREFIX.len()).splitn(2, '/').collect();
+        if parts.len() < 2 || parts[0].is_empty() || parts[1].is_empty() {
+            return None;
+        }
+        let name = format!("{}{}/{}", INET_PREFIX, parts[0], parts[1]);
+        let addr = format!("{}:{}", parts[0], parts[1]);
+        if !self.inet.lock().unwrap().contains(&addr) {
+            return None;
+        }
+        let pool = Arc::new(self.pool(&[addr]));
+        pools.insert(name, pool.clone());
+        Some(pool)
+    }
+
+    fn pool(&self, addrs: &[String]) -> Pool {
+        let pool = Pool::new(addrs, self.config);
+        match self.metrics {
+            None => pool,
+            Some(ref metrics) => pool.metrics(metrics.clone()),
+        }
+    }
+
+    fn forward(&self, req: Request, discard: Option<&Discard>) -> Rmsg {
+        let pool = match self.resolve(req.dst.as_slice(), &req.dtab) {
+            None => return Rmsg::DispatchError(Vec::new(), format!("no route: {}", req.dst)),
+            Some(pool) => pool,
+        };
+
+        let msg = Tmsg::Dispatch(req.contexts, req.dst, req.dtab, req.body);
+        let rsp = match discard {
+            None => pool.call(&msg),
+            Some(discard) => pool.call_discardable(&msg, discard),
+        };
+        match rsp {
+            Ok(rsp @ Rmsg::DispatchOk(_, _))
+            | Ok(rsp @ Rmsg::DispatchError(_, _))
+            | Ok(rsp @ Rmsg::DispatchNack(_)) => rsp,
+            Ok(Rmsg::Err(msg)) => Rmsg::DispatchError(Vec::new(), msg),
+            Ok(rsp) => Rmsg::DispatchError(Vec::new(), format!("unexpected response: {:?}", rsp)),
+            // nothing reached the backend, or its session failed: either
+            // way the client may retry elsewhere.
+            Err(_) => Rmsg::DispatchNack(Vec::new()),
+        }
+    }
+}
+
+impl Handler for Proxy {
+    fn dispatch(&self, req: Request) -> Rmsg {
+        self.forward(req, None)
+    }
+
+    fn dispatch_stream<T: Transport>(&self, mut req: Request, mut body: BodyReader, rsp: Responder<T>) {
+        let reply = match body.read_to_end() {
+            Err(ioe) => Rmsg::DispatchError(Vec::new(), ioe.desc.to_string()),
+            Ok(bytes) => {
+                req.body = bytes;
+                self.forward(req, Some(&rsp.discard_handle()))
+            },
+        };
+        rsp.send(reply).ok();
+    }
+}
+
+/// True if `prefix` is a prefix of `path`, component by component.
+fn has_prefix(path: &str, prefix: &str) -> bool {
+    path.starts_with(prefix) && (path.len() == prefix.len() || path.as_bytes()[prefix.len()] == b'/')
+}
+
+#[cfg(test)]
+mod test {
+    use std::old_io::{Acceptor, Listener};
+    use std::old_io::net::tcp::TcpListener;
+    use std::old_io::timer::sleep;
+    use std::sync::mpsc::channel;
+    use std::thread::Thread;
+    use std::time::Duration;
+
+    use metrics::SessionMetrics;
+    use misc::Dtab;
+    use pool::PoolConfig;
+    use proto::{Tag, Tmsg, Rmsg};
+    use reader::MuxReader;
+    use server::Server;
+    use session::{ClientSession, Discard};
+    use session::test::serve_echo;
+    use super::Proxy;
+
+    fn serve(proxy: Proxy) -> String {
+        let mut listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("{}", listener.socket_name().unwrap());
+        let acceptor = listener.listen().unwrap();
+        let server = Server::new(proxy);
+        Thread::spawn(move|| { server.serve_all(acceptor); });
+        addr
+    }
+
+    fn dispatch(dst: &str, dtab: &str, body: &[u8]) -> Tmsg {
+        Tmsg::Dispatch(Vec::new(), dst.to_string(), Dtab::parse(dtab).unwrap(), body.to_vec())
+    }
+
+    #[test]
+    fn test_resolve() {
+        let echo = serve_echo(false);
+        let base = Dtab::parse("/s=>/#/echo;/a=>/b;/b=>/a").unwrap();
+        let proxy = Proxy::new(base, PoolConfig::default()).cluster("/#/echo", &[echo.clone()]);
+
+        assert!(proxy.resolve("/s/users", &Dtab::empty()).is_some());
+        assert!(proxy.resolve("/#/echo", &Dtab::empty()).is_some());
+        assert!(proxy.resolve("/#/echoes", &Dtab::empty()).is_none());
+        assert!(proxy.resolve("/t", &Dtab::empty()).is_none());
+        assert!(proxy.resolve("/t", &Dtab::parse("/t=>/s").unwrap()).is_some());
+        // loops are unroutable.
+        assert!(proxy.resolve("/a", &Dtab::empty()).is_none());
+
+        let colon = echo.rfind(':').unwrap();
+        let inet = format!("/s=>/$/inet/{}/{}", echo.slice_to(colon), echo.slice_from(colon + 1));
+        let local = Dtab::parse(inet.as_slice()).unwrap();
+        assert!(proxy.resolve("/s/users", &local).is_none());
+        let proxy = proxy.allow_inet(echo.as_slice());
+        let pool = proxy.resolve("/s/users", &local).unwrap();
+        assert_eq!(pool.available(), vec![echo]);
+        assert!(proxy.resolve("/$/inet/127.0.0.1/1", &Dtab::empty()).is_none());
+
+        *proxy.base_dtab().lock().unwrap() = Dtab::parse("/t=>/#/echo").unwrap();
+        assert!(proxy.resolve("/t", &Dtab::empty()).is_some());
+        assert!(proxy.resolve("/s/users", &Dtab::empty()).is_none());
+    }
+
+    #[test]
+    fn test_longest_cluster() {
+        let (outer, inner) = (serve_echo(false), serve_echo(false));
+        let proxy = Proxy::new(Dtab::empty(), PoolConfig::default())
+            .cluster("/#/users", &[outer.clone()])
+            .cluster("/#/users/admin", &[inner.clone()])
+            .cluster("/#", &[serve_echo(false)]);
+
+        for _ in range(0, 8) {
+            assert_eq!(proxy.resolve("/#/users/admin/x", &Dtab::empty()).unwrap().available(), vec![inner.clone()]);
+            assert_eq!(proxy.resolve("/#/users/x", &Dtab::empty()).unwrap().available(), vec![outer.clone()]);
+        }
+    }
+
+    #[test]
+    fn test_proxy() {
+        let base = Dtab::parse("/s=>/#/echo").unwrap();
+        let proxy = Proxy::new(base, PoolConfig::default()).cluster("/#/echo", &[serve_echo(false), serve_echo(false)]);
+        let session = ClientSession::connect(serve(proxy).as_slice()).unwrap();
+
+        for _ in range(0, 4) {
+            assert_eq!(session.call(&dispatch("/s/x", "", b"mom")).unwrap(),
+                       Rmsg::DispatchOk(Vec::new(), b"mom".to_vec()));
+        }
+        assert_eq!(session.call(&dispatch("/t/x", "", b"mom")).unwrap(),
+                   Rmsg::DispatchError(Vec::new(), "no route: /t/x".to_string()));
+        assert_eq!(session.call(&dispatch("/t/x", "/t=>/s", b"mom")).unwrap(),
+                   Rmsg::DispatchOk(Vec::new(), b"mom".to_vec()));
+        session.ping().unwrap();
+    }
+
+    #[test]
+    fn test_backend_metrics() {
+        let metrics = SessionMetrics::new();
+        let echo = serve_echo(false);
+        let proxy = Proxy::new(Dtab::empty(), PoolConfig::default())
+            .metrics(metrics.clone())
+            .cluster("/#/echo", &[echo.clone()])
+            .allow_inet(echo.as_slice());
+        let colon = echo.rfind(':').unwrap();
+        let inet = format!("/$/inet/{}/{}", echo.slice_to(colon), echo.slice_from(colon + 1));
+        let session = ClientSession::connect(serve(proxy).as_slice()).unwrap();
+
+        session.call(&dispatch("/#/echo", "", b"mom")).unwrap();
+        session.call(&dispatch(inet.as_slice(), "", b"mom")).unwrap();
+        assert_eq!(metrics.requests.get(), 2);
+    }
+
+    #[test]
+    fn test_relays_discards() {
+        // a backend that never responds, and reports what it's sent.
+        let mut listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend = format!("{}", listener.socket_name().unwrap());
+        let mut acceptor = listener.listen().unwrap();
+        let (tx, rx) = channel();
+        Thread::spawn(move|| {
+            let mut conn = acceptor.accept().unwrap();
+            loop {
+                match conn.read_mux_framed_tmsg() {
+                    Err(_) => break,
+                    Ok(msg) => if tx.send(msg).is_err() { break },
+                }
+            }
+        });
+
+        let proxy = Proxy::new(Dtab::empty(), PoolConfig::default()).cluster("/#/hang", &[backend]);
+        let session = ClientSession::connect(serve(proxy).as_slice()).unwrap();
+        let discard = Discard::new();
+        let d = discard.clone();
+        Thread::spawn(move|| {
+            sleep(Duration::milliseconds(100));
+            d.discard("impatient");
+        });
+        assert!(session.call_discardable(&dispatch("/#/hang", "", b"mom"), &discard).is_err());
+
+        let (tag, req) = rx.recv().unwrap();
+        assert_eq!(req, dispatch("/#/hang", "", b"mom"));
+        assert_eq!(rx.recv().unwrap(), (Tag(0, 0, 0), Tmsg::Discarded(tag, "impatient".to_string())));
+    }
+}
@@ -1,17 +1,29 @@
 #[allow(unstable)]
 
+use std::cmp;
 use std::old_io::{IoResult, IoError, Reader, InvalidInput, BufReader};
 
-use misc::{Context, Dtab, Dentry, Trace, Detailed};
-use proto::{Tmsg, Rmsg, MsgType, Tag};
+use misc::{Context, Dtab, Dentry, Header, Trace, Detailed};
+use proto::{Msg, Tmsg, Rmsg, MsgType, Tag};
 
 struct TraceId(u64, u64, u64);
 
+/// The largest frame a reader will accept.  Frame buffers are allocated
+/// up front, so a bound keeps a peer from claiming an arbitrary size.
+pub static MAX_FRAME: usize = 16 * 1024 * 1024;
+
 pub trait FrameReader: Reader {
     fn read_frame_len(&mut self) -> IoResult<u32> { self.read_be_u32() }
 
     fn read_frame(&mut self) -> IoResult<Vec<u8>> {
         self.read_frame_len().and_then(|sz| {
+            if sz as usize > MAX_FRAME {
+                return Err(IoError {
+                    kind: InvalidInput,
+                    desc: "bad frame size",
+                    detail: Some(format!("{} bytes", sz)),
+                });
+            }
             self.read_exact(sz as usize)
         })
     }
@@ -35,6 +47,35 @@ pub trait MuxReader: FrameReader {
         })
     }
 
+    fn read_mux_framed_msg(&mut self) -> IoResult<Msg> {
+        self.read_frame().and_then(|bytes| {
+            let mut buf = BufReader::new(bytes.as_slice());
+            buf.read_mux_msg()
+        })
+    }
+
+    /// Reads either a T-message or an R-message, as determined by the sign
+    /// of the type.  Both peers of a session may send T-messages (i.e. Tdrain,
+    /// Tping, Tlease), so session readers must accept either.
+    fn read_mux_msg(&mut self) -> IoResult<Msg> {
+        self.read_i8().and_then(move |t| match MsgType::from_i8(t) {
+            None => Err(IoError {
+                kind: InvalidInput,
+                desc: "unknown message type",
+                detail: Some(format!("{}", t)),
+            }),
+            Some(typ) => {
+                self.read_mux_tag().and_then(move |tag| {
+                    if t > 0 {
+                        self.read_mux_tmsg_msg(typ).map(move |msg| Msg::Tx(tag, msg))
+                    } else {
+                        self.read_mux_rmsg_msg(typ).map(move |msg| Msg::Rx(tag, msg))
+                    }
+                })
+            }
+        })
+    }
+
     fn read_mux_tmsg(&mut self) -> IoResult<(Tag, Tmsg)> {
         self.read_i8().and_then(move |t| match MsgType::from_i8(t) {
             None => Err(IoError {
@@ -79,6 +120,8 @@ pub trait MuxReader: FrameReader {
 
             MsgType::Tlease => self.read_mux_tlease(),
 
+            MsgType::Tinit => self.read_mux_init().map(|(v, hs)| Tmsg::Init(v, hs)),
+
             _ => Err(IoError {
                 kind: InvalidInput,
                 desc: "unknown tx type",
@@ -97,6 +140,8 @@ pub trait MuxReader: FrameReader {
 
             MsgType::Rping => Ok(Rmsg::Ping),
 
+            MsgType::Rinit => self.read_mux_init().map(|(v, hs)| Rmsg::Init(v, hs)),
+
             MsgType::Rerr => self.read_to_string().map(|msg| Rmsg::Err(msg)),
 
             _ => Err(IoError {
@@ -112,7 +157,9 @@ pub trait MuxReader: FrameReader {
         len: usize,
         mut f: F
      ) -> IoResult<Vec<T>> {
-        let mut vec = Vec::with_capacity(len);
+        // the length comes off the wire: don't reserve more than a few
+        // elements before they've actually been read.
+        let mut vec = Vec::with_capacity(cmp::min(len, 16));
         for i in range(0, len) {
             match f(self, i) {
                 Err(ioe) => return Err(ioe),
@@ -299,6 +346,29 @@ pub trait MuxReader: FrameReader {
         })
     }
 
+    /// Reads a version followed by headers, which extend to the end of the
+    /// message.
+    fn read_mux_init(&mut self) -> IoResult<(u16, Vec<Header>)> {
+        self.read_be_u16().and_then(move |version| {
+            self.read_to_end().and_then(move |bytes| {
+                let mut buf = BufReader::new(bytes.as_slice());
+                let mut headers = Vec::new();
+                while !buf.eof() {
+                    let header = read_header_field(&mut buf, bytes.len())
+                        .and_then(|key| {
+                            read_header_field(&mut buf, bytes.len())
+                                .map(move |val| Header::new(key, val))
+                        });
+                    match header {
+                        Err(ioe) => return Err(ioe.detail("in init header")),
+                        Ok(h) => headers.push(h),
+                    }
+                }
+                Ok((version, headers))
+            })
+        })
+    }
+
     fn read_mux_tlease(&mut self) -> IoResult<Tmsg> {
         self.read_u8().and_then(|unit| {
             self.read_be_u64().map(|val| Tmsg::Lease(unit, val))
@@ -308,6 +378,21 @@ pub trait MuxReader: FrameReader {
 
 impl<R: Reader> MuxReader for R {}
 
+/// Reads a u32-prefixed header key or value, refusing lengths longer than
+/// the message they're in.
+fn read_header_field(buf: &mut BufReader, max: usize) -> IoResult<Vec<u8>> {
+    buf.read_be_u32().and_then(|n| {
+        if n as usize > max {
+            return Err(IoError {
+                kind: InvalidInput,
+                desc: "header too long",
+                detail: Some(format!("{} bytes", n)),
+            });
+        }
+        buf.read_exact(n as usize)
+    })
+}
+
 #[cfg(test)]
 mod test {
     extern crate test;
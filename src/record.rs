@@ -0,0 +1,528 @@
+//! Recording sessions' frames, and replaying them against a server.
+//!
+//! A recording begins with `MAGIC`, followed by a record for each frame:
+//! the direction in which it was sent (a byte: 0 from the client, 1 from
+//! the server), the connection it was sent on (numbered from 0 as they're
+//! relayed, as a big-endian u32), when it was sent (nanoseconds since the
+//! epoch, as a big-endian u64) and the frame itself, length-prefixed as on
+//! the wire.
+//!
+//! Each connection is replayed on a connection of its own, as tags are
+//! only meaningful within one (see `by_connection`).  Replaying sends a
+//! connection's requests to a server, reusing each tag
+//! only once its last request has been answered, and compares each
+//! response with the one recorded for the same tag.  Responses may arrive
+//! in any order.  Tdiscarded and other marker-tag messages aren't replayed,
+//! since their effect depends on timing.
+
+#[allow(unstable)]
+
+use std::collections::{HashMap, HashSet, RingBuf};
+use std::old_io::{IoResult, IoError, BufReader, EndOfFile, InvalidInput, MemWriter, Reader, Writer};
+use std::old_io::timer::sleep;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUint, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::Thread;
+use std::time::Duration;
+use time;
+use time::Timespec;
+
+use framebuf::FrameBuf;
+use proto::{Msg, Tag, Tmsg, Rmsg};
+use reader::{FrameReader, MuxReader};
+use stream::{Frame, Reassembler};
+use transport::Transport;
+use writer::{FrameWriter, frame_rmsg, write_locked};
+
+pub static MAGIC: &'static [u8] = b"muxrec\x00\x02";
+
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum Direction {
+    FromClient,
+    FromServer,
+}
+
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct Record {
+    pub direction: Direction,
+    pub connection: u32,
+    pub time: Timespec,
+    /// The frame's type, tag and payload.
+    pub frame: Vec<u8>,
+}
+
+impl Record {
+    /// Records a frame sent now.
+    pub fn new(direction: Direction, connection: u32, frame: Vec<u8>) -> Record {
+        Record { direction: direction, connection: connection, time: time::get_time(), frame: frame }
+    }
+
+    /// The frame's tag, including its fragment flag.
+    pub fn tag(&self) -> Tag {
+        Tag(self.frame[1], self.frame[2], self.frame[3])
+    }
+
+    /// Decodes the frame, which fails for fragments but the last.
+    pub fn msg(&self) -> IoResult<Msg> {
+        BufReader::new(self.frame.as_slice()).read_mux_msg()
+    }
+}
+
+pub struct RecordWriter<W> {
+    writer: W,
+    connections: u32,
+}
+
+impl<W: Writer> RecordWriter<W> {
+    pub fn new(mut writer: W) -> IoResult<RecordWriter<W>> {
+        writer.write(MAGIC).map(|_| RecordWriter { writer: writer, connections: 0 })
+    }
+
+    /// Numbers a new connection to be recorded.
+    pub fn next_connection(&mut self) -> u32 {
+        self.connections += 1;
+        self.connections - 1
+    }
+
+    pub fn write(&mut self, record: &Record) -> IoResult<()> {
+        let direction = match record.direction { Direction::FromClient => 0, Direction::FromServer => 1 };
+        let ns = record.time.sec as u64 * 1000000000 + record.time.nsec as u64;
+        let w = &mut self.writer;
+        w.write_u8(direction)
+            .and_then(|_| w.write_be_u32(record.connection))
+            .and_then(|_| w.write_be_u64(ns))
+            .and_then(|_| w.write_be_u32_frame(record.frame.as_slice()))
+            .and_then(|_| w.flush())
+    }
+}
+
+pub struct RecordReader<R> {
+    reader: R,
+}
+
+impl<R: Reader> RecordReader<R> {
+    pub fn new(mut reader: R) -> IoResult<RecordReader<R>> {
+        match reader.read_exact(MAGIC.len()) {
+            Err(ioe) => Err(ioe),
+            Ok(ref magic) if magic.as_slice() != MAGIC => Err(bad_record("not a recording")),
+            Ok(_) => Ok(RecordReader { reader: reader }),
+        }
+    }
+
+    /// Reads the next record, or returns None at the end of the recording.
+    pub fn next_record(&mut self) -> IoResult<Option<Record>> {
+        let direction = match self.reader.read_u8() {
+            Err(ref ioe) if ioe.kind == EndOfFile => return Ok(None),
+            Err(ioe) => return Err(ioe),
+            Ok(0) => Direction::FromClient,
+            Ok(1) => Direction::FromServer,
+            Ok(_) => return Err(bad_record("bad direction")),
+        };
+        let r = &mut self.reader;
+        let connection = match r.read_be_u32() {
+            Err(ioe) => return Err(ioe),
+            Ok(connection) => connection,
+        };
+        r.read_be_u64().and_then(|ns| r.read_frame().and_then(|frame| {
+            if frame.len() < 4 {
+                return Err(bad_record("bad frame size"));
+            }
+            Ok(Some(Record {
+                direction: direction,
+                connection: connection,
+                time: Timespec::new((ns / 1000000000) as i64, (ns % 1000000000) as i32),
+                frame: frame,
+            }))
+        }))
+    }
+}
+
+/// Reads a whole recording.
+pub fn read_all<R: Reader>(reader: R) -> IoResult<Vec<Record>> {
+    RecordReader::new(reader).and_then(|mut r| {
+        let mut records = Vec::new();
+        loop {
+            match r.next_record() {
+                Err(ioe) => return Err(ioe),
+                Ok(None) => return Ok(records),
+                Ok(Some(record)) => records.push(record),
+            }
+        }
+    })
+}
+
+/// Splits a recording by connection, in the order in which connections
+/// were first recorded.
+pub fn by_connection(records: &[Record]) -> Vec<(u32, Vec<Record>)> {
+    let mut connections: Vec<(u32, Vec<Record>)> = Vec::new();
+    let mut index = HashMap::new();
+    for r in records.iter() {
+        if !index.contains_key(&r.connection) {
+            index.insert(r.connection, connections.len());
+            connections.push((r.connection, Vec::new()));
+        }
+        connections[*index.get(&r.connection).unwrap()].1.push(r.clone());
+    }
+    connections
+}
+
+fn bad_record(desc: &'static str) -> IoError {
+    IoError { kind: InvalidInput, desc: desc, detail: None }
+}
+
+/// Relays frames between a client and a server on background threads,
+/// recording each, under a connection number of its own, before it's
+/// passed on.  Both connections are closed once either fails.
+pub fn relay<C: Transport, S: Transport, W: Writer + Send + 'static>(
+    client: C,
+    server: S,
+    recording: Arc<Mutex<RecordWriter<W>>>
+) {
+    let connection = recording.lock().unwrap().next_connection();
+    let (c, s, r) = (client.clone(), server.clone(), recording.clone());
+    Thread::spawn(move|| pump(c, s, Direction::FromClient, connection, r));
+    Thread::spawn(move|| pump(server, client, Direction::FromServer, connection, recording));
+}
+
+fn pump<A: Transport, B: Transport, W: Writer>(
+    mut from: A,
+    mut to: B,
+    direction: Direction,
+    connection: u32,
+    recording: Arc<Mutex<RecordWriter<W>>>
+) {
+    loop {
+        let frame = match from.read_frame() {
+            Err(_) => break,
+            Ok(frame) => frame,
+        };
+        // a recording that can't be written shouldn't break the session.
+        recording.lock().unwrap().write(&Record::new(direction, connection, frame.clone())).ok();
+        if to.write_be_u32_frame(frame.as_slice()).and_then(|_| to.flush()).is_err() {
+            break;
+        }
+    }
+    from.close();
+    to.close();
+}
+
+/// A response that differed from the recording.  `expected` is None if
+/// none was recorded, and `actual` is None if none arrived.
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct Mismatch {
+    pub tag: Tag,
+    pub expected: Option<Rmsg>,
+    pub actual: Option<Rmsg>,
+}
+
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct Replay {
+    /// The number of requests sent.
+    pub requests: usize,
+    /// The number of responses that matched the recording.
+    pub matched: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+/// Replays one connection's requests on `conn`, comparing responses with
+/// those recorded.  Gives up on outstanding requests once `timeout` passes
+/// without a request sent or a response received.
+pub fn replay<T: Transport>(records: &[Record], conn: T, timeout: Duration) -> Replay {
+    let writer = Arc::new(Mutex::new(conn.clone()));
+    let (tx, rx) = channel();
+    let progress = Arc::new(AtomicUint::new(0));
+    let done = Arc::new(AtomicBool::new(false));
+    {
+        let (tx, writer) = (tx.clone(), writer.clone());
+        Thread::spawn(move|| read_responses(conn, &*writer, tx));
+    }
+    {
+        let (progress, done) = (progress.clone(), done.clone());
+        Thread::spawn(move|| loop {
+            let before = progress.load(Ordering::SeqCst);
+            sleep(timeout);
+            if done.load(Ordering::SeqCst) {
+                break;
+            }
+            if progress.load(Ordering::SeqCst) == before {
+                tx.send(None).ok();
+                break;
+            }
+        });
+    }
+
+    let mut state = ReplayState {
+        expected: recorded_responses(records),
+        outstanding: HashSet::new(),
+        report: Replay { requests: 0, matched: 0, mismatches: Vec::new() },
+    };
+    // tags whose requests are partly sent.
+    let mut sending = HashSet::new();
+    let mut closed = false;
+    for record in records.iter().filter(|r| is_request(r)) {
+        let tag = record.tag();
+        let key = tag.with_fragment(false).to_u32();
+        if !sending.contains(&key) {
+            while !closed && state.outstanding.contains(&key) {
+                closed = !state.receive(rx.recv().ok().and_then(|rsp| rsp));
+                progress.fetch_add(1, Ordering::SeqCst);
+            }
+            if closed {
+                break;
+            }
+            state.outstanding.insert(key);
+            state.report.requests += 1;
+        }
+        if tag.is_fragment() {
+            sending.insert(key);
+        } else {
+            sending.remove(&key);
+        }
+        if write_locked(&*writer, |w| w.write_be_u32_frame(record.frame.as_slice())).is_err() {
+            break;
+        }
+        progress.fetch_add(1, Ordering::SeqCst);
+    }
+    while !closed && !state.outstanding.is_empty() {
+        closed = !state.receive(rx.recv().ok().and_then(|rsp| rsp));
+        progress.fetch_add(1, Ordering::SeqCst);
+    }
+    done.store(true, Ordering::SeqCst);
+    writer.lock().unwrap().close();
+
+    let mut unanswered: Vec<u32> = state.outstanding.iter().map(|k| *k).collect();
+    unanswered.sort();
+    for key in unanswered.into_iter() {
+        let want = state.expected.get_mut(&key).and_then(|q| q.pop_front());
+        state.report.mismatches.push(Mismatch { tag: Tag::from_u32(key), expected: want, actual: None });
+    }
+    state.report
+}
+
+struct ReplayState {
+    // the recorded responses to each tag, in order.
+    expected: HashMap<u32, RingBuf<Rmsg>>,
+    outstanding: HashSet<u32>,
+    report: Replay,
+}
+
+impl ReplayState {
+    /// Compares a response with the recording.  Returns false if there
+    /// will be no more.
+    fn receive(&mut self, rsp: Option<(Tag, Rmsg)>) -> bool {
+        let (tag, rsp) = match rsp {
+            None => return false,
+            Some(rsp) => rsp,
+        };
+        let key = tag.to_u32();
+        self.outstanding.remove(&key);
+        let want = self.expected.get_mut(&key).and_then(|q| q.pop_front());
+        if want.as_ref() == Some(&rsp) {
+            self.report.matched += 1;
+        } else {
+            self.report.mismatches.push(Mismatch { tag: tag, expected: want, actual: Some(rsp) });
+        }
+        true
+    }
+}
+
+/// True for frames of the client's requests, excluding responses to the
+/// server's control messages and marker-tag messages.
+fn is_request(record: &Record) -> bool {
+    record.direction == Direction::FromClient
+        && (record.frame[0] as i8) > 0
+        && record.tag().with_fragment(false).to_u32() != 0
+}
+
+/// The server's responses, reassembled and queued by tag.
+fn recorded_responses(records: &[Record]) -> HashMap<u32, RingBuf<Rmsg>> {
+    let mut w = MemWriter::new();
+    for r in records.iter().filter(|r| r.direction == Direction::FromServer) {
+        w.write_be_u32_frame(r.frame.as_slice()).unwrap();
+    }
+    let bytes = w.into_inner();
+
+    let mut frames = FrameBuf::new(BufReader::new(bytes.as_slice()));
+    let mut fragments = Reassembler::new();
+    let mut responses = HashMap::new();
+    loop {
+        match fragments.read(&mut frames, |_| false) {
+            Ok(Frame::Whole(Msg::Rx(tag, rsp))) => {
+                if !responses.contains_key(&tag.to_u32()) {
+                    responses.insert(tag.to_u32(), RingBuf::new());
+                }
+                responses.get_mut(&tag.to_u32()).unwrap().push_back(rsp);
+            },
+            Ok(_) => (),
+            Err(_) => return responses,
+        }
+    }
+}
+
+/// Reads responses until the connection fails, answering the server's
+/// pings and drains.
+fn read_responses<T: Transport>(conn: T, writer: &Mutex<T>, tx: Sender<Option<(Tag, Rmsg)>>) {
+    let mut frames = FrameBuf::new(conn);
+    let mut fragments = Reassembler::new();
+    loop {
+        let reply = match fragments.read(&mut frames, |_| false) {
+            Err(_) => break,
+            Ok(Frame::Whole(Msg::Rx(tag, rsp))) => {
+                if tx.send(Some((tag, rsp))).is_err() {
+                    break;
+                }
+                None
+            },
+            Ok(Frame::Whole(Msg::Tx(tag, Tmsg::Ping))) => Some((tag, Rmsg::Ping)),
+            Ok(Frame::Whole(Msg::Tx(tag, Tmsg::Drain))) => Some((tag, Rmsg::Drain)),
+            Ok(_) => None,
+        };
+        match reply {
+            None => (),
+            Some((tag, rsp)) => if write_locked(writer, |buf| frame_rmsg(buf, &tag, &rsp)).is_err() { break },
+        }
+    }
+    tx.send(None).ok();
+}
+
+#[cfg(test)]
+mod test {
+    use std::old_io::{Acceptor, BufReader, IoResult, Listener, MemWriter, Writer};
+    use std::old_io::net::tcp::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread::Thread;
+    use std::time::Duration;
+
+    use misc::Dtab;
+    use mock;
+    use mock::Mock;
+    use proto::{Tag, Tmsg, Rmsg};
+    use server::Server;
+    use session::ClientSession;
+    use session::test::serve_echo;
+    use writer::MuxWriter;
+    use super::{Direction, Mismatch, Record, RecordWriter, by_connection, read_all, relay, replay};
+
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Writer for Shared {
+        fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+            self.0.lock().unwrap().push_all(buf);
+            Ok(())
+        }
+    }
+
+    fn dispatch(body: &[u8]) -> Tmsg {
+        Tmsg::Dispatch(Vec::new(), "/".to_string(), Dtab::empty(), body.to_vec())
+    }
+
+    #[test]
+    fn test_read_write() {
+        let records = vec![Record::new(Direction::FromClient, 0, vec![65, 0, 0, 1]),
+                           Record::new(Direction::FromServer, 7, vec![-65i8 as u8, 0, 0, 1])];
+        let mut w = RecordWriter::new(MemWriter::new()).unwrap();
+        for r in records.iter() {
+            w.write(r).unwrap();
+        }
+        let bytes = w.writer.into_inner();
+        assert_eq!(read_all(BufReader::new(bytes.as_slice())).unwrap(), records);
+        assert!(read_all(BufReader::new(bytes.slice_to(bytes.len() - 1))).is_err());
+        assert!(read_all(BufReader::new(bytes.slice_from(1))).is_err());
+    }
+
+    /// Relays connections to `upstream`, recording them to `recorded`.
+    fn serve_recorded(upstream: String, recorded: Arc<Mutex<Vec<u8>>>) -> String {
+        let mut listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("{}", listener.socket_name().unwrap());
+        let mut acceptor = listener.listen().unwrap();
+        let recording = Arc::new(Mutex::new(RecordWriter::new(Shared(recorded)).unwrap()));
+        Thread::spawn(move|| {
+            for client in acceptor.incoming() {
+                let client = match client { Err(_) => break, Ok(c) => c };
+                relay(client, TcpStream::connect(upstream.as_slice()).unwrap(), recording.clone());
+            }
+        });
+        addr
+    }
+
+    fn serve_mock(script: &str) -> String {
+        let mut listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("{}", listener.socket_name().unwrap());
+        let acceptor = listener.listen().unwrap();
+        let server = Server::new(Mock::new(mock::parse(script).unwrap()));
+        Thread::spawn(move|| { server.serve_all(acceptor); });
+        addr
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+        let session = ClientSession::connect(serve_recorded(serve_echo(false), recorded.clone()).as_slice()).unwrap();
+        session.call(&dispatch(b"one")).unwrap();
+        session.ping().unwrap();
+        session.call(&dispatch(b"two")).unwrap();
+        session.close();
+
+        let bytes = recorded.lock().unwrap().clone();
+        let records = read_all(BufReader::new(bytes.as_slice())).unwrap();
+        assert_eq!(records.len(), 6);
+        assert!(records.iter().all(|r| r.connection == 0));
+        assert_eq!(records[1].direction, Direction::FromServer);
+        assert_eq!(records[1].msg().unwrap(), ::proto::Msg::Rx(records[0].tag(), Rmsg::DispatchOk(Vec::new(), b"one".to_vec())));
+
+        let timeout = Duration::seconds(5);
+        let same = replay(records.as_slice(), TcpStream::connect(serve_echo(false).as_slice()).unwrap(), timeout);
+        assert_eq!((same.requests, same.matched), (3, 3));
+        assert_eq!(same.mismatches, Vec::new());
+
+        let other = serve_mock("body=two ok three");
+        let differs = replay(records.as_slice(), TcpStream::connect(other.as_slice()).unwrap(), timeout);
+        assert_eq!((differs.requests, differs.matched), (3, 2));
+        assert_eq!(differs.mismatches, vec![Mismatch {
+            tag: records[4].tag(),
+            expected: Some(Rmsg::DispatchOk(Vec::new(), b"two".to_vec())),
+            actual: Some(Rmsg::DispatchOk(Vec::new(), b"three".to_vec())),
+        }]);
+    }
+
+    #[test]
+    fn test_connections() {
+        // two connections, each using the same tag for a different request.
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+        let addr = serve_recorded(serve_echo(false), recorded.clone());
+        let (one, two) = (ClientSession::connect(addr.as_slice()).unwrap(), ClientSession::connect(addr.as_slice()).unwrap());
+        one.call(&dispatch(b"one")).unwrap();
+        two.call(&dispatch(b"two")).unwrap();
+        one.call(&dispatch(b"three")).unwrap();
+        one.close();
+        two.close();
+
+        let bytes = recorded.lock().unwrap().clone();
+        let connections = by_connection(read_all(BufReader::new(bytes.as_slice())).unwrap().as_slice());
+        assert_eq!(connections.iter().map(|&(c, ref records)| (c, records.len())).collect::<Vec<_>>(), vec![(0, 4), (1, 2)]);
+        for &(_, ref records) in connections.iter() {
+            let report = replay(records.as_slice(), TcpStream::connect(serve_echo(false).as_slice()).unwrap(), Duration::seconds(5));
+            assert_eq!(report.mismatches, Vec::new());
+        }
+    }
+
+    #[test]
+    fn test_replay_timeout() {
+        // the recorded response never comes.
+        let mut listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("{}", listener.socket_name().unwrap());
+        let mut acceptor = listener.listen().unwrap();
+        Thread::spawn(move|| {
+            let _conn = acceptor.accept().unwrap();
+            ::std::old_io::timer::sleep(Duration::seconds(5));
+        });
+
+        let mut req = MemWriter::new();
+        req.write_mux_tmsg(&Tag(0, 0, 1), &dispatch(b"x")).unwrap();
+        let records = vec![Record::new(Direction::FromClient, 0, req.into_inner())];
+        let report = replay(records.as_slice(), TcpStream::connect(addr.as_slice()).unwrap(), Duration::milliseconds(100));
+        assert_eq!(report.requests, 1);
+        assert_eq!(report.mismatches, vec![Mismatch { tag: Tag(0, 0, 1), expected: None, actual: None }]);
+    }
+}
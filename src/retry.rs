@@ -0,0 +1,260 @@
+//! Retries failed requests, within a budget.
+//!
+//! Retries are only safe for idempotent requests, so a `Retry` filter
+//! should only wrap clients that issue them.  The budget bounds the
+//! additional load retries may add to a struggling backend: each request
+//! deposits a fraction of a retry, and a small reserve is replenished over
+//! time so that low-volume clients may still retry.
+
+#[allow(unstable)]
+
+use std::num::Float;
+use std::old_io::IoResult;
+use std::old_io::timer::sleep;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use time::precise_time_ns;
+
+use backoff::Backoff;
+use misc::Context;
+use proto::{Tmsg, Rmsg};
+use session::Client;
+
+pub use misc::{RETRIES_KEY, retries};
+
+pub fn retries_context(n: u32) -> Context {
+    Context::new(RETRIES_KEY.to_vec(), vec![(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8])
+}
+
+/// Sets the retry count on a Tdispatch.  Treq carries no contexts, so it's
+/// returned unchanged.
+pub fn with_retries(msg: &Tmsg, n: u32) -> Tmsg {
+    match *msg {
+        Tmsg::Dispatch(ref contexts, ref dst, ref dtab, ref body) => {
+            let mut ctxs: Vec<Context> = contexts.iter()
+                .filter(|c| c.key.as_slice() != RETRIES_KEY)
+                .map(|c| c.clone())
+                .collect();
+            ctxs.push(retries_context(n));
+            Tmsg::Dispatch(ctxs, dst.clone(), dtab.clone(), body.clone())
+        },
+        _ => msg.clone(),
+    }
+}
+
+/// Retries nacks, which the server guarantees were not processed.
+pub fn nacks(rsp: &IoResult<Rmsg>) -> bool {
+    match *rsp {
+        Ok(Rmsg::ReqNack) | Ok(Rmsg::DispatchNack(_)) => true,
+        _ => false,
+    }
+}
+
+/// Retries nacks, Rerr responses and connection failures.  Suitable only
+/// for idempotent requests.
+pub fn failures(rsp: &IoResult<Rmsg>) -> bool {
+    match *rsp {
+        Err(_) | Ok(Rmsg::Err(_)) => true,
+        _ => nacks(rsp),
+    }
+}
+
+static MAX_BALANCE: f64 = 100.0;
+
+/// A token bucket permitting retries as a percentage of requests, plus a
+/// minimum number of retries per second.
+pub struct RetryBudget {
+    percent: f64,
+    min_per_sec: f64,
+    state: Mutex<BudgetState>,
+}
+
+struct BudgetState {
+    balance: f64,
+    reserve: f64,
+    stamp_ns: u64,
+}
+
+impl RetryBudget {
+    /// `percent` is the fraction of requests (e.g. 0.2) that may be retried.
+    pub fn new(percent: f64, min_per_sec: u32) -> RetryBudget {
+        RetryBudget {
+            percent: percent,
+            min_per_sec: min_per_sec as f64,
+            state: Mutex::new(BudgetState {
+                balance: 0.0,
+                reserve: min_per_sec as f64,
+                stamp_ns: precise_time_ns(),
+            }),
+        }
+    }
+
+    pub fn default() -> RetryBudget { RetryBudget::new(0.2, 10) }
+
+    /// Records a request.
+    pub fn deposit(&self) {
+        let mut state = self.state.lock().unwrap();
+        // a long healthy period shouldn't fund a retry storm.
+        state.balance = (state.balance + self.percent).min(MAX_BALANCE);
+    }
+
+    /// Withdraws a retry, if one is available.
+    pub fn try_withdraw(&self) -> bool {
+        self.try_withdraw_at(precise_time_ns())
+    }
+
+    fn try_withdraw_at(&self, now: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let elapsed = if now > state.stamp_ns { (now - state.stamp_ns) as f64 } else { 0.0 };
+        state.reserve = (state.reserve + elapsed * self.min_per_sec / 1e9).min(self.min_per_sec);
+        state.stamp_ns = now;
+
+        if state.balance >= 1.0 {
+            state.balance -= 1.0;
+            true
+        } else if state.reserve >= 1.0 {
+            state.reserve -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+pub struct Retry<C> {
+    client: C,
+    budget: RetryBudget,
+    backoff: Backoff,
+    max_retries: u32,
+    should_retry: fn(&IoResult<Rmsg>) -> bool,
+}
+
+impl<C: Client> Retry<C> {
+    pub fn new(client: C, should_retry: fn(&IoResult<Rmsg>) -> bool) -> Retry<C> {
+        Retry {
+            client: client,
+            budget: RetryBudget::default(),
+            backoff: Backoff::decorrelated(Duration::milliseconds(5), Duration::seconds(1)),
+            max_retries: 3,
+            should_retry: should_retry,
+        }
+    }
+
+    pub fn budget(mut self, budget: RetryBudget) -> Retry<C> {
+        self.budget = budget;
+        self
+    }
+
+    pub fn backoff(mut self, backoff: Backoff) -> Retry<C> {
+        self.backoff = backoff;
+        self
+    }
+
+    pub fn max_retries(mut self, n: u32) -> Retry<C> {
+        self.max_retries = n;
+        self
+    }
+}
+
+impl<C: Client> Client for Retry<C> {
+    fn call(&self, msg: &Tmsg) -> IoResult<Rmsg> {
+        self.budget.deposit();
+
+        let mut backoff = self.backoff.clone();
+        backoff.reset();
+
+        let mut rsp = self.client.call(msg);
+        let mut retries = 0;
+        while retries < self.max_retries && (self.should_retry)(&rsp) && self.budget.try_withdraw() {
+            retries += 1;
+            sleep(backoff.next().unwrap());
+            rsp = self.client.call(&with_retries(msg, retries));
+        }
+        rsp
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::old_io::{IoResult, IoError, ConnectionReset};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use backoff::Backoff;
+    use misc::{Context, Dtab};
+    use proto::{Tmsg, Rmsg};
+    use session::Client;
+    use super::{Retry, RetryBudget, retries, failures, nacks};
+
+    /// Fails until the given number of calls have been made, recording each
+    /// request's retry count.
+    struct Flaky {
+        failures: u32,
+        seen: Mutex<Vec<Option<u32>>>,
+    }
+
+    impl Client for Flaky {
+        fn call(&self, msg: &Tmsg) -> IoResult<Rmsg> {
+            let mut seen = self.seen.lock().unwrap();
+            let n = match *msg {
+                Tmsg::Dispatch(ref ctxs, _, _, _) => retries(ctxs.as_slice()),
+                _ => None,
+            };
+            seen.push(n);
+            if (seen.len() as u32) <= self.failures {
+                Err(IoError { kind: ConnectionReset, desc: "flaky", detail: None })
+            } else {
+                Ok(Rmsg::DispatchOk(Vec::new(), Vec::new()))
+            }
+        }
+    }
+
+    fn flaky(failures: u32) -> Flaky {
+        Flaky { failures: failures, seen: Mutex::new(Vec::new()) }
+    }
+
+    fn dispatch() -> Tmsg {
+        Tmsg::Dispatch(vec![Context::new(b"k".to_vec(), b"v".to_vec())],
+                       "/".to_string(), Dtab::empty(), Vec::new())
+    }
+
+    fn fast() -> Backoff {
+        Backoff::exponential(Duration::milliseconds(1), Duration::milliseconds(1))
+    }
+
+    #[test]
+    fn test_retries_with_context() {
+        let retry = Retry::new(flaky(2), failures).backoff(fast());
+        assert!(retry.call(&dispatch()).is_ok());
+        assert_eq!(*retry.client.seen.lock().unwrap(), vec![None, Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn test_predicate() {
+        let retry = Retry::new(flaky(1), nacks).backoff(fast());
+        assert!(retry.call(&dispatch()).is_err());
+        assert_eq!(retry.client.seen.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_max_retries() {
+        let retry = Retry::new(flaky(10), failures).backoff(fast()).max_retries(2);
+        assert!(retry.call(&dispatch()).is_err());
+        assert_eq!(retry.client.seen.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_budget() {
+        let budget = RetryBudget::new(0.5, 1);
+        // the reserve permits one retry; two requests earn another.
+        assert!(budget.try_withdraw_at(0));
+        assert!(!budget.try_withdraw_at(0));
+        budget.deposit();
+        budget.deposit();
+        assert!(budget.try_withdraw_at(0));
+        assert!(!budget.try_withdraw_at(0));
+        // the reserve refills over time.
+        assert!(budget.try_withdraw_at(1000000000));
+    }
+}
@@ -0,0 +1,796 @@
+//! A server session dispatches a connection's requests to a handler.
+//!
+//! Each request is handled on its own thread, so a slow request doesn't
+//! delay others on the connection.  Requests arriving as legacy Treqs are
+//! presented to the handler as if they were Tdispatches.  Handlers may
+//! consume fragmented request bodies and stream response bodies; see
+//! `stream`.
+//!
+//! A server keeps the `Sessions` it's serving, through which operators may
+//! inspect and drain them (see `admin`).
+
+#[allow(unstable)]
+
+use std::collections::HashMap;
+use std::mem;
+use std::num::Int;
+use std::old_io::{IoResult, IoError, Acceptor, Closed, Listener, Reader};
+use std::old_io::net::ip::ToSocketAddr;
+use std::old_io::net::tcp::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread::Thread;
+use std::time::Duration;
+use log::LogLevel;
+use time::precise_time_ns;
+
+use legacy;
+use metrics::{Metered, SessionMetrics};
+use misc::{Context, Dtab, Trace};
+use session::Discard;
+use proto::{Msg, Tag, Tmsg, Rmsg, MARKER_TAG, show_headers};
+use framebuf::FrameBuf;
+use span;
+use span::Span;
+use stream;
+use stream::{BodyReader, BodyWriter, Frame, Reassembler};
+use thrift::TINIT_CHECK;
+use transport::{PeerCredentials, PeerIdentity, Transport};
+use unix::{UnixAddr, UnixSocketListener};
+use writer::{frame_rmsg, frame_tmsg, write_locked};
+
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct Request {
+    pub contexts: Vec<Context>,
+    pub dst: String,
+    pub dtab: Dtab,
+    pub trace: Option<Trace>,
+    pub body: Vec<u8>,
+    /// The identity the client authenticated with, if any.
+    pub peer_identity: Option<PeerIdentity>,
+    /// The credentials of the client process, for local connections.
+    pub peer_credentials: Option<PeerCredentials>,
+}
+
+impl Request {
+    /// Builds a request from a Tdispatch or Treq.
+    pub fn from_tmsg(msg: Tmsg) -> Option<Request> {
+        match legacy::upgrade_tmsg(msg) {
+            Tmsg::Dispatch(contexts, dst, dtab, body) => {
+                let trace = Trace::from_contexts(contexts.as_slice());
+                Some(Request {
+                    contexts: contexts,
+                    dst: dst,
+                    dtab: dtab,
+                    trace: trace,
+                    body: body,
+                    peer_identity: None,
+                    peer_credentials: None,
+                })
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Handlers respond with an Rdispatch (DispatchOk, DispatchError or
+/// DispatchNack), which is converted to an Rreq for legacy requests.
+pub trait Handler: Send + Sync {
+    fn dispatch(&self, req: Request) -> Rmsg;
+
+    /// Handles a request whose body is read from `body` as it arrives;
+    /// `req.body` is empty.  The response is sent, or streamed, through
+    /// `rsp`.  By default the body is read in full and the request passed
+    /// to `dispatch`.
+    fn dispatch_stream<T: Transport>(&self, mut req: Request, mut body: BodyReader, rsp: Responder<T>) {
+        let reply = match body.read_to_end() {
+            Err(ioe) => Rmsg::DispatchError(Vec::new(), ioe.desc.to_string()),
+            Ok(bytes) => {
+                req.body = bytes;
+                self.dispatch(req)
+            },
+        };
+        rsp.send(reply).ok();
+    }
+}
+
+impl<F: Fn(Request) -> Rmsg + Send + Sync> Handler for F {
+    fn dispatch(&self, req: Request) -> Rmsg { (*self)(req) }
+}
+
+struct State {
+    // requests being handled, by tag: the handle through which each may be
+    // discarded, and when it was received.
+    pending: HashMap<u32, (Discard, u64)>,
+    // when the lease last granted to the client expires.
+    lease: Option<u64>,
+    draining: bool,
+    drain_acked: bool,
+    closed: bool,
+    metrics: SessionMetrics,
+}
+
+impl State {
+    fn is_drained(&self) -> bool {
+        self.draining && self.drain_acked && self.pending.is_empty()
+    }
+
+    fn remove_pending(&mut self, tag: u32) {
+        self.pending.remove(&tag);
+        self.metrics.pending.set(self.pending.len() as isize);
+    }
+}
+
+/// Responds to one request.  Discarded requests are answered with an
+/// error in place of their response, since the client holds their tags
+/// until they're answered.
+pub struct Responder<T> {
+    tag: Tag,
+    legacy: bool,
+    discard: Discard,
+    // when the request was received.
+    started: u64,
+    // the request's span, if requests are logged.
+    span: Option<Span>,
+    state: Arc<Mutex<State>>,
+    writer: Arc<Mutex<T>>,
+}
+
+impl<T: Transport> Responder<T> {
+    pub fn is_discarded(&self) -> bool {
+        self.discard.is_discarded()
+    }
+
+    /// The handle through which the client's Tdiscarded arrives.  Calls
+    /// made with it (see `ClientSession::call_discardable`) are discarded
+    /// in turn.
+    pub fn discard_handle(&self) -> Discard {
+        self.discard.clone()
+    }
+
+    /// Sends an Rdispatch, converted to an Rreq for legacy requests.
+    pub fn send(self, rsp: Rmsg) -> IoResult<()> {
+        let rsp = if self.is_discarded() { discarded_rsp() } else { rsp };
+        let rsp = if self.legacy { legacy::downgrade_rmsg(rsp) } else { rsp };
+        self.complete(&rsp);
+        let sent = write_locked(&*self.writer, |buf| frame_rmsg(buf, &self.tag, &rsp));
+        close_if_drained(&*self.state, &*self.writer);
+        sent
+    }
+
+    /// Sends a successful response whose body is written by `f` and
+    /// streamed as it's produced.  Writes fail once the request is
+    /// discarded.  If `f` fails after part of the body is sent, the
+    /// connection is closed, since the response can't be retracted.
+    pub fn stream<F>(self, contexts: Vec<Context>, f: F) -> IoResult<()>
+        where F: FnOnce(&mut BodyWriter<T>) -> IoResult<()>
+    {
+        let header = if self.legacy { Rmsg::ReqOk(Vec::new()) } else { Rmsg::DispatchOk(contexts, Vec::new()) };
+        let mut body = BodyWriter::rmsg(self.writer.clone(), self.tag, &header)
+            .discardable(self.discard.flag());
+        let sent = f(&mut body).and_then(|_| body.finish());
+        self.complete(&header);
+
+        match sent {
+            Err(_) if self.is_discarded() && body.is_started() => { body.abort().ok(); },
+            Err(_) if self.is_discarded() => {
+                let rsp = if self.legacy { legacy::downgrade_rmsg(discarded_rsp()) } else { discarded_rsp() };
+                write_locked(&*self.writer, |buf| frame_rmsg(buf, &self.tag, &rsp)).ok();
+            },
+            Err(_) if body.is_started() => self.writer.lock().unwrap().close(),
+            _ => (),
+        }
+        close_if_drained(&*self.state, &*self.writer);
+        sent
+    }
+
+    /// Stops tracking the request, recording and logging its outcome and
+    /// latency.
+    fn complete(&self, rsp: &Rmsg) {
+        let latency = (precise_time_ns() - self.started) / 1000;
+        let mut state = self.state.lock().unwrap();
+        state.remove_pending(self.tag.to_u32());
+        match *rsp {
+            Rmsg::DispatchNack(_) | Rmsg::ReqNack => state.metrics.nacks.incr(),
+            _ => (),
+        }
+        state.metrics.latency.record(latency);
+        match self.span {
+            None => (),
+            Some(ref req_span) => {
+                let outcome = if self.is_discarded() { "discarded" } else { span::outcome(rsp) };
+                debug!("{}", req_span.with("outcome", outcome).with("latency_us", latency));
+            },
+        }
+    }
+}
+
+pub struct ServerSession<T> {
+    state: Arc<Mutex<State>>,
+    writer: Arc<Mutex<Metered<T>>>,
+    metrics: SessionMetrics,
+}
+
+static DRAIN_TAG: Tag = Tag(0, 0, 1);
+
+// the only unit in which leases are measured.
+static LEASE_MILLISECONDS: u8 = 0;
+
+impl<T: Transport> ServerSession<T> {
+    /// Sends the client a Tdrain.  Subsequent requests are nacked, and the
+    /// session closes once the client acknowledges the drain and
+    /// outstanding requests complete.
+    pub fn drain(&self) -> IoResult<()> {
+        self.state.lock().unwrap().draining = true;
+        self.metrics.drains.incr();
+        write_locked(&*self.writer, |buf| frame_tmsg(buf, &DRAIN_TAG, &Tmsg::Drain))
+    }
+
+    /// Sends the client a Tlease, granting it `duration` in which to send
+    /// requests.
+    pub fn lease(&self, duration: Duration) -> IoResult<()> {
+        let lease = Tmsg::Lease(LEASE_MILLISECONDS, duration.num_milliseconds() as u64);
+        self.metrics.leases.incr();
+        self.state.lock().unwrap().lease = Some(precise_time_ns() + duration.num_milliseconds() as u64 * 1_000_000);
+        write_locked(&*self.writer, |buf| frame_tmsg(buf, &MARKER_TAG, &lease))
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.state.lock().unwrap().draining
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    /// The number of requests being handled.
+    pub fn load(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+
+    pub fn metrics(&self) -> &SessionMetrics {
+        &self.metrics
+    }
+
+    pub fn close(&self) {
+        self.writer.lock().unwrap().close();
+    }
+}
+
+/// The sessions a server is serving.  Sessions are forgotten once their
+/// connections close.
+#[derive(Clone)]
+pub struct Sessions {
+    sessions: Arc<Mutex<Vec<Arc<Tracked>>>>,
+}
+
+struct Tracked {
+    id: usize,
+    peer: String,
+    state: Arc<Mutex<State>>,
+    drain: Box<Fn() -> IoResult<()> + Send + Sync>,
+}
+
+/// A session's state when `Sessions::status` was called.
+#[derive(Clone)]
+pub struct SessionStatus {
+    /// The session's id, as in its log records (see `span`).
+    pub id: usize,
+    /// The peer's address, or its credentials for local connections, and
+    /// the identity it authenticated with, if any.
+    pub peer: String,
+    /// The tags of requests being handled, and how long each has been.
+    pub pending: Vec<(u32, Duration)>,
+    /// The time left on the lease last granted to the client, if any.
+    pub lease: Option<Duration>,
+    pub draining: bool,
+    pub drain_acked: bool,
+    pub metrics: SessionMetrics,
+}
+
+impl Sessions {
+    pub fn new() -> Sessions {
+        Sessions { sessions: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    /// The status of each session, in the order they were established.
+    pub fn status(&self) -> Vec<SessionStatus> {
+        let now = precise_time_ns();
+        let elapsed = |since: u64| Duration::nanoseconds(now.saturating_sub(since) as i64);
+        let sessions = self.sessions.lock().unwrap();
+        sessions.iter().map(|s| {
+            let state = s.state.lock().unwrap();
+            let mut pending: Vec<(u32, Duration)> =
+                state.pending.iter().map(|(tag, &(_, received))| (*tag, elapsed(received))).collect();
+            pending.sort_by(|a, b| a.0.cmp(&b.0));
+            SessionStatus {
+                id: s.id,
+                peer: s.peer.clone(),
+                pending: pending,
+                lease: state.lease.map(|expires| Duration::nanoseconds(expires.saturating_sub(now) as i64)),
+                draining: state.draining,
+                drain_acked: state.drain_acked,
+                metrics: state.metrics.clone(),
+            }
+        }).collect()
+    }
+
+    /// Drains the session `id` (see `ServerSession::drain`), or returns
+    /// None if there's no such session.
+    pub fn drain(&self, id: usize) -> Option<IoResult<()>> {
+        let session = self.sessions.lock().unwrap().iter().find(|s| s.id == id).map(|s| s.clone());
+        session.map(|s| (*s.drain)())
+    }
+
+    /// Drains every session, returning the number sent a Tdrain.
+    pub fn drain_all(&self) -> usize {
+        let sessions = self.sessions.lock().unwrap().clone();
+        sessions.iter().filter(|s| (*s.drain)().is_ok()).count()
+    }
+
+    fn add<T: Transport>(&self, id: usize, peer: String, session: ServerSession<T>) {
+        let state = session.state.clone();
+        let tracked = Tracked { id: id, peer: peer, state: state, drain: Box::new(move|| session.drain()) };
+        self.sessions.lock().unwrap().push(Arc::new(tracked));
+    }
+
+    fn remove(&self, id: usize) {
+        self.sessions.lock().unwrap().retain(|s| s.id != id);
+    }
+}
+
+pub struct Server<H> {
+    handler: Arc<H>,
+    legacy: bool,
+    metrics: Option<SessionMetrics>,
+    sessions: Sessions,
+}
+
+impl<H: Handler + 'static> Server<H> {
+    pub fn new(handler: H) -> Server<H> {
+        Server { handler: Arc::new(handler), legacy: false, metrics: None, sessions: Sessions::new() }
+    }
+
+    /// Accepts Treq as well as Tdispatch, for clients that predate
+    /// Tdispatch.  Otherwise, Treqs are answered with Rerr.
+    pub fn accept_legacy(mut self, legacy: bool) -> Server<H> {
+        self.legacy = legacy;
+        self
+    }
+
+    /// Each session keeps a `child` of `metrics`.
+    pub fn metrics(mut self, metrics: SessionMetrics) -> Server<H> {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// The sessions being served.
+    pub fn sessions(&self) -> Sessions {
+        self.sessions.clone()
+    }
+
+    /// Serves a connection on a background thread.
+    pub fn serve<T: Transport>(&self, conn: T) -> ServerSession<T> {
+        let metrics = match self.metrics {
+            None => SessionMetrics::new(),
+            Some(ref metrics) => metrics.child(),
+        };
+        let reader = Metered::new(conn.clone(), &metrics);
+        let peer = (conn.peer_identity(), conn.peer_credentials());
+        let id = span::session_id();
+        let description = span::describe_peer(&conn);
+        let session_span = Span::new().with("session", id).with("side", "server").with("peer", description.as_slice());
+        info!("{} established", session_span);
+        let state = Arc::new(Mutex::new(State {
+            pending: HashMap::new(),
+            lease: None,
+            draining: false,
+            drain_acked: false,
+            closed: false,
+            metrics: metrics.clone(),
+        }));
+        let writer = Arc::new(Mutex::new(Metered::new(conn, &metrics)));
+
+        let tracked = ServerSession { state: state.clone(), writer: writer.clone(), metrics: metrics.clone() };
+        self.sessions.add(id, description, tracked);
+
+        let rstate = state.clone();
+        let rwriter = writer.clone();
+        let handler = self.handler.clone();
+        let legacy = self.legacy;
+        let sessions = self.sessions.clone();
+        Thread::spawn(move|| {
+            let ioe = read_loop(reader, &rstate, &rwriter, &handler, legacy, peer, &session_span);
+            session_span.closed(&ioe);
+            rstate.lock().unwrap().closed = true;
+            sessions.remove(id);
+        });
+
+        ServerSession { state: state, writer: writer, metrics: metrics }
+    }
+
+    /// Accepts and serves connections until the acceptor fails.
+    pub fn serve_all<T: Transport, A: Acceptor<T>>(&self, mut acceptor: A) -> IoError {
+        loop {
+            match acceptor.accept() {
+                Err(ioe) => return ioe,
+                Ok(conn) => { self.serve(conn); },
+            }
+        }
+    }
+
+    /// Accepts and serves TCP connections until the listener fails.
+    pub fn listen<A: ToSocketAddr>(&self, addr: A) -> IoError {
+        match TcpListener::bind(addr).and_then(|l| l.listen()) {
+            Err(ioe) => ioe,
+            Ok(acceptor) => self.serve_all(acceptor),
+        }
+    }
+
+    /// Accepts and serves Unix domain socket connections until the
+    /// listener fails.
+    pub fn listen_unix(&self, addr: &UnixAddr) -> IoError {
+        match UnixSocketListener::bind(addr) {
+            Err(ioe) => ioe,
+            Ok(listener) => self.serve_all(listener),
+        }
+    }
+}
+
+fn read_loop<T: Transport, H: Handler + 'static>(
+    reader: T,
+    state: &Arc<Mutex<State>>,
+    writer: &Arc<Mutex<T>>,
+    handler: &Arc<H>,
+    legacy: bool,
+    peer: (Option<PeerIdentity>, Option<PeerCredentials>),
+    span: &Span
+) -> IoError {
+    let mut frames = FrameBuf::new(reader);
+    let mut fragments = Reassembler::new();
+    // the bodies of requests being streamed to handlers.
+    let mut bodies = HashMap::new();
+    loop {
+        let is_dispatch = |msg: &Msg| match *msg { Msg::Tx(_, Tmsg::Dispatch(_, _, _, _)) => true, _ => false };
+        let (msg, streaming) = match fragments.read(&mut frames, is_dispatch) {
+            Err(ioe) => return ioe,
+            Ok(Frame::Whole(msg)) => (msg, false),
+            Ok(Frame::Start(msg)) => (msg, true),
+            Ok(Frame::Body(tag, bytes, last)) => {
+                let body = if last { bodies.remove(&tag.to_u32()) } else { bodies.get(&tag.to_u32()).map(|b| b.clone()) };
+                // the rest of a body whose handler has fallen behind, or
+                // gone away, is dropped.
+                match body {
+                    None => (),
+                    Some(body) => {
+                        if !stream::send_chunk(&body, bytes, last) && !last {
+                            bodies.remove(&tag.to_u32());
+                        }
+                    },
+                }
+                continue;
+            },
+        };
+
+        let reply = match msg {
+            Msg::Tx(tag, Tmsg::Ping) => Some((tag, Rmsg::Ping)),
+
+            Msg::Tx(tag, Tmsg::Drain) => {
+                state.lock().unwrap().metrics.drains.incr();
+                Some((tag, Rmsg::Drain))
+            },
+
+            Msg::Tx(_, Tmsg::Discarded(which, why)) => {
+                let discard = state.lock().unwrap().pending.get(&which.to_u32()).map(|&(ref d, _)| d.clone());
+                match discard {
+                    None => (),
+                    Some(discard) => {
+                        state.lock().unwrap().metrics.discards.incr();
+                        discard.discard(why.as_slice());
+                    },
+                }
+                match bodies.remove(&which.to_u32()) {
+                    None => (),
+                    Some(body) => {
+                        body.fail(IoError { kind: Closed, desc: "request discarded", detail: None });
+                    },
+                }
+                None
+            },
+
+            Msg::Tx(_, Tmsg::Lease(_, _)) => {
+                state.lock().unwrap().metrics.leases.incr();
+                None
+            },
+
+            // TLS is negotiated before the session is established (see
+            // `tls`), so no headers are supported here.
+            Msg::Tx(tag, Tmsg::Init(version, headers)) => {
+                info!("{}", span.with("init_version", version).with("headers", show_headers(headers.as_slice())));
+                Some((tag, Rmsg::Init(version, Vec::new())))
+            },
+
+            Msg::Tx(tag, Tmsg::Req(_, _)) if !legacy => {
+                Some((tag, Rmsg::Err("Treq is not supported".to_string())))
+            },
+
+            Msg::Tx(tag, req) => {
+                let is_legacy = match req { Tmsg::Req(_, _) => true, _ => false };
+                // requests are logged as they complete, at debug.
+                let req_span = if log_enabled!(LogLevel::Debug) { Some(span.request(&tag, &req)) } else { None };
+                let discard = Discard::new();
+                let draining = {
+                    let mut state = state.lock().unwrap();
+                    state.metrics.requests.incr();
+                    if state.draining {
+                        state.metrics.nacks.incr();
+                    } else {
+                        state.pending.insert(tag.to_u32(), (discard.clone(), precise_time_ns()));
+                        state.metrics.pending.set(state.pending.len() as isize);
+                    }
+                    state.draining
+                };
+
+                if draining {
+                    match req_span {
+                        None => (),
+                        Some(req_span) => debug!("{}", req_span.with("outcome", "nack").with("latency_us", 0)),
+                    }
+                    let nack = Rmsg::DispatchNack(Vec::new());
+                    Some((tag, if is_legacy { legacy::downgrade_rmsg(nack) } else { nack }))
+                } else {
+                    let mut request = Request::from_tmsg(req).unwrap();
+                    request.peer_identity = peer.0.clone();
+                    request.peer_credentials = peer.1.clone();
+
+                    let (tx, body) = stream::body_channel();
+                    stream::send_chunk(&tx, mem::replace(&mut request.body, Vec::new()), !streaming);
+                    if streaming {
+                        bodies.insert(tag.to_u32(), tx);
+                    }
+
+                    let rsp = Responder {
+                        tag: tag,
+                        legacy: is_legacy,
+                        discard: discard,
+                        started: precise_time_ns(),
+                        span: req_span,
+                        state: state.clone(),
+                        writer: writer.clone(),
+                    };
+                    let handler = handler.clone();
+                    Thread::spawn(move|| handler.dispatch_stream(request, body, rsp));
+                    None
+                }
+            },
+
+            // a mux client probing whether we speak mux.
+            Msg::Rx(tag, Rmsg::Err(ref s)) if s.as_slice() == TINIT_CHECK => {
+                Some((tag, Rmsg::Err(s.clone())))
+            },
+
+            Msg::Rx(_, Rmsg::Drain) => {
+                state.lock().unwrap().drain_acked = true;
+                close_if_drained(&**state, &**writer);
+                None
+            },
+
+            Msg::Rx(_, _) => None,
+        };
+
+        match reply {
+            None => (),
+            Some((tag, rsp)) => match write_locked(&**writer, |buf| frame_rmsg(buf, &tag, &rsp)) {
+                Err(ioe) => return ioe,
+                Ok(_) => (),
+            },
+        }
+    }
+}
+
+/// The answer to a discarded request.
+fn discarded_rsp() -> Rmsg {
+    Rmsg::DispatchError(Vec::new(), "request discarded".to_string())
+}
+
+fn close_if_drained<T: Transport>(state: &Mutex<State>, writer: &Mutex<T>) {
+    if state.lock().unwrap().is_drained() {
+        writer.lock().unwrap().close();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::old_io::{Acceptor, Listener, Reader, Writer};
+    use std::old_io::net::tcp::{TcpListener, TcpStream};
+    use std::old_io::timer::sleep;
+    use std::sync::Mutex;
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread::Thread;
+
+    use std::time::Duration;
+
+    use memory;
+    use metrics::{Metrics, SessionMetrics};
+    use misc::{Dtab, Trace};
+    use proto::{Tag, Tmsg, Rmsg};
+    use session::ClientSession;
+    use stream::BodyReader;
+    use transport::Transport;
+    use reader::MuxReader;
+    use writer::MuxWriter;
+    use super::{Handler, Request, Responder, Server};
+
+    fn echo(req: Request) -> Rmsg {
+        match req.trace {
+            None => Rmsg::DispatchOk(Vec::new(), req.body),
+            Some(_) => Rmsg::DispatchError(Vec::new(), "traced".to_string()),
+        }
+    }
+
+    fn serve(legacy: bool) -> ClientSession<TcpStream> {
+        let mut listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.socket_name().unwrap();
+        let mut acceptor = listener.listen().unwrap();
+        Thread::spawn(move|| {
+            let server = Server::new(echo).accept_legacy(legacy);
+            for conn in acceptor.incoming() {
+                match conn {
+                    Err(_) => break,
+                    Ok(conn) => { server.serve(conn); },
+                }
+            }
+        });
+        ClientSession::new(TcpStream::connect(addr).unwrap())
+    }
+
+    static TRACE: Trace = Trace { span_id: 1, parent_id: 2, trace_id: 3, flags: 0 };
+
+    #[test]
+    fn test_dispatch() {
+        let client = serve(false);
+        let req = Tmsg::Dispatch(Vec::new(), "/".to_string(), Dtab::empty(), b"mom".to_vec());
+        assert_eq!(client.call(&req).unwrap(), Rmsg::DispatchOk(Vec::new(), b"mom".to_vec()));
+
+        let traced = Tmsg::Dispatch(vec![TRACE.to_context()], "/".to_string(), Dtab::empty(), Vec::new());
+        assert_eq!(client.call(&traced).unwrap(), Rmsg::DispatchError(Vec::new(), "traced".to_string()));
+
+        client.ping().unwrap();
+        match client.call(&Tmsg::Req(None, Vec::new())).unwrap() {
+            Rmsg::Err(_) => (),
+            rsp => panic!("unexpected response: {:?}", rsp),
+        }
+    }
+
+    #[test]
+    fn test_legacy() {
+        let client = serve(true);
+        assert_eq!(client.call(&Tmsg::Req(None, b"mom".to_vec())).unwrap(), Rmsg::ReqOk(b"mom".to_vec()));
+        assert_eq!(client.call(&Tmsg::Req(Some(TRACE), Vec::new())).unwrap(),
+                   Rmsg::ReqError("traced".to_string()));
+    }
+
+    #[test]
+    fn test_metrics() {
+        let registry = Metrics::new();
+        let server = Server::new(echo).metrics(SessionMetrics::registered(&registry, "mux_server"));
+        let (client, conn) = memory::pair();
+        let session = server.serve(conn);
+        let client = ClientSession::new(client);
+
+        let req = Tmsg::Dispatch(Vec::new(), "/".to_string(), Dtab::empty(), b"mom".to_vec());
+        client.call(&req).unwrap();
+        let metrics = session.metrics();
+        assert_eq!(metrics.requests.get(), 1);
+        assert_eq!(metrics.pending.get(), 0);
+        assert_eq!(metrics.bytes_read.get(), client.metrics().bytes_written.get());
+        assert_eq!(metrics.latency.snapshot().count(), 1);
+
+        session.lease(Duration::seconds(1)).unwrap();
+        assert_eq!(metrics.leases.get(), 1);
+
+        let rendered = registry.prometheus();
+        assert!(rendered.contains("\nmux_server_requests_total 1\n"), "{}", rendered);
+        assert!(rendered.contains("\nmux_server_leases_total 1\n"), "{}", rendered);
+        assert!(rendered.contains("\nmux_server_latency_microseconds_count 1\n"), "{}", rendered);
+    }
+
+    /// Holds each request until it's released.
+    struct Hold(Mutex<Receiver<()>>);
+
+    impl Handler for Hold {
+        fn dispatch(&self, req: Request) -> Rmsg {
+            self.0.lock().unwrap().recv().ok();
+            Rmsg::DispatchOk(Vec::new(), req.body)
+        }
+    }
+
+    #[test]
+    fn test_sessions() {
+        let (release, held) = channel();
+        let server = Server::new(Hold(Mutex::new(held)));
+        let sessions = server.sessions();
+        let (mut client, conn) = memory::pair();
+        let session = server.serve(conn);
+
+        let req = Tmsg::Dispatch(Vec::new(), "/".to_string(), Dtab::empty(), b"mom".to_vec());
+        client.write_mux_framed_tmsg(&Tag(0, 0, 7), &req).unwrap();
+        session.lease(Duration::seconds(10)).unwrap();
+        while session.load() == 0 {
+            sleep(Duration::milliseconds(1));
+        }
+
+        let status = sessions.status();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].peer, "-".to_string());
+        assert_eq!(status[0].pending.iter().map(|&(tag, _)| tag).collect::<Vec<u32>>(), vec![7]);
+        assert!(status[0].lease.unwrap() > Duration::seconds(9));
+        assert!(!status[0].draining);
+
+        assert!(sessions.drain(status[0].id + 1).is_none());
+        assert!(sessions.drain(status[0].id).unwrap().is_ok());
+        assert!(sessions.status()[0].draining);
+
+        // closed sessions are forgotten.
+        release.send(()).unwrap();
+        client.close();
+        while !sessions.status().is_empty() {
+            sleep(Duration::milliseconds(1));
+        }
+    }
+
+    #[test]
+    fn test_discarded() {
+        let (release, held) = channel();
+        let server = Server::new(Hold(Mutex::new(held)));
+        let (mut client, conn) = memory::pair();
+        let session = server.serve(conn);
+
+        // the discarded request's tag is answered, once its handler returns.
+        let req = Tmsg::Dispatch(Vec::new(), "/".to_string(), Dtab::empty(), b"mom".to_vec());
+        client.write_mux_framed_tmsg(&Tag(0, 0, 7), &req).unwrap();
+        client.write_mux_framed_tmsg(&Tag(0, 0, 0), &Tmsg::Discarded(Tag(0, 0, 7), "gone".to_string())).unwrap();
+        while session.metrics().discards.get() == 0 {
+            sleep(Duration::milliseconds(1));
+        }
+        release.send(()).unwrap();
+        assert_eq!(client.read_mux_framed_rmsg().unwrap(),
+                   (Tag(0, 0, 7), Rmsg::DispatchError(Vec::new(), "request discarded".to_string())));
+    }
+
+    /// Streams each chunk of a request's body back as it arrives.
+    struct StreamingEcho;
+
+    impl Handler for StreamingEcho {
+        fn dispatch(&self, _: Request) -> Rmsg { Rmsg::DispatchNack(Vec::new()) }
+
+        fn dispatch_stream<T: Transport>(&self, _: Request, mut body: BodyReader, rsp: Responder<T>) {
+            rsp.stream(Vec::new(), |w| loop {
+                match body.next_chunk() {
+                    Err(ioe) => return Err(ioe),
+                    Ok(None) => return Ok(()),
+                    Ok(Some(chunk)) => match w.write(chunk.as_slice()) {
+                        Err(ioe) => return Err(ioe),
+                        Ok(_) => (),
+                    },
+                }
+            }).ok();
+        }
+    }
+
+    #[test]
+    fn test_stream() {
+        let (client, server) = memory::pair();
+        let _session = Server::new(StreamingEcho).serve(server);
+        let client = ClientSession::new(client);
+
+        let (rsp, mut body) = client.dispatch_stream(Vec::new(), "/".to_string(), Dtab::empty(), |w| {
+            w.write(b"mo").and_then(|_| w.write(b"m"))
+        }).unwrap();
+        assert_eq!(rsp, Rmsg::DispatchOk(Vec::new(), Vec::new()));
+        assert_eq!(body.read_to_end().unwrap(), b"mom".to_vec());
+
+        // a streamed response is assembled for an ordinary call.
+        let req = Tmsg::Dispatch(Vec::new(), "/".to_string(), Dtab::empty(), b"mom".to_vec());
+        assert_eq!(client.call(&req).unwrap(), Rmsg::DispatchOk(Vec::new(), b"mom".to_vec()));
+    }
+}
@@ -0,0 +1,741 @@
+//! A client session multiplexes concurrent requests over one connection.
+//!
+//! Callers block on a response while a background thread reads frames from
+//! the peer, completes outstanding tags, and answers the peer's control
+//! messages (Tdrain, Tping).  Sessions may be established over any
+//! `Transport`.
+
+#[allow(unstable)]
+
+use std::collections::HashMap;
+use std::old_io::{IoResult, IoError, Closed, ResourceUnavailable, InvalidInput};
+use std::old_io::net::ip::ToSocketAddr;
+use std::old_io::net::tcp::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::Thread;
+use log::LogLevel;
+use time::precise_time_ns;
+
+use legacy;
+use metrics::{Metered, SessionMetrics};
+use misc::{Context, Dtab};
+use proto::{Msg, Tag, Tmsg, Rmsg, MAX_TAG, MARKER_TAG, show_headers};
+use framebuf::FrameBuf;
+use span;
+use span::Span;
+use stream;
+use stream::{BodyReader, BodySender, BodyWriter, Frame, Reassembler};
+use transport::Transport;
+use unix::{UnixAddr, UnixSocket};
+use writer::{frame_rmsg, frame_tmsg, write_locked};
+
+/// Anything that issues requests: a session, a pool, or a filter over
+/// either.
+pub trait Client {
+    fn call(&self, msg: &Tmsg) -> IoResult<Rmsg>;
+}
+
+impl<C: Client> Client for Arc<C> {
+    fn call(&self, msg: &Tmsg) -> IoResult<Rmsg> { (**self).call(msg) }
+}
+
+struct State {
+    pending: HashMap<u32, Sender<IoResult<Rmsg>>>,
+    // the bodies of streaming calls' responses.
+    bodies: HashMap<u32, BodySender>,
+    next_tag: u32,
+    draining: bool,
+    closed: Option<IoError>,
+    legacy_fallback: bool,
+    downgraded: bool,
+    metrics: SessionMetrics,
+}
+
+impl State {
+    fn new(metrics: SessionMetrics) -> State {
+        State {
+            pending: HashMap::new(),
+            bodies: HashMap::new(),
+            next_tag: 1,
+            draining: false,
+            closed: None,
+            legacy_fallback: false,
+            downgraded: false,
+            metrics: metrics,
+        }
+    }
+
+    /// Stops awaiting a response to `tag`.
+    fn remove_pending(&mut self, tag: u32) -> Option<Sender<IoResult<Rmsg>>> {
+        let tx = self.pending.remove(&tag);
+        self.metrics.pending.set(self.pending.len() as isize);
+        tx
+    }
+}
+
+struct DiscardState {
+    why: Option<String>,
+    hook: Option<Box<FnMut(&str) + Send>>,
+}
+
+/// A handle through which a call may be abandoned from another thread: the
+/// caller fails immediately, and the peer is sent a Tdiscarded.  Servers
+/// hold one for each request being handled, which a proxy may pass along
+/// with the request to its backend.
+#[derive(Clone)]
+pub struct Discard {
+    flag: Arc<AtomicBool>,
+    state: Arc<Mutex<DiscardState>>,
+}
+
+impl Discard {
+    pub fn new() -> Discard {
+        Discard {
+            flag: Arc::new(AtomicBool::new(false)),
+            state: Arc::new(Mutex::new(DiscardState { why: None, hook: None })),
+        }
+    }
+
+    pub fn is_discarded(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+
+    /// The flag set on discard, as taken by `BodyWriter::discardable`.
+    pub fn flag(&self) -> Arc<AtomicBool> {
+        self.flag.clone()
+    }
+
+    /// Abandons the call in flight, if any.  Calls made with this handle
+    /// later are abandoned as soon as they're sent.
+    pub fn discard(&self, why: &str) {
+        let mut state = self.state.lock().unwrap();
+        if state.why.is_some() {
+            return;
+        }
+        state.why = Some(why.to_string());
+        self.flag.store(true, Ordering::SeqCst);
+        match state.hook.take() {
+            None => (),
+            Some(mut hook) => (*hook)(why),
+        }
+    }
+
+    /// Runs `hook` on discard, or now if already discarded.
+    fn arm(&self, mut hook: Box<FnMut(&str) + Send>) {
+        let mut state = self.state.lock().unwrap();
+        let why = match state.why {
+            None => None,
+            Some(ref why) => Some(why.clone()),
+        };
+        match why {
+            None => state.hook = Some(hook),
+            Some(why) => (*hook)(why.as_slice()),
+        }
+    }
+
+    fn disarm(&self) {
+        self.state.lock().unwrap().hook = None;
+    }
+}
+
+pub struct ClientSession<T> {
+    state: Arc<Mutex<State>>,
+    writer: Arc<Mutex<Metered<T>>>,
+    metrics: SessionMetrics,
+    span: Span,
+}
+
+impl ClientSession<TcpStream> {
+    pub fn connect<A: ToSocketAddr>(addr: A) -> IoResult<ClientSession<TcpStream>> {
+        TcpStream::connect(addr).map(|conn| ClientSession::new(conn))
+    }
+}
+
+impl ClientSession<UnixSocket> {
+    pub fn connect_unix(addr: &UnixAddr) -> IoResult<ClientSession<UnixSocket>> {
+        UnixSocket::connect(addr).map(|conn| ClientSession::new(conn))
+    }
+}
+
+impl<T: Transport> ClientSession<T> {
+    pub fn new(conn: T) -> ClientSession<T> {
+        ClientSession::metered(conn, SessionMetrics::new())
+    }
+
+    /// Establishes a session that keeps `metrics`, typically a `child` of
+    /// metrics shared by many sessions.
+    pub fn metered(conn: T, metrics: SessionMetrics) -> ClientSession<T> {
+        let span = Span::new()
+            .with("session", span::session_id())
+            .with("side", "client")
+            .with("peer", span::describe_peer(&conn));
+        info!("{} established", span);
+        let reader = Metered::new(conn.clone(), &metrics);
+        let state = Arc::new(Mutex::new(State::new(metrics.clone())));
+        let writer = Arc::new(Mutex::new(Metered::new(conn, &metrics)));
+
+        let rstate = state.clone();
+        let rwriter = writer.clone();
+        let rspan = span.clone();
+        Thread::spawn(move|| {
+            let ioe = read_loop(reader, &*rstate, &*rwriter, &rspan);
+            rspan.closed(&ioe);
+            fail_pending(&*rstate, ioe);
+        });
+
+        ClientSession { state: state, writer: writer, metrics: metrics, span: span }
+    }
+
+    /// If the peer rejects a Tdispatch with an Rerr naming an unknown
+    /// message type, as servers that predate Tdispatch do, resend it and
+    /// all subsequent Tdispatches as Treqs.  Downgraded requests lose their
+    /// Dtab and contexts but keep their trace.  Rreq responses are
+    /// presented as Rdispatches.
+    pub fn legacy_fallback(self, enabled: bool) -> ClientSession<T> {
+        self.state.lock().unwrap().legacy_fallback = enabled;
+        self
+    }
+
+    /// True once Tdispatches are being sent as Treqs.
+    pub fn is_downgraded(&self) -> bool {
+        self.state.lock().unwrap().downgraded
+    }
+
+    /// Sends a request and blocks until its response is received.
+    pub fn call(&self, msg: &Tmsg) -> IoResult<Rmsg> {
+        self.call_with(msg, None)
+    }
+
+    /// Like `call`, but the call may be abandoned through `discard`.
+    pub fn call_discardable(&self, msg: &Tmsg, discard: &Discard) -> IoResult<Rmsg> {
+        self.call_with(msg, Some(discard))
+    }
+
+    fn call_with(&self, msg: &Tmsg, discard: Option<&Discard>) -> IoResult<Rmsg> {
+        let (fallback, downgraded) = {
+            let state = self.state.lock().unwrap();
+            (state.legacy_fallback, state.downgraded)
+        };
+
+        if downgraded {
+            match legacy::downgrade_tmsg(msg) {
+                Some(req) => return self.send(&req, discard).map(legacy::upgrade_rmsg),
+                None => (),
+            }
+        }
+
+        let rsp = self.send(msg, discard);
+        let rejected = match (msg, &rsp) {
+            (&Tmsg::Dispatch(_, _, _, _), &Ok(ref rsp)) => fallback && legacy::is_unknown_type(rsp),
+            _ => false,
+        };
+        if rejected {
+            self.state.lock().unwrap().downgraded = true;
+            return self.call_with(msg, discard);
+        }
+        rsp
+    }
+
+    fn send(&self, msg: &Tmsg, discard: Option<&Discard>) -> IoResult<Rmsg> {
+        let (tx, rx) = channel();
+        let tag = match self.register(tx) {
+            Err(ioe) => return Err(ioe),
+            Ok(tag) => tag,
+        };
+
+        let started = precise_time_ns();
+        match write_locked(&*self.writer, |buf| frame_tmsg(buf, &tag, msg)) {
+            Err(ioe) => {
+                self.state.lock().unwrap().remove_pending(tag.to_u32());
+                return Err(ioe);
+            },
+            Ok(_) => (),
+        }
+
+        // once sent, a discard fails the caller and tells the peer.  The
+        // tag stays reserved until the peer answers it, so that the answer
+        // can't be taken for that of a later request.
+        match discard {
+            None => (),
+            Some(discard) => {
+                let (state, writer, metrics) = (self.state.clone(), self.writer.clone(), self.metrics.clone());
+                discard.arm(Box::new(move |why: &str| {
+                    let tx = state.lock().unwrap().pending.get(&tag.to_u32()).map(|tx| tx.clone());
+                    match tx {
+                        // already answered.
+                        None => (),
+                        Some(tx) => {
+                            metrics.discards.incr();
+                            tx.send(Err(discarded_error())).ok();
+                            let discarded = Tmsg::Discarded(tag, why.to_string());
+                            write_locked(&*writer, |buf| frame_tmsg(buf, &MARKER_TAG, &discarded)).ok();
+                        },
+                    }
+                }));
+            },
+        }
+
+        let rsp = match rx.recv() {
+            Err(_) => Err(closed_error()),
+            Ok(rsp) => rsp,
+        };
+        match discard {
+            None => (),
+            Some(discard) => discard.disarm(),
+        }
+        self.observe(&tag, msg, started, &rsp);
+        rsp
+    }
+
+    /// Logs a response's outcome and latency, and records its latency and
+    /// whether it's a nack.  The request's span is only built if it's to be
+    /// logged.
+    fn observe(&self, tag: &Tag, req: &Tmsg, started: u64, rsp: &IoResult<Rmsg>) {
+        let latency = (precise_time_ns() - started) / 1000;
+        if log_enabled!(LogLevel::Debug) {
+            let outcome = match *rsp {
+                Err(ref ioe) => ioe.desc,
+                Ok(ref rsp) => span::outcome(rsp),
+            };
+            debug!("{}", self.span.request(tag, req).with("outcome", outcome).with("latency_us", latency));
+        }
+        match *rsp {
+            Err(_) => return,
+            Ok(Rmsg::DispatchNack(_)) | Ok(Rmsg::ReqNack) => self.metrics.nacks.incr(),
+            Ok(_) => (),
+        }
+        self.metrics.latency.record(latency);
+    }
+
+    /// Sends a Tdispatch whose body is written by `f` and streamed as it's
+    /// produced.  Returns once the response's header arrives: its body is
+    /// read from the returned `BodyReader`, and is empty in the `Rmsg`.
+    ///
+    /// If `f` fails after part of the body is sent, the request is
+    /// discarded.
+    pub fn dispatch_stream<F>(&self, contexts: Vec<Context>, dst: String, dtab: Dtab, f: F)
+        -> IoResult<(Rmsg, BodyReader)>
+        where F: FnOnce(&mut BodyWriter<Metered<T>>) -> IoResult<()>
+    {
+        let (tx, rx) = channel();
+        let tag = match self.register(tx) {
+            Err(ioe) => return Err(ioe),
+            Ok(tag) => tag,
+        };
+        let (body_tx, body) = stream::body_channel();
+        self.state.lock().unwrap().bodies.insert(tag.to_u32(), body_tx);
+
+        let started = precise_time_ns();
+        let header = Tmsg::Dispatch(contexts, dst, dtab, Vec::new());
+        let mut writer = BodyWriter::tmsg(self.writer.clone(), tag, &header);
+        match f(&mut writer).and_then(|_| writer.finish()) {
+            Err(ioe) => {
+                {
+                    // once the peer has seen the tag, it's held until the
+                    // peer answers it.
+                    let mut state = self.state.lock().unwrap();
+                    if !writer.is_started() {
+                        state.remove_pending(tag.to_u32());
+                    }
+                    state.bodies.remove(&tag.to_u32());
+                }
+                if writer.is_started() {
+                    self.metrics.discards.incr();
+                    let discard = Tmsg::Discarded(tag, ioe.desc.to_string());
+                    write_locked(&*self.writer, |buf| frame_tmsg(buf, &MARKER_TAG, &discard)).ok();
+                    writer.abort().ok();
+                }
+                return Err(ioe);
+            },
+            Ok(_) => (),
+        }
+
+        let rsp = match rx.recv() {
+            Err(_) => Err(closed_error()),
+            Ok(rsp) => rsp,
+        };
+        self.observe(&tag, &header, started, &rsp);
+        rsp.map(move |rsp| (rsp, body))
+    }
+
+    pub fn ping(&self) -> IoResult<()> {
+        self.call(&Tmsg::Ping).and_then(|rsp| match rsp {
+            Rmsg::Ping => Ok(()),
+            _ => Err(IoError {
+                kind: InvalidInput,
+                desc: "unexpected ping response",
+                detail: Some(format!("{:?}", rsp)),
+            }),
+        })
+    }
+
+    /// The number of requests awaiting a response.
+    pub fn load(&self) -> usize {
+        self.state.lock().unwrap().pending.len()
+    }
+
+    pub fn metrics(&self) -> &SessionMetrics {
+        &self.metrics
+    }
+
+    /// True once the peer has asked us to stop sending requests.
+    pub fn is_draining(&self) -> bool {
+        self.state.lock().unwrap().draining
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed.is_some()
+    }
+
+    pub fn is_available(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        !state.draining && state.closed.is_none()
+    }
+
+    /// Closes the connection.  Outstanding requests fail.
+    pub fn close(&self) {
+        self.writer.lock().unwrap().close();
+    }
+
+    fn register(&self, tx: Sender<IoResult<Rmsg>>) -> IoResult<Tag> {
+        let mut state = self.state.lock().unwrap();
+        match state.closed {
+            Some(ref ioe) => return Err(ioe.clone()),
+            None => (),
+        }
+        if state.draining {
+            return Err(IoError {
+                kind: Closed,
+                desc: "session is draining",
+                detail: None,
+            });
+        }
+        if state.pending.len() >= MAX_TAG as usize {
+            return Err(IoError {
+                kind: ResourceUnavailable,
+                desc: "no tags available",
+                detail: None,
+            });
+        }
+
+        let mut t = state.next_tag;
+        while state.pending.contains_key(&t) {
+            t = if t >= MAX_TAG { 1 } else { t + 1 };
+        }
+        state.next_tag = if t >= MAX_TAG { 1 } else { t + 1 };
+        state.pending.insert(t, tx);
+        state.metrics.requests.incr();
+        state.metrics.pending.set(state.pending.len() as isize);
+        Ok(Tag::from_u32(t))
+    }
+}
+
+impl<T: Transport> Client for ClientSession<T> {
+    fn call(&self, msg: &Tmsg) -> IoResult<Rmsg> { ClientSession::call(self, msg) }
+}
+
+impl<T: Transport> Drop for ClientSession<T> {
+    fn drop(&mut self) { self.close(); }
+}
+
+fn discarded_error() -> IoError {
+    IoError {
+        kind: Closed,
+        desc: "request discarded",
+        detail: None,
+    }
+}
+
+fn closed_error() -> IoError {
+    IoError {
+        kind: Closed,
+        desc: "session closed",
+        detail: None,
+    }
+}
+
+/// Reads messages from the peer until the connection fails.
+fn read_loop<T: Transport>(reader: T, state: &Mutex<State>, writer: &Mutex<T>, span: &Span) -> IoError {
+    let mut frames = FrameBuf::new(reader);
+    let mut fragments = Reassembler::new();
+    loop {
+        // only streaming calls' responses are streamed.
+        let frame = fragments.read(&mut frames, |msg| match *msg {
+            Msg::Rx(ref tag, _) => state.lock().unwrap().bodies.contains_key(&tag.to_u32()),
+            Msg::Tx(_, _) => false,
+        });
+
+        match frame {
+            Err(ioe) => return ioe,
+
+            Ok(Frame::Whole(Msg::Rx(tag, rsp))) => {
+                let mut state = state.lock().unwrap();
+                let rsp = match state.bodies.remove(&tag.to_u32()) {
+                    None => rsp,
+                    Some(body) => {
+                        let (rsp, bytes) = stream::split_body(rsp);
+                        stream::send_chunk(&body, bytes, true);
+                        rsp
+                    },
+                };
+                match state.remove_pending(tag.to_u32()) {
+                    // the caller has gone away.
+                    None => (),
+                    Some(tx) => { tx.send(Ok(rsp)).ok(); }
+                }
+            },
+
+            Ok(Frame::Start(Msg::Rx(tag, rsp))) => {
+                let (rsp, bytes) = stream::split_body(rsp);
+                let (tx, body) = {
+                    let mut state = state.lock().unwrap();
+                    (state.remove_pending(tag.to_u32()), state.bodies.get(&tag.to_u32()).map(|b| b.clone()))
+                };
+                match tx {
+                    None => (),
+                    Some(tx) => { tx.send(Ok(rsp)).ok(); }
+                }
+                match body {
+                    None => (),
+                    Some(body) => {
+                        if !stream::send_chunk(&body, bytes, false) {
+                            state.lock().unwrap().bodies.remove(&tag.to_u32());
+                        }
+                    },
+                }
+            },
+
+            Ok(Frame::Body(tag, bytes, last)) => {
+                let body = {
+                    let mut state = state.lock().unwrap();
+                    if last {
+                        state.bodies.remove(&tag.to_u32())
+                    } else {
+                        state.bodies.get(&tag.to_u32()).map(|b| b.clone())
+                    }
+                };
+                // the rest of a body whose caller has fallen behind, or gone
+                // away, is dropped.
+                match body {
+                    None => (),
+                    Some(body) => {
+                        if !stream::send_chunk(&body, bytes, last) && !last {
+                            state.lock().unwrap().bodies.remove(&tag.to_u32());
+                        }
+                    },
+                }
+            },
+
+            Ok(Frame::Whole(Msg::Tx(tag, req))) | Ok(Frame::Start(Msg::Tx(tag, req))) => {
+                let rsp = match req {
+                    Tmsg::Drain => {
+                        let mut state = state.lock().unwrap();
+                        state.draining = true;
+                        state.metrics.drains.incr();
+                        Rmsg::Drain
+                    },
+                    Tmsg::Ping => Rmsg::Ping,
+                    // leases are advisory and discards have no response.
+                    Tmsg::Lease(_, _) => {
+                        state.lock().unwrap().metrics.leases.incr();
+                        continue;
+                    },
+                    Tmsg::Discarded(_, _) => continue,
+                    Tmsg::Init(version, headers) => {
+                        info!("{}", span.with("init_version", version).with("headers", show_headers(headers.as_slice())));
+                        Rmsg::Init(version, Vec::new())
+                    },
+                    _ => Rmsg::Err("clients do not serve requests".to_string()),
+                };
+                match write_locked(writer, |buf| frame_rmsg(buf, &tag, &rsp)) {
+                    Err(ioe) => return ioe,
+                    Ok(_) => (),
+                }
+            },
+        }
+    }
+}
+
+fn fail_pending(state: &Mutex<State>, ioe: IoError) {
+    let mut state = state.lock().unwrap();
+    for (_, tx) in state.pending.drain() {
+        tx.send(Err(ioe.clone())).ok();
+    }
+    state.metrics.pending.set(0);
+    // readers of unfinished bodies see them truncated.
+    state.bodies.clear();
+    state.closed = Some(ioe);
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::old_io::{Acceptor, Listener, Writer};
+    use std::old_io::net::tcp::{TcpListener, TcpStream};
+    use std::old_io::timer::sleep;
+    use std::sync::mpsc::channel;
+    use std::thread::Thread;
+    use std::time::Duration;
+
+    use metrics::{Metrics, SessionMetrics};
+    use misc::Trace;
+    use proto::{Msg, Tag, Tmsg, Rmsg};
+    use reader::MuxReader;
+    use transport::Transport;
+    use writer::{frame_rmsg, frame_tmsg};
+    use super::{ClientSession, Discard};
+
+    /// Serves echo responses on an ephemeral port.  If `drain` is set, each
+    /// connection is sent a Tdrain after its first response.
+    pub fn serve_echo(drain: bool) -> String {
+        let mut listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("{}", listener.socket_name().unwrap());
+        let mut acceptor = listener.listen().unwrap();
+        Thread::spawn(move|| {
+            for conn in acceptor.incoming() {
+                let mut conn = match conn { Err(_) => break, Ok(c) => c };
+                Thread::spawn(move|| {
+                    let mut drained = false;
+                    loop {
+                        let (tag, rsp) = match conn.read_mux_framed_msg() {
+                            Err(_) => break,
+                            Ok(Msg::Rx(_, _)) => continue,
+                            Ok(Msg::Tx(tag, Tmsg::Dispatch(ctxs, _, _, body))) =>
+                                (tag, Rmsg::DispatchOk(ctxs, body)),
+                            Ok(Msg::Tx(tag, Tmsg::Ping)) => (tag, Rmsg::Ping),
+                            Ok(Msg::Tx(tag, _)) => (tag, Rmsg::Err("idk man".to_string())),
+                        };
+                        if conn.write_frame(|buf| frame_rmsg(buf, &tag, &rsp)).is_err() { break }
+                        if drain && !drained {
+                            drained = true;
+                            if conn.write_frame(|buf| frame_tmsg(buf, &Tag(0, 0, 1), &Tmsg::Drain)).is_err() {
+                                break
+                            }
+                        }
+                        conn.flush().ok();
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    fn dispatch(body: &[u8]) -> Tmsg {
+        Tmsg::Dispatch(Vec::new(), "/".to_string(), ::misc::Dtab::empty(), body.to_vec())
+    }
+
+    #[test]
+    fn test_call() {
+        let session = ClientSession::connect(serve_echo(false).as_slice()).unwrap();
+        assert_eq!(session.call(&dispatch(b"mom")).unwrap(),
+                   Rmsg::DispatchOk(Vec::new(), b"mom".to_vec()));
+        session.ping().unwrap();
+        assert_eq!(session.load(), 0);
+        assert!(session.is_available());
+    }
+
+    #[test]
+    fn test_metrics() {
+        let aggregate = SessionMetrics::registered(&Metrics::new(), "mux_client");
+        let conn = TcpStream::connect(serve_echo(false).as_slice()).unwrap();
+        let session = ClientSession::metered(conn, aggregate.child());
+        session.call(&dispatch(b"mom")).unwrap();
+        session.ping().unwrap();
+
+        let metrics = session.metrics();
+        assert_eq!(metrics.requests.get(), 2);
+        assert_eq!(metrics.pending.get(), 0);
+        // a Tdispatch of 14 bytes and a Tping of 4, each with its prefix.
+        assert_eq!(metrics.bytes_written.get(), 18 + 8);
+        // an Rdispatch of 10 bytes and an Rping of 4.
+        assert_eq!(metrics.bytes_read.get(), 14 + 8);
+        assert_eq!(metrics.frames_read.snapshot().max(), 10);
+        assert_eq!(metrics.latency.snapshot().count(), 2);
+        assert_eq!(aggregate.requests.get(), 2);
+    }
+
+    /// Serves like a peer that predates Tdispatch.
+    fn serve_legacy() -> String {
+        let mut listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("{}", listener.socket_name().unwrap());
+        let mut acceptor = listener.listen().unwrap();
+        Thread::spawn(move|| {
+            let mut conn = acceptor.accept().unwrap();
+            loop {
+                let (tag, rsp) = match conn.read_mux_framed_tmsg() {
+                    Err(_) => break,
+                    Ok((tag, Tmsg::Req(trace, _))) => (tag, Rmsg::ReqOk(format!("{:?}", trace).into_bytes())),
+                    Ok((tag, _)) => (tag, Rmsg::Err("unknown message type: 2".to_string())),
+                };
+                if conn.write_frame(|buf| frame_rmsg(buf, &tag, &rsp)).and_then(|_| conn.flush()).is_err() { break }
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_legacy_fallback() {
+        let trace = Trace { span_id: 1, parent_id: 2, trace_id: 3, flags: 0 };
+        let req = Tmsg::Dispatch(vec![trace.to_context()], "/".to_string(), ::misc::Dtab::empty(), Vec::new());
+        let expected = Rmsg::DispatchOk(Vec::new(), format!("{:?}", Some(trace)).into_bytes());
+
+        let session = ClientSession::connect(serve_legacy().as_slice()).unwrap().legacy_fallback(true);
+        assert_eq!(session.call(&req).unwrap(), expected);
+        assert!(session.is_downgraded());
+        assert_eq!(session.call(&req).unwrap(), expected);
+
+        let strict = ClientSession::connect(serve_legacy().as_slice()).unwrap();
+        assert_eq!(strict.call(&req).unwrap(), Rmsg::Err("unknown message type: 2".to_string()));
+        assert!(!strict.is_downgraded());
+    }
+
+    #[test]
+    fn test_drain() {
+        let session = ClientSession::connect(serve_echo(true).as_slice()).unwrap();
+        session.call(&dispatch(b"mom")).unwrap();
+        // the drain races the response; a ping round-trip orders them.
+        session.ping().ok();
+        assert!(session.is_draining());
+        assert!(session.call(&dispatch(b"mom")).is_err());
+    }
+
+    #[test]
+    fn test_call_discardable() {
+        // a peer that never responds, and reports what it's sent.
+        let mut listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("{}", listener.socket_name().unwrap());
+        let mut acceptor = listener.listen().unwrap();
+        let (tx, rx) = channel();
+        Thread::spawn(move|| {
+            let mut conn = acceptor.accept().unwrap();
+            loop {
+                match conn.read_mux_framed_tmsg() {
+                    Err(_) => break,
+                    Ok(msg) => if tx.send(msg).is_err() { break },
+                }
+            }
+        });
+
+        let session = ClientSession::connect(addr.as_slice()).unwrap();
+        let discard = Discard::new();
+        let d = discard.clone();
+        Thread::spawn(move|| {
+            sleep(Duration::milliseconds(50));
+            d.discard("client went away");
+        });
+        let err = session.call_discardable(&dispatch(b"mom"), &discard).unwrap_err();
+        assert_eq!(err.desc, "request discarded");
+        assert!(discard.is_discarded());
+        // the tag is held until the peer answers it.
+        assert_eq!(session.load(), 1);
+
+        let (tag, _) = rx.recv().unwrap();
+        assert_eq!(rx.recv().unwrap(), (Tag(0, 0, 0), Tmsg::Discarded(tag, "client went away".to_string())));
+
+        // calls made once discarded are abandoned as soon as they're sent.
+        assert!(session.call_discardable(&dispatch(b"mom"), &discard).is_err());
+        let (tag, _) = rx.recv().unwrap();
+        assert_eq!(rx.recv().unwrap(), (Tag(0, 0, 0), Tmsg::Discarded(tag, "client went away".to_string())));
+    }
+}
@@ -0,0 +1,129 @@
+//! Context for the library's log records.
+//!
+//! Sessions and requests are logged through the `log` crate.  Each record
+//! begins with a `Span`: `key=value` fields naming what it concerns, so
+//! records about one session, or one of its tags, can be found together:
+//!
+//!     session=3 side=server peer=127.0.0.1:50047 established
+//!     session=3 side=server peer=127.0.0.1:50047 init_version=1 headers="[tls=on]"
+//!     session=3 side=server peer=127.0.0.1:50047 tag=7 type=Tdispatch dst=/s/users outcome=ok latency_us=412
+//!     session=3 side=server peer=127.0.0.1:50047 closed: end of file
+//!
+//! Sessions are logged as they're established and closed, at `info`, and
+//! requests as they complete, at `debug`.  A session that fails to decode
+//! its peer's frames is logged at `warn`, with the offset within the frame
+//! at which decoding failed.
+
+#[allow(unstable)]
+
+use std::fmt;
+use std::old_io::{IoError, InvalidInput};
+use std::sync::atomic::{AtomicUint, ATOMIC_UINT_INIT, Ordering};
+
+use proto::{Rmsg, Tag, Tmsg};
+use transport::Transport;
+
+static SESSION_IDS: AtomicUint = ATOMIC_UINT_INIT;
+
+/// An id for a new session, client or server, unique within the process.
+pub fn session_id() -> usize {
+    SESSION_IDS.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct Span {
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Span {
+    pub fn new() -> Span {
+        Span { fields: Vec::new() }
+    }
+
+    /// This span's fields followed by `key=value`.
+    pub fn with<V: fmt::Display>(&self, key: &'static str, value: V) -> Span {
+        let mut fields = self.fields.clone();
+        fields.push((key, value.to_string()));
+        Span { fields: fields }
+    }
+
+    /// This span followed by a request's tag, type and destination.
+    pub fn request(&self, tag: &Tag, msg: &Tmsg) -> Span {
+        let span = self.with("tag", tag.to_u32()).with("type", format!("{:?}", msg.get_type()));
+        match *msg {
+            Tmsg::Dispatch(_, ref dst, _, _) => span.with("dst", dst),
+            _ => span,
+        }
+    }
+
+    /// Logs that the session this span describes has ended, because of
+    /// `ioe`.  Decoding errors are warned of; anything else is how sessions
+    /// usually end.
+    pub fn closed(&self, ioe: &IoError) {
+        match ioe.kind {
+            InvalidInput => warn!("{} failed: {}", self, ioe),
+            _ => info!("{} closed: {}", self, ioe),
+        }
+    }
+}
+
+/// Fields are separated by spaces.  Values that are empty or contain
+/// spaces, quotes or `=` are quoted.
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let shown: Vec<String> = self.fields.iter().map(|&(key, ref val)| {
+            let plain = !val.is_empty() && !val.chars().any(|c| c.is_whitespace() || c == '"' || c == '=');
+            if plain { format!("{}={}", key, val) } else { format!("{}={:?}", key, val) }
+        }).collect();
+        f.write_str(shown.connect(" ").as_slice())
+    }
+}
+
+/// How a request was answered: ok, error, nack or rerr.
+pub fn outcome(rsp: &Rmsg) -> &'static str {
+    match *rsp {
+        Rmsg::ReqOk(_) | Rmsg::DispatchOk(_, _) => "ok",
+        Rmsg::ReqError(_) | Rmsg::DispatchError(_, _) => "error",
+        Rmsg::ReqNack | Rmsg::DispatchNack(_) => "nack",
+        Rmsg::Err(_) => "rerr",
+        _ => "ok",
+    }
+}
+
+/// Describes a connection's peer: its address, or its credentials for local
+/// connections, and the identity it authenticated with, if any.
+pub fn describe_peer<T: Transport>(conn: &T) -> String {
+    let addr = match (conn.peer_addr(), conn.peer_credentials()) {
+        (Some(addr), _) => addr,
+        (None, Some(creds)) => format!("pid={} uid={}", creds.pid, creds.uid),
+        (None, None) => "-".to_string(),
+    };
+    match conn.peer_identity() {
+        None => addr,
+        Some(id) => format!("{} ({})", addr, id.subject),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use misc::Dtab;
+    use proto::{Tag, Tmsg};
+    use super::{Span, session_id};
+
+    #[test]
+    fn test_display() {
+        let session = Span::new().with("session", 3).with("peer", "pid=10 uid=0");
+        assert_eq!(session.to_string(), "session=3 peer=\"pid=10 uid=0\"".to_string());
+
+        let req = Tmsg::Dispatch(Vec::new(), "/s/users".to_string(), Dtab::empty(), Vec::new());
+        assert_eq!(session.request(&Tag(0, 0, 7), &req).with("outcome", "").to_string(),
+                   "session=3 peer=\"pid=10 uid=0\" tag=7 type=Tdispatch dst=/s/users outcome=\"\"".to_string());
+        assert_eq!(Span::new().request(&Tag(0, 1, 0), &Tmsg::Ping).to_string(), "tag=256 type=Tping".to_string());
+    }
+
+    #[test]
+    fn test_session_id() {
+        let id = session_id();
+        assert!(session_id() > id);
+    }
+}
@@ -0,0 +1,512 @@
+//! TLS through OpenSSL, for `tls::connect` and `tls::accept`.
+//!
+//! Enabled by the `ssl` feature.  Both sides present a certificate and
+//! verify their peer's against a CA file, so a server's handlers see the
+//! client's certificate subject and DNS names as `Request::peer_identity`.
+//! Clients also check that the server's certificate names the host they
+//! meant to reach: among its subject alternative names, or as its common
+//! name if it lists no DNS names.
+
+#[allow(unstable)]
+
+use libc::{c_int, c_long, c_void};
+use std::ascii::AsciiExt;
+use std::cmp;
+use std::mem;
+use std::old_io::{IoResult, IoError, OtherIoError, ResourceUnavailable, Reader, Writer};
+use std::old_io::net::ip::IpAddr;
+use std::old_io::net::tcp::TcpStream;
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+use openssl::nid::Nid;
+use openssl::ssl::{SslContext, SslMethod, SslStream, SslVerifyMode};
+use openssl::ssl::error::SslError;
+use openssl::x509::{X509, X509FileType};
+
+use tls::{TlsAcceptor, TlsConnector};
+use transport::{PeerIdentity, Transport};
+
+/// Performs either side of a handshake with the same certificate and CAs.
+pub struct OpenSsl {
+    ctx: SslContext,
+}
+
+impl OpenSsl {
+    /// Presents the PEM certificate `cert` with the private key `key`, and
+    /// trusts peers whose certificates are signed by a CA in `ca`.
+    pub fn new(cert: &Path, key: &Path, ca: &Path) -> IoResult<OpenSsl> {
+        let mut ctx = match SslContext::new(SslMethod::Sslv23) {
+            Err(e) => return Err(ssl_error(e)),
+            Ok(ctx) => ctx,
+        };
+        ctx.set_verify(SslVerifyMode::SslVerifyPeer, None);
+        let configured = check(ctx.set_certificate_file(cert, X509FileType::PEM))
+            .and_then(|_| check(ctx.set_private_key_file(key, X509FileType::PEM)))
+            .and_then(|_| check(ctx.check_private_key()))
+            .and_then(|_| check(ctx.set_CA_file(ca)));
+        configured.map(|_| OpenSsl { ctx: ctx })
+    }
+}
+
+impl TlsConnector for OpenSsl {
+    type Stream = SslTransport;
+
+    /// Fails unless the server's certificate names `host`.
+    fn connect(&self, conn: TcpStream, host: &str) -> IoResult<SslTransport> {
+        let mut socket = conn.clone();
+        let connected = SslStream::new(&self.ctx, Bridge::new(conn)).map_err(ssl_error)
+            .and_then(|s| SslTransport::new(s, socket.clone()))
+            .and_then(|t| if t.names_host(host) {
+                Ok(t)
+            } else {
+                Err(IoError {
+                    kind: OtherIoError,
+                    desc: "server certificate doesn't name the host",
+                    detail: Some(host.to_string()),
+                })
+            });
+        if connected.is_err() {
+            socket.close();
+        }
+        connected
+    }
+}
+
+impl TlsAcceptor for OpenSsl {
+    type Stream = SslTransport;
+
+    /// Clients must present a certificate.
+    fn accept(&self, conn: TcpStream) -> IoResult<SslTransport> {
+        let socket = conn.clone();
+        SslStream::new_server(&self.ctx, Bridge::new(conn)).map_err(ssl_error)
+            .and_then(|s| SslTransport::new(s, socket))
+    }
+}
+
+/// The stream beneath an `SslStream`.  During the handshake it's the
+/// socket.  Afterwards OpenSSL sees only buffers: ciphertext read from the
+/// socket is queued in `incoming`, and what OpenSSL writes is queued in
+/// `outgoing` to be sent, so no thread waits on the socket while holding
+/// the connection.
+struct Bridge {
+    socket: TcpStream,
+    buffered: bool,
+    incoming: Vec<u8>,
+    outgoing: Vec<u8>,
+}
+
+impl Bridge {
+    fn new(socket: TcpStream) -> Bridge {
+        Bridge { socket: socket, buffered: false, incoming: Vec::new(), outgoing: Vec::new() }
+    }
+}
+
+/// Reads fail with ResourceUnavailable while nothing is queued, which
+/// `SslStream` passes on without losing what it has read so far.
+impl Reader for Bridge {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if !self.buffered {
+            return self.socket.read(buf);
+        }
+        if self.incoming.is_empty() {
+            return Err(IoError { kind: ResourceUnavailable, desc: "no ciphertext queued", detail: None });
+        }
+        let n = cmp::min(buf.len(), self.incoming.len());
+        for i in range(0, n) {
+            buf[i] = self.incoming[i];
+        }
+        self.incoming = self.incoming.slice_from(n).to_vec();
+        Ok(n)
+    }
+}
+
+impl Writer for Bridge {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        if self.buffered {
+            self.outgoing.push_all(buf);
+            Ok(())
+        } else {
+            self.socket.write(buf)
+        }
+    }
+}
+
+/// An OpenSSL connection shared by a session's threads.
+///
+/// OpenSSL connections can't be used from two threads at once, so clones
+/// share one behind a lock, but it's only held while OpenSSL works on
+/// buffers.  A read waits for ciphertext holding only `reader`, and a
+/// write sends it holding only `writer`, so neither direction waits on the
+/// other's socket I/O.  Ciphertext is taken from the connection and sent
+/// under `writer`, keeping it in the order OpenSSL produced it; `writer`
+/// is always locked before `ssl`.
+#[derive(Clone)]
+pub struct SslTransport {
+    ssl: Arc<Mutex<SslStream<Bridge>>>,
+    reader: Arc<Mutex<TcpStream>>,
+    writer: Arc<Mutex<TcpStream>>,
+    // a clone of the socket, for closing it while a read waits.
+    socket: TcpStream,
+    // the peer certificate's common name and subject alternative names.
+    cn: String,
+    dns_names: Vec<String>,
+    ips: Vec<Vec<u8>>,
+}
+
+impl SslTransport {
+    fn new(mut stream: SslStream<Bridge>, socket: TcpStream) -> IoResult<SslTransport> {
+        let cert = match stream.get_peer_certificate() {
+            None => return Err(IoError {
+                kind: OtherIoError,
+                desc: "peer presented no certificate",
+                detail: None,
+            }),
+            Some(cert) => cert,
+        };
+        let cn = cert.subject_name().text_by_nid(Nid::CN).map(|cn| cn.to_string()).unwrap_or(String::new());
+        let (dns_names, ips) = alt_names(&cert);
+        stream.get_inner().buffered = true;
+        Ok(SslTransport {
+            ssl: Arc::new(Mutex::new(stream)),
+            reader: Arc::new(Mutex::new(socket.clone())),
+            writer: Arc::new(Mutex::new(socket.clone())),
+            socket: socket,
+            cn: cn,
+            dns_names: dns_names,
+            ips: ips,
+        })
+    }
+
+    fn names_host(&self, host: &str) -> bool {
+        names_host(host, self.cn.as_slice(), self.dns_names.as_slice(), self.ips.as_slice())
+    }
+
+    /// Sends the ciphertext OpenSSL has produced.
+    fn send_queued(&self) -> IoResult<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let queued = mem::replace(&mut self.ssl.lock().unwrap().get_inner().outgoing, Vec::new());
+        if queued.is_empty() { Ok(()) } else { writer.write(queued.as_slice()) }
+    }
+}
+
+impl Reader for SslTransport {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let mut socket = self.reader.lock().unwrap();
+        let mut ciphertext = [0u8; 16 * 1024];
+        loop {
+            let read = self.ssl.lock().unwrap().read(buf);
+            // reading may answer the peer, e.g. with an alert.
+            match self.send_queued() {
+                Err(ioe) => return Err(ioe),
+                Ok(_) => (),
+            }
+            match read {
+                Err(ref ioe) if ioe.kind == ResourceUnavailable => (),
+                read => return read,
+            }
+            match socket.read(&mut ciphertext) {
+                Err(ioe) => return Err(ioe),
+                Ok(n) => self.ssl.lock().unwrap().get_inner().incoming.push_all(ciphertext.slice_to(n)),
+            }
+        }
+    }
+}
+
+impl Writer for SslTransport {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let queued = {
+            let mut ssl = self.ssl.lock().unwrap();
+            match ssl.write(buf).and_then(|_| ssl.flush()) {
+                Err(ioe) => return Err(ioe),
+                Ok(_) => mem::replace(&mut ssl.get_inner().outgoing, Vec::new()),
+            }
+        };
+        writer.write(queued.as_slice())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.send_queued()
+    }
+}
+
+impl Transport for SslTransport {
+    fn close(&mut self) {
+        self.socket.close()
+    }
+
+    fn peer_identity(&self) -> Option<PeerIdentity> {
+        Some(PeerIdentity { subject: format!("CN={}", self.cn), dns_names: self.dns_names.clone() })
+    }
+
+    fn peer_addr(&self) -> Option<String> {
+        self.socket.peer_addr()
+    }
+}
+
+/// True if a certificate with the common name `cn` and the subject
+/// alternative names `dns_names` and `ips` names `host`.  The common name
+/// is only consulted if there are no DNS names, and a wildcard matches one
+/// whole leftmost label.
+fn names_host(host: &str, cn: &str, dns_names: &[String], ips: &[Vec<u8>]) -> bool {
+    match host.parse::<IpAddr>() {
+        None => (),
+        Some(ip) => {
+            let ip = ip_bytes(ip);
+            return ips.iter().any(|a| *a == ip);
+        },
+    }
+    let host = host.trim_right_matches('.').to_ascii_lowercase();
+    if dns_names.is_empty() {
+        return matches_name(host.as_slice(), cn);
+    }
+    dns_names.iter().any(|name| matches_name(host.as_slice(), name.as_slice()))
+}
+
+fn matches_name(host: &str, name: &str) -> bool {
+    let name = name.trim_right_matches('.').to_ascii_lowercase();
+    if !name.starts_with("*.") {
+        return !name.is_empty() && host == name.as_slice();
+    }
+    // a wildcard must be followed by at least two labels.
+    let suffix = name.slice_from(1);
+    match host.find('.') {
+        Some(dot) if dot > 0 && suffix.slice_from(1).contains(".") => host.slice_from(dot) == suffix,
+        _ => false,
+    }
+}
+
+fn ip_bytes(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::Ipv4Addr(a, b, c, d) => vec![a, b, c, d],
+        IpAddr::Ipv6Addr(a, b, c, d, e, f, g, h) => {
+            [a, b, c, d, e, f, g, h].iter().flat_map(|w| vec![(*w >> 8) as u8, *w as u8].into_iter()).collect()
+        },
+    }
+}
+
+// subject alternative names, which the openssl crate doesn't expose.
+static NID_SUBJECT_ALT_NAME: c_int = 85;
+static GEN_DNS: c_int = 2;
+static GEN_IPADD: c_int = 7;
+
+#[repr(C)]
+struct Asn1String {
+    length: c_int,
+    typ: c_int,
+    data: *const u8,
+    flags: c_long,
+}
+
+#[repr(C)]
+struct GeneralName {
+    typ: c_int,
+    d: *const Asn1String,
+}
+
+#[link(name = "crypto")]
+extern {
+    fn X509_get_ext_d2i(x: *mut c_void, nid: c_int, crit: *mut c_int, idx: *mut c_int) -> *mut c_void;
+    fn sk_num(st: *const c_void) -> c_int;
+    fn sk_value(st: *const c_void, i: c_int) -> *mut c_void;
+    fn GENERAL_NAMES_free(names: *mut c_void);
+}
+
+/// The DNS names and IP addresses among a certificate's subject
+/// alternative names.
+fn alt_names(cert: &X509) -> (Vec<String>, Vec<Vec<u8>>) {
+    let (mut dns_names, mut ips) = (Vec::new(), Vec::new());
+    unsafe {
+        let handle = cert.get_handle() as *mut c_void;
+        let names = X509_get_ext_d2i(handle, NID_SUBJECT_ALT_NAME, ptr::null_mut(), ptr::null_mut());
+        if names.is_null() {
+            return (dns_names, ips);
+        }
+        for i in range(0, sk_num(names)) {
+            let name = &*(sk_value(names, i) as *const GeneralName);
+            if name.d.is_null() {
+                continue;
+            }
+            let bytes = slice::from_raw_buf(&(*name.d).data, (*name.d).length as usize).to_vec();
+            if name.typ == GEN_DNS {
+                match String::from_utf8(bytes) {
+                    Err(_) => (),
+                    Ok(dns) => dns_names.push(dns),
+                }
+            } else if name.typ == GEN_IPADD {
+                ips.push(bytes);
+            }
+        }
+        GENERAL_NAMES_free(names);
+    }
+    (dns_names, ips)
+}
+
+fn check(err: Option<SslError>) -> IoResult<()> {
+    match err {
+        None => Ok(()),
+        Some(e) => Err(ssl_error(e)),
+    }
+}
+
+fn ssl_error(e: SslError) -> IoError {
+    match e {
+        SslError::StreamError(ioe) => ioe,
+        e => IoError {
+            kind: OtherIoError,
+            desc: "tls error",
+            detail: Some(format!("{:?}", e)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::old_io::{Acceptor, Listener, File, TempDir};
+    use std::old_io::net::tcp::{TcpListener, TcpStream};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread::Thread;
+
+    use openssl::crypto::hash::HashType;
+    use openssl::x509::X509Generator;
+    use openssl::x509::extension::{AltNameOption, Extension};
+
+    use misc::Dtab;
+    use proto::{Tmsg, Rmsg};
+    use server::{Request, Server};
+    use session::ClientSession;
+    use tls::{TlsLevel, accept, connect};
+    use super::{OpenSsl, names_host};
+
+    /// Generates a self-signed certificate for `cn`, with the subject
+    /// alternative names `sans`, in `dir`, returning the paths of the
+    /// certificate and its key.  Self-signed certificates are their own
+    /// CAs.
+    fn generate(dir: &TempDir, cn: &str, sans: Vec<(AltNameOption, String)>) -> (Path, Path) {
+        let mut gen = X509Generator::new()
+            .set_bitlength(2048)
+            .set_valid_period(1)
+            .set_CN(cn)
+            .set_sign_hash(HashType::SHA256);
+        if !sans.is_empty() {
+            gen = gen.add_extension(Extension::SubjectAltName(sans));
+        }
+        let (cert, key) = gen.generate().unwrap();
+        let cert_path = dir.path().join(format!("{}.crt", cn));
+        let key_path = dir.path().join(format!("{}.key", cn));
+        cert.write_pem(&mut File::create(&cert_path).unwrap()).unwrap();
+        key.write_pem(&mut File::create(&key_path).unwrap()).unwrap();
+        (cert_path, key_path)
+    }
+
+    fn server_names() -> Vec<(AltNameOption, String)> {
+        vec![(AltNameOption::DNS, "localhost".to_string()), (AltNameOption::IPAddress, "127.0.0.1".to_string())]
+    }
+
+    /// Answers with the client's subject and DNS names.
+    fn whoami(req: Request) -> Rmsg {
+        let id = req.peer_identity.map(|id| format!("{} {}", id.subject, id.dns_names.connect(",")));
+        Rmsg::DispatchOk(Vec::new(), id.unwrap_or("anonymous".to_string()).into_bytes())
+    }
+
+    /// Serves `whoami` on an ephemeral port, reporting each connection's
+    /// handshake.
+    fn serve(ssl: OpenSsl) -> (String, Receiver<bool>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("{}", listener.socket_name().unwrap());
+        let mut acceptor = listener.listen().unwrap();
+        let (tx, rx) = channel();
+        Thread::spawn(move|| {
+            let server = Server::new(whoami);
+            for conn in acceptor.incoming() {
+                match conn.and_then(|c| accept(c, TlsLevel::Required, &ssl)) {
+                    Err(_) => { tx.send(false).ok(); },
+                    Ok(conn) => {
+                        tx.send(true).ok();
+                        server.serve(conn);
+                    },
+                }
+            }
+        });
+        (addr, rx)
+    }
+
+    #[test]
+    fn test_mutual_tls() {
+        let dir = TempDir::new("mux-ssl").unwrap();
+        let (server_cert, server_key) = generate(&dir, "server", server_names());
+        let (client_cert, client_key) = generate(&dir, "client", vec![(AltNameOption::DNS, "client.test".to_string())]);
+
+        let (addr, handshakes) = serve(OpenSsl::new(&server_cert, &server_key, &client_cert).unwrap());
+        let ssl = OpenSsl::new(&client_cert, &client_key, &server_cert).unwrap();
+        let conn = connect(TcpStream::connect(addr.as_slice()).unwrap(), "localhost", TlsLevel::Required, &ssl).unwrap();
+        assert!(conn.is_tls());
+        assert!(handshakes.recv().unwrap());
+
+        let session = ClientSession::new(conn);
+        let req = Tmsg::Dispatch(Vec::new(), "/".to_string(), Dtab::empty(), Vec::new());
+        assert_eq!(session.call(&req).unwrap(), Rmsg::DispatchOk(Vec::new(), b"CN=client client.test".to_vec()));
+        // requests and responses interleave on the shared connection, and
+        // concurrent callers don't wait on one another's reads.
+        let threads: Vec<_> = range(0, 4).map(|_| {
+            let session = session.clone();
+            Thread::scoped(move|| for _ in range(0, 50) { session.ping().unwrap(); })
+        }).collect();
+        drop(threads);
+    }
+
+    #[test]
+    fn test_hostname() {
+        let dir = TempDir::new("mux-ssl").unwrap();
+        let (server_cert, server_key) = generate(&dir, "server", server_names());
+        let (client_cert, client_key) = generate(&dir, "client", Vec::new());
+
+        let (addr, handshakes) = serve(OpenSsl::new(&server_cert, &server_key, &client_cert).unwrap());
+        let ssl = OpenSsl::new(&client_cert, &client_key, &server_cert).unwrap();
+        for host in ["localhost", "127.0.0.1"].iter() {
+            assert!(connect(TcpStream::connect(addr.as_slice()).unwrap(), *host, TlsLevel::Required, &ssl).is_ok());
+            assert!(handshakes.recv().unwrap());
+        }
+        // a trusted certificate for some other host is refused.
+        for host in ["server", "example.com", "127.0.0.2"].iter() {
+            assert!(connect(TcpStream::connect(addr.as_slice()).unwrap(), *host, TlsLevel::Required, &ssl).is_err());
+            handshakes.recv().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_untrusted_client() {
+        let dir = TempDir::new("mux-ssl").unwrap();
+        let (server_cert, server_key) = generate(&dir, "server", server_names());
+        let (client_cert, _) = generate(&dir, "client", Vec::new());
+        let (other_cert, other_key) = generate(&dir, "other", Vec::new());
+
+        let (addr, handshakes) = serve(OpenSsl::new(&server_cert, &server_key, &client_cert).unwrap());
+        let ssl = OpenSsl::new(&other_cert, &other_key, &server_cert).unwrap();
+        connect(TcpStream::connect(addr.as_slice()).unwrap(), "localhost", TlsLevel::Required, &ssl).ok();
+        assert!(!handshakes.recv().unwrap());
+    }
+
+    #[test]
+    fn test_names_host() {
+        let dns = vec!["api.example.com".to_string(), "*.svc.example.com".to_string()];
+        let ips = vec![vec![10, 0, 0, 1], vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]];
+        assert!(names_host("api.example.com", "ignored", dns.as_slice(), ips.as_slice()));
+        assert!(names_host("API.Example.com.", "ignored", dns.as_slice(), ips.as_slice()));
+        assert!(names_host("users.svc.example.com", "ignored", dns.as_slice(), ips.as_slice()));
+        assert!(!names_host("a.users.svc.example.com", "ignored", dns.as_slice(), ips.as_slice()));
+        assert!(!names_host("svc.example.com", "ignored", dns.as_slice(), ips.as_slice()));
+        // the common name is ignored once there are DNS names.
+        assert!(!names_host("ignored", "ignored", dns.as_slice(), ips.as_slice()));
+        assert!(names_host("10.0.0.1", "ignored", dns.as_slice(), ips.as_slice()));
+        assert!(names_host("::1", "ignored", dns.as_slice(), ips.as_slice()));
+        assert!(!names_host("10.0.0.2", "10.0.0.2", dns.as_slice(), ips.as_slice()));
+
+        assert!(names_host("server", "server", &[], &[]));
+        assert!(!names_host("other", "server", &[], &[]));
+        assert!(!names_host("example.com", "*.com", &[], &[]));
+        assert!(!names_host("", "", &[], &[]));
+    }
+}
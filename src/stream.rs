@@ -0,0 +1,583 @@
+//! Streaming message bodies.
+//!
+//! A frame whose tag has its high bit set is a fragment: the message
+//! continues in subsequent frames with the same tag, up to one without the
+//! bit, and the fragments' payloads concatenate to form it.  A dispatch
+//! body may thus be sent as it's produced and consumed as it arrives.
+//!
+//! A `BodyWriter` blocks while the connection's write buffer is full.  A
+//! session's reader never blocks on a `BodyReader`, so that one slow body
+//! doesn't stall the other requests on the connection: once a reader falls
+//! `WINDOW` bytes behind, its body fails with ResourceUnavailable and the
+//! rest of it is dropped.  Readers of large bodies must keep up with them.
+
+#[allow(unstable)]
+
+use std::collections::HashMap;
+use std::mem;
+use std::old_io::{IoResult, IoError, MemWriter, Reader, Writer};
+use std::old_io::{Closed, ConnectionReset, EndOfFile, InvalidInput, ResourceUnavailable, standard_error};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUint, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use framebuf::{FrameBuf, decode};
+use proto::{Msg, Tag, Tmsg, Rmsg};
+use reader::MAX_FRAME;
+use transport::Transport;
+use writer::{MuxWriter, frame_fragment, write_locked};
+
+/// The most body bytes a `BodyWriter` sends in one fragment.
+pub static MAX_FRAGMENT: usize = 32 * 1024;
+
+/// The most body bytes received ahead of a `BodyReader`: sixteen full
+/// fragments.
+pub static WINDOW: usize = 16 * 32 * 1024;
+
+/// What a `Reassembler` read.
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub enum Frame {
+    /// A complete message, which may have been fragmented.
+    Whole(Msg),
+
+    /// The start of a streamed message, with as much of its body as
+    /// arrived with its header.
+    Start(Msg),
+
+    /// More of a streamed message's body, and whether it's the last.
+    Body(Tag, Vec<u8>, bool),
+}
+
+enum Partial {
+    // fragments (type, tag and payload) whose header is incomplete, and
+    // their length when they last failed to decode.
+    Header(Vec<u8>, usize),
+    // a message being assembled in full, and its size so far.
+    Whole(Msg, usize),
+    Streaming,
+}
+
+/// The most messages a `Reassembler` assembles at once, by default.
+pub static MAX_PARTIAL: usize = 64;
+
+/// The most bytes a `Reassembler` holds across the messages it's
+/// assembling, by default.
+pub static MAX_REASSEMBLED: usize = 64 * 1024 * 1024;
+
+/// Reassembles fragmented messages read from a connection.
+///
+/// What a peer may make it hold is bounded: each message reassembled in
+/// full, or whose header is incomplete, may reach `max_message` bytes, and
+/// `max_reassembled` bytes in all; and at most `max_partial` tags may be
+/// fragmented at once, including those being streamed.  Exceeding any of
+/// these fails the read with InvalidInput.
+pub struct Reassembler {
+    partial: HashMap<u32, Partial>,
+    // the bytes held across partial messages.
+    held: usize,
+    max_message: usize,
+    max_partial: usize,
+    max_reassembled: usize,
+}
+
+impl Reassembler {
+    /// Messages may reach `MAX_FRAME` bytes.
+    pub fn new() -> Reassembler {
+        Reassembler {
+            partial: HashMap::new(),
+            held: 0,
+            max_message: MAX_FRAME,
+            max_partial: MAX_PARTIAL,
+            max_reassembled: MAX_REASSEMBLED,
+        }
+    }
+
+    pub fn max_message(mut self, max_message: usize) -> Reassembler {
+        self.max_message = max_message;
+        self
+    }
+
+    pub fn max_partial(mut self, max_partial: usize) -> Reassembler {
+        self.max_partial = max_partial;
+        self
+    }
+
+    pub fn max_reassembled(mut self, max_reassembled: usize) -> Reassembler {
+        self.max_reassembled = max_reassembled;
+        self
+    }
+
+    /// Reads frames until one completes a message or starts or continues a
+    /// streamed one.  Once the header of a fragmented message with a body
+    /// arrives, `stream` decides whether its body is streamed or assembled.
+    pub fn read<R: Reader, F: FnMut(&Msg) -> bool>(&mut self, frames: &mut FrameBuf<R>, mut stream: F) -> IoResult<Frame> {
+        loop {
+            let frame = match frames.next_frame() {
+                Err(ioe) => return Err(ioe),
+                Ok(frame) => frame,
+            };
+            let more = frame.tag().is_fragment();
+            let tag = frame.tag().with_fragment(false);
+            let key = tag.to_u32();
+
+            let partial = match self.partial.remove(&key) {
+                // unfragmented messages are decoded from the frame buffer.
+                None if !more => return frame.to_msg().map(Frame::Whole),
+                None => {
+                    if self.partial.len() >= self.max_partial {
+                        return Err(limit_error("too many fragmented messages", self.max_partial));
+                    }
+                    match self.hold(0, frame.bytes.len()) {
+                        Err(ioe) => return Err(ioe),
+                        Ok(_) => (),
+                    }
+                    let mut buf = frame.bytes.to_vec();
+                    buf[1] &= 0x7f;
+                    Partial::Header(buf, 0)
+                },
+
+                Some(Partial::Header(mut buf, tried)) => {
+                    match self.hold(buf.len(), frame.payload().len()) {
+                        Err(ioe) => return Err(ioe),
+                        Ok(_) => (),
+                    }
+                    buf.push_all(frame.payload());
+                    if !more {
+                        self.held -= buf.len();
+                        return decode(buf.as_slice()).map(Frame::Whole);
+                    }
+                    Partial::Header(buf, tried)
+                },
+
+                Some(Partial::Whole(mut msg, size)) => {
+                    let n = frame.payload().len();
+                    match self.hold(size, n) {
+                        Err(ioe) => return Err(ioe),
+                        Ok(_) => (),
+                    }
+                    append_body(&mut msg, frame.payload());
+                    if !more {
+                        self.held -= size + n;
+                        return Ok(Frame::Whole(msg));
+                    }
+                    Partial::Whole(msg, size + n)
+                },
+
+                Some(Partial::Streaming) => {
+                    if more {
+                        self.partial.insert(key, Partial::Streaming);
+                    }
+                    return Ok(Frame::Body(tag, frame.payload().to_vec(), !more));
+                },
+            };
+
+            // a header is complete once it decodes; messages without bodies
+            // are assembled in full.  A header that doesn't decode is tried
+            // again once its fragments have doubled, so one sent a byte at a
+            // time isn't decoded once per byte.
+            let partial = match partial {
+                Partial::Header(buf, tried) if buf.len() >= 2 * tried => {
+                    let len = buf.len();
+                    match decode(buf.as_slice()) {
+                        Ok(ref msg) if !has_body(msg) => Partial::Header(buf, len),
+                        Ok(msg) => {
+                            if stream(&msg) {
+                                self.held -= len;
+                                self.partial.insert(key, Partial::Streaming);
+                                return Ok(Frame::Start(msg));
+                            }
+                            Partial::Whole(msg, len)
+                        },
+                        Err(_) => Partial::Header(buf, len),
+                    }
+                },
+                partial => partial,
+            };
+            self.partial.insert(key, partial);
+        }
+    }
+
+    /// Accounts for `n` more bytes of a message already `size` bytes long.
+    fn hold(&mut self, size: usize, n: usize) -> IoResult<()> {
+        if size + n > self.max_message {
+            return Err(limit_error("fragmented message too large", self.max_message));
+        }
+        if self.held + n > self.max_reassembled {
+            return Err(limit_error("too many bytes in fragmented messages", self.max_reassembled));
+        }
+        self.held += n;
+        Ok(())
+    }
+}
+
+fn limit_error(desc: &'static str, limit: usize) -> IoError {
+    IoError { kind: InvalidInput, desc: desc, detail: Some(format!("limit {}", limit)) }
+}
+
+fn has_body(msg: &Msg) -> bool {
+    match *msg {
+        Msg::Tx(_, Tmsg::Req(_, _)) | Msg::Tx(_, Tmsg::Dispatch(_, _, _, _)) => true,
+        Msg::Rx(_, Rmsg::ReqOk(_)) | Msg::Rx(_, Rmsg::DispatchOk(_, _)) => true,
+        _ => false,
+    }
+}
+
+fn append_body(msg: &mut Msg, bytes: &[u8]) {
+    match *msg {
+        Msg::Tx(_, Tmsg::Req(_, ref mut body)) => body.push_all(bytes),
+        Msg::Tx(_, Tmsg::Dispatch(_, _, _, ref mut body)) => body.push_all(bytes),
+        Msg::Rx(_, Rmsg::ReqOk(ref mut body)) => body.push_all(bytes),
+        Msg::Rx(_, Rmsg::DispatchOk(_, ref mut body)) => body.push_all(bytes),
+        _ => (),
+    }
+}
+
+/// Separates a response from its body, if it has one.
+pub fn split_body(mut rsp: Rmsg) -> (Rmsg, Vec<u8>) {
+    let body = match rsp {
+        Rmsg::ReqOk(ref mut body) => mem::replace(body, Vec::new()),
+        Rmsg::DispatchOk(_, ref mut body) => mem::replace(body, Vec::new()),
+        _ => Vec::new(),
+    };
+    (rsp, body)
+}
+
+/// Passes a body's chunks to its `BodyReader`, an empty chunk marking its
+/// end.
+#[derive(Clone)]
+pub struct BodySender {
+    chunks: Sender<IoResult<Vec<u8>>>,
+    // the bytes sent but not yet received.
+    unread: Arc<AtomicUint>,
+    failed: Arc<AtomicBool>,
+}
+
+impl BodySender {
+    /// Fails the body: its reader sees `ioe` once it has read what was sent
+    /// before, and later chunks are dropped.
+    pub fn fail(&self, ioe: IoError) {
+        if !self.failed.swap(true, Ordering::SeqCst) {
+            self.chunks.send(Err(ioe)).ok();
+        }
+    }
+}
+
+pub fn body_channel() -> (BodySender, BodyReader) {
+    let (tx, rx) = channel();
+    let unread = Arc::new(AtomicUint::new(0));
+    let sender = BodySender { chunks: tx, unread: unread.clone(), failed: Arc::new(AtomicBool::new(false)) };
+    (sender, BodyReader { chunks: rx, unread: unread, buf: Vec::new(), pos: 0, done: false })
+}
+
+/// Passes part of a body to its reader without blocking.  If the reader has
+/// more than `WINDOW` bytes unread, the body fails instead.  Returns false if
+/// the body has failed or the reader has gone away.
+pub fn send_chunk(tx: &BodySender, chunk: Vec<u8>, last: bool) -> bool {
+    if tx.failed.load(Ordering::SeqCst) {
+        return false;
+    }
+    if !chunk.is_empty() {
+        // a chunk always fits an empty window.
+        let unread = tx.unread.fetch_add(chunk.len(), Ordering::SeqCst);
+        if unread > 0 && unread + chunk.len() > WINDOW {
+            tx.fail(IoError {
+                kind: ResourceUnavailable,
+                desc: "body not read fast enough",
+                detail: Some(format!("{} bytes unread", unread)),
+            });
+            return false;
+        }
+        if tx.chunks.send(Ok(chunk)).is_err() {
+            return false;
+        }
+    }
+    !last || tx.chunks.send(Ok(Vec::new())).is_ok()
+}
+
+/// A message body, read as it arrives.
+pub struct BodyReader {
+    chunks: Receiver<IoResult<Vec<u8>>>,
+    unread: Arc<AtomicUint>,
+    buf: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl BodyReader {
+    /// A reader over a body received in full.
+    pub fn whole(body: Vec<u8>) -> BodyReader {
+        let (tx, reader) = body_channel();
+        send_chunk(&tx, body, true);
+        reader
+    }
+
+    /// Blocks until more of the body arrives, returning None at its end.
+    pub fn next_chunk(&mut self) -> IoResult<Option<Vec<u8>>> {
+        if self.pos < self.buf.len() {
+            let rest = self.buf.slice_from(self.pos).to_vec();
+            self.buf.clear();
+            self.pos = 0;
+            return Ok(Some(rest));
+        }
+        if self.done {
+            return Ok(None);
+        }
+
+        match self.chunks.recv() {
+            Err(_) => Err(IoError {
+                kind: ConnectionReset,
+                desc: "body truncated",
+                detail: None,
+            }),
+            Ok(Err(ioe)) => Err(ioe),
+            Ok(Ok(chunk)) => {
+                self.unread.fetch_sub(chunk.len(), Ordering::SeqCst);
+                if chunk.is_empty() {
+                    self.done = true;
+                    Ok(None)
+                } else {
+                    Ok(Some(chunk))
+                }
+            },
+        }
+    }
+}
+
+impl Reader for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if self.pos == self.buf.len() {
+            match self.next_chunk() {
+                Err(ioe) => return Err(ioe),
+                Ok(None) => return Err(standard_error(EndOfFile)),
+                Ok(Some(chunk)) => {
+                    self.buf = chunk;
+                    self.pos = 0;
+                },
+            }
+        }
+
+        let n = if buf.len() < self.buf.len() - self.pos { buf.len() } else { self.buf.len() - self.pos };
+        for i in range(0, n) {
+            buf[i] = self.buf[self.pos + i];
+        }
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Writes a message's body as fragments.  The message's header goes with
+/// the first fragment, and `finish` sends the last.
+pub struct BodyWriter<T> {
+    writer: Arc<Mutex<T>>,
+    msg_type: i8,
+    tag: Tag,
+    header: Option<Vec<u8>>,
+    finished: bool,
+    discarded: Option<Arc<AtomicBool>>,
+}
+
+impl<T: Transport> BodyWriter<T> {
+    /// Streams the body of `msg`, whose own body is ignored.
+    pub fn tmsg(writer: Arc<Mutex<T>>, tag: Tag, msg: &Tmsg) -> BodyWriter<T> {
+        let mut header = MemWriter::new();
+        header.write_mux_tmsg_msg(msg).unwrap();
+        BodyWriter::new(writer, msg.get_type().to_i8(), tag, header.into_inner())
+    }
+
+    /// Streams the body of `msg`, whose own body is ignored.
+    pub fn rmsg(writer: Arc<Mutex<T>>, tag: Tag, msg: &Rmsg) -> BodyWriter<T> {
+        let mut header = MemWriter::new();
+        header.write_mux_rmsg_msg(msg).unwrap();
+        BodyWriter::new(writer, msg.get_type().to_i8(), tag, header.into_inner())
+    }
+
+    fn new(writer: Arc<Mutex<T>>, msg_type: i8, tag: Tag, header: Vec<u8>) -> BodyWriter<T> {
+        BodyWriter {
+            writer: writer,
+            msg_type: msg_type,
+            tag: tag,
+            header: Some(header),
+            finished: false,
+            discarded: None,
+        }
+    }
+
+    /// Fails subsequent writes once `discarded` is set.
+    pub fn discardable(mut self, discarded: Arc<AtomicBool>) -> BodyWriter<T> {
+        self.discarded = Some(discarded);
+        self
+    }
+
+    /// True once any of the message has been sent.
+    pub fn is_started(&self) -> bool {
+        self.header.is_none()
+    }
+
+    /// Sends the last fragment, completing the message.
+    pub fn finish(&mut self) -> IoResult<()> {
+        self.send(&[], false)
+    }
+
+    /// Completes a message that won't be finished, even if it was
+    /// discarded, so that the peer stops reassembling it.  The peer sees a
+    /// truncated body.
+    pub fn abort(&mut self) -> IoResult<()> {
+        if !self.is_started() || self.finished {
+            return Ok(());
+        }
+        self.discarded = None;
+        self.finish()
+    }
+
+    fn send(&mut self, chunk: &[u8], more: bool) -> IoResult<()> {
+        let discarded = self.discarded.as_ref().map_or(false, |d| d.load(Ordering::SeqCst));
+        if discarded || self.finished {
+            return Err(IoError {
+                kind: Closed,
+                desc: if discarded { "message discarded" } else { "message finished" },
+                detail: None,
+            });
+        }
+
+        let payload = match self.header.take() {
+            None => chunk.to_vec(),
+            Some(mut header) => {
+                header.push_all(chunk);
+                header
+            },
+        };
+        self.finished = !more;
+        let (msg_type, tag) = (self.msg_type, self.tag);
+        write_locked(&*self.writer, |buf| frame_fragment(buf, msg_type, &tag, more, payload.as_slice()))
+    }
+}
+
+impl<T: Transport> Writer for BodyWriter<T> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        for chunk in buf.chunks(MAX_FRAGMENT) {
+            match self.send(chunk, true) {
+                Err(ioe) => return Err(ioe),
+                Ok(_) => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::old_io::{BufReader, InvalidInput, MemWriter, Reader, ResourceUnavailable};
+
+    use framebuf::FrameBuf;
+    use misc::Dtab;
+    use proto::{Msg, Tag, Tmsg, Rmsg};
+    use writer::MuxWriter;
+    use super::{Frame, Reassembler, BodyReader, WINDOW, body_channel, send_chunk};
+
+    static TDISPATCH: i8 = 2;
+
+    fn fragments(parts: &[&[u8]]) -> Vec<u8> {
+        let mut header = MemWriter::new();
+        header.write_mux_tmsg_msg(&Tmsg::Dispatch(Vec::new(), "/dst".to_string(), Dtab::empty(), Vec::new())).unwrap();
+        let header = header.into_inner();
+
+        // the header is split across the first two fragments.
+        let mut buf = MemWriter::new();
+        buf.write_mux_fragment(TDISPATCH, &Tag(0, 0, 1), true, header.slice_to(3)).unwrap();
+        buf.write_mux_fragment(TDISPATCH, &Tag(0, 0, 1), true, header.slice_from(3)).unwrap();
+        for (i, part) in parts.iter().enumerate() {
+            buf.write_mux_fragment(TDISPATCH, &Tag(0, 0, 1), i + 1 < parts.len(), *part).unwrap();
+        }
+        buf.into_inner()
+    }
+
+    #[test]
+    fn test_assemble() {
+        let bytes = fragments(&[b"mo", b"m"]);
+        let mut reader = FrameBuf::new(BufReader::new(bytes.as_slice()));
+        assert_eq!(Reassembler::new().read(&mut reader, |_| false).unwrap(),
+                   Frame::Whole(Msg::Tx(Tag(0, 0, 1), Tmsg::Dispatch(Vec::new(), "/dst".to_string(), Dtab::empty(), b"mom".to_vec()))));
+    }
+
+    #[test]
+    fn test_stream() {
+        let bytes = fragments(&[b"mo", b"m"]);
+        let mut reader = FrameBuf::new(BufReader::new(bytes.as_slice()));
+        let mut fragments = Reassembler::new();
+        assert_eq!(fragments.read(&mut reader, |_| true).unwrap(),
+                   Frame::Start(Msg::Tx(Tag(0, 0, 1), Tmsg::Dispatch(Vec::new(), "/dst".to_string(), Dtab::empty(), Vec::new()))));
+        assert_eq!(fragments.read(&mut reader, |_| true).unwrap(), Frame::Body(Tag(0, 0, 1), b"mo".to_vec(), false));
+        assert_eq!(fragments.read(&mut reader, |_| true).unwrap(), Frame::Body(Tag(0, 0, 1), b"m".to_vec(), true));
+    }
+
+    #[test]
+    fn test_interleaved() {
+        let mut buf = MemWriter::new();
+        buf.write_mux_fragment(-2, &Tag(0, 0, 1), true, &[0, 0, 0, b'm']).unwrap();
+        buf.write_mux_framed_rmsg(&Tag(0, 0, 2), &Rmsg::Ping).unwrap();
+        buf.write_mux_fragment(-2, &Tag(0, 0, 1), false, b"om").unwrap();
+        let bytes = buf.into_inner();
+
+        let mut reader = FrameBuf::new(BufReader::new(bytes.as_slice()));
+        let mut fragments = Reassembler::new();
+        assert_eq!(fragments.read(&mut reader, |_| false).unwrap(), Frame::Whole(Msg::Rx(Tag(0, 0, 2), Rmsg::Ping)));
+        assert_eq!(fragments.read(&mut reader, |_| false).unwrap(),
+                   Frame::Whole(Msg::Rx(Tag(0, 0, 1), Rmsg::DispatchOk(Vec::new(), b"mom".to_vec()))));
+    }
+
+    #[test]
+    fn test_body_reader() {
+        let mut body = BodyReader::whole(b"mom".to_vec());
+        assert_eq!(body.read_exact(2).unwrap(), b"mo".to_vec());
+        assert_eq!(body.read_to_end().unwrap(), b"m".to_vec());
+        assert_eq!(BodyReader::whole(Vec::new()).next_chunk().unwrap(), None);
+    }
+
+    #[test]
+    fn test_byte_fragments() {
+        let mut header = MemWriter::new();
+        header.write_mux_tmsg_msg(&Tmsg::Dispatch(Vec::new(), "/dst".to_string(), Dtab::empty(), Vec::new())).unwrap();
+        let mut buf = MemWriter::new();
+        for byte in header.into_inner().iter() {
+            buf.write_mux_fragment(TDISPATCH, &Tag(0, 0, 1), true, &[*byte]).unwrap();
+        }
+        buf.write_mux_fragment(TDISPATCH, &Tag(0, 0, 1), false, b"mom").unwrap();
+        let bytes = buf.into_inner();
+
+        let mut reader = FrameBuf::new(BufReader::new(bytes.as_slice()));
+        assert_eq!(Reassembler::new().read(&mut reader, |_| false).unwrap(),
+                   Frame::Whole(Msg::Tx(Tag(0, 0, 1), Tmsg::Dispatch(Vec::new(), "/dst".to_string(), Dtab::empty(), b"mom".to_vec()))));
+    }
+
+    #[test]
+    fn test_limits() {
+        let body = [0u8; 100];
+        let bytes = fragments(&[&body, &body]);
+        let mut reader = FrameBuf::new(BufReader::new(bytes.as_slice()));
+        assert_eq!(Reassembler::new().max_message(150).read(&mut reader, |_| false).unwrap_err().kind, InvalidInput);
+
+        let mut reader = FrameBuf::new(BufReader::new(bytes.as_slice()));
+        assert_eq!(Reassembler::new().max_reassembled(150).read(&mut reader, |_| false).unwrap_err().kind, InvalidInput);
+
+        let mut buf = MemWriter::new();
+        buf.write_mux_fragment(-2, &Tag(0, 0, 1), true, &[0, 0]).unwrap();
+        buf.write_mux_fragment(-2, &Tag(0, 0, 2), true, &[0, 0]).unwrap();
+        let bytes = buf.into_inner();
+        let mut reader = FrameBuf::new(BufReader::new(bytes.as_slice()));
+        assert_eq!(Reassembler::new().max_partial(1).read(&mut reader, |_| false).unwrap_err().kind, InvalidInput);
+    }
+
+    #[test]
+    fn test_slow_reader() {
+        let (tx, mut body) = body_channel();
+        assert!(send_chunk(&tx, vec![0; WINDOW], false));
+        assert_eq!(body.next_chunk().unwrap().unwrap().len(), WINDOW);
+
+        // the window is empty again, but one byte over it fails the body.
+        assert!(send_chunk(&tx, vec![0; WINDOW], false));
+        assert!(!send_chunk(&tx, vec![0], false));
+        assert!(!send_chunk(&tx, Vec::new(), true));
+        assert_eq!(body.next_chunk().unwrap().unwrap().len(), WINDOW);
+        assert_eq!(body.next_chunk().unwrap_err().kind, ResourceUnavailable);
+    }
+}
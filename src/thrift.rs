@@ -0,0 +1,685 @@
+//! ThriftMux: Thrift calls carried in Tdispatch bodies.
+//!
+//! A Tdispatch body holds exactly one Thrift message -- the method name,
+//! message type and sequence id, followed by the encoded arguments struct.
+//! The Rdispatch body holds the corresponding reply.  Argument and result
+//! structs are opaque here; they are encoded by generated code.
+
+#[allow(unstable)]
+
+use std::cmp;
+use std::old_io::{IoResult, IoError, InvalidInput, OtherIoError, ResourceUnavailable,
+                  Reader, Writer, BufReader, MemWriter};
+
+use misc::{Context, Dtab};
+use proto::{Msg, Tag, Tmsg, Rmsg};
+use reader::{FrameReader, MuxReader};
+use writer::frame_rmsg;
+
+static VERSION_1: u32 = 0x80010000;
+static VERSION_MASK: u32 = 0xffff0000;
+
+static COMPACT_PROTOCOL_ID: u8 = 0x82;
+static COMPACT_VERSION: u8 = 1;
+
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum Protocol {
+    Binary,
+    Compact,
+}
+
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum MessageType {
+    Call,
+    Reply,
+    Exception,
+    Oneway,
+}
+
+impl MessageType {
+    pub fn from_u8(code: u8) -> Option<MessageType> {
+        match code {
+            1 => Some(MessageType::Call),
+            2 => Some(MessageType::Reply),
+            3 => Some(MessageType::Exception),
+            4 => Some(MessageType::Oneway),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            MessageType::Call => 1,
+            MessageType::Reply => 2,
+            MessageType::Exception => 3,
+            MessageType::Oneway => 4,
+        }
+    }
+}
+
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct ThriftMessage {
+    pub name: String,
+    pub kind: MessageType,
+    pub seqid: i32,
+    /// The encoded arguments or result struct.
+    pub body: Vec<u8>,
+}
+
+impl ThriftMessage {
+    pub fn new(name: String, kind: MessageType, seqid: i32, body: Vec<u8>) -> ThriftMessage {
+        ThriftMessage { name: name, kind: kind, seqid: seqid, body: body }
+    }
+}
+
+/// The exception Thrift servers return for failures outside of a method's
+/// declared exceptions.
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct ApplicationException {
+    pub message: String,
+    pub kind: i32,
+}
+
+pub mod exception_types {
+    pub const UNKNOWN: i32 = 0;
+    pub const UNKNOWN_METHOD: i32 = 1;
+    pub const INVALID_MESSAGE_TYPE: i32 = 2;
+    pub const WRONG_METHOD_NAME: i32 = 3;
+    pub const BAD_SEQUENCE_ID: i32 = 4;
+    pub const MISSING_RESULT: i32 = 5;
+    pub const INTERNAL_ERROR: i32 = 6;
+    pub const PROTOCOL_ERROR: i32 = 7;
+}
+
+// field types, as they appear in each protocol.
+static BINARY_STOP: u8 = 0;
+static BINARY_I32: u8 = 8;
+static BINARY_STRING: u8 = 11;
+static COMPACT_I32: u8 = 5;
+static COMPACT_BINARY: u8 = 8;
+
+// how deeply nested a skipped value may be.
+const MAX_DEPTH: usize = 32;
+
+pub trait ThriftWriter: Writer {
+    fn write_thrift_message(&mut self, p: Protocol, msg: &ThriftMessage) -> IoResult<()> {
+        let header = match p {
+            Protocol::Binary => {
+                self.write_be_u32(VERSION_1 | (msg.kind.to_u8() as u32))
+                    .and_then(|_| self.write_thrift_binary_string(msg.name.as_slice()))
+                    .and_then(|_| self.write_be_i32(msg.seqid))
+            },
+            Protocol::Compact => {
+                self.write(&[COMPACT_PROTOCOL_ID, (msg.kind.to_u8() << 5) | COMPACT_VERSION])
+                    .and_then(|_| self.write_thrift_varint(msg.seqid as u32))
+                    .and_then(|_| self.write_thrift_compact_string(msg.name.as_slice()))
+            },
+        };
+        header.and_then(|_| self.write(msg.body.as_slice()))
+    }
+
+    fn write_thrift_exception(&mut self, p: Protocol, ex: &ApplicationException) -> IoResult<()> {
+        match p {
+            Protocol::Binary => {
+                self.write_u8(BINARY_STRING)
+                    .and_then(|_| self.write_be_i16(1))
+                    .and_then(|_| self.write_thrift_binary_string(ex.message.as_slice()))
+                    .and_then(|_| self.write_u8(BINARY_I32))
+                    .and_then(|_| self.write_be_i16(2))
+                    .and_then(|_| self.write_be_i32(ex.kind))
+                    .and_then(|_| self.write_u8(BINARY_STOP))
+            },
+            Protocol::Compact => {
+                // field headers carry the field id delta in the high nibble.
+                self.write_u8((1 << 4) | COMPACT_BINARY)
+                    .and_then(|_| self.write_thrift_compact_string(ex.message.as_slice()))
+                    .and_then(|_| self.write_u8((1 << 4) | COMPACT_I32))
+                    .and_then(|_| self.write_thrift_varint(zigzag(ex.kind)))
+                    .and_then(|_| self.write_u8(BINARY_STOP))
+            },
+        }
+    }
+
+    fn write_thrift_binary_string(&mut self, s: &str) -> IoResult<()> {
+        self.write_be_i32(s.len() as i32).and_then(|_| self.write_str(s))
+    }
+
+    fn write_thrift_compact_string(&mut self, s: &str) -> IoResult<()> {
+        self.write_thrift_varint(s.len() as u32).and_then(|_| self.write_str(s))
+    }
+
+    fn write_thrift_varint(&mut self, n: u32) -> IoResult<()> {
+        let mut n = n;
+        let mut buf = Vec::with_capacity(5);
+        while n >= 0x80 {
+            buf.push(((n & 0x7f) | 0x80) as u8);
+            n = n >> 7;
+        }
+        buf.push(n as u8);
+        self.write(buf.as_slice())
+    }
+}
+
+impl<W: Writer> ThriftWriter for W {}
+
+pub trait ThriftReader: Reader {
+    fn read_thrift_message(&mut self, p: Protocol) -> IoResult<ThriftMessage> {
+        let header = match p {
+            Protocol::Binary => self.read_thrift_binary_header(),
+            Protocol::Compact => self.read_thrift_compact_header(),
+        };
+        header.and_then(move |(name, kind, seqid)| {
+            self.read_to_end().map(move |body| ThriftMessage::new(name, kind, seqid, body))
+        })
+    }
+
+    fn read_thrift_binary_header(&mut self) -> IoResult<(String, MessageType, i32)> {
+        self.read_be_u32().and_then(|v| {
+            if v & 0x80000000 != 0 {
+                if v & VERSION_MASK != VERSION_1 {
+                    return Err(invalid("bad thrift version", format!("{:x}", v)));
+                }
+                let kind = match MessageType::from_u8((v & 0xff) as u8) {
+                    None => return Err(invalid("unknown thrift message type", format!("{}", v & 0xff))),
+                    Some(k) => k,
+                };
+                self.read_be_i32()
+                    .and_then(|len| self.read_thrift_string(len as i64))
+                    .and_then(move |name| self.read_be_i32().map(move |seqid| (name, kind, seqid)))
+            } else {
+                // non-strict messages lead with the name's length.
+                self.read_thrift_string(v as i64).and_then(move |name| {
+                    self.read_u8().and_then(move |k| match MessageType::from_u8(k) {
+                        None => Err(invalid("unknown thrift message type", format!("{}", k))),
+                        Some(kind) => self.read_be_i32().map(move |seqid| (name, kind, seqid)),
+                    })
+                })
+            }
+        })
+    }
+
+    fn read_thrift_compact_header(&mut self) -> IoResult<(String, MessageType, i32)> {
+        self.read_u8().and_then(|id| {
+            if id != COMPACT_PROTOCOL_ID {
+                return Err(invalid("bad compact protocol id", format!("{:x}", id)));
+            }
+            self.read_u8().and_then(|vt| {
+                if vt & 0x1f != COMPACT_VERSION {
+                    return Err(invalid("bad compact protocol version", format!("{}", vt & 0x1f)));
+                }
+                let kind = match MessageType::from_u8((vt >> 5) & 0x07) {
+                    None => return Err(invalid("unknown thrift message type", format!("{}", vt >> 5))),
+                    Some(k) => k,
+                };
+                self.read_thrift_varint().and_then(move |seqid| {
+                    self.read_thrift_varint()
+                        .and_then(|len| self.read_thrift_string(len as i64))
+                        .map(move |name| (name, kind, seqid as i32))
+                })
+            })
+        })
+    }
+
+    fn read_thrift_exception(&mut self, p: Protocol) -> IoResult<ApplicationException> {
+        let mut ex = ApplicationException { message: String::new(), kind: exception_types::UNKNOWN };
+        let mut last_id = 0i16;
+        loop {
+            let header = match self.read_u8() {
+                Err(ioe) => return Err(ioe),
+                Ok(b) => b,
+            };
+            if header == BINARY_STOP {
+                return Ok(ex);
+            }
+
+            let (id, field_type) = match p {
+                Protocol::Binary => match self.read_be_i16() {
+                    Err(ioe) => return Err(ioe),
+                    Ok(id) => (id, header),
+                },
+                Protocol::Compact => {
+                    let delta = (header >> 4) as i16;
+                    let id = if delta == 0 {
+                        match self.read_thrift_varint() {
+                            Err(ioe) => return Err(ioe),
+                            Ok(n) => unzigzag(n) as i16,
+                        }
+                    } else {
+                        last_id + delta
+                    };
+                    (id, header & 0x0f)
+                },
+            };
+            last_id = id;
+
+            let read = match (id, field_type, p) {
+                (1, BINARY_STRING, Protocol::Binary) => {
+                    self.read_be_i32().and_then(|n| self.read_thrift_string(n as i64)).map(|s| ex.message = s)
+                },
+                (1, COMPACT_BINARY, Protocol::Compact) => {
+                    self.read_thrift_varint().and_then(|n| self.read_thrift_string(n as i64)).map(|s| ex.message = s)
+                },
+                (2, BINARY_I32, Protocol::Binary) => self.read_be_i32().map(|k| ex.kind = k),
+                (2, COMPACT_I32, Protocol::Compact) => self.read_thrift_varint().map(|k| ex.kind = unzigzag(k)),
+                // fields added by newer servers.
+                _ => self.skip_thrift_value(p, field_type, true, 0),
+            };
+            match read {
+                Err(ioe) => return Err(ioe),
+                Ok(_) => (),
+            }
+        }
+    }
+
+    /// Reads a string whose length came off the wire.
+    fn read_thrift_string(&mut self, len: i64) -> IoResult<String> {
+        self.read_thrift_bytes(len).and_then(|bytes| {
+            String::from_utf8(bytes).map_err(|_| IoError {
+                kind: InvalidInput,
+                desc: "not a utf8 string",
+                detail: None,
+            })
+        })
+    }
+
+    /// Reads `len` bytes, where `len` came off the wire.  Negative lengths
+    /// are refused, and the bytes are read as they arrive rather than
+    /// allocated up front, so a bogus length can claim no more memory than
+    /// the input holds.
+    fn read_thrift_bytes(&mut self, len: i64) -> IoResult<Vec<u8>> {
+        if len < 0 {
+            return Err(invalid("negative thrift length", format!("{}", len)));
+        }
+        let len = len as usize;
+        let mut bytes = Vec::with_capacity(cmp::min(len, 1024));
+        let mut buf = [0u8; 1024];
+        while bytes.len() < len {
+            let want = cmp::min(len - bytes.len(), buf.len());
+            match self.read(buf.slice_to_mut(want)) {
+                Err(ioe) => return Err(ioe),
+                Ok(n) => bytes.push_all(buf.slice_to(n)),
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Skips a value of field type `t`, as for a struct's unknown fields.
+    /// Compact booleans are held in a field's type, but take a byte as
+    /// elements of a collection.
+    fn skip_thrift_value(&mut self, p: Protocol, t: u8, is_field: bool, depth: usize) -> IoResult<()> {
+        if depth > MAX_DEPTH {
+            return Err(invalid("thrift value nested too deeply", format!("{}", depth)));
+        }
+        match (p, t) {
+            // bool and byte; i16; i32; double and i64.
+            (Protocol::Binary, 2) | (Protocol::Binary, 3) => self.read_thrift_bytes(1).map(|_| ()),
+            (Protocol::Binary, 6) => self.read_thrift_bytes(2).map(|_| ()),
+            (Protocol::Binary, 8) => self.read_thrift_bytes(4).map(|_| ()),
+            (Protocol::Binary, 4) | (Protocol::Binary, 10) => self.read_thrift_bytes(8).map(|_| ()),
+            (Protocol::Binary, 11) => self.read_be_i32().and_then(|n| self.read_thrift_bytes(n as i64)).map(|_| ()),
+            (Protocol::Binary, 12) => self.skip_thrift_struct(p, depth),
+            // map: key type, value type, size.
+            (Protocol::Binary, 13) => {
+                let header = self.read_u8().and_then(|k| self.read_u8().and_then(|v| self.read_be_i32().map(|n| (k, v, n))));
+                match header {
+                    Err(ioe) => Err(ioe),
+                    Ok((k, v, n)) => self.skip_thrift_elements(p, &[k, v], n as i64, depth),
+                }
+            },
+            // set and list: element type, size.
+            (Protocol::Binary, 14) | (Protocol::Binary, 15) => {
+                match self.read_u8().and_then(|e| self.read_be_i32().map(|n| (e, n))) {
+                    Err(ioe) => Err(ioe),
+                    Ok((e, n)) => self.skip_thrift_elements(p, &[e], n as i64, depth),
+                }
+            },
+
+            // booleans; byte; i16, i32 and i64; double.
+            (Protocol::Compact, 1) | (Protocol::Compact, 2) if is_field => Ok(()),
+            (Protocol::Compact, 1) | (Protocol::Compact, 2) | (Protocol::Compact, 3) => self.read_thrift_bytes(1).map(|_| ()),
+            (Protocol::Compact, 4) | (Protocol::Compact, 5) | (Protocol::Compact, 6) => self.skip_thrift_varint(),
+            (Protocol::Compact, 7) => self.read_thrift_bytes(8).map(|_| ()),
+            (Protocol::Compact, 8) => self.read_thrift_varint().and_then(|n| self.read_thrift_bytes(n as i64)).map(|_| ()),
+            // list and set: size and element type, with larger sizes following.
+            (Protocol::Compact, 9) | (Protocol::Compact, 10) => {
+                let header = self.read_u8().and_then(|h| {
+                    if h >> 4 == 15 {
+                        self.read_thrift_varint().map(|n| (h & 0x0f, n))
+                    } else {
+                        Ok((h & 0x0f, (h >> 4) as u32))
+                    }
+                });
+                match header {
+                    Err(ioe) => Err(ioe),
+                    Ok((e, n)) => self.skip_thrift_elements(p, &[e], n as i64, depth),
+                }
+            },
+            // map: size, then key and value types unless empty.
+            (Protocol::Compact, 11) => {
+                let header = self.read_thrift_varint().and_then(|n| {
+                    if n == 0 { Ok((0, 0, n)) } else { self.read_u8().map(|kv| (kv >> 4, kv & 0x0f, n)) }
+                });
+                match header {
+                    Err(ioe) => Err(ioe),
+                    Ok((k, v, n)) => self.skip_thrift_elements(p, &[k, v], n as i64, depth),
+                }
+            },
+            (Protocol::Compact, 12) => self.skip_thrift_struct(p, depth),
+
+            _ => Err(invalid("unknown thrift field type", format!("{}", t))),
+        }
+    }
+
+    fn skip_thrift_struct(&mut self, p: Protocol, depth: usize) -> IoResult<()> {
+        loop {
+            let header = match self.read_u8() {
+                Err(ioe) => return Err(ioe),
+                Ok(b) => b,
+            };
+            if header == BINARY_STOP {
+                return Ok(());
+            }
+            let field = match p {
+                Protocol::Binary => self.read_be_i16().map(|_| header),
+                // a zero delta is followed by the field id.
+                Protocol::Compact if header >> 4 == 0 => self.read_thrift_varint().map(|_| header & 0x0f),
+                Protocol::Compact => Ok(header & 0x0f),
+            };
+            match field.and_then(|t| self.skip_thrift_value(p, t, true, depth + 1)) {
+                Err(ioe) => return Err(ioe),
+                Ok(_) => (),
+            }
+        }
+    }
+
+    /// Skips `n` elements, each of which is a value of each of `types`.
+    /// Every value takes at least a byte, so a bogus count runs out of
+    /// input.
+    fn skip_thrift_elements(&mut self, p: Protocol, types: &[u8], n: i64, depth: usize) -> IoResult<()> {
+        if n < 0 {
+            return Err(invalid("negative thrift length", format!("{}", n)));
+        }
+        for _ in range(0, n) {
+            for &t in types.iter() {
+                match self.skip_thrift_value(p, t, false, depth + 1) {
+                    Err(ioe) => return Err(ioe),
+                    Ok(_) => (),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Skips a varint of up to 64 bits.
+    fn skip_thrift_varint(&mut self) -> IoResult<()> {
+        for _ in range(0, 10) {
+            match self.read_u8() {
+                Err(ioe) => return Err(ioe),
+                Ok(b) if b & 0x80 == 0 => return Ok(()),
+                Ok(_) => (),
+            }
+        }
+        Err(invalid("varint too long", String::new()))
+    }
+
+    fn read_thrift_varint(&mut self) -> IoResult<u32> {
+        let mut n = 0u32;
+        for i in range(0, 5) {
+            match self.read_u8() {
+                Err(ioe) => return Err(ioe),
+                Ok(b) => {
+                    n = n | (((b & 0x7f) as u32) << (7 * i));
+                    if b & 0x80 == 0 {
+                        return Ok(n);
+                    }
+                }
+            }
+        }
+        Err(invalid("varint too long", String::new()))
+    }
+}
+
+impl<R: Reader> ThriftReader for R {}
+
+fn zigzag(n: i32) -> u32 { ((n << 1) ^ (n >> 31)) as u32 }
+
+fn unzigzag(n: u32) -> i32 { ((n >> 1) as i32) ^ -((n & 1) as i32) }
+
+fn invalid(desc: &'static str, detail: String) -> IoError {
+    IoError {
+        kind: InvalidInput,
+        desc: desc,
+        detail: Some(detail),
+    }
+}
+
+pub fn encode(p: Protocol, msg: &ThriftMessage) -> Vec<u8> {
+    let mut buf = MemWriter::new();
+    buf.write_thrift_message(p, msg).ok();
+    buf.into_inner()
+}
+
+pub fn decode(p: Protocol, bytes: &[u8]) -> IoResult<ThriftMessage> {
+    BufReader::new(bytes).read_thrift_message(p)
+}
+
+/// Frames a Thrift call as a Tdispatch.
+pub fn dispatch(p: Protocol, contexts: Vec<Context>, dst: String, dtab: Dtab, call: &ThriftMessage) -> Tmsg {
+    Tmsg::Dispatch(contexts, dst, dtab, encode(p, call))
+}
+
+/// Frames a server's reply as an Rdispatch.  Application exceptions become
+/// Rdispatch errors; declared exceptions are part of the reply struct and
+/// are returned intact.
+pub fn reply_to_rmsg(p: Protocol, reply: &ThriftMessage) -> Rmsg {
+    match reply.kind {
+        MessageType::Exception => {
+            let desc = match BufReader::new(reply.body.as_slice()).read_thrift_exception(p) {
+                Err(_) => "undecodable application exception".to_string(),
+                Ok(ex) => ex.message,
+            };
+            Rmsg::DispatchError(Vec::new(), desc)
+        },
+        _ => Rmsg::DispatchOk(Vec::new(), encode(p, reply)),
+    }
+}
+
+/// Reads the reply to `call` from its Rdispatch.  Rdispatch errors are
+/// presented as application exceptions, as a Thrift client expects.
+/// Replies must name the call's method and sequence id.
+pub fn rmsg_to_reply(p: Protocol, call: &ThriftMessage, rsp: Rmsg) -> IoResult<ThriftMessage> {
+    match rsp {
+        Rmsg::DispatchOk(_, body) => decode(p, body.as_slice()).and_then(|reply| {
+            if reply.seqid != call.seqid {
+                Err(invalid("thrift reply has the wrong sequence id", format!("{} for {}", reply.seqid, call.seqid)))
+            } else if reply.name != call.name {
+                Err(invalid("thrift reply names the wrong method", format!("{} for {}", reply.name, call.name)))
+            } else {
+                Ok(reply)
+            }
+        }),
+
+        Rmsg::DispatchError(_, desc) => {
+            let ex = ApplicationException { message: desc, kind: exception_types::INTERNAL_ERROR };
+            let mut buf = MemWriter::new();
+            buf.write_thrift_exception(p, &ex).map(move |_| {
+                ThriftMessage::new(call.name.clone(), MessageType::Exception, call.seqid, buf.into_inner())
+            })
+        },
+
+        Rmsg::DispatchNack(_) => Err(IoError {
+            kind: ResourceUnavailable,
+            desc: "request nacked",
+            detail: None,
+        }),
+
+        Rmsg::Err(msg) => Err(IoError {
+            kind: OtherIoError,
+            desc: "session error",
+            detail: Some(msg),
+        }),
+
+        rsp => Err(invalid("unexpected response to dispatch", format!("{:?}", rsp))),
+    }
+}
+
+/// Finagle clients learn whether a server speaks mux by sending it an Rerr
+/// with this message.  A mux server echoes it back; a plain Thrift server
+/// fails to decode it.
+pub static TINIT_CHECK: &'static str = "tinit check";
+
+static TINIT_CHECK_TAG: Tag = Tag(0, 0, 1);
+
+pub fn is_tinit_check(msg: &Msg) -> bool {
+    match *msg {
+        Msg::Rx(_, Rmsg::Err(ref s)) => s.as_slice() == TINIT_CHECK,
+        _ => false,
+    }
+}
+
+/// Determines whether the peer on a new connection speaks mux.  If it does
+/// not, the caller should speak framed Thrift on the connection instead.
+pub fn check_mux<S: Reader + Writer>(conn: &mut S) -> IoResult<bool> {
+    // written in one call, as `conn` may be unbuffered.
+    let mut check = Vec::new();
+    frame_rmsg(&mut check, &TINIT_CHECK_TAG, &Rmsg::Err(TINIT_CHECK.to_string()))
+        .and_then(|_| conn.write(check.as_slice()))
+        .and_then(|_| conn.flush())
+        .and_then(|_| conn.read_frame())
+        .map(|frame| match BufReader::new(frame.as_slice()).read_mux_msg() {
+            Ok(ref msg) => is_tinit_check(msg),
+            Err(_) => false,
+        })
+}
+
+/// True if a frame holds a Thrift message rather than a mux message, as
+/// when a plain Thrift client connects to a ThriftMux server.
+pub fn is_thrift_frame(frame: &[u8]) -> bool {
+    frame.len() >= 2 && (
+        (frame[0] == 0x80 && frame[1] == 0x01) ||
+        (frame[0] == COMPACT_PROTOCOL_ID && frame[1] & 0x1f == COMPACT_VERSION))
+}
+
+#[cfg(test)]
+mod test {
+    use std::old_io::{BufReader, MemWriter, InvalidInput};
+
+    use misc::Dtab;
+    use proto::{Msg, Tag, Tmsg, Rmsg};
+    use writer::MuxWriter;
+    use super::{ApplicationException, MessageType, Protocol, ThriftMessage,
+                ThriftReader, ThriftWriter, exception_types,
+                decode, dispatch, encode, is_thrift_frame, is_tinit_check,
+                reply_to_rmsg, rmsg_to_reply, TINIT_CHECK};
+
+    fn call() -> ThriftMessage {
+        ThriftMessage::new("getUser".to_string(), MessageType::Call, 300, vec![0])
+    }
+
+    #[test]
+    fn test_binary() {
+        let bytes = encode(Protocol::Binary, &call());
+        assert_eq!(bytes, vec![
+            0x80, 0x01, 0x00, 0x01, // version | call
+            0, 0, 0, 7, 103, 101, 116, 85, 115, 101, 114, // "getUser"
+            0, 0, 1, 44, // seqid
+            0, // empty args
+            ]);
+        assert_eq!(decode(Protocol::Binary, bytes.as_slice()).unwrap(), call());
+        assert!(is_thrift_frame(bytes.as_slice()));
+    }
+
+    #[test]
+    fn test_compact() {
+        let bytes = encode(Protocol::Compact, &call());
+        assert_eq!(bytes, vec![
+            0x82, 0x21, // protocol id, call | version
+            0xac, 0x02, // seqid: 300
+            7, 103, 101, 116, 85, 115, 101, 114, // "getUser"
+            0, // empty args
+            ]);
+        assert_eq!(decode(Protocol::Compact, bytes.as_slice()).unwrap(), call());
+        assert!(is_thrift_frame(bytes.as_slice()));
+    }
+
+    #[test]
+    fn test_exception() {
+        let ex = ApplicationException { message: "nope".to_string(), kind: exception_types::UNKNOWN_METHOD };
+        for p in vec![Protocol::Binary, Protocol::Compact].into_iter() {
+            let mut buf = MemWriter::new();
+            buf.write_thrift_exception(p, &ex).unwrap();
+            let bytes = buf.into_inner();
+            assert_eq!(BufReader::new(bytes.as_slice()).read_thrift_exception(p).unwrap(), ex);
+
+            let reply = ThriftMessage::new("getUser".to_string(), MessageType::Exception, 300, bytes);
+            let rsp = reply_to_rmsg(p, &reply);
+            assert_eq!(rsp, Rmsg::DispatchError(Vec::new(), "nope".to_string()));
+
+            let back = rmsg_to_reply(p, &call(), rsp).unwrap();
+            assert_eq!(back.kind, MessageType::Exception);
+            assert_eq!(back.seqid, 300);
+            let ex1 = BufReader::new(back.body.as_slice()).read_thrift_exception(p).unwrap();
+            assert_eq!(ex1.message, "nope".to_string());
+            assert_eq!(ex1.kind, exception_types::INTERNAL_ERROR);
+        }
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let tmsg = dispatch(Protocol::Binary, Vec::new(), "/s/users".to_string(), Dtab::empty(), &call());
+        let body = match tmsg {
+            Tmsg::Dispatch(_, _, _, body) => body,
+            _ => panic!("not a dispatch"),
+        };
+        let reply = ThriftMessage::new("getUser".to_string(), MessageType::Reply, 300, vec![0]);
+        let rsp = reply_to_rmsg(Protocol::Binary, &reply);
+        assert_eq!(rmsg_to_reply(Protocol::Binary, &call(), rsp).unwrap(), reply);
+        assert_eq!(decode(Protocol::Binary, body.as_slice()).unwrap(), call());
+        assert!(rmsg_to_reply(Protocol::Binary, &call(), Rmsg::DispatchNack(Vec::new())).is_err());
+
+        let stale = ThriftMessage::new("getUser".to_string(), MessageType::Reply, 299, vec![0]);
+        assert!(rmsg_to_reply(Protocol::Binary, &call(), reply_to_rmsg(Protocol::Binary, &stale)).is_err());
+        let other = ThriftMessage::new("getGroup".to_string(), MessageType::Reply, 300, vec![0]);
+        assert!(rmsg_to_reply(Protocol::Binary, &call(), reply_to_rmsg(Protocol::Binary, &other)).is_err());
+    }
+
+    #[test]
+    fn test_bad_lengths() {
+        // a negative name length, then one far longer than the input.
+        let negative = [0x80, 0x01, 0x00, 0x01, 0xff, 0xff, 0xff, 0xf0, 0, 0, 0, 1];
+        assert_eq!(decode(Protocol::Binary, &negative).unwrap_err().kind, InvalidInput);
+        let long = [0x80, 0x01, 0x00, 0x01, 0x7f, 0xff, 0xff, 0xff, 103, 101, 116];
+        assert!(decode(Protocol::Binary, &long).is_err());
+        let compact = [0x82, 0x21, 0xac, 0x02, 0xff, 0xff, 0xff, 0xff, 0x0f, 103];
+        assert!(decode(Protocol::Compact, &compact).is_err());
+    }
+
+    #[test]
+    fn test_exception_unknown_fields() {
+        let ex = ApplicationException { message: "nope".to_string(), kind: exception_types::UNKNOWN_METHOD };
+        // field 3: a struct holding an i32, before the closing stop.
+        let extra = vec![
+            (Protocol::Binary, vec![12, 0, 3, 8, 0, 1, 0, 0, 0, 5, 0]),
+            (Protocol::Compact, vec![0x1c, 0x15, 10, 0]),
+        ];
+        for (p, field) in extra.into_iter() {
+            let mut buf = MemWriter::new();
+            buf.write_thrift_exception(p, &ex).unwrap();
+            let mut bytes = buf.into_inner();
+            bytes.pop();
+            bytes.push_all(field.as_slice());
+            bytes.push(0);
+            assert_eq!(BufReader::new(bytes.as_slice()).read_thrift_exception(p).unwrap(), ex);
+        }
+    }
+
+    #[test]
+    fn test_tinit_check() {
+        let mut buf = MemWriter::new();
+        buf.write_mux_rmsg(&Tag(0, 0, 1), &Rmsg::Err(TINIT_CHECK.to_string())).unwrap();
+        let bytes = buf.into_inner();
+        assert!(!is_thrift_frame(bytes.as_slice()));
+        assert!(is_tinit_check(&Msg::Rx(Tag(0, 0, 1), Rmsg::Err(TINIT_CHECK.to_string()))));
+        assert!(!is_tinit_check(&Msg::Rx(Tag(0, 0, 1), Rmsg::Err("nope".to_string()))));
+    }
+}
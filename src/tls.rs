@@ -0,0 +1,377 @@
+//! TLS for mux sessions.
+//!
+//! TLS is negotiated in band, before a session is established: the client's
+//! Tinit advertises its TLS level in the `tls` header and the server's Rinit
+//! answers with its own.  If both peers permit TLS, each begins the TLS
+//! handshake on the same connection immediately after the Rinit.
+//!
+//! The TLS implementation is supplied by a `TlsConnector` or `TlsAcceptor`,
+//! which wraps an established connection.  `ssl::OpenSsl`, with the `ssl`
+//! feature, is both, and authenticates clients by their certificates.
+//! Server handlers see the identity a client authenticated with as
+//! `Request::peer_identity`.
+
+#[allow(unstable)]
+
+use std::old_io::{IoResult, IoError, InvalidInput, Reader, Writer, BufReader, MemWriter};
+use std::old_io::net::tcp::TcpStream;
+
+use misc::Header;
+use proto::{Msg, Tag, Tmsg, Rmsg, show_headers};
+use reader::{FrameReader, MuxReader};
+use span::{Span, describe_peer};
+use transport::{PeerIdentity, Transport};
+use writer::{FrameWriter, frame_tmsg, frame_rmsg};
+
+pub static TLS_HEADER: &'static [u8] = b"tls";
+
+pub static INIT_VERSION: u16 = 1;
+
+static INIT_TAG: Tag = Tag(0, 0, 1);
+
+static OFF: &'static [u8] = b"off";
+static DESIRED: &'static [u8] = b"desired";
+static REQUIRED: &'static [u8] = b"on";
+
+#[derive(Clone,Copy,PartialEq,Eq,Debug)]
+pub enum TlsLevel {
+    Off,
+    /// Use TLS if the peer permits it.
+    Desired,
+    /// Refuse to communicate without TLS.
+    Required,
+}
+
+impl TlsLevel {
+    pub fn from_bytes(b: &[u8]) -> Option<TlsLevel> {
+        if b == OFF {
+            Some(TlsLevel::Off)
+        } else if b == DESIRED {
+            Some(TlsLevel::Desired)
+        } else if b == REQUIRED {
+            Some(TlsLevel::Required)
+        } else {
+            None
+        }
+    }
+
+    pub fn to_bytes(self) -> &'static [u8] {
+        match self {
+            TlsLevel::Off => OFF,
+            TlsLevel::Desired => DESIRED,
+            TlsLevel::Required => REQUIRED,
+        }
+    }
+
+    pub fn to_header(self) -> Header {
+        Header::new(TLS_HEADER.to_vec(), self.to_bytes().to_vec())
+    }
+
+    /// Peers that don't advertise a level don't support TLS.
+    pub fn from_headers(headers: &[Header]) -> TlsLevel {
+        headers.iter()
+            .find(|h| h.key.as_slice() == TLS_HEADER)
+            .and_then(|h| TlsLevel::from_bytes(h.val.as_slice()))
+            .unwrap_or(TlsLevel::Off)
+    }
+}
+
+/// Determines whether TLS is used, given each peer's level.
+pub fn negotiate(local: TlsLevel, remote: TlsLevel) -> IoResult<bool> {
+    match (local, remote) {
+        (TlsLevel::Off, TlsLevel::Required) | (TlsLevel::Required, TlsLevel::Off) => Err(IoError {
+            kind: InvalidInput,
+            desc: "incompatible tls levels",
+            detail: Some(format!("local={:?} remote={:?}", local, remote)),
+        }),
+        (TlsLevel::Off, _) | (_, TlsLevel::Off) => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+pub trait TlsConnector {
+    type Stream: Transport;
+
+    /// Performs the client side of a TLS handshake over `conn` with `host`,
+    /// the name the client dialed, which the server should prove it is.
+    fn connect(&self, conn: TcpStream, host: &str) -> IoResult<Self::Stream>;
+}
+
+pub trait TlsAcceptor {
+    type Stream: Transport;
+
+    /// Performs the server side of a TLS handshake over `conn`.
+    fn accept(&self, conn: TcpStream) -> IoResult<Self::Stream>;
+}
+
+/// A connection on which TLS may or may not have been negotiated.
+#[derive(Clone)]
+pub enum MaybeTls<S> {
+    /// A plaintext connection, preceded by any bytes that were read during
+    /// negotiation but belong to the session.
+    Plain(TcpStream, Vec<u8>),
+    Tls(S),
+}
+
+impl<S: Transport> Reader for MaybeTls<S> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        match *self {
+            MaybeTls::Tls(ref mut s) => s.read(buf),
+
+            MaybeTls::Plain(ref mut conn, ref mut replay) => {
+                if replay.is_empty() {
+                    conn.read(buf)
+                } else {
+                    let n = if buf.len() < replay.len() { buf.len() } else { replay.len() };
+                    for i in range(0, n) {
+                        buf[i] = replay[i];
+                    }
+                    let rest = replay.slice_from(n).to_vec();
+                    *replay = rest;
+                    Ok(n)
+                }
+            },
+        }
+    }
+}
+
+impl<S: Transport> Writer for MaybeTls<S> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        match *self {
+            MaybeTls::Plain(ref mut conn, _) => conn.write(buf),
+            MaybeTls::Tls(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        match *self {
+            MaybeTls::Plain(ref mut conn, _) => conn.flush(),
+            MaybeTls::Tls(ref mut s) => s.flush(),
+        }
+    }
+}
+
+impl<S: Transport> Transport for MaybeTls<S> {
+    fn close(&mut self) {
+        match *self {
+            MaybeTls::Plain(ref mut conn, _) => conn.close(),
+            MaybeTls::Tls(ref mut s) => s.close(),
+        }
+    }
+
+    fn peer_identity(&self) -> Option<PeerIdentity> {
+        match *self {
+            MaybeTls::Plain(_, _) => None,
+            MaybeTls::Tls(ref s) => s.peer_identity(),
+        }
+    }
+
+    fn peer_addr(&self) -> Option<String> {
+        match *self {
+            MaybeTls::Plain(ref conn, _) => conn.peer_addr(),
+            MaybeTls::Tls(ref s) => s.peer_addr(),
+        }
+    }
+}
+
+impl<S> MaybeTls<S> {
+    pub fn is_tls(&self) -> bool {
+        match *self {
+            MaybeTls::Plain(_, _) => false,
+            MaybeTls::Tls(_) => true,
+        }
+    }
+}
+
+/// Negotiates TLS with `host`, returning a connection over which a client
+/// session may be established.
+pub fn connect<C: TlsConnector>(conn: TcpStream, host: &str, level: TlsLevel, connector: &C)
+    -> IoResult<MaybeTls<C::Stream>>
+{
+    let mut conn = conn;
+    if level == TlsLevel::Off {
+        return Ok(MaybeTls::Plain(conn, Vec::new()));
+    }
+
+    let span = Span::new().with("side", "client").with("peer", describe_peer(&conn));
+    let init = Tmsg::Init(INIT_VERSION, vec![level.to_header()]);
+    let remote = conn.write_frame(|buf| frame_tmsg(buf, &INIT_TAG, &init))
+        .and_then(|_| conn.flush())
+        .and_then(|_| conn.read_mux_framed_msg())
+        .map(|msg| match msg {
+            Msg::Rx(_, Rmsg::Init(version, headers)) => {
+                info!("{}", span.with("init_version", version).with("headers", show_headers(headers.as_slice())));
+                TlsLevel::from_headers(headers.as_slice())
+            },
+            // servers that predate Tinit answer it with Rerr.
+            _ => TlsLevel::Off,
+        });
+
+    match remote.and_then(|remote| negotiate(level, remote)) {
+        Err(ioe) => Err(ioe),
+        Ok(false) => Ok(MaybeTls::Plain(conn, Vec::new())),
+        Ok(true) => connector.connect(conn, host).map(MaybeTls::Tls),
+    }
+}
+
+/// Negotiates TLS with a client, returning a connection over which a server
+/// session may be established.  Clients that don't send a Tinit are served
+/// in plaintext unless TLS is required.
+pub fn accept<A: TlsAcceptor>(conn: TcpStream, level: TlsLevel, acceptor: &A) -> IoResult<MaybeTls<A::Stream>> {
+    let mut conn = conn;
+    if level == TlsLevel::Off {
+        return Ok(MaybeTls::Plain(conn, Vec::new()));
+    }
+
+    let frame = match conn.read_frame() {
+        Err(ioe) => return Err(ioe),
+        Ok(frame) => frame,
+    };
+
+    let init = match BufReader::new(frame.as_slice()).read_mux_msg() {
+        Ok(Msg::Tx(tag, Tmsg::Init(version, headers))) => {
+            let span = Span::new().with("side", "server").with("peer", describe_peer(&conn));
+            info!("{}", span.with("init_version", version).with("headers", show_headers(headers.as_slice())));
+            Some((tag, version, TlsLevel::from_headers(headers.as_slice())))
+        },
+        _ => None,
+    };
+
+    match init {
+        None => {
+            // the first message belongs to the session.
+            match negotiate(level, TlsLevel::Off) {
+                Err(ioe) => return Err(ioe),
+                Ok(_) => (),
+            }
+            let mut replay = MemWriter::new();
+            replay.write_be_u32_frame(frame.as_slice()).map(move |_| {
+                MaybeTls::Plain(conn, replay.into_inner())
+            })
+        },
+
+        Some((tag, version, remote)) => {
+            let tls = negotiate(level, remote);
+            let rinit = Rmsg::Init(version, vec![level.to_header()]);
+            match conn.write_frame(|buf| frame_rmsg(buf, &tag, &rinit)).and_then(|_| conn.flush()) {
+                Err(ioe) => return Err(ioe),
+                Ok(_) => (),
+            }
+            match tls {
+                Err(ioe) => Err(ioe),
+                Ok(false) => Ok(MaybeTls::Plain(conn, Vec::new())),
+                Ok(true) => acceptor.accept(conn).map(MaybeTls::Tls),
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::old_io::{Acceptor, Listener, IoResult, Reader, Writer};
+    use std::old_io::net::tcp::{TcpListener, TcpStream};
+    use std::thread::Thread;
+
+    use misc::Dtab;
+    use proto::{Tmsg, Rmsg};
+    use server::{Request, Server};
+    use session::ClientSession;
+    use transport::{PeerIdentity, Transport};
+    use super::{TlsAcceptor, TlsConnector, TlsLevel, accept, connect, negotiate};
+
+    /// Stands in for a TLS stream: authenticates every peer as "client".
+    #[derive(Clone)]
+    struct Fake(TcpStream);
+
+    impl Reader for Fake {
+        fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> { self.0.read(buf) }
+    }
+
+    impl Writer for Fake {
+        fn write(&mut self, buf: &[u8]) -> IoResult<()> { self.0.write(buf) }
+        fn flush(&mut self) -> IoResult<()> { self.0.flush() }
+    }
+
+    impl Transport for Fake {
+        fn close(&mut self) { self.0.close() }
+
+        fn peer_identity(&self) -> Option<PeerIdentity> {
+            Some(PeerIdentity { subject: "CN=client".to_string(), dns_names: Vec::new() })
+        }
+    }
+
+    struct FakeTls;
+
+    impl TlsConnector for FakeTls {
+        type Stream = Fake;
+        fn connect(&self, conn: TcpStream, _: &str) -> IoResult<Fake> { Ok(Fake(conn)) }
+    }
+
+    impl TlsAcceptor for FakeTls {
+        type Stream = Fake;
+        fn accept(&self, conn: TcpStream) -> IoResult<Fake> { Ok(Fake(conn)) }
+    }
+
+    fn whoami(req: Request) -> Rmsg {
+        let subject = req.peer_identity.map(|id| id.subject).unwrap_or("anonymous".to_string());
+        Rmsg::DispatchOk(Vec::new(), subject.into_bytes())
+    }
+
+    fn serve(level: TlsLevel) -> String {
+        let mut listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("{}", listener.socket_name().unwrap());
+        let mut acceptor = listener.listen().unwrap();
+        Thread::spawn(move|| {
+            let server = Server::new(whoami);
+            for conn in acceptor.incoming() {
+                match conn.and_then(|c| accept(c, level, &FakeTls)) {
+                    Err(_) => (),
+                    Ok(conn) => { server.serve(conn); },
+                }
+            }
+        });
+        addr
+    }
+
+    fn whoami_req() -> Tmsg {
+        Tmsg::Dispatch(Vec::new(), "/".to_string(), Dtab::empty(), Vec::new())
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(TlsLevel::Off, TlsLevel::Off), Ok(false));
+        assert_eq!(negotiate(TlsLevel::Off, TlsLevel::Desired), Ok(false));
+        assert_eq!(negotiate(TlsLevel::Desired, TlsLevel::Desired), Ok(true));
+        assert_eq!(negotiate(TlsLevel::Desired, TlsLevel::Required), Ok(true));
+        assert!(negotiate(TlsLevel::Required, TlsLevel::Off).is_err());
+    }
+
+    #[test]
+    fn test_mutual_tls() {
+        let addr = serve(TlsLevel::Desired);
+        let conn = connect(TcpStream::connect(addr.as_slice()).unwrap(), "localhost", TlsLevel::Required, &FakeTls).unwrap();
+        assert!(conn.is_tls());
+        let session = ClientSession::new(conn);
+        assert_eq!(session.call(&whoami_req()).unwrap(),
+                   Rmsg::DispatchOk(Vec::new(), b"CN=client".to_vec()));
+    }
+
+    #[test]
+    fn test_plaintext_client() {
+        // a client that doesn't negotiate is served in plaintext.
+        let addr = serve(TlsLevel::Desired);
+        let session = ClientSession::new(TcpStream::connect(addr.as_slice()).unwrap());
+        assert_eq!(session.call(&whoami_req()).unwrap(),
+                   Rmsg::DispatchOk(Vec::new(), b"anonymous".to_vec()));
+
+        let conn = connect(TcpStream::connect(addr.as_slice()).unwrap(), "localhost", TlsLevel::Off, &FakeTls).unwrap();
+        assert!(!conn.is_tls());
+    }
+
+    #[test]
+    fn test_required() {
+        let addr = serve(TlsLevel::Off);
+        // the server's session answers Tinit without a tls header.
+        assert!(connect(TcpStream::connect(addr.as_slice()).unwrap(), "localhost", TlsLevel::Required, &FakeTls).is_err());
+    }
+}
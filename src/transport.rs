@@ -0,0 +1,69 @@
+//! Connections that sessions may be established over.
+//!
+//! A session reads from one clone of its transport on a background thread
+//! while writing to another, so clones must share the underlying
+//! connection.
+
+#[allow(unstable)]
+
+use std::old_io::{IoResult, Reader, Writer};
+use std::old_io::net::pipe::UnixStream;
+use std::old_io::net::tcp::TcpStream;
+
+/// The identity a peer authenticated with, e.g. by presenting a TLS client
+/// certificate.
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct PeerIdentity {
+    pub subject: String,
+    pub dns_names: Vec<String>,
+}
+
+/// The credentials of the process on the other end of a local socket.
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub struct PeerCredentials {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+pub trait Transport: Reader + Writer + Clone + Send + 'static {
+    /// Closes the connection in both directions, waking any thread blocked
+    /// reading from a clone.
+    fn close(&mut self);
+
+    fn peer_identity(&self) -> Option<PeerIdentity> { None }
+
+    fn peer_credentials(&self) -> Option<PeerCredentials> { None }
+
+    /// The peer's address, for transports that have one to report.
+    fn peer_addr(&self) -> Option<String> { None }
+
+    /// Writes whatever `f` appends to the buffer it's given, in one write.
+    /// Transports that batch writes hand `f` their batch, so that frames
+    /// are encoded directly into it.
+    fn write_frame<F: FnOnce(&mut Vec<u8>) -> IoResult<()>>(&mut self, f: F) -> IoResult<()> {
+        let mut buf = Vec::new();
+        f(&mut buf).and_then(|_| self.write(buf.as_slice()))
+    }
+}
+
+impl Transport for TcpStream {
+    fn close(&mut self) {
+        self.close_read().ok();
+        self.close_write().ok();
+    }
+
+    fn peer_addr(&self) -> Option<String> {
+        // peer_name takes &mut self, but clones share the socket.
+        self.clone().peer_name().ok().map(|addr| addr.to_string())
+    }
+}
+
+/// Filesystem Unix sockets without peer credentials; see `unix` for abstract
+/// namespace sockets and credentials.
+impl Transport for UnixStream {
+    fn close(&mut self) {
+        self.close_read().ok();
+        self.close_write().ok();
+    }
+}
@@ -0,0 +1,277 @@
+//! Unix domain socket transport.
+//!
+//! Sockets are managed directly (rather than with `old_io::net::pipe`) so
+//! that they may be bound in Linux's abstract namespace, which has no
+//! filesystem presence, and so that peers' credentials may be read.
+
+#[allow(unstable)]
+
+use libc;
+use std::mem;
+use std::old_io::{IoResult, IoError, InvalidInput, EndOfFile, ShortWrite, Acceptor, Reader, Writer,
+                  standard_error};
+use std::old_io::fs;
+use std::os;
+use std::ptr;
+use std::sync::Arc;
+
+use transport::{PeerCredentials, Transport};
+
+#[derive(Clone,PartialEq,Eq,Debug)]
+pub enum UnixAddr {
+    /// A socket bound to a filesystem path.
+    Path(Path),
+
+    /// A socket named in the abstract namespace (Linux only).
+    Abstract(Vec<u8>),
+}
+
+impl UnixAddr {
+    /// Parses `unix:PATH`, or `unix:@NAME` for an abstract name.  Returns
+    /// `None` for anything else, such as a TCP address.
+    pub fn parse(s: &str) -> Option<UnixAddr> {
+        if !s.starts_with("unix:") {
+            return None;
+        }
+        let name = s.slice_from(5);
+        if name.starts_with("@") {
+            Some(UnixAddr::Abstract(name.slice_from(1).as_bytes().to_vec()))
+        } else {
+            Some(UnixAddr::Path(Path::new(name)))
+        }
+    }
+
+    fn to_sockaddr(&self) -> IoResult<(libc::sockaddr_un, libc::socklen_t)> {
+        let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+        // abstract names are distinguished by a leading NUL.
+        let (name, offset) = match *self {
+            UnixAddr::Path(ref p) => (p.as_vec(), 0),
+            UnixAddr::Abstract(ref n) => (n.as_slice(), 1),
+        };
+        if offset + name.len() >= addr.sun_path.len() {
+            return Err(IoError {
+                kind: InvalidInput,
+                desc: "unix socket name too long",
+                detail: Some(format!("{:?}", self)),
+            });
+        }
+        for (i, b) in name.iter().enumerate() {
+            addr.sun_path[offset + i] = *b as libc::c_char;
+        }
+
+        let len = mem::size_of::<libc::sa_family_t>() + offset + name.len();
+        Ok((addr, len as libc::socklen_t))
+    }
+}
+
+struct Fd(libc::c_int);
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0); }
+    }
+}
+
+fn cvt(ret: libc::c_int) -> IoResult<libc::c_int> {
+    if ret < 0 { Err(IoError::last_error()) } else { Ok(ret) }
+}
+
+/// Calls `f` again while it's interrupted by a signal.
+fn retry<F: FnMut() -> i64>(mut f: F) -> i64 {
+    loop {
+        let ret = f();
+        if ret >= 0 || os::errno() as libc::c_int != libc::EINTR {
+            return ret;
+        }
+    }
+}
+
+fn socket() -> IoResult<Fd> {
+    cvt(unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_STREAM, 0) }).map(Fd)
+}
+
+/// A connected Unix domain socket.  Clones share the connection.
+#[derive(Clone)]
+pub struct UnixSocket {
+    fd: Arc<Fd>,
+}
+
+impl UnixSocket {
+    pub fn connect(addr: &UnixAddr) -> IoResult<UnixSocket> {
+        addr.to_sockaddr().and_then(|(sa, len)| {
+            socket().and_then(move |fd| {
+                let sap = &sa as *const libc::sockaddr_un as *const libc::sockaddr;
+                cvt(unsafe { libc::connect(fd.0, sap, len) })
+                    .map(move |_| UnixSocket { fd: Arc::new(fd) })
+            })
+        })
+    }
+
+    /// The credentials of the process that created the peer socket.
+    pub fn peer_credentials(&self) -> IoResult<PeerCredentials> {
+        peer_credentials(self.fd.0)
+    }
+}
+
+// SO_PEERCRED, which libc doesn't define, varies by architecture.
+#[cfg(all(target_os = "linux", any(target_arch = "powerpc", target_arch = "powerpc64")))]
+static SO_PEERCRED: libc::c_int = 21;
+#[cfg(all(target_os = "linux", any(target_arch = "mips", target_arch = "mipsel")))]
+static SO_PEERCRED: libc::c_int = 18;
+#[cfg(all(target_os = "linux", not(any(target_arch = "powerpc", target_arch = "powerpc64",
+                                       target_arch = "mips", target_arch = "mipsel"))))]
+static SO_PEERCRED: libc::c_int = 17;
+
+#[cfg(target_os = "linux")]
+fn peer_credentials(fd: libc::c_int) -> IoResult<PeerCredentials> {
+    #[repr(C)]
+    struct Ucred { pid: libc::pid_t, uid: libc::uid_t, gid: libc::gid_t }
+
+    let mut cred = Ucred { pid: 0, uid: 0, gid: 0 };
+    let mut len = mem::size_of::<Ucred>() as libc::socklen_t;
+    let credp = &mut cred as *mut Ucred as *mut libc::c_void;
+    cvt(unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, SO_PEERCRED, credp, &mut len) })
+        .map(|_| PeerCredentials { pid: cred.pid as i32, uid: cred.uid as u32, gid: cred.gid as u32 })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_credentials(_: libc::c_int) -> IoResult<PeerCredentials> {
+    Err(IoError {
+        kind: InvalidInput,
+        desc: "peer credentials are only available on linux",
+        detail: None,
+    })
+}
+
+impl Reader for UnixSocket {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let bufp = buf.as_mut_ptr() as *mut libc::c_void;
+        let n = retry(|| unsafe { libc::read(self.fd.0, bufp, buf.len() as libc::size_t) as i64 });
+        if n < 0 {
+            Err(IoError::last_error())
+        } else if n == 0 {
+            Err(standard_error(EndOfFile))
+        } else {
+            Ok(n as usize)
+        }
+    }
+}
+
+impl Writer for UnixSocket {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        let mut off = 0;
+        while off < buf.len() {
+            let rest = buf.slice_from(off);
+            let restp = rest.as_ptr() as *const libc::c_void;
+            let n = retry(|| unsafe { libc::write(self.fd.0, restp, rest.len() as libc::size_t) as i64 });
+            if n < 0 {
+                return Err(IoError::last_error());
+            }
+            if n == 0 {
+                return Err(IoError {
+                    kind: ShortWrite(off),
+                    desc: "unix socket accepted no bytes",
+                    detail: None,
+                });
+            }
+            off += n as usize;
+        }
+        Ok(())
+    }
+}
+
+impl Transport for UnixSocket {
+    fn close(&mut self) {
+        // SHUT_RDWR
+        unsafe { libc::shutdown(self.fd.0, 2); }
+    }
+
+    fn peer_credentials(&self) -> Option<PeerCredentials> {
+        UnixSocket::peer_credentials(self).ok()
+    }
+}
+
+pub struct UnixSocketListener {
+    fd: Fd,
+    path: Option<Path>,
+}
+
+impl UnixSocketListener {
+    /// Binds and listens on `addr`.  A filesystem socket is removed when the
+    /// listener is dropped.
+    pub fn bind(addr: &UnixAddr) -> IoResult<UnixSocketListener> {
+        addr.to_sockaddr().and_then(|(sa, len)| {
+            socket().and_then(move |fd| {
+                let sap = &sa as *const libc::sockaddr_un as *const libc::sockaddr;
+                cvt(unsafe { libc::bind(fd.0, sap, len) })
+                    .and_then(|_| cvt(unsafe { libc::listen(fd.0, 128) }))
+                    .map(move |_| {
+                        let path = match *addr {
+                            UnixAddr::Path(ref p) => Some(p.clone()),
+                            UnixAddr::Abstract(_) => None,
+                        };
+                        UnixSocketListener { fd: fd, path: path }
+                    })
+            })
+        })
+    }
+}
+
+impl Acceptor<UnixSocket> for UnixSocketListener {
+    fn accept(&mut self) -> IoResult<UnixSocket> {
+        let fd = retry(|| unsafe { libc::accept(self.fd.0, ptr::null_mut(), ptr::null_mut()) as i64 });
+        cvt(fd as libc::c_int).map(|fd| UnixSocket { fd: Arc::new(Fd(fd)) })
+    }
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        match self.path {
+            None => (),
+            Some(ref p) => { fs::unlink(p).ok(); },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use libc;
+    use std::thread::Thread;
+
+    use misc::Dtab;
+    use proto::{Tmsg, Rmsg};
+    use server::{Request, Server};
+    use session::ClientSession;
+    use super::{UnixAddr, UnixSocketListener};
+
+    fn whoami(req: Request) -> Rmsg {
+        let creds = req.peer_credentials.unwrap();
+        Rmsg::DispatchOk(Vec::new(), format!("{} {}", creds.pid, creds.uid).into_bytes())
+    }
+
+    fn test_addr(addr: UnixAddr) {
+        let listener = UnixSocketListener::bind(&addr).unwrap();
+        Thread::spawn(move|| {
+            Server::new(whoami).serve_all(listener);
+        });
+
+        let session = ClientSession::connect_unix(&addr).unwrap();
+        let req = Tmsg::Dispatch(Vec::new(), "/".to_string(), Dtab::empty(), Vec::new());
+        let expected = unsafe { format!("{} {}", libc::getpid(), libc::getuid()) };
+        assert_eq!(session.call(&req).unwrap(), Rmsg::DispatchOk(Vec::new(), expected.into_bytes()));
+    }
+
+    #[test]
+    fn test_path() {
+        let pid = unsafe { libc::getpid() };
+        test_addr(UnixAddr::Path(Path::new(format!("/tmp/mux-test-{}.sock", pid))));
+    }
+
+    #[test]
+    fn test_abstract() {
+        let pid = unsafe { libc::getpid() };
+        test_addr(UnixAddr::Abstract(format!("mux-test-{}", pid).into_bytes()));
+    }
+}
@@ -1,9 +1,11 @@
 #[allow(unstable)]
 
-use std::old_io::{IoResult, Writer, MemWriter};
+use std::old_io::{IoResult, Writer};
+use std::sync::Mutex;
 
-use misc::{Context, Dtab, Dentry, Trace};
+use misc::{Context, Dtab, Dentry, Header, Trace};
 use proto::{Tag, Tmsg, Rmsg};
+use transport::Transport;
 
 pub trait FrameWriter: Writer {
     fn write_be_u32_frame(&mut self, frame: &[u8]) -> IoResult<()> {
@@ -16,18 +18,27 @@ impl<W: Writer> FrameWriter for W {}
 
 pub trait MuxWriter: FrameWriter {
 
+    /// Writes the frame's length, from `encoded_len`, then encodes the
+    /// message straight into the writer, which sees several writes per
+    /// frame.  To write to an unbuffered connection in one call, encode the
+    /// frame with `frame_tmsg` instead (as `Transport::write_frame` does).
     fn write_mux_framed_tmsg<'t>(&mut self, tag: &'t Tag, msg: &'t Tmsg) -> IoResult<()> {
-        let mut buf = MemWriter::new();
-        buf.write_mux_tmsg(tag, msg).and_then(move|_| {
-            self.write_be_u32_frame(buf.into_inner().as_slice())
-        })
+        self.write_be_u32(msg.encoded_len() as u32)
+            .and_then(|_| self.write_mux_tmsg(tag, msg))
     }
 
     fn write_mux_framed_rmsg<'t>(&mut self, tag: &'t Tag, msg: &'t Rmsg) -> IoResult<()> {
-        let mut buf = MemWriter::new();
-        buf.write_mux_rmsg(tag, msg).and_then(move|_| {
-            self.write_be_u32_frame(buf.into_inner().as_slice())
-        })
+        self.write_be_u32(msg.encoded_len() as u32)
+            .and_then(|_| self.write_mux_rmsg(tag, msg))
+    }
+
+    /// Writes one fragment of a message: its type, its tag (flagged unless
+    /// this is the last fragment) and part of its payload.
+    fn write_mux_fragment(&mut self, msg_type: i8, tag: &Tag, more: bool, payload: &[u8]) -> IoResult<()> {
+        self.write_be_u32(4 + payload.len() as u32)
+            .and_then(|_| self.write_i8(msg_type))
+            .and_then(|_| self.write_mux_tag(&tag.with_fragment(more)))
+            .and_then(|_| self.write(payload))
     }
 
     fn write_mux_tmsg<'t>(&mut self, tag: &'t Tag, msg: &'t Tmsg) -> IoResult<()> {
@@ -69,14 +80,23 @@ pub trait MuxWriter: FrameWriter {
             &Tmsg::Lease(unit, amount) => {
                 self.write_u8(unit).and_then(|_| self.write_be_u64(amount))
             },
+
+            &Tmsg::Init(version, ref headers) => self.write_mux_init(version, headers.as_slice()),
         }
     }
 
     fn write_mux_rmsg_msg(&mut self, m: &Rmsg) -> IoResult<()> {
         match m {
-            &Rmsg::ReqOk(ref body) => self.write(body.as_slice()),
-            &Rmsg::ReqError(ref s) => self.write_str(s.as_slice()),
-            &Rmsg::ReqNack => Ok(()),
+            // Rreqs lead with a status byte, as Rdispatches do.
+            &Rmsg::ReqOk(ref body) => {
+                self.write_u8(0) // status
+                    .and_then(|_| self.write(body.as_slice()))
+            },
+            &Rmsg::ReqError(ref s) => {
+                self.write_u8(1) // status
+                    .and_then(|_| self.write_str(s.as_slice()))
+            },
+            &Rmsg::ReqNack => self.write_u8(2),
 
             &Rmsg::DispatchOk(ref contexts, ref body) => {
                 self.write_u8(0) // status
@@ -96,6 +116,8 @@ pub trait MuxWriter: FrameWriter {
 
             &Rmsg::Drain | &Rmsg::Ping => Ok(()),
 
+            &Rmsg::Init(version, ref headers) => self.write_mux_init(version, headers.as_slice()),
+
             &Rmsg::Err(ref msg) => self.write_str(msg.as_slice())
         }
     }
@@ -149,19 +171,38 @@ pub trait MuxWriter: FrameWriter {
         self.write_len_vec(dentries.as_slice(), |w, d| w.write_mux_dentry(d))
     }
 
+    fn write_mux_init(&mut self, version: u16, headers: &[Header]) -> IoResult<()> {
+        match self.write_be_u16(version) {
+            Err(ioe) => Err(ioe),
+            Ok(_) => {
+                for h in headers.iter() {
+                    match self.write_be_u32(h.key.len() as u32)
+                        .and_then(|_| self.write(h.key.as_slice()))
+                        .and_then(|_| self.write_be_u32(h.val.len() as u32))
+                        .and_then(|_| self.write(h.val.as_slice())) {
+                        Err(ioe) => return Err(ioe),
+                        Ok(_) => (),
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
     fn write_mux_trace<'t>(&mut self, trace: &'t Option<Trace>) -> IoResult<()> {
         match *trace {
             None => self.write_u8(0),
 
+            // keys are numbered from 1, as `read_mux_trace` expects.
             Some(ref trace) => {
                 // two trace variables:
                 self.write_u8(2)
-                    .and_then(|_| self.write_u8(0)) // var 0: trace id
+                    .and_then(|_| self.write_u8(1)) // key 1: trace id
                     .and_then(|_| self.write_u8(24)) // 3 u64 ids:
                     .and_then(|_| self.write_be_u64(trace.span_id))
                     .and_then(|_| self.write_be_u64(trace.parent_id))
                     .and_then(|_| self.write_be_u64(trace.trace_id))
-                    .and_then(|_| self.write(&[1, 1, trace.flags])) // var 1: flags
+                    .and_then(|_| self.write(&[2, 1, trace.flags])) // key 2: flags
             }
         }
     }
@@ -170,11 +211,41 @@ pub trait MuxWriter: FrameWriter {
 
 impl<W: FrameWriter> MuxWriter for W {}
 
+/// Appends a framed Tmsg to `buf`, reserving room for all of it first.
+pub fn frame_tmsg(buf: &mut Vec<u8>, tag: &Tag, msg: &Tmsg) -> IoResult<()> {
+    buf.reserve(4 + msg.encoded_len());
+    buf.write_mux_framed_tmsg(tag, msg)
+}
+
+/// Appends a framed Rmsg to `buf`, reserving room for all of it first.
+pub fn frame_rmsg(buf: &mut Vec<u8>, tag: &Tag, msg: &Rmsg) -> IoResult<()> {
+    buf.reserve(4 + msg.encoded_len());
+    buf.write_mux_framed_rmsg(tag, msg)
+}
+
+/// Appends a fragment (see `write_mux_fragment`) to `buf`.
+pub fn frame_fragment(buf: &mut Vec<u8>, msg_type: i8, tag: &Tag, more: bool, payload: &[u8]) -> IoResult<()> {
+    buf.reserve(8 + payload.len());
+    buf.write_mux_fragment(msg_type, tag, more, payload)
+}
+
+/// Writes to a connection shared by a session's threads.  `f` appends
+/// frames to the buffer given by `Transport::write_frame`, which is
+/// written in one call and flushed before the lock is released.
+pub fn write_locked<T: Transport, F: FnOnce(&mut Vec<u8>) -> IoResult<()>>(
+    writer: &Mutex<T>,
+    f: F
+) -> IoResult<()> {
+    let mut w = writer.lock().unwrap();
+    w.write_frame(f).and_then(|_| w.flush())
+}
+
 #[cfg(test)]
 mod test {
     use std::old_io::MemWriter;
-    use proto::{Tmsg, Tag};
-    use super::MuxWriter;
+    use misc::{Context, Dentry, Dtab, Header, Trace};
+    use proto::{Tmsg, Rmsg, Tag};
+    use super::{MuxWriter, frame_tmsg, frame_rmsg, frame_fragment};
 
     fn encode_frame(tag: Tag, msg: Tmsg) -> Vec<u8> {
         let mut buf = MemWriter::new();
@@ -193,4 +264,56 @@ mod test {
             66, 65, 68, // msg: BAD
             ]);
     }
+
+    #[test]
+    fn test_frame() {
+        let msg = Tmsg::Dispatch(vec![Context::new(b"k".to_vec(), b"val".to_vec())], "/dst".to_string(),
+                                 Dtab::empty(), b"mom".to_vec());
+        let mut buf = Vec::new();
+        frame_tmsg(&mut buf, &Tag(0, 0, 1), &msg).unwrap();
+        assert_eq!(buf, encode_frame(Tag(0, 0, 1), msg));
+
+        let mut buf = Vec::new();
+        frame_rmsg(&mut buf, &Tag(0, 0, 1), &Rmsg::Ping).unwrap();
+        frame_fragment(&mut buf, 2, &Tag(0, 0, 1), true, b"mom").unwrap();
+        assert_eq!(buf, vec![0, 0, 0, 4, 0xbf, 0, 0, 1, 0, 0, 0, 7, 2, 0x80, 0, 1, 109, 111, 109]);
+    }
+
+    #[test]
+    fn test_encoded_len() {
+        let trace = Some(Trace { span_id: 1, parent_id: 2, trace_id: 3, flags: 4 });
+        let contexts = vec![Context::new(b"k".to_vec(), b"val".to_vec())];
+        let dtab = Dtab(vec![Dentry::new("/a".to_string(), "/b".to_string())]);
+        let tmsgs = vec![
+            Tmsg::Req(None, b"mom".to_vec()),
+            Tmsg::Req(trace, b"mom".to_vec()),
+            Tmsg::Dispatch(contexts.clone(), "/dst".to_string(), dtab, b"mom".to_vec()),
+            Tmsg::Drain,
+            Tmsg::Ping,
+            Tmsg::Discarded(Tag(0, 1, 2), "BAD".to_string()),
+            Tmsg::Lease(0, 30),
+            Tmsg::Init(1, vec![Header::new(b"k".to_vec(), b"v".to_vec())]),
+        ];
+        for msg in tmsgs.iter() {
+            assert_eq!(encode_frame(Tag(0, 0, 1), msg.clone()).len(), 4 + msg.encoded_len());
+        }
+
+        let rmsgs = vec![
+            Rmsg::ReqOk(b"mom".to_vec()),
+            Rmsg::ReqError("BAD".to_string()),
+            Rmsg::ReqNack,
+            Rmsg::DispatchOk(contexts.clone(), b"mom".to_vec()),
+            Rmsg::DispatchError(contexts.clone(), "BAD".to_string()),
+            Rmsg::DispatchNack(contexts),
+            Rmsg::Drain,
+            Rmsg::Ping,
+            Rmsg::Init(1, Vec::new()),
+            Rmsg::Err("BAD".to_string()),
+        ];
+        for msg in rmsgs.iter() {
+            let mut buf = MemWriter::new();
+            buf.write_mux_rmsg(&Tag(0, 0, 1), msg).unwrap();
+            assert_eq!(buf.into_inner().len(), msg.encoded_len());
+        }
+    }
 }
@@ -0,0 +1,333 @@
+//! Mutation fuzzing of the decoder.
+//!
+//! Each target starts from a corpus of valid encodings (the vectors in
+//! `codec.rs`, plus one of each message type, and Thrift calls and
+//! exceptions in both protocols) and decodes random
+//! mutations of them: bit flips, overwritten bytes, truncations, and
+//! length prefixes inflated to their maximum.  Decoding must never panic,
+//! and whatever decodes must survive re-encoding unchanged.
+//!
+//! Fragment streams are also fed through `FrameBuf` and `Reassembler`
+//! with small limits, which must hold: whatever mutated fragments claim,
+//! nothing reassembled outgrows them.
+//!
+//! Runs are seeded, so failures reproduce.  Set `MUX_FUZZ_ITERATIONS` to
+//! run longer than the default, and `MUX_FUZZ_SEED` to explore elsewhere.
+
+#![allow(unstable)]
+
+extern crate mux;
+
+use std::os;
+use std::old_io::{BufReader, InvalidInput};
+use std::rand::{Rng, SeedableRng, XorShiftRng};
+use std::thread::Thread;
+
+use mux::{Tag, Msg, Tmsg, Rmsg, MuxReader, MuxWriter};
+use mux::framebuf::FrameBuf;
+use mux::misc::{Context, Dentry, Dtab, Header, Trace};
+use mux::stream::{Frame, Reassembler};
+use mux::thrift::{self, ApplicationException, MessageType, Protocol, ThriftMessage,
+                  ThriftReader, ThriftWriter};
+
+static TDISPATCH_BUF: &'static [u8] = &[
+    0, 0, 0, 65, // frame size
+    2, // TDISPATCH
+    4, 7, 9, // tag
+    0, 2, // 2 contexts
+    0, 4, 1, 2, 3, 4, // context 0 key
+    0, 2, 6, 7, // context 0 val
+    0, 2, 3, 4, // context 1 key
+    0, 3, 6, 7, 8, // context 1 val
+    0, 4, '/' as u8, 66, 65, 68, // dst: "/BAD"
+    0, 1, // dtab: /BAD => /DAD
+    0, 4, '/' as u8, 66, 65, 68,
+    0, 4, '/' as u8, 68, 65, 68,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19];
+
+static RDISPATCH_BUF: &'static [u8] = &[
+    0x00, 0x00, 0x00, 0x0b, // frame
+    0xfe, // rdispatch
+    0x01, 0x02, 0x03, // tag
+    0x00, // status
+    0x00, 0x00, // contexts
+    0x6e, 0x6f, 0x70, 0x65]; // "nope"
+
+static TAG: Tag = Tag(4, 7, 9);
+
+// limits small enough for mutated fragment streams to run into.
+static MAX_MESSAGE: usize = 256;
+static MAX_PARTIAL: usize = 2;
+
+fn iterations() -> usize {
+    os::getenv("MUX_FUZZ_ITERATIONS").and_then(|n| n.parse()).unwrap_or(2000)
+}
+
+fn rng() -> XorShiftRng {
+    let seed = os::getenv("MUX_FUZZ_SEED").and_then(|n| n.parse()).unwrap_or(1);
+    SeedableRng::from_seed([seed, 0x193a6754, 0xa8a7d469, 0x97830e05])
+}
+
+fn trace() -> Option<Trace> {
+    Some(Trace { span_id: 1, parent_id: 2, trace_id: 3, flags: 4 })
+}
+
+fn contexts() -> Vec<Context> {
+    vec![Context::new(b"k".to_vec(), b"v".to_vec()), Context::new(Vec::new(), b"empty".to_vec())]
+}
+
+fn tmsg_corpus() -> Vec<Vec<u8>> {
+    let msgs = vec![
+        Tmsg::Req(None, b"body".to_vec()),
+        Tmsg::Req(trace(), b"body".to_vec()),
+        Tmsg::Dispatch(contexts(), "/s".to_string(),
+                       Dtab(vec![Dentry::new("/s".to_string(), "/$/inet/x/1".to_string())]),
+                       b"body".to_vec()),
+        Tmsg::Drain,
+        Tmsg::Ping,
+        Tmsg::Discarded(TAG, "gone".to_string()),
+        Tmsg::Lease(0, 1000),
+        Tmsg::Init(1, vec![Header::new(b"tls".to_vec(), b"off".to_vec())]),
+    ];
+    let mut corpus = vec![TDISPATCH_BUF.to_vec()];
+    for msg in msgs.iter() {
+        let mut buf = Vec::new();
+        buf.write_mux_framed_tmsg(&TAG, msg).unwrap();
+        corpus.push(buf);
+    }
+    corpus
+}
+
+fn rmsg_corpus() -> Vec<Vec<u8>> {
+    let msgs = vec![
+        Rmsg::ReqOk(b"body".to_vec()),
+        Rmsg::ReqError("failed".to_string()),
+        Rmsg::ReqNack,
+        Rmsg::DispatchOk(contexts(), b"body".to_vec()),
+        Rmsg::DispatchError(contexts(), "failed".to_string()),
+        Rmsg::DispatchNack(contexts()),
+        Rmsg::Drain,
+        Rmsg::Ping,
+        Rmsg::Init(1, vec![Header::new(b"tls".to_vec(), b"on".to_vec())]),
+        Rmsg::Err("unknown".to_string()),
+    ];
+    let mut corpus = vec![RDISPATCH_BUF.to_vec()];
+    for msg in msgs.iter() {
+        let mut buf = Vec::new();
+        buf.write_mux_framed_rmsg(&TAG, msg).unwrap();
+        corpus.push(buf);
+    }
+    corpus
+}
+
+/// A Tdispatch whose header is split across fragments, and fragmented
+/// Tdispatch and Rdispatch messages interleaved with each other and a ping.
+fn fragment_corpus() -> Vec<Vec<u8>> {
+    let mut header = Vec::new();
+    header.write_mux_tmsg_msg(&Tmsg::Dispatch(contexts(), "/s".to_string(), Dtab::empty(), Vec::new())).unwrap();
+
+    let mut split = Vec::new();
+    split.write_mux_fragment(2, &TAG, true, header.slice_to(3)).unwrap();
+    split.write_mux_fragment(2, &TAG, true, header.slice_from(3)).unwrap();
+    split.write_mux_fragment(2, &TAG, true, b"bo").unwrap();
+    split.write_mux_fragment(2, &TAG, false, b"dy").unwrap();
+
+    let mut interleaved = Vec::new();
+    interleaved.write_mux_fragment(2, &TAG, true, header.as_slice()).unwrap();
+    interleaved.write_mux_fragment(-2, &Tag(0, 0, 2), true, &[0, 0, 0, b'b']).unwrap();
+    interleaved.write_mux_framed_tmsg(&Tag(0, 0, 3), &Tmsg::Ping).unwrap();
+    interleaved.write_mux_fragment(2, &TAG, false, b"body").unwrap();
+    interleaved.write_mux_fragment(-2, &Tag(0, 0, 2), false, b"ody").unwrap();
+
+    vec![split, interleaved]
+}
+
+fn trace_corpus() -> Vec<Vec<u8>> {
+    [None, trace()].iter().map(|t| {
+        let mut buf = Vec::new();
+        buf.write_mux_trace(t).unwrap();
+        buf
+    }).collect()
+}
+
+fn thrift_corpus() -> Vec<Vec<u8>> {
+    let call = ThriftMessage::new("getUser".to_string(), MessageType::Call, 300, vec![0]);
+    let ex = ApplicationException { message: "nope".to_string(), kind: 1 };
+    let mut corpus = Vec::new();
+    for &p in [Protocol::Binary, Protocol::Compact].iter() {
+        corpus.push(thrift::encode(p, &call));
+        let mut buf = Vec::new();
+        buf.write_thrift_exception(p, &ex).unwrap();
+        corpus.push(buf);
+    }
+    corpus
+}
+
+/// Applies one to four random mutations.
+fn mutate<R: Rng>(rng: &mut R, seed: &[u8]) -> Vec<u8> {
+    let mut bytes = seed.to_vec();
+    for _ in range(0, rng.gen_range(1, 5)) {
+        if bytes.is_empty() {
+            bytes.push(rng.gen());
+            continue;
+        }
+        let i = rng.gen_range(0, bytes.len());
+        match rng.gen_range(0u8, 6) {
+            0 => bytes[i] ^= 1u8 << rng.gen_range(0usize, 8),
+            1 => bytes[i] = rng.gen(),
+            2 => bytes.truncate(i),
+            3 => {
+                // inflate what may be a length prefix.
+                for j in range(i, std::cmp::min(i + rng.gen_range(2, 5), bytes.len())) {
+                    bytes[j] = 0xff;
+                }
+            },
+            4 => bytes.insert(i, rng.gen()),
+            _ => { bytes.remove(i); },
+        }
+    }
+    bytes
+}
+
+/// Decodes mutations of each seed with `check`, on a thread of its own so
+/// that a panic can be reported along with its input.
+fn fuzz(corpus: Vec<Vec<u8>>, check: fn(&[u8])) {
+    let mut rng = rng();
+    for _ in range(0, iterations()) {
+        let seed = rng.choose(corpus.as_slice()).unwrap();
+        let input = mutate(&mut rng, seed.as_slice());
+        let copy = input.clone();
+        if Thread::scoped(move|| check(copy.as_slice())).join().is_err() {
+            panic!("decoding panicked on {:?}", input);
+        }
+    }
+}
+
+fn check_tmsg(bytes: &[u8]) {
+    match BufReader::new(bytes).read_mux_framed_tmsg() {
+        Err(_) => (),
+        Ok((tag, msg)) => {
+            let mut buf = Vec::new();
+            buf.write_mux_framed_tmsg(&tag, &msg).unwrap();
+            assert_eq!(BufReader::new(buf.as_slice()).read_mux_framed_tmsg().unwrap(), (tag, msg));
+        },
+    }
+}
+
+fn check_rmsg(bytes: &[u8]) {
+    match BufReader::new(bytes).read_mux_framed_rmsg() {
+        Err(_) => (),
+        Ok((tag, msg)) => {
+            let mut buf = Vec::new();
+            buf.write_mux_framed_rmsg(&tag, &msg).unwrap();
+            assert_eq!(BufReader::new(buf.as_slice()).read_mux_framed_rmsg().unwrap(), (tag, msg));
+        },
+    }
+}
+
+fn encoded_len(msg: &Msg) -> usize {
+    match *msg {
+        Msg::Tx(_, ref tmsg) => tmsg.encoded_len(),
+        Msg::Rx(_, ref rmsg) => rmsg.encoded_len(),
+    }
+}
+
+fn check_fragments(bytes: &[u8]) {
+    let mut frames = FrameBuf::new(BufReader::new(bytes));
+    let mut fragments = Reassembler::new()
+        .max_message(MAX_MESSAGE)
+        .max_partial(MAX_PARTIAL)
+        .max_reassembled(2 * MAX_MESSAGE);
+    // unfragmented messages are bounded by the input instead.
+    let bound = std::cmp::max(MAX_MESSAGE, bytes.len());
+    let mut streamed = 0;
+    loop {
+        // odd tags' bodies are streamed.
+        let frame = fragments.read(&mut frames, |msg| match *msg {
+            Msg::Tx(ref tag, _) | Msg::Rx(ref tag, _) => tag.to_u32() % 2 == 1,
+        });
+        match frame {
+            Err(_) => break,
+            Ok(Frame::Whole(msg)) | Ok(Frame::Start(msg)) => assert!(encoded_len(&msg) <= bound),
+            Ok(Frame::Body(_, body, _)) => streamed += body.len(),
+        }
+        assert!(streamed <= bytes.len());
+    }
+}
+
+fn check_trace(bytes: &[u8]) {
+    match BufReader::new(bytes).read_mux_trace() {
+        Err(_) => (),
+        Ok(trace) => {
+            let mut buf = Vec::new();
+            buf.write_mux_trace(&trace).unwrap();
+            assert_eq!(BufReader::new(buf.as_slice()).read_mux_trace().unwrap(), trace);
+        },
+    }
+}
+
+fn check_thrift(bytes: &[u8]) {
+    for &p in [Protocol::Binary, Protocol::Compact].iter() {
+        match thrift::decode(p, bytes) {
+            Err(_) => (),
+            Ok(msg) => assert_eq!(thrift::decode(p, thrift::encode(p, &msg).as_slice()).unwrap(), msg),
+        }
+        // exceptions are only checked for panics: unknown fields are dropped.
+        let _ = BufReader::new(bytes).read_thrift_exception(p);
+    }
+}
+
+#[test]
+fn fuzz_read_mux_framed_tmsg() {
+    fuzz(tmsg_corpus(), check_tmsg);
+}
+
+#[test]
+fn fuzz_read_mux_framed_rmsg() {
+    fuzz(rmsg_corpus(), check_rmsg);
+}
+
+#[test]
+fn fuzz_reassemble() {
+    fuzz(fragment_corpus(), check_fragments);
+}
+
+#[test]
+fn fuzz_read_mux_trace() {
+    fuzz(trace_corpus(), check_trace);
+}
+
+#[test]
+fn fuzz_thrift() {
+    fuzz(thrift_corpus(), check_thrift);
+}
+
+#[test]
+fn fuzz_oversized_lengths() {
+    // a frame claiming 4GB is refused before anything is allocated for it.
+    let frame = [0xff, 0xff, 0xff, 0xff, 1, 0, 0, 1];
+    assert_eq!(BufReader::new(&frame).read_mux_framed_tmsg().unwrap_err().kind, InvalidInput);
+
+    // as is an init header claiming more than the frame holds.
+    let init = [0, 0, 0, 14, 68, 0, 0, 1, 0, 1, 0xff, 0xff, 0xff, 0xff, 0, 0, 0, 0];
+    assert_eq!(BufReader::new(&init).read_mux_framed_tmsg().unwrap_err().kind, InvalidInput);
+}
+
+#[test]
+fn fuzz_endless_fragments() {
+    // a message that never ends fails once it outgrows the limit, as do
+    // more fragmented tags than are allowed.
+    let mut endless = Vec::new();
+    for _ in range(0, 100) {
+        endless.write_mux_fragment(2, &TAG, true, &[0; 16]).unwrap();
+    }
+    let mut tags = Vec::new();
+    for i in range(0, 100) {
+        tags.write_mux_fragment(2, &Tag(0, 0, i), true, &[0, 0]).unwrap();
+    }
+    for bytes in [endless, tags].iter() {
+        let mut frames = FrameBuf::new(BufReader::new(bytes.as_slice()));
+        let mut fragments = Reassembler::new().max_message(MAX_MESSAGE).max_partial(MAX_PARTIAL);
+        assert_eq!(fragments.read(&mut frames, |_| false).unwrap_err().kind, InvalidInput);
+    }
+}